@@ -13,9 +13,19 @@ mod tests {
             message: Some(activity::Activity {
                 activity_type: "event".to_string(),
                 content: "{\"subject\":\"Allen\",\"act\":\"love\",\"object\":\"rust\"}".to_string(),
+                compaction_key: String::new(),
+                idempotency_key: String::new(),
+                expires_at: 0,
+                deliver_at: 0,
+                reply_to: String::new(),
+                blob: vec![],
+                blob_url: String::new(),
+                audience: None,
+                sender: String::new(),
             }),
 
             receivers: vec!["gandum".to_string(), "00".to_string()],
+            quantity: 0,
         });
 
         let response = client.active(request).await.expect("error request");
@@ -0,0 +1,39 @@
+use super::command::Command;
+
+/// commands only admins run
+pub const ADMIN_COMMANDS: &[&str] = &["/broadcast"];
+
+/// what a session may do, `Admin` when its upgrade request carried the admin token
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    User,
+    Admin,
+}
+
+/// consulted before a websocket session runs a command
+pub trait CommandAuthorizer: Send + Sync {
+    fn permits(&self, role: Role, command: &Command) -> bool;
+}
+
+/// `ADMIN_COMMANDS` are for admins alone, the rest for everyone
+#[derive(Debug, Default)]
+pub struct AdminCommands;
+
+impl CommandAuthorizer for AdminCommands {
+    fn permits(&self, role: Role, command: &Command) -> bool {
+        role == Role::Admin || !ADMIN_COMMANDS.contains(&command.name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn admin_commands_are_kept_for_admins() {
+        let broadcast = Command::Broadcast("all hands".to_string());
+        assert!(AdminCommands.permits(Role::Admin, &broadcast));
+        assert!(!AdminCommands.permits(Role::User, &broadcast));
+        assert!(AdminCommands.permits(Role::User, &Command::List));
+    }
+}
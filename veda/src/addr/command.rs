@@ -0,0 +1,860 @@
+use std::{collections::HashSet, fmt, str::FromStr, time::Duration};
+
+use super::ws::validate_name;
+use crate::{
+    constants::{HISTORY_PAGE_LIMIT, MAX_NAME_LEN, MISSED_LIMIT},
+    entity::{Platform, ACTIVITY_FIELDS},
+};
+
+/// 客户端发来的`/xxx`命令,参数在解析时已经校验过
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Login(String),
+    Switch(String),
+    Platform(Platform),
+    Join(String),
+    Leave(String),
+    /// `/post <room> [^<id>] <text>`,`^<id>`回复房间里的一条消息
+    Post {
+        room: String,
+        text: String,
+        reply_to: Option<String>,
+    },
+    /// `None`清除状态
+    Status(Option<String>),
+    Clear,
+    Missed(usize),
+    /// 读取自己队列中的一条消息,不确认也不删除
+    Get(String),
+    /// 读取自己队列中这个unix毫秒及之后写入的消息,不确认也不删除
+    SinceTs(u64),
+    /// 读取自己队列中`before_id`之前的`count`条消息,最新的在前,用于向上翻页
+    HistoryPage {
+        before_id: String,
+        count: usize,
+    },
+    /// 推送在各接收者处的投递状态,参数为推送时返回的push id
+    StatusOf(HashSet<String>),
+    /// 实时收到自己发出的推送的投递和确认,不用轮询`/status-of`
+    Track(bool),
+    Badges,
+    Cursor,
+    BatchSize(usize),
+    /// 应用在前台,更快地投递消息
+    Foreground,
+    /// 应用在后台,放慢投递,每次投递更多消息
+    Background,
+    Caps(HashSet<String>),
+    /// `None`恢复发送所有字段
+    Fields(Option<HashSet<String>>),
+    Public(bool),
+    /// 是否收到自己发到房间的消息
+    Echo(bool),
+    Follow(String),
+    Unfollow(String),
+    /// 不再收到该用户发送的消息
+    Mute(String),
+    Unmute(String),
+    Presence(String),
+    List,
+    /// 发送消息给所有连接中的session
+    Broadcast(String),
+    /// 列出自己登录的所有session
+    Sessions,
+    /// 断开自己登录的另一个session
+    Revoke(usize),
+    Whoami,
+    Time,
+    Diag,
+    /// 推送一条消息到自己的队列,确认投递链路正常
+    SelfTest,
+    /// 立即发出等待发送给自己的帧,`true`时丢弃
+    Flush(bool),
+    /// 服务器ping的间隔,`None`时不ping,由客户端自己发心跳
+    Keepalive(Option<Duration>),
+    Hello,
+    Subscriptions,
+    /// 列出支持的命令、协议版本以及格式
+    Help,
+    /// 客户端遇到的错误,json对象
+    Report(serde_json::Value),
+    /// 回复服务器的请求
+    Reply {
+        req_id: String,
+        reply: serde_json::Value,
+    },
+}
+
+/// every command the parser knows, as `/help` lists them
+pub const COMMANDS: &[&str] = &[
+    "/login",
+    "/switch",
+    "/platform",
+    "/join",
+    "/leave",
+    "/post",
+    "/status",
+    "/clear",
+    "/missed",
+    "/get",
+    "/since-ts",
+    "/history-page",
+    "/status-of",
+    "/track",
+    "/badges",
+    "/cursor",
+    "/batch-size",
+    "/foreground",
+    "/background",
+    "/caps",
+    "/fields",
+    "/public",
+    "/echo",
+    "/follow",
+    "/unfollow",
+    "/mute",
+    "/unmute",
+    "/presence",
+    "/list",
+    "/broadcast",
+    "/sessions",
+    "/revoke",
+    "/whoami",
+    "/time",
+    "/diag",
+    "/selftest",
+    "/flush",
+    "/keepalive",
+    "/hello",
+    "/subscriptions",
+    "/help",
+    "/report",
+    "/reply",
+];
+
+impl Command {
+    /// what the client types to run it
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Login(_) => "/login",
+            Command::Switch(_) => "/switch",
+            Command::Platform(_) => "/platform",
+            Command::Join(_) => "/join",
+            Command::Leave(_) => "/leave",
+            Command::Post { .. } => "/post",
+            Command::Status(_) => "/status",
+            Command::Clear => "/clear",
+            Command::Missed(_) => "/missed",
+            Command::Get(_) => "/get",
+            Command::SinceTs(_) => "/since-ts",
+            Command::HistoryPage { .. } => "/history-page",
+            Command::StatusOf(_) => "/status-of",
+            Command::Track(_) => "/track",
+            Command::Badges => "/badges",
+            Command::Cursor => "/cursor",
+            Command::BatchSize(_) => "/batch-size",
+            Command::Foreground => "/foreground",
+            Command::Background => "/background",
+            Command::Caps(_) => "/caps",
+            Command::Fields(_) => "/fields",
+            Command::Public(_) => "/public",
+            Command::Echo(_) => "/echo",
+            Command::Follow(_) => "/follow",
+            Command::Unfollow(_) => "/unfollow",
+            Command::Mute(_) => "/mute",
+            Command::Unmute(_) => "/unmute",
+            Command::Presence(_) => "/presence",
+            Command::List => "/list",
+            Command::Broadcast(_) => "/broadcast",
+            Command::Sessions => "/sessions",
+            Command::Revoke(_) => "/revoke",
+            Command::Whoami => "/whoami",
+            Command::Time => "/time",
+            Command::Diag => "/diag",
+            Command::SelfTest => "/selftest",
+            Command::Flush(_) => "/flush",
+            Command::Keepalive(_) => "/keepalive",
+            Command::Hello => "/hello",
+            Command::Subscriptions => "/subscriptions",
+            Command::Help => "/help",
+            Command::Report(_) => "/report",
+            Command::Reply { .. } => "/reply",
+        }
+    }
+}
+
+/// renamed commands, the old name still runs the command but is deprecated
+pub const ALIASES: &[(&str, &str)] = &[("/batchsize", "/batch-size")];
+
+/// the deprecated name `text` starts with and the name replacing it
+pub fn deprecated(text: &str) -> Option<(&'static str, &'static str)> {
+    let name = text.trim().split(char::is_whitespace).next()?;
+    ALIASES.iter().find(|(old, _)| *old == name).copied()
+}
+
+/// 命令无法解析的原因,原样发给客户端
+#[derive(Debug, PartialEq)]
+pub struct CommandError(pub String);
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl CommandError {
+    fn new(reason: impl Into<String>) -> Self {
+        Self(reason.into())
+    }
+}
+
+impl FromStr for Command {
+    type Err = CommandError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let text = text.trim();
+        let (name, args) = match text.split_once(char::is_whitespace) {
+            Some((name, args)) => (name, args.trim()),
+            None => (text, ""),
+        };
+        let name = deprecated(name).map_or(name, |(_, renamed)| renamed);
+        let command = match name {
+            "/login" => Command::Login(required(args, "name is required")?),
+            "/switch" => Command::Switch(required(args, "name is required")?),
+            "/platform" => {
+                if args.is_empty() {
+                    return Err(CommandError::new("platform is required"));
+                }
+                let platform = serde_json::from_str(args)
+                    .map_err(|e| CommandError(format!("invalid platform: {}", e)))?;
+                Command::Platform(platform)
+            }
+            "/join" => Command::Join(room(required(args, "room is required")?)?),
+            "/leave" => Command::Leave(room(required(args, "room is required")?)?),
+            "/post" => {
+                let (name, text) = first_arg(args)?;
+                let (reply_to, text) = reply_target(text.trim_start());
+                match (name, text.trim()) {
+                    (Some(name), text) if !text.is_empty() => Command::Post {
+                        room: room(name)?,
+                        text: text.to_owned(),
+                        reply_to,
+                    },
+                    _ => return Err(CommandError::new("room and text are required")),
+                }
+            }
+            "/status" => Command::Status(optional(args)?),
+            "/clear" => Command::Clear,
+            "/missed" => match optional(args)? {
+                Some(limit) => Command::Missed(
+                    limit
+                        .parse()
+                        .map_err(|_| CommandError::new("limit must be a number"))?,
+                ),
+                None => Command::Missed(MISSED_LIMIT),
+            },
+            "/get" => Command::Get(stream_id(args)?),
+            "/since-ts" => match args.parse() {
+                Ok(ms) => Command::SinceTs(ms),
+                Err(_) if args.is_empty() => {
+                    return Err(CommandError::new("timestamp is required"))
+                }
+                Err(_) => return Err(CommandError::new("timestamp must be unix milliseconds")),
+            },
+            "/history-page" => match args.split_once(char::is_whitespace) {
+                Some((before_id, count)) => Command::HistoryPage {
+                    before_id: stream_id(before_id)?,
+                    count: match count.trim().parse::<usize>() {
+                        Ok(count) if count > 0 => count.min(HISTORY_PAGE_LIMIT),
+                        _ => return Err(CommandError::new("count must be a positive number")),
+                    },
+                },
+                None => return Err(CommandError::new("before id and count are required")),
+            },
+            "/status-of" => match list(args) {
+                ids if ids.is_empty() => return Err(CommandError::new("push id is required")),
+                ids => Command::StatusOf(ids),
+            },
+            "/track" => match args {
+                "on" => Command::Track(true),
+                "off" => Command::Track(false),
+                _ => return Err(CommandError::new("on or off is required")),
+            },
+            "/badges" => Command::Badges,
+            "/cursor" => Command::Cursor,
+            "/batch-size" => match args.parse::<usize>() {
+                Ok(size) if size > 0 => Command::BatchSize(size),
+                _ => return Err(CommandError::new("batch size must be a positive number")),
+            },
+            "/foreground" => Command::Foreground,
+            "/background" => Command::Background,
+            "/caps" => Command::Caps(list(args)),
+            "/fields" => {
+                let fields = list(args);
+                if let Some(unknown) = fields
+                    .iter()
+                    .find(|field| !ACTIVITY_FIELDS.contains(&field.as_str()))
+                {
+                    return Err(CommandError(format!("unknown field {}", unknown)));
+                }
+                // no field at all sends every field again
+                Command::Fields(Some(fields).filter(|fields| !fields.is_empty()))
+            }
+            "/public" => match args {
+                "on" => Command::Public(true),
+                "off" => Command::Public(false),
+                _ => return Err(CommandError::new("on or off is required")),
+            },
+            "/echo" => match args {
+                "on" => Command::Echo(true),
+                "off" => Command::Echo(false),
+                _ => return Err(CommandError::new("on or off is required")),
+            },
+            "/follow" => Command::Follow(required(args, "username is required")?),
+            "/unfollow" => Command::Unfollow(required(args, "username is required")?),
+            "/mute" => Command::Mute(required(args, "username is required")?),
+            "/unmute" => Command::Unmute(required(args, "username is required")?),
+            "/presence" => Command::Presence(required(args, "name is required")?),
+            "/list" => Command::List,
+            "/broadcast" => Command::Broadcast(required(args, "message is required")?),
+            "/sessions" => Command::Sessions,
+            "/revoke" => match args.parse() {
+                Ok(id) => Command::Revoke(id),
+                Err(_) if args.is_empty() => {
+                    return Err(CommandError::new("session id is required"))
+                }
+                Err(_) => return Err(CommandError::new("session id must be a number")),
+            },
+            "/whoami" => Command::Whoami,
+            "/time" => Command::Time,
+            "/diag" => Command::Diag,
+            "/selftest" => Command::SelfTest,
+            "/flush" => match args {
+                "" => Command::Flush(false),
+                "discard" => Command::Flush(true),
+                _ => return Err(CommandError::new("only discard is allowed")),
+            },
+            "/keepalive" => match args {
+                "off" => Command::Keepalive(None),
+                secs => match secs.parse::<u64>() {
+                    Ok(secs) if secs > 0 => Command::Keepalive(Some(Duration::from_secs(secs))),
+                    _ => return Err(CommandError::new("seconds or off is required")),
+                },
+            },
+            "/hello" => Command::Hello,
+            "/subscriptions" => Command::Subscriptions,
+            "/help" => Command::Help,
+            "/reply" => match first_arg(args)? {
+                (Some(req_id), reply) if !reply.trim().is_empty() => Command::Reply {
+                    req_id,
+                    reply: serde_json::from_str(reply)
+                        .map_err(|e| CommandError(format!("invalid reply: {}", e)))?,
+                },
+                _ => return Err(CommandError::new("request id and reply are required")),
+            },
+            "/report" => match serde_json::from_str(args) {
+                Ok(report @ serde_json::Value::Object(_)) => Command::Report(report),
+                _ if args.is_empty() => return Err(CommandError::new("report is required")),
+                _ => return Err(CommandError::new("report must be a json object")),
+            },
+            _ => return Err(CommandError(format!("unknown command: {:?}", text))),
+        };
+        Ok(command)
+    }
+}
+
+/// the first argument, either a bare word or a double quoted string, and what follows it
+fn first_arg(args: &str) -> Result<(Option<String>, &str), CommandError> {
+    let args = args.trim_start();
+    if let Some(quoted) = args.strip_prefix('"') {
+        return match quoted.split_once('"') {
+            Some((arg, rest)) => Ok((Some(arg.to_owned()).filter(|arg| !arg.is_empty()), rest)),
+            None => Err(CommandError::new("unterminated quote")),
+        };
+    }
+    Ok(match args.split_once(char::is_whitespace) {
+        Some((arg, rest)) => (Some(arg.to_owned()), rest),
+        None => (Some(args.to_owned()).filter(|arg| !arg.is_empty()), ""),
+    })
+}
+
+/// a leading `^<id>` names the entry the text answers
+fn reply_target(text: &str) -> (Option<String>, &str) {
+    match text
+        .strip_prefix('^')
+        .map(|rest| rest.split_once(char::is_whitespace).unwrap_or((rest, "")))
+    {
+        Some((id, rest)) if !id.is_empty() => (Some(id.to_owned()), rest),
+        _ => (None, text),
+    }
+}
+
+/// the whole argument, which may be quoted to keep its spaces
+fn optional(args: &str) -> Result<Option<String>, CommandError> {
+    if !args.starts_with('"') {
+        return Ok(Some(args.to_owned()).filter(|arg| !arg.is_empty()));
+    }
+    match first_arg(args)? {
+        (arg, rest) if rest.trim().is_empty() => Ok(arg),
+        _ => Err(CommandError::new("unexpected text after the quote")),
+    }
+}
+
+fn required(args: &str, missing: &str) -> Result<String, CommandError> {
+    optional(args)?.ok_or_else(|| CommandError::new(missing))
+}
+
+/// a room name, held to the same rules as user names since it ends up in redis keys
+fn room(name: String) -> Result<String, CommandError> {
+    validate_name(&name, "room", MAX_NAME_LEN).map_err(CommandError)?;
+    Ok(name)
+}
+
+/// a full stream entry id, `<millis>-<sequence>`
+fn stream_id(args: &str) -> Result<String, CommandError> {
+    if args.is_empty() {
+        return Err(CommandError::new("stream id is required"));
+    }
+    let digits = |part: &str| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit());
+    match args.split_once('-') {
+        Some((ms, seq)) if digits(ms) && digits(seq) => Ok(args.to_owned()),
+        _ => Err(CommandError(format!("invalid stream id {}", args))),
+    }
+}
+
+/// comma or space separated names
+fn list(args: &str) -> HashSet<String> {
+    args.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|item| !item.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Command, String> {
+        text.parse::<Command>().map_err(|e| e.to_string())
+    }
+
+    fn set(items: &[&str]) -> HashSet<String> {
+        items.iter().map(|item| item.to_string()).collect()
+    }
+
+    #[test]
+    fn get_takes_a_stream_id() {
+        assert_eq!(
+            parse("/get 1526919030474-55"),
+            Ok(Command::Get("1526919030474-55".into()))
+        );
+        assert_eq!(parse("/get"), Err("stream id is required".into()));
+        for id in ["1526919030474", "1-", "-0", "1-0-0", "+", "a-b"] {
+            assert_eq!(
+                parse(&format!("/get {}", id)),
+                Err(format!("invalid stream id {}", id))
+            );
+        }
+    }
+
+    #[test]
+    fn since_ts_takes_unix_milliseconds() {
+        assert_eq!(
+            parse("/since-ts 1526919030474"),
+            Ok(Command::SinceTs(1526919030474))
+        );
+        assert_eq!(parse("/since-ts"), Err("timestamp is required".into()));
+        assert_eq!(
+            parse("/since-ts 1526919030474-0"),
+            Err("timestamp must be unix milliseconds".into())
+        );
+    }
+
+    #[test]
+    fn history_page_takes_a_stream_id_and_a_count() {
+        assert_eq!(
+            parse("/history-page 1526919030474-55 20"),
+            Ok(Command::HistoryPage {
+                before_id: "1526919030474-55".into(),
+                count: 20
+            })
+        );
+        assert_eq!(
+            parse("/history-page 1526919030474-55 100000"),
+            Ok(Command::HistoryPage {
+                before_id: "1526919030474-55".into(),
+                count: HISTORY_PAGE_LIMIT
+            })
+        );
+        assert_eq!(
+            parse("/history-page 1526919030474-55"),
+            Err("before id and count are required".into())
+        );
+        assert_eq!(
+            parse("/history-page 1526919030474-55 0"),
+            Err("count must be a positive number".into())
+        );
+        assert_eq!(
+            parse("/history-page latest 20"),
+            Err("invalid stream id latest".into())
+        );
+    }
+
+    #[test]
+    fn commands_without_arguments() {
+        let cases = vec![
+            ("/clear", Command::Clear),
+            ("/badges", Command::Badges),
+            ("/cursor", Command::Cursor),
+            ("/list", Command::List),
+            ("/sessions", Command::Sessions),
+            ("/whoami", Command::Whoami),
+            ("/time", Command::Time),
+            ("/diag", Command::Diag),
+            ("/selftest", Command::SelfTest),
+            ("/hello", Command::Hello),
+            ("/subscriptions", Command::Subscriptions),
+            ("/help", Command::Help),
+        ];
+        for (text, command) in cases {
+            assert_eq!(parse(text), Ok(command));
+        }
+    }
+
+    #[test]
+    fn status_of_takes_push_ids() {
+        let ids: HashSet<String> = ["00c0ffee", "0badf00d"]
+            .iter()
+            .map(|id| id.to_string())
+            .collect();
+        assert_eq!(
+            parse("/status-of 00c0ffee,0badf00d"),
+            Ok(Command::StatusOf(ids))
+        );
+        assert_eq!(parse("/status-of"), Err("push id is required".into()));
+    }
+
+    #[test]
+    fn revoke_takes_a_session_id() {
+        assert_eq!(parse("/revoke 42"), Ok(Command::Revoke(42)));
+        assert_eq!(parse("/revoke"), Err("session id is required".into()));
+        assert_eq!(
+            parse("/revoke tieria"),
+            Err("session id must be a number".into())
+        );
+    }
+
+    #[test]
+    fn commands_with_a_name() {
+        assert_eq!(
+            parse("/login setsuna"),
+            Ok(Command::Login("setsuna".into()))
+        );
+        assert_eq!(
+            parse("/switch  lockon "),
+            Ok(Command::Switch("lockon".into()))
+        );
+        assert_eq!(parse("/join ptolemy"), Ok(Command::Join("ptolemy".into())));
+        assert_eq!(
+            parse("/leave \"ptolemy\""),
+            Ok(Command::Leave("ptolemy".into()))
+        );
+        assert_eq!(
+            parse("/follow tieria"),
+            Ok(Command::Follow("tieria".into()))
+        );
+        assert_eq!(
+            parse("/unfollow tieria"),
+            Ok(Command::Unfollow("tieria".into()))
+        );
+        assert_eq!(parse("/mute graham"), Ok(Command::Mute("graham".into())));
+        assert_eq!(
+            parse("/unmute graham"),
+            Ok(Command::Unmute("graham".into()))
+        );
+        assert_eq!(
+            parse("/presence allelujah"),
+            Ok(Command::Presence("allelujah".into()))
+        );
+
+        assert_eq!(parse("/login"), Err("name is required".into()));
+        assert_eq!(parse("/switch   "), Err("name is required".into()));
+        assert_eq!(parse("/join"), Err("room is required".into()));
+        assert_eq!(parse("/leave \"\""), Err("room is required".into()));
+        assert_eq!(parse("/follow"), Err("username is required".into()));
+        assert_eq!(parse("/mute"), Err("username is required".into()));
+        assert_eq!(parse("/presence"), Err("name is required".into()));
+        assert_eq!(parse("/join \"ptolemy"), Err("unterminated quote".into()));
+        assert_eq!(
+            parse("/join \"ptolemy\" trinity"),
+            Err("unexpected text after the quote".into())
+        );
+        assert_eq!(
+            parse("/join \"the ptolemy\""),
+            Err("` ` is not allowed in a room".into())
+        );
+        assert_eq!(
+            parse("/leave room:x"),
+            Err("`:` is not allowed in a room".into())
+        );
+        assert_eq!(
+            parse(&format!("/join {}", "a".repeat(MAX_NAME_LEN + 1))),
+            Err(format!("room is longer than {} characters", MAX_NAME_LEN))
+        );
+    }
+
+    #[test]
+    fn post_takes_a_room_and_the_text() {
+        assert_eq!(
+            parse("/post ptolemy trans-am  ready"),
+            Ok(Command::Post {
+                room: "ptolemy".into(),
+                text: "trans-am  ready".into(),
+                reply_to: None,
+            })
+        );
+        assert_eq!(
+            parse("/post \"ptolemy\" ready"),
+            Ok(Command::Post {
+                room: "ptolemy".into(),
+                text: "ready".into(),
+                reply_to: None,
+            })
+        );
+        assert_eq!(
+            parse("/post \"the ptolemy\" ready"),
+            Err("` ` is not allowed in a room".into())
+        );
+        assert_eq!(
+            parse("/post ptolemy ^1-0 roger that"),
+            Ok(Command::Post {
+                room: "ptolemy".into(),
+                text: "roger that".into(),
+                reply_to: Some("1-0".into()),
+            })
+        );
+        assert_eq!(
+            parse("/post ptolemy ^1-0"),
+            Err("room and text are required".into())
+        );
+        assert_eq!(parse("/post"), Err("room and text are required".into()));
+        assert_eq!(
+            parse("/post ptolemy"),
+            Err("room and text are required".into())
+        );
+        assert_eq!(
+            parse("/post \"ptolemy\""),
+            Err("room and text are required".into())
+        );
+        assert_eq!(
+            parse("/post \"ptolemy ready"),
+            Err("unterminated quote".into())
+        );
+    }
+
+    #[test]
+    fn commands_with_a_value() {
+        assert_eq!(parse("/status"), Ok(Command::Status(None)));
+        assert_eq!(
+            parse("/status \"in orbit\""),
+            Ok(Command::Status(Some("in orbit".into())))
+        );
+        assert_eq!(parse("/missed"), Ok(Command::Missed(MISSED_LIMIT)));
+        assert_eq!(parse("/missed 3"), Ok(Command::Missed(3)));
+        assert_eq!(parse("/missed three"), Err("limit must be a number".into()));
+        assert_eq!(parse("/batch-size 10"), Ok(Command::BatchSize(10)));
+        assert_eq!(
+            parse("/batch-size 0"),
+            Err("batch size must be a positive number".into())
+        );
+        assert_eq!(
+            parse("/batch-size"),
+            Err("batch size must be a positive number".into())
+        );
+        assert_eq!(parse("/public on"), Ok(Command::Public(true)));
+        assert_eq!(parse("/public off"), Ok(Command::Public(false)));
+        assert_eq!(parse("/public yes"), Err("on or off is required".into()));
+        assert_eq!(parse("/track on"), Ok(Command::Track(true)));
+        assert_eq!(parse("/track off"), Ok(Command::Track(false)));
+        assert_eq!(parse("/track"), Err("on or off is required".into()));
+        assert_eq!(parse("/flush"), Ok(Command::Flush(false)));
+        assert_eq!(parse("/flush discard"), Ok(Command::Flush(true)));
+        assert_eq!(parse("/flush all"), Err("only discard is allowed".into()));
+        assert_eq!(
+            parse("/keepalive 15"),
+            Ok(Command::Keepalive(Some(Duration::from_secs(15))))
+        );
+        assert_eq!(parse("/keepalive off"), Ok(Command::Keepalive(None)));
+        assert_eq!(
+            parse("/keepalive 0"),
+            Err("seconds or off is required".into())
+        );
+    }
+
+    #[test]
+    fn commands_with_a_list() {
+        assert_eq!(parse("/caps"), Ok(Command::Caps(HashSet::new())));
+        assert_eq!(
+            parse("/caps a, b c"),
+            Ok(Command::Caps(set(&["a", "b", "c"])))
+        );
+        assert_eq!(
+            parse("/fields activity,compaction_key"),
+            Ok(Command::Fields(Some(set(&["activity", "compaction_key"]))))
+        );
+        assert_eq!(parse("/fields"), Ok(Command::Fields(None)));
+        assert_eq!(
+            parse("/fields activity,body"),
+            Err("unknown field body".into())
+        );
+    }
+
+    #[test]
+    fn platform_is_json() {
+        let platform = r#"/platform {"platform":"Web","device":{"device_name":"chrome"}}"#;
+        assert!(matches!(
+            parse(platform),
+            Ok(Command::Platform(Platform::Web(_)))
+        ));
+        assert_eq!(parse("/platform"), Err("platform is required".into()));
+        assert!(parse("/platform {")
+            .unwrap_err()
+            .starts_with("invalid platform"));
+    }
+
+    #[test]
+    fn report_is_a_json_object() {
+        assert_eq!(
+            parse(r#"/report {"kind":"render","line":3}"#),
+            Ok(Command::Report(
+                serde_json::json!({"kind": "render", "line": 3})
+            ))
+        );
+        assert_eq!(parse("/report"), Err("report is required".into()));
+        assert_eq!(
+            parse("/report [1]"),
+            Err("report must be a json object".into())
+        );
+        assert_eq!(
+            parse("/report {"),
+            Err("report must be a json object".into())
+        );
+    }
+
+    #[test]
+    fn reply_takes_the_request_id_and_json() {
+        assert_eq!(
+            parse(r#"/reply 7 {"confirmed":true}"#),
+            Ok(Command::Reply {
+                req_id: "7".into(),
+                reply: serde_json::json!({"confirmed": true}),
+            })
+        );
+        assert_eq!(
+            parse("/reply 7 \"later\""),
+            Ok(Command::Reply {
+                req_id: "7".into(),
+                reply: serde_json::json!("later"),
+            })
+        );
+        assert_eq!(
+            parse("/reply"),
+            Err("request id and reply are required".into())
+        );
+        assert_eq!(
+            parse("/reply 7"),
+            Err("request id and reply are required".into())
+        );
+        assert!(parse("/reply 7 {")
+            .unwrap_err()
+            .starts_with("invalid reply"));
+    }
+
+    #[test]
+    fn an_old_name_runs_the_renamed_command() {
+        assert_eq!(parse("/batchsize 10"), parse("/batch-size 10"));
+        assert_eq!(parse("/batchsize 0"), parse("/batch-size 0"));
+        assert_eq!(
+            deprecated("/batchsize 10"),
+            Some(("/batchsize", "/batch-size"))
+        );
+        assert_eq!(deprecated("/batch-size 10"), None);
+        assert_eq!(deprecated("/batchsizes 10"), None);
+        for (_, renamed) in ALIASES {
+            assert!(COMMANDS.contains(renamed), "{}", renamed);
+        }
+    }
+
+    #[test]
+    fn every_command_is_listed() {
+        let platform = r#"{"platform":"Web","device":{"device_name":"chrome"}}"#;
+        let samples = vec![
+            "/login setsuna".to_string(),
+            "/switch lockon".to_string(),
+            format!("/platform {}", platform),
+            "/join ptolemy".to_string(),
+            "/leave ptolemy".to_string(),
+            "/post ptolemy ready".to_string(),
+            "/status".to_string(),
+            "/clear".to_string(),
+            "/missed".to_string(),
+            "/get 1-0".to_string(),
+            "/since-ts 0".to_string(),
+            "/history-page 1-0 20".to_string(),
+            "/status-of 00c0ffee".to_string(),
+            "/track on".to_string(),
+            "/badges".to_string(),
+            "/cursor".to_string(),
+            "/batch-size 2".to_string(),
+            "/foreground".to_string(),
+            "/background".to_string(),
+            "/caps".to_string(),
+            "/fields".to_string(),
+            "/public on".to_string(),
+            "/echo off".to_string(),
+            "/follow tieria".to_string(),
+            "/unfollow tieria".to_string(),
+            "/mute graham".to_string(),
+            "/unmute graham".to_string(),
+            "/presence tieria".to_string(),
+            "/list".to_string(),
+            "/broadcast all hands".to_string(),
+            "/sessions".to_string(),
+            "/revoke 1".to_string(),
+            "/whoami".to_string(),
+            "/time".to_string(),
+            "/diag".to_string(),
+            "/selftest".to_string(),
+            "/flush".to_string(),
+            "/keepalive 15".to_string(),
+            "/hello".to_string(),
+            "/subscriptions".to_string(),
+            "/help".to_string(),
+            "/report {}".to_string(),
+            "/reply 1 {}".to_string(),
+        ];
+        let names: Vec<&str> = samples
+            .iter()
+            .map(|sample| sample.parse::<Command>().unwrap().name())
+            .collect();
+        assert_eq!(names, COMMANDS);
+        // nothing listed is unknown to the parser
+        for name in COMMANDS {
+            let err = name.parse::<Command>().err().map(|e| e.to_string());
+            assert!(
+                !err.unwrap_or_default().starts_with("unknown command"),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_commands_are_rejected() {
+        assert_eq!(
+            parse("/warp  now"),
+            Err("unknown command: \"/warp  now\"".into())
+        );
+        assert_eq!(parse("/"), Err("unknown command: \"/\"".into()));
+    }
+}
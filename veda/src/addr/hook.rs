@@ -0,0 +1,91 @@
+use std::{collections::HashMap, sync::Arc};
+
+use serde_json::Value;
+
+use crate::entity::Activity;
+
+/// 投递前改写消息,例如把发送者的id换成显示名称、加上本地化的文本
+pub trait DeliveryHook: Send + Sync {
+    /// 返回`None`时不投递这条消息,默认原样投递
+    fn apply(&self, activity: Activity) -> Option<Activity> {
+        Some(activity)
+    }
+}
+
+/// 依次经过每一个hook,被丢弃后不再经过后面的
+pub fn run_hooks(hooks: &[Arc<dyn DeliveryHook>], activity: Activity) -> Option<Activity> {
+    hooks
+        .iter()
+        .try_fold(activity, |activity, hook| hook.apply(activity))
+}
+
+/// 带有发送者的消息加上`sender_name`,名称由`id=name`配置
+#[derive(Debug, Default)]
+pub struct DisplayNames {
+    names: HashMap<String, String>,
+}
+
+impl DisplayNames {
+    /// 不是`id=name`的配置被忽略
+    pub fn new(pairs: &[String]) -> Self {
+        Self {
+            names: pairs
+                .iter()
+                .filter_map(|pair| pair.split_once('='))
+                .map(|(id, name)| (id.trim().to_owned(), name.trim().to_owned()))
+                .collect(),
+        }
+    }
+}
+
+impl DeliveryHook for DisplayNames {
+    fn apply(&self, mut activity: Activity) -> Option<Activity> {
+        if let Some(name) = activity.sender.as_ref().and_then(|id| self.names.get(id)) {
+            activity
+                .extra
+                .insert("sender_name".to_owned(), Value::String(name.clone()));
+        }
+        Some(activity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// keeps nothing from `sender`
+    struct Silence(&'static str);
+
+    impl DeliveryHook for Silence {
+        fn apply(&self, activity: Activity) -> Option<Activity> {
+            (activity.sender.as_deref() != Some(self.0)).then_some(activity)
+        }
+    }
+
+    /// the default, changes nothing
+    struct Noop;
+
+    impl DeliveryHook for Noop {}
+
+    #[test]
+    fn hooks_run_in_order_until_one_drops() {
+        let hooks: Vec<Arc<dyn DeliveryHook>> = vec![
+            Arc::new(Noop),
+            Arc::new(DisplayNames::new(&[
+                "setsuna=Setsuna F. Seiei".to_string(),
+                "malformed".to_string(),
+            ])),
+            Arc::new(Silence("ali")),
+        ];
+        let from = |sender: &str| Activity {
+            sender: Some(sender.to_string()),
+            ..Default::default()
+        };
+
+        let named = run_hooks(&hooks, from("setsuna")).unwrap();
+        assert_eq!(named.extra["sender_name"], "Setsuna F. Seiei");
+        assert!(run_hooks(&hooks, from("tieria")).unwrap().extra.is_empty());
+        assert_eq!(run_hooks(&hooks, from("ali")), None);
+        assert_eq!(run_hooks(&[], from("ali")), Some(from("ali")));
+    }
+}
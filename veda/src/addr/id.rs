@@ -0,0 +1,151 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::warn;
+use rand::{prelude::ThreadRng, Rng};
+
+/// websocket session id
+pub type SessionId = usize;
+
+/// hands out the id of every new websocket session
+pub trait IdGenerator {
+    fn next_id(&mut self) -> SessionId;
+}
+
+/// random ids, what a single node uses unless told otherwise
+#[derive(Debug, Default)]
+pub struct Random {
+    rng: ThreadRng,
+}
+
+impl IdGenerator for Random {
+    fn next_id(&mut self) -> SessionId {
+        self.rng.gen::<SessionId>()
+    }
+}
+
+/// 1, 2, 3... restarts from 1 with the process
+#[derive(Debug, Default)]
+pub struct Sequential {
+    last: SessionId,
+}
+
+impl IdGenerator for Sequential {
+    fn next_id(&mut self) -> SessionId {
+        self.last += 1;
+        self.last
+    }
+}
+
+/// 2021-01-01T00:00:00Z, keeps the timestamp part small
+const SNOWFLAKE_EPOCH_MILLIS: u64 = 1_609_459_200_000;
+const NODE_BITS: u32 = 10;
+const SEQUENCE_BITS: u32 = 12;
+const MAX_SEQUENCE: u64 = (1 << SEQUENCE_BITS) - 1;
+
+/// milliseconds, node and sequence packed into 64 bits,
+/// unique across restarts and across nodes with distinct `node` ids.
+/// More than 4096 ids in a millisecond borrow the following milliseconds
+/// instead of waiting for them, and a clock that goes back is ignored
+/// until it catches up with the last id handed out.
+#[derive(Debug)]
+pub struct Snowflake {
+    node: u64,
+    last_millis: u64,
+    sequence: u64,
+    /// the clock is behind `last_millis`, warned once until it catches up
+    behind: bool,
+}
+
+impl Snowflake {
+    /// only the lowest 10 bits of `node` are kept
+    pub fn new(node: u16) -> Self {
+        Self {
+            node: u64::from(node) & ((1 << NODE_BITS) - 1),
+            last_millis: 0,
+            sequence: 0,
+            behind: false,
+        }
+    }
+
+    fn millis() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default()
+            .saturating_sub(SNOWFLAKE_EPOCH_MILLIS)
+    }
+}
+
+impl IdGenerator for Snowflake {
+    fn next_id(&mut self) -> SessionId {
+        let now = Self::millis();
+        if now < self.last_millis && !self.behind {
+            warn!(
+                "clock went back {}ms, session ids go on from the last one handed out",
+                self.last_millis - now
+            );
+        }
+        self.behind = now < self.last_millis;
+        if now > self.last_millis {
+            self.last_millis = now;
+            self.sequence = 0;
+        } else {
+            self.sequence = (self.sequence + 1) & MAX_SEQUENCE;
+            if self.sequence == 0 {
+                // sequence exhausted, borrow the next millisecond rather than block the actor
+                self.last_millis += 1;
+            }
+        }
+        ((self.last_millis << (NODE_BITS + SEQUENCE_BITS))
+            | (self.node << SEQUENCE_BITS)
+            | self.sequence) as SessionId
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, thread, time::Duration};
+
+    use super::*;
+
+    #[test]
+    fn sequential_starts_after_zero() {
+        let mut ids = Sequential::default();
+        assert_eq!((ids.next_id(), ids.next_id()), (1, 2));
+    }
+
+    #[test]
+    fn snowflake_ids_are_unique_across_restarts() {
+        let mut seen = HashSet::new();
+        for _ in 0..3 {
+            // every run is a fresh process, which takes at least a millisecond to come back
+            let mut ids = Snowflake::new(7);
+            for _ in 0..10_000 {
+                assert!(seen.insert(ids.next_id()));
+            }
+            // 10_000 ids borrow at most 2 milliseconds ahead
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        // other nodes never hand out the same ids
+        let mut other = Snowflake::new(8);
+        for _ in 0..10_000 {
+            assert!(seen.insert(other.next_id()));
+        }
+    }
+
+    #[test]
+    fn snowflake_ids_keep_growing_when_the_clock_goes_back() {
+        let mut ids = Snowflake::new(7);
+        // as if the last id was handed out a second from now
+        ids.last_millis = Snowflake::millis() + 1_000;
+        let mut last = ids.next_id();
+        assert!(ids.behind);
+        for _ in 0..10_000 {
+            let id = ids.next_id();
+            assert!(id > last);
+            last = id;
+        }
+        assert!(ids.last_millis > Snowflake::millis());
+    }
+}
@@ -0,0 +1,382 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use redis::{
+    streams::{StreamId, StreamKey, StreamReadReply},
+    ErrorKind, RedisError, RedisResult, ToRedisArgs, Value,
+};
+
+use super::store::{Connector, StreamInfo, StreamStore};
+use crate::entity::Activity;
+
+/// an in-memory `StreamStore`, every clone shares the same data
+#[derive(Clone, Default)]
+pub struct MemoryStore(Arc<Mutex<Memory>>);
+
+/// stream entries ordered by (millis, sequence)
+type Entries = BTreeMap<(u64, u64), HashMap<String, String>>;
+
+#[derive(Default)]
+pub struct Memory {
+    pub streams: HashMap<String, Entries>,
+    last_ids: HashMap<String, (u64, u64)>,
+    pub strings: HashMap<String, String>,
+    pub hashes: HashMap<String, HashMap<String, String>>,
+    pub sets: HashMap<String, HashSet<String>>,
+    pub zsets: HashMap<String, HashMap<String, i64>>,
+    /// keys given a time to live, they are never actually dropped
+    pub ttls: HashMap<String, Duration>,
+    /// how many of the following commands fail
+    failures: usize,
+    clock: u64,
+}
+
+fn parse_id(id: &str) -> (u64, u64) {
+    let mut parts = id.splitn(2, '-');
+    let ms = parts.next().and_then(|ms| ms.parse().ok()).unwrap_or(0);
+    let seq = parts.next().and_then(|seq| seq.parse().ok()).unwrap_or(0);
+    (ms, seq)
+}
+
+fn format_id((ms, seq): (u64, u64)) -> String {
+    format!("{}-{}", ms, seq)
+}
+
+/// `-` and `+` are the smallest and the greatest ids
+fn parse_bound(id: &str) -> (u64, u64) {
+    match id {
+        "-" => (0, 0),
+        "+" => (u64::MAX, u64::MAX),
+        id => parse_id(id),
+    }
+}
+
+fn stream_id((id, fields): (&(u64, u64), &HashMap<String, String>)) -> StreamId {
+    StreamId {
+        id: format_id(*id),
+        map: fields
+            .iter()
+            .map(|(k, v)| (k.clone(), Value::Data(v.as_bytes().to_vec())))
+            .collect(),
+    }
+}
+
+impl MemoryStore {
+    pub fn lock(&self) -> MutexGuard<'_, Memory> {
+        self.0.lock().unwrap()
+    }
+
+    /// make the next `times` commands fail with an io error
+    pub fn fail_next(&self, times: usize) {
+        self.lock().failures = times;
+    }
+
+    /// ids of the entries in a stream, in order
+    pub fn stream_ids(&self, key: &str) -> Vec<String> {
+        self.lock()
+            .streams
+            .get(key)
+            .map(|entries| entries.keys().copied().map(format_id).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn hget(&self, key: &str, field: &str) -> Option<String> {
+        self.lock()
+            .hashes
+            .get(key)
+            .and_then(|hash| hash.get(field).cloned())
+    }
+
+    fn check(&self) -> RedisResult<MutexGuard<'_, Memory>> {
+        let mut memory = self.lock();
+        if memory.failures > 0 {
+            memory.failures -= 1;
+            return Err((ErrorKind::IoError, "injected failure").into());
+        }
+        Ok(memory)
+    }
+}
+
+impl Connector for MemoryStore {
+    fn connect(&self) -> RedisResult<Box<dyn StreamStore>> {
+        Ok(Box::new(self.clone()))
+    }
+}
+
+impl StreamStore for MemoryStore {
+    fn xadd(&mut self, key: &str, activity: &Activity) -> RedisResult<String> {
+        let mut memory = self.check()?;
+        // ids keep growing even within the same millisecond
+        memory.clock += 1;
+        let id = (memory.clock, 0);
+        let fields = activity
+            .to_redis_args()
+            .chunks(2)
+            .map(|kv| {
+                (
+                    String::from_utf8_lossy(&kv[0]).into_owned(),
+                    String::from_utf8_lossy(&kv[1]).into_owned(),
+                )
+            })
+            .collect();
+        memory
+            .streams
+            .entry(key.to_owned())
+            .or_default()
+            .insert(id, fields);
+        memory.last_ids.insert(key.to_owned(), id);
+        Ok(format_id(id))
+    }
+
+    fn xadd_batch(&mut self, entries: &[(String, &Activity)]) -> RedisResult<Vec<String>> {
+        drop(self.check()?);
+        entries
+            .iter()
+            .map(|(key, activity)| self.xadd(key, activity))
+            .collect()
+    }
+
+    fn xinfo(&mut self, key: &str) -> RedisResult<Option<StreamInfo>> {
+        let memory = self.check()?;
+        Ok(memory.streams.get(key).map(|entries| StreamInfo {
+            length: entries.len(),
+            last_generated_id: format_id(memory.last_ids[key]),
+        }))
+    }
+
+    fn xread(
+        &mut self,
+        keys: &[String],
+        ids: &[String],
+        count: usize,
+        _block: Option<usize>,
+    ) -> RedisResult<StreamReadReply> {
+        let memory = self.check()?;
+        let mut reply = StreamReadReply::default();
+        for (key, id) in keys.iter().zip(ids) {
+            let after = parse_id(id);
+            let ids: Vec<StreamId> = memory
+                .streams
+                .get(key)
+                .into_iter()
+                .flat_map(|entries| entries.iter())
+                .filter(|(id, _)| **id > after)
+                .take(count)
+                .map(stream_id)
+                .collect();
+            if !ids.is_empty() {
+                reply.keys.push(StreamKey {
+                    key: key.clone(),
+                    ids,
+                });
+            }
+        }
+        Ok(reply)
+    }
+
+    fn xdel(&mut self, key: &str, ids: &[String]) -> RedisResult<usize> {
+        let mut memory = self.check()?;
+        let entries = match memory.streams.get_mut(key) {
+            Some(entries) => entries,
+            None => return Ok(0),
+        };
+        Ok(ids
+            .iter()
+            .filter(|id| entries.remove(&parse_id(id)).is_some())
+            .count())
+    }
+
+    fn xlen(&mut self, key: &str) -> RedisResult<usize> {
+        let memory = self.check()?;
+        Ok(memory.streams.get(key).map_or(0, BTreeMap::len))
+    }
+
+    fn xtrim_minid(&mut self, key: &str, min_id: &str) -> RedisResult<usize> {
+        let mut memory = self.check()?;
+        let entries = match memory.streams.get_mut(key) {
+            Some(entries) => entries,
+            None => return Ok(0),
+        };
+        let kept = entries.split_off(&parse_id(min_id));
+        Ok(std::mem::replace(entries, kept).len())
+    }
+
+    fn xrange(
+        &mut self,
+        key: &str,
+        start: &str,
+        end: &str,
+        count: usize,
+    ) -> RedisResult<Vec<StreamId>> {
+        let memory = self.check()?;
+        let (start, end) = (parse_bound(start), parse_bound(end));
+        if start > end {
+            return Ok(vec![]);
+        }
+        Ok(memory
+            .streams
+            .get(key)
+            .into_iter()
+            .flat_map(|entries| entries.range(start..=end))
+            .take(count)
+            .map(stream_id)
+            .collect())
+    }
+
+    fn xrevrange(
+        &mut self,
+        key: &str,
+        end: &str,
+        start: &str,
+        count: usize,
+    ) -> RedisResult<Vec<StreamId>> {
+        let memory = self.check()?;
+        let (start, end) = (parse_bound(start), parse_bound(end));
+        if start > end {
+            return Ok(vec![]);
+        }
+        Ok(memory
+            .streams
+            .get(key)
+            .into_iter()
+            .flat_map(|entries| entries.range(start..=end).rev())
+            .take(count)
+            .map(stream_id)
+            .collect())
+    }
+
+    fn get(&mut self, key: &str) -> RedisResult<Option<String>> {
+        let memory = self.check()?;
+        Ok(memory.strings.get(key).cloned())
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> RedisResult<()> {
+        let mut memory = self.check()?;
+        memory.strings.insert(key.to_owned(), value.to_owned());
+        Ok(())
+    }
+
+    fn expire(&mut self, key: &str, ttl: Duration) -> RedisResult<()> {
+        self.check()?.ttls.insert(key.to_owned(), ttl);
+        Ok(())
+    }
+
+    fn del(&mut self, key: &str) -> RedisResult<()> {
+        let mut memory = self.check()?;
+        memory.ttls.remove(key);
+        memory.strings.remove(key);
+        memory.hashes.remove(key);
+        memory.sets.remove(key);
+        memory.zsets.remove(key);
+        if memory.streams.remove(key).is_some() {
+            memory.last_ids.remove(key);
+        }
+        Ok(())
+    }
+
+    fn incr(&mut self, key: &str) -> RedisResult<i64> {
+        let mut memory = self.check()?;
+        let count = match memory.strings.get(key) {
+            Some(value) => value.parse::<i64>().map_err(|_| {
+                RedisError::from((ErrorKind::ResponseError, "value is not an integer"))
+            })?,
+            None => 0,
+        } + 1;
+        memory.strings.insert(key.to_owned(), count.to_string());
+        Ok(count)
+    }
+
+    fn hset(&mut self, key: &str, field: &str, value: &str) -> RedisResult<()> {
+        let mut memory = self.check()?;
+        memory
+            .hashes
+            .entry(key.to_owned())
+            .or_default()
+            .insert(field.to_owned(), value.to_owned());
+        Ok(())
+    }
+
+    fn hget(&mut self, key: &str, field: &str) -> RedisResult<Option<String>> {
+        drop(self.check()?);
+        Ok(MemoryStore::hget(self, key, field))
+    }
+
+    fn hdel(&mut self, key: &str, field: &str) -> RedisResult<()> {
+        let mut memory = self.check()?;
+        if let Some(hash) = memory.hashes.get_mut(key) {
+            hash.remove(field);
+        }
+        Ok(())
+    }
+
+    fn hgetall(&mut self, key: &str) -> RedisResult<HashMap<String, String>> {
+        let memory = self.check()?;
+        Ok(memory.hashes.get(key).cloned().unwrap_or_default())
+    }
+
+    fn sadd(&mut self, key: &str, member: &str) -> RedisResult<()> {
+        let mut memory = self.check()?;
+        memory
+            .sets
+            .entry(key.to_owned())
+            .or_default()
+            .insert(member.to_owned());
+        Ok(())
+    }
+
+    fn srem(&mut self, key: &str, member: &str) -> RedisResult<()> {
+        let mut memory = self.check()?;
+        if let Some(set) = memory.sets.get_mut(key) {
+            set.remove(member);
+        }
+        Ok(())
+    }
+
+    fn smembers(&mut self, key: &str) -> RedisResult<Vec<String>> {
+        let memory = self.check()?;
+        Ok(memory
+            .sets
+            .get(key)
+            .map(|set| set.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    fn sismember(&mut self, key: &str, member: &str) -> RedisResult<bool> {
+        let memory = self.check()?;
+        Ok(memory.sets.get(key).is_some_and(|set| set.contains(member)))
+    }
+
+    fn zadd(&mut self, key: &str, member: &str, score: i64) -> RedisResult<()> {
+        let mut memory = self.check()?;
+        memory
+            .zsets
+            .entry(key.to_owned())
+            .or_default()
+            .insert(member.to_owned(), score);
+        Ok(())
+    }
+
+    fn zrem(&mut self, key: &str, member: &str) -> RedisResult<bool> {
+        let mut memory = self.check()?;
+        Ok(memory
+            .zsets
+            .get_mut(key)
+            .is_some_and(|zset| zset.remove(member).is_some()))
+    }
+
+    fn zrangebyscore(&mut self, key: &str, max: i64) -> RedisResult<Vec<String>> {
+        let memory = self.check()?;
+        let mut members: Vec<(&String, &i64)> = memory
+            .zsets
+            .get(key)
+            .into_iter()
+            .flat_map(|zset| zset.iter())
+            .filter(|(_, score)| **score <= max)
+            .collect();
+        members.sort_by_key(|(_, score)| **score);
+        Ok(members.into_iter().map(|(m, _)| m.clone()).collect())
+    }
+}
@@ -1,20 +1,254 @@
+mod authz;
+mod command;
+mod hook;
+mod id;
+#[cfg(test)]
+mod memory;
+mod request;
 mod rs;
+mod schedule;
 mod seravee;
+mod shard;
+mod store;
+mod webhook;
 mod ws;
 
+use std::{io, time::Duration};
+
 use actix::{Actor, Addr};
-use actix_web::web::{self, Data};
-use redis::Client;
+use redis::{Client, ConnectionAddr, ConnectionInfo, ErrorKind, IntoConnectionInfo, RedisError};
+
+use crate::{
+    config::{Config, SessionIdMode},
+    constants::WEBHOOK_TIMEOUT,
+    entity::apns,
+    retry::RetryPolicy,
+};
+
+#[cfg(test)]
+pub(crate) use self::memory::*;
+pub(crate) use self::{authz::*, hook::*, id::*, request::*, rs::*, seravee::*, ws::*};
+
+/// credentials and transport of the redis connection, on top of what the url says
+#[derive(Debug, Default, Clone)]
+pub struct RedisAuth {
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub use_tls: bool,
+}
+
+/// tells a rejected password apart from an unreachable server
+fn redis_error(redis_url: &str, e: RedisError) -> io::Error {
+    let (kind, reason) = match e.kind() {
+        ErrorKind::AuthenticationFailed => {
+            (io::ErrorKind::PermissionDenied, "authentication failed")
+        }
+        ErrorKind::InvalidClientConfig => (io::ErrorKind::InvalidInput, "invalid configuration"),
+        _ if e.is_io_error() => (io::ErrorKind::ConnectionRefused, "cannot connect"),
+        _ => (io::ErrorKind::Other, "cannot set up the connection"),
+    };
+    io::Error::new(kind, format!("redis {}: {}: {}", redis_url, reason, e))
+}
 
-pub(crate) use self::{rs::*, seravee::*, ws::*};
+/// open a client and check the server accepts it before any actor uses it
+fn connect_redis(redis_url: &str, auth: &RedisAuth) -> io::Result<Client> {
+    let mut info: ConnectionInfo = redis_url
+        .into_connection_info()
+        .map_err(|e| redis_error(redis_url, e))?;
+    if auth.username.is_some() {
+        info.redis.username = auth.username.clone();
+    }
+    if auth.password.is_some() {
+        info.redis.password = auth.password.clone();
+    }
+    if auth.use_tls {
+        if let ConnectionAddr::Tcp(host, port) = info.addr {
+            info.addr = ConnectionAddr::TcpTls {
+                host,
+                port,
+                insecure: false,
+            };
+        }
+    }
+    let cli = Client::open(info).map_err(|e| redis_error(redis_url, e))?;
+    cli.get_connection()
+        .map_err(|e| redis_error(redis_url, e))?;
+    Ok(cli)
+}
+
+/// the redis actor, set up as `config` says
+pub fn init_redis(config: &Config) -> io::Result<Addr<Redis>> {
+    let auth = RedisAuth {
+        username: config.redis_username.clone(),
+        password: config.redis_password.clone(),
+        use_tls: config.redis_tls,
+    };
+    let cli = connect_redis(&config.redis_url, &auth)?;
+    let mut redis = Redis::new(cli)
+        .track_last_seen(config.track_last_seen)
+        .last_seen_interval(Duration::from_secs(config.last_seen_write_secs))
+        .offline_grace(Duration::from_secs(config.offline_grace_secs))
+        .delivery_rate(config.delivery_rate)
+        .delivery_retry(RetryPolicy {
+            max_attempts: config.delivery_retries + 1,
+            backoff: Duration::from_millis(config.delivery_backoff_ms),
+            timeout: Some(Duration::from_millis(config.delivery_timeout_ms))
+                .filter(|timeout| !timeout.is_zero()),
+        })
+        .stagger_reads(config.stagger_reads)
+        .node_id(config.node_id)
+        .store_client_reports(config.store_client_reports)
+        .webhook_retry(RetryPolicy {
+            max_attempts: config.webhook_retries + 1,
+            backoff: Duration::from_millis(config.webhook_backoff_ms),
+            timeout: Some(WEBHOOK_TIMEOUT),
+        })
+        .trial_chunk_size(config.trial_chunk_size)
+        .backlog_sampling(
+            Duration::from_secs(config.backlog_sample_secs),
+            config.backlog_warn_len,
+        )
+        .persist_mutes(config.persist_mutes)
+        .max_frame_bytes(config.max_frame_bytes)
+        .stream_shards(config.stream_shards)
+        .push_quota(
+            config.push_quota,
+            Duration::from_secs(config.push_quota_window_secs),
+        )
+        .poll_interval(Duration::from_millis(config.poll_interval_ms));
+    if config.apns_payloads {
+        redis = redis.render("IPhone", apns).render("IPad", apns);
+    }
+    if !config.display_names.is_empty() {
+        redis = redis.hook(DisplayNames::new(&config.display_names));
+    }
+    Ok(redis.start())
+}
 
-pub fn init_redis(redis_url: &str) -> Addr<Redis> {
-    let cli = Client::open(redis_url)
-        .expect(format!("unable to connect to redis:{}", redis_url).as_str());
-    Redis::new(cli).start()
+/// the websocket server, set up as `config` says
+pub fn init_websocket(config: &Config) -> Addr<Websocket> {
+    let websocket = match config.session_ids {
+        SessionIdMode::Random => Websocket::default(),
+        SessionIdMode::Sequential => Websocket::default().id_generator(Sequential::default()),
+        SessionIdMode::Snowflake => {
+            Websocket::default().id_generator(Snowflake::new(config.node_id))
+        }
+    };
+    websocket
+        .exclusive_names(config.exclusive_names)
+        .private_names(config.private_names)
+        .shards(config.session_shards)
+        .max_name_len(config.max_name_len)
+        .outbound_queue(config.outbound_queue_depth, config.outbound_overflow)
+        .presence_debounce(Duration::from_millis(config.presence_debounce_ms))
+        .max_sessions_per_user(config.max_sessions_per_user, config.session_limit)
+        .start()
 }
 
-pub fn add_websocket(cfg: &mut web::ServiceConfig) {
-    let addr = Websocket::default().start();
-    cfg.app_data(Data::new(addr));
+/// actors shared by the tests of the `addr` module
+#[cfg(test)]
+pub(crate) mod testing {
+    use std::sync::{Arc, Mutex};
+
+    use actix::{Actor, Context, Handler};
+
+    use super::{Ask, Kick, RequestError, WsMessage};
+
+    /// stands in for a websocket session, collecting every message it receives
+    #[derive(Clone, Default)]
+    pub struct Collector(pub Arc<Mutex<Vec<String>>>);
+
+    impl Collector {
+        pub fn received(&self) -> Vec<String> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    impl Actor for Collector {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<WsMessage> for Collector {
+        type Result = ();
+
+        fn handle(&mut self, msg: WsMessage, _: &mut Self::Context) {
+            self.0.lock().unwrap().push(msg.0);
+        }
+    }
+
+    impl Handler<Ask> for Collector {
+        type Result = Result<serde_json::Value, RequestError>;
+
+        fn handle(&mut self, msg: Ask, _: &mut Self::Context) -> Self::Result {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("asked: {}", msg.payload));
+            Err(RequestError::Timeout)
+        }
+    }
+
+    impl Handler<Kick> for Collector {
+        type Result = ();
+
+        fn handle(&mut self, msg: Kick, _: &mut Self::Context) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("kicked: {}", msg.reason));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    use super::*;
+
+    /// a redis server that rejects every `AUTH`
+    fn wrong_password_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf).unwrap();
+            stream
+                .write_all(b"-WRONGPASS invalid username-password pair or user is disabled.\r\n")
+                .unwrap();
+        });
+        format!("redis://127.0.0.1:{}", port)
+    }
+
+    #[test]
+    fn wrong_password_is_an_auth_error() {
+        let redis_url = wrong_password_server();
+        let auth = RedisAuth {
+            username: Some("veda".to_string()),
+            password: Some("wrong".to_string()),
+            use_tls: false,
+        };
+        let err = connect_redis(&redis_url, &auth).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(err.to_string().contains("authentication failed"), "{}", err);
+    }
+
+    #[test]
+    fn unreachable_server_is_a_connection_error() {
+        // nothing listens on the port once the listener is dropped
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let redis_url = format!("redis://127.0.0.1:{}", port);
+        let err = connect_redis(&redis_url, &RedisAuth::default()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        assert!(err.to_string().contains(&redis_url), "{}", err);
+    }
 }
@@ -0,0 +1,110 @@
+use std::{collections::HashMap, time::Duration};
+
+use actix::prelude::*;
+use futures::channel::oneshot;
+use serde::Serialize;
+use serde_json::Value;
+
+/// 服务器向客户端发起的请求,等待客户端用`/reply <req_id> <json>`回复
+#[derive(Message, Debug)]
+#[rtype(result = "Result<Value, RequestError>")]
+pub struct Ask {
+    pub payload: Value,
+    /// 超过时间没有回复时返回`RequestError::Timeout`
+    pub timeout: Duration,
+}
+
+/// 向指定的websocket session发起请求
+#[derive(Message, Debug)]
+#[rtype(result = "Result<Value, RequestError>")]
+pub struct AskSession {
+    /// websocket session id
+    pub id: usize,
+    pub payload: Value,
+    pub timeout: Duration,
+}
+
+/// 请求没有得到回复的原因
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RequestError {
+    /// 没有这个session
+    UnknownSession,
+    /// 客户端没有及时回复
+    Timeout,
+    /// session在回复前断开
+    Closed,
+}
+
+/// the frame a request is sent in
+#[derive(Serialize)]
+struct RequestFrame<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    req_id: &'a str,
+    payload: &'a Value,
+}
+
+/// requests a session sent to its client and is still waiting on
+#[derive(Default)]
+pub struct PendingRequests {
+    next: u64,
+    waiting: HashMap<String, oneshot::Sender<Value>>,
+}
+
+impl PendingRequests {
+    /// register a request, returns its id, the frame to send and where the reply arrives
+    pub fn open(&mut self, payload: &Value) -> (String, String, oneshot::Receiver<Value>) {
+        self.next += 1;
+        let req_id = self.next.to_string();
+        let frame = serde_json::to_string(&RequestFrame {
+            kind: "request",
+            req_id: &req_id,
+            payload,
+        })
+        .unwrap_or_default();
+        let (tx, rx) = oneshot::channel();
+        self.waiting.insert(req_id.clone(), tx);
+        (req_id, frame, rx)
+    }
+
+    /// hand the reply to whoever waits on `req_id`, `false` when nobody does
+    pub fn resolve(&mut self, req_id: &str, reply: Value) -> bool {
+        match self.waiting.remove(req_id) {
+            Some(tx) => tx.send(reply).is_ok(),
+            None => false,
+        }
+    }
+
+    /// stop waiting on `req_id`, a late reply is then unknown
+    pub fn cancel(&mut self, req_id: &str) {
+        self.waiting.remove(req_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_rt::test]
+    async fn a_reply_resolves_its_request() {
+        let mut requests = PendingRequests::default();
+        let (first, frame, rx) = requests.open(&serde_json::json!({"confirm": "purge"}));
+        let (second, _, _) = requests.open(&Value::Null);
+        assert_ne!(first, second);
+
+        let frame: Value = serde_json::from_str(&frame).unwrap();
+        assert_eq!(frame["type"], "request");
+        assert_eq!(frame["req_id"], first.as_str());
+        assert_eq!(frame["payload"]["confirm"], "purge");
+
+        assert!(requests.resolve(&first, serde_json::json!(true)));
+        assert_eq!(rx.await.unwrap(), serde_json::json!(true));
+        // each request takes one reply
+        assert!(!requests.resolve(&first, serde_json::json!(true)));
+        assert!(!requests.resolve("unknown", Value::Null));
+
+        requests.cancel(&second);
+        assert!(!requests.resolve(&second, Value::Null));
+    }
+}
@@ -1,35 +1,283 @@
 use actix::{prelude::*, Recipient};
 
-use std::{collections::HashMap, usize};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    future::Future,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use log::info;
-use redis::streams::{StreamId, StreamInfoStreamReply, StreamReadOptions};
+use actix_web_actors::ws::CloseCode;
+use chrono::{DateTime, TimeZone, Utc};
+use futures::{channel::mpsc::UnboundedSender, lock::Mutex, stream, StreamExt};
+use log::{debug, info, warn};
 use redis::{
-    streams::{StreamKey, StreamReadReply},
-    Client, Commands, Connection, RedisResult,
+    streams::{StreamId, StreamKey, StreamReadReply},
+    RedisError, RedisResult,
 };
+use serde::{Deserialize, Serialize};
 
-use super::WsMessage;
+use super::{
+    hook::{run_hooks, DeliveryHook},
+    request::{Ask, AskSession, RequestError},
+    schedule::ReadScheduler,
+    store::{Connector, StreamInfo, StreamStore},
+    webhook::{DeadLetter, PostActivity, WebhookSink},
+    Kick, WsMessage,
+};
 
 use crate::{
-    constants::{BLOCK_MILLIS, MESSAGE_INTERVAL},
-    entity::{Activity, Platform},
+    constants::{
+        BACKGROUND_INTERVAL, BACKLOG_SAMPLE_INTERVAL, BACKLOG_WARN_LEN, BADGE_COUNT, BADGE_ROOMS,
+        BLOB_CAPABILITY, BLOB_TTL, BLOCK_MILLIS, CLAIM_TTL, CLIENT_TIMEOUT, ERROR_RATE_THRESHOLD,
+        ERROR_RATE_WINDOW, EXPORT_LIMIT, FOREGROUND_INTERVAL, GATED_TYPES, HISTORY_PAGE_LIMIT,
+        IDEMPOTENCY_TTL, LAST_SEEN_WRITE_INTERVAL, MAX_FRAME_BYTES, MESSAGE_INTERVAL, MISSED_LIMIT,
+        NO_CURSOR, PUBLIC_TYPES, PUSH_QUOTA_WINDOW, QUOTA_EXCEEDED, READ_COUNT, READ_SLOTS,
+        RECEIPT_TTL, RECLAIM_IDLE, RECLAIM_INTERVAL, SCHEDULE_TICK, SINCE_LIMIT, SWEEP_INTERVAL,
+        TRIAL_CHUNK_SIZE, WEBHOOK_TIMEOUT,
+    },
+    entity::{Activity, Audience, DeliveryMode, FramingMode, Platform, Render, Room},
+    metrics::{ErrorRate, BACKLOG, DELIVERIES, REDIS_ERRORS},
+    retry::RetryPolicy,
+    throttle::Throttle,
+    trace,
 };
 
 pub struct Redis {
-    cli: Client,
-    sessions: HashMap<usize, Recipient<RedisOffline>>,
+    connector: Box<dyn Connector>,
+    sessions: HashMap<usize, SessionEntry>,
+    /// 是否维护`last-seen`有序集合,并定时清理过期session
+    track_last_seen: bool,
+    /// 心跳刷新`last-seen:{username}`的最短间隔
+    last_seen_interval: Duration,
+    /// 各用户最近一次写入`last-seen:{username}`的时间
+    last_seen_written: HashMap<String, Instant>,
+    /// 断开连接后等待重连的时间,期间用户重新上线可以复用redis session
+    offline_grace: Duration,
+    // pending.key: username
+    // pending.value: 等待下线的websocket session id以及下线任务
+    pending: HashMap<String, Vec<(usize, SpawnHandle)>>,
+    /// 每个session每秒最多投递的消息数量,0为不限制
+    delivery_rate: u32,
+    /// websocket session忙碌时重新发送的策略
+    delivery_retry: RetryPolicy,
+    /// 统一调度各session的读取,`None`时各session自行定时读取
+    scheduler: Option<ReadScheduler>,
+    /// 本节点的id,记录在`identity-node`中
+    node_id: u16,
+    /// 客户端`/report`的错误是否写入`client-errors`
+    store_client_reports: bool,
+    /// `/mute`的用户保存在`muted:{username}`,重新上线后仍然有效
+    persist_mutes: bool,
+    /// 配置了webhook的用户的消息同时POST到webhook
+    webhooks: Option<Addr<WebhookSink>>,
+    webhook_retry: RetryPolicy,
+    /// 一次推送每写入这么多接收者,就让其他消息先处理
+    trial_chunk_size: usize,
+    /// 一帧消息的大致字节数上限
+    max_frame_bytes: usize,
+    /// 每个用户的消息队列分成这么多个,1时不分片
+    stream_shards: usize,
+    /// 推送按到达的顺序写入,前一次写完之前后一次等待
+    trials: Arc<Mutex<()>>,
+    /// 统计在线用户队列积压的间隔
+    backlog_interval: Duration,
+    /// 积压超过这个数量的用户记录到日志
+    backlog_warn_len: usize,
+    /// 按session的平台改写发给客户端的消息,没有的平台原样发送
+    renders: HashMap<&'static str, Render>,
+    /// 投递前依次改写消息
+    hooks: Vec<Arc<dyn DeliveryHook>>,
+    /// 每个推送者在`push_quota_window`内最多推送的次数,0为不限制
+    push_quota: u32,
+    push_quota_window: Duration,
+    /// 新上线的session读取消息的间隔,`ReloadConfig`在运行时修改
+    poll_interval: Duration,
+    /// `/track on`的session id,实时收到自己发出的推送的回执
+    trackers: HashSet<usize>,
+}
+
+/// 在线的redis session
+struct SessionEntry {
+    name: String,
+    addr: Addr<RedisSession>,
+    /// 强制断开websocket session
+    kick: Recipient<Kick>,
+    /// 向websocket session的客户端发起请求
+    ask: Recipient<Ask>,
+    /// 由scheduler调度时所在的时间片
+    slot: Option<usize>,
 }
 
 impl Actor for Redis {
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if self.track_last_seen {
+            ctx.run_interval(SWEEP_INTERVAL, |_, ctx| ctx.notify(SweepStale));
+        }
+        if let Some(scheduler) = &self.scheduler {
+            ctx.run_interval(scheduler.tick_interval(), |act, _| act.dispatch_reads());
+        }
+        ctx.run_interval(self.backlog_interval, |_, ctx| ctx.notify(SampleBacklog));
+        ctx.run_interval(SCHEDULE_TICK, |_, ctx| ctx.notify(DeliverDue));
+        let dead_letter = ctx.address().recipient();
+        self.webhooks = Some(WebhookSink::new(self.webhook_retry.clone(), dead_letter).start());
+    }
 }
 impl Redis {
-    pub fn new(cli: Client) -> Self {
+    pub fn new(connector: impl Connector + 'static) -> Self {
         Self {
-            cli,
+            connector: Box::new(connector),
             sessions: HashMap::with_capacity(1),
+            track_last_seen: false,
+            last_seen_interval: LAST_SEEN_WRITE_INTERVAL,
+            last_seen_written: HashMap::new(),
+            offline_grace: Duration::ZERO,
+            pending: HashMap::new(),
+            delivery_rate: 0,
+            delivery_retry: RetryPolicy::default(),
+            scheduler: None,
+            node_id: 0,
+            store_client_reports: false,
+            persist_mutes: false,
+            webhooks: None,
+            webhook_retry: RetryPolicy {
+                timeout: Some(WEBHOOK_TIMEOUT),
+                ..RetryPolicy::default()
+            },
+            trial_chunk_size: TRIAL_CHUNK_SIZE,
+            max_frame_bytes: MAX_FRAME_BYTES,
+            stream_shards: 1,
+            hooks: vec![],
+            push_quota: 0,
+            push_quota_window: PUSH_QUOTA_WINDOW,
+            poll_interval: MESSAGE_INTERVAL,
+            trackers: HashSet::new(),
+            trials: Arc::new(Mutex::new(())),
+            backlog_interval: BACKLOG_SAMPLE_INTERVAL,
+            backlog_warn_len: BACKLOG_WARN_LEN,
+            renders: HashMap::new(),
+        }
+    }
+
+    /// `platform`为`Platform::name`,该平台的session收到`render`改写后的消息
+    pub fn render(mut self, platform: &'static str, render: Render) -> Self {
+        self.renders.insert(platform, render);
+        self
+    }
+
+    /// 在已经注册的hook之后运行
+    pub fn hook(mut self, hook: impl DeliveryHook + 'static) -> Self {
+        self.hooks.push(Arc::new(hook));
+        self
+    }
+
+    pub fn track_last_seen(mut self, track_last_seen: bool) -> Self {
+        self.track_last_seen = track_last_seen;
+        self
+    }
+
+    /// 0时使用`LAST_SEEN_WRITE_INTERVAL`
+    pub fn last_seen_interval(mut self, interval: Duration) -> Self {
+        if !interval.is_zero() {
+            self.last_seen_interval = interval;
+        }
+        self
+    }
+
+    pub fn offline_grace(mut self, offline_grace: Duration) -> Self {
+        self.offline_grace = offline_grace;
+        self
+    }
+
+    pub fn delivery_rate(mut self, delivery_rate: u32) -> Self {
+        self.delivery_rate = delivery_rate;
+        self
+    }
+
+    pub fn delivery_retry(mut self, delivery_retry: RetryPolicy) -> Self {
+        self.delivery_retry = delivery_retry;
+        self
+    }
+
+    pub fn node_id(mut self, node_id: u16) -> Self {
+        self.node_id = node_id;
+        self
+    }
+
+    pub fn store_client_reports(mut self, store_client_reports: bool) -> Self {
+        self.store_client_reports = store_client_reports;
+        self
+    }
+
+    pub fn persist_mutes(mut self, persist_mutes: bool) -> Self {
+        self.persist_mutes = persist_mutes;
+        self
+    }
+
+    pub fn webhook_retry(mut self, webhook_retry: RetryPolicy) -> Self {
+        self.webhook_retry = webhook_retry;
+        self
+    }
+
+    /// 每隔`interval`统计一次积压,0为`BACKLOG_SAMPLE_INTERVAL`,`warn_len`为0时使用`BACKLOG_WARN_LEN`
+    pub fn backlog_sampling(mut self, interval: Duration, warn_len: usize) -> Self {
+        if !interval.is_zero() {
+            self.backlog_interval = interval;
+        }
+        if warn_len > 0 {
+            self.backlog_warn_len = warn_len;
+        }
+        self
+    }
+
+    /// 0为`TRIAL_CHUNK_SIZE`
+    pub fn trial_chunk_size(mut self, trial_chunk_size: usize) -> Self {
+        self.trial_chunk_size = match trial_chunk_size {
+            0 => TRIAL_CHUNK_SIZE,
+            size => size,
+        };
+        self
+    }
+
+    /// 0时使用`MAX_FRAME_BYTES`
+    pub fn max_frame_bytes(mut self, max_frame_bytes: usize) -> Self {
+        self.max_frame_bytes = match max_frame_bytes {
+            0 => MAX_FRAME_BYTES,
+            bytes => bytes,
+        };
+        self
+    }
+
+    /// 0和1都不分片
+    pub fn stream_shards(mut self, stream_shards: usize) -> Self {
+        self.stream_shards = stream_shards.max(1);
+        self
+    }
+
+    /// `window`为0时使用`PUSH_QUOTA_WINDOW`
+    pub fn push_quota(mut self, push_quota: u32, window: Duration) -> Self {
+        self.push_quota = push_quota;
+        if !window.is_zero() {
+            self.push_quota_window = window;
+        }
+        self
+    }
+
+    /// session读取消息的间隔,0为`MESSAGE_INTERVAL`
+    pub fn poll_interval(mut self, poll_interval: Duration) -> Self {
+        if !poll_interval.is_zero() {
+            self.poll_interval = poll_interval;
         }
+        self
+    }
+
+    pub fn stagger_reads(mut self, stagger_reads: bool) -> Self {
+        self.scheduler = stagger_reads.then(|| ReadScheduler::new(MESSAGE_INTERVAL, READ_SLOTS));
+        self
     }
     /// 用户的设备hset
     pub fn key_platform(&self, username: &str) -> String {
@@ -43,235 +291,6299 @@ impl Redis {
     pub fn key_activity(&self, username: &str) -> String {
         format!("veda-activity:{}", username)
     }
+    /// 用户的所有消息队列,分片时为`veda-activity:{username}:{shard}`
+    pub fn keys_activity(&self, username: &str) -> Vec<String> {
+        match self.stream_shards {
+            1 => vec![self.key_activity(username)],
+            shards => (0..shards)
+                .map(|shard| format!("{}:{}", self.key_activity(username), shard))
+                .collect(),
+        }
+    }
+    /// 消息写入的队列,同一个`compaction_key`的消息在同一个分片才能互相覆盖
+    pub fn key_activity_of(&self, username: &str, message: &Activity) -> String {
+        if self.stream_shards == 1 {
+            return self.key_activity(username);
+        }
+        let hash = match &message.compaction_key {
+            Some(compaction_key) => fnv1a(&[compaction_key.as_bytes()]),
+            None => fnv1a(&[
+                message.activity_type.as_bytes(),
+                message.activity.as_bytes(),
+                message
+                    .correlation_id
+                    .as_deref()
+                    .unwrap_or_default()
+                    .as_bytes(),
+            ]),
+        };
+        let shard = (hash % self.stream_shards as u64) as usize;
+        format!("{}:{}", self.key_activity(username), shard)
+    }
+    /// 用户的session所在节点hset,field为session id,value为节点id
+    pub fn key_identity_node(&self, username: &str) -> String {
+        format!("identity-node:{}", username)
+    }
+    /// 房间消息队列
+    pub fn key_room_activity(&self, room: &Room) -> String {
+        format!("veda-activity:room:{}", room.name())
+    }
+    /// 房间成员set
+    pub fn key_room_members(&self, room: &Room) -> String {
+        format!("room-members:{}", room.name())
+    }
+    /// session最后心跳时间zset
+    pub fn zset_last_seen(&self) -> &'static str {
+        "last-seen"
+    }
+    /// 限量消息已被领取的数量,`{key}:winners`为领取到的接收者
+    pub fn key_claims(&self, claim_ref: &str) -> String {
+        format!("claims:{}", claim_ref)
+    }
+    /// 用户最近一次心跳的unix毫秒
+    pub fn key_last_seen(&self, username: &str) -> String {
+        format!("last-seen:{}", username)
+    }
+    /// 无法投递的消息队列
+    pub fn key_dead_letter(&self, username: &str) -> String {
+        format!("veda-dead-letter:{}", username)
+    }
+    /// 用户自定义状态
+    pub fn key_status(&self, username: &str) -> String {
+        format!("status:{}", username)
+    }
+
+    /// 关注用户的人set
+    pub fn key_followers(&self, username: &str) -> String {
+        format!("followers:{}", username)
+    }
+    /// 用户是否公开,公开用户的消息才会复制给关注者
+    pub fn key_public(&self, username: &str) -> String {
+        format!("public:{}", username)
+    }
+
+    /// 用户配置的webhook url
+    pub fn key_webhook(&self, username: &str) -> String {
+        format!("webhook:{}", username)
+    }
+    /// 消息的附件,base64
+    pub fn key_blob(&self, blob_ref: &str) -> String {
+        format!("blob:{}", blob_ref)
+    }
+    /// 定时投递的消息zset,score为投递时间,unix毫秒
+    pub fn zset_scheduled(&self) -> &'static str {
+        "scheduled"
+    }
+    /// 用户不想收到消息的发送者set
+    pub fn key_muted(&self, username: &str) -> String {
+        format!("muted:{}", username)
+    }
+    /// 一次推送在各接收者处的状态hset,field为接收者
+    pub fn key_receipts(&self, push_id: &str) -> String {
+        format!("receipts:{}", push_id)
+    }
+    /// 推送的发送者,只有他能查询推送的状态
+    pub fn key_receipt_sender(&self, push_id: &str) -> String {
+        format!("receipt-sender:{}", push_id)
+    }
+    /// 推送者在第`window`个窗口内推送的次数
+    pub fn key_push_quota(&self, sender: &str, window: i64) -> String {
+        format!("push-quota:{}:{}", sender, window)
+    }
+    /// 没有推送者的推送在第`window`个窗口内共同的推送次数
+    pub fn key_anonymous_push_quota(&self, window: i64) -> String {
+        format!("push-quota-anonymous:{}", window)
+    }
+    /// 最近推送过的idempotency key hset,value为写入的消息id
+    pub fn key_seen(&self, username: &str) -> String {
+        format!("seen:{}", username)
+    }
+
+    fn store(&self) -> Box<dyn StreamStore> {
+        self.connector
+            .connect()
+            .expect("get redis connection error")
+    }
+
+    /// 客户端报告的错误队列
+    pub fn stream_client_errors(&self) -> &'static str {
+        "client-errors"
+    }
+
+    /// 公开用户收到公开类型的消息时,复制一份给关注者,已经是接收者的关注者不重复发送
+    fn fan_out(
+        &self,
+        con: &mut dyn StreamStore,
+        username: &str,
+        message: &Activity,
+        receivers: &[String],
+    ) {
+        if !PUBLIC_TYPES.contains(&message.activity_type.as_str()) {
+            return;
+        }
+        if !matches!(con.get(&self.key_public(username)), Ok(Some(_))) {
+            return;
+        }
+        let followers = match con.smembers(&self.key_followers(username)) {
+            Ok(followers) => followers,
+            Err(e) => return warn!("cannot read the followers of `{}`: {}", username, e),
+        };
+        for follower in followers
+            .iter()
+            .filter(|follower| !receivers.contains(follower))
+        {
+            match con.xadd(&self.key_activity_of(follower, message), message) {
+                Ok(_) => DELIVERIES.inc("pushed"),
+                Err(_) => DELIVERIES.inc("push_failed"),
+            }
+        }
+    }
+
+    /// 最近用`key`推送给`receiver`的消息id,读取失败时当作没有推送过
+    fn seen(&self, con: &mut dyn StreamStore, receiver: &str, key: &str) -> Option<String> {
+        con.hget(&self.key_seen(receiver), key)
+            .map_err(|e| warn!("cannot check the idempotency key of `{}`: {}", receiver, e))
+            .ok()
+            .flatten()
+    }
+
+    /// 记住`key`对应的消息id,`IDEMPOTENCY_TTL`内不再重复写入
+    fn remember(&self, con: &mut dyn StreamStore, receiver: &str, key: &str, id: &str) {
+        let seen = self.key_seen(receiver);
+        if let Err(e) = con
+            .hset(&seen, key, id)
+            .and_then(|_| con.expire(&seen, IDEMPOTENCY_TTL))
+        {
+            warn!(
+                "cannot remember the idempotency key of `{}`: {}",
+                receiver, e
+            );
+        }
+    }
+
+    /// 用户配置了webhook时,消息在写入队列之外再POST到webhook
+    fn post_webhook(&self, con: &mut dyn StreamStore, username: &str, message: &Activity) {
+        let url = match con.get(&self.key_webhook(username)) {
+            Ok(Some(url)) => url,
+            Ok(None) => return,
+            Err(e) => return warn!("cannot read the webhook of `{}`: {}", username, e),
+        };
+        if let Some(webhooks) = &self.webhooks {
+            webhooks.do_send(PostActivity {
+                username: username.to_owned(),
+                url,
+                activity: message.clone(),
+            });
+        }
+    }
+
+    /// 推送者在当前窗口内的推送次数加一,超过`push_quota`时返回false,
+    /// 没有推送者的推送共用一份配额,计数失败时不限制
+    fn within_quota(&self, con: &mut dyn StreamStore, sender: Option<&str>) -> bool {
+        if self.push_quota == 0 {
+            return true;
+        }
+        let window = Utc::now().timestamp_millis() / self.push_quota_window.as_millis() as i64;
+        let key = match sender {
+            Some(sender) => self.key_push_quota(sender, window),
+            None => self.key_anonymous_push_quota(window),
+        };
+        let sender = sender.unwrap_or("pushes without a sender");
+        match con.incr(&key) {
+            Ok(count) => {
+                if count == 1 {
+                    // expire只精确到秒,多留一秒
+                    let _ = con.expire(&key, self.push_quota_window + Duration::from_secs(1));
+                }
+                count <= self.push_quota as i64
+            }
+            Err(e) => {
+                warn!("cannot count the pushes of {}, let through: {}", sender, e);
+                true
+            }
+        }
+    }
+
+    /// 推送者超过配额时拒绝推送,返回各接收者的`QUOTA_EXCEEDED`
+    fn reject_over_quota(
+        &self,
+        con: &mut dyn StreamStore,
+        message: &Activity,
+        receivers: &[String],
+    ) -> Option<Vec<(String, String)>> {
+        let sender = message.sender.as_deref();
+        if self.within_quota(con, sender) {
+            return None;
+        }
+        warn!(
+            "{} is over its push quota, push rejected",
+            sender.unwrap_or("pushes without a sender")
+        );
+        DELIVERIES.inc_by(QUOTA_EXCEEDED, receivers.len() as u64);
+        Some(
+            receivers
+                .iter()
+                .map(|receiv| (receiv.to_owned(), QUOTA_EXCEEDED.to_string()))
+                .collect(),
+        )
+    }
+
+    /// 限量消息的计数器与消息分开保存`CLAIM_TTL`,接收者读取时计数,超过数量的接收者收到`sold_out`
+    fn limit_quantity(&self, con: &mut dyn StreamStore, message: &mut Activity, quantity: u32) {
+        let key = self.key_claims(&format!("{:016x}", rand::random::<u64>()));
+        if let Err(e) = con.set(&key, "0").and_then(|_| con.expire(&key, CLAIM_TTL)) {
+            return warn!(
+                "cannot limit the quantity of an activity, pushed without it: {}",
+                e
+            );
+        }
+        message.claim_key = Some(key);
+        message.quantity = Some(quantity);
+    }
+
+    /// 附件与消息分开保存`BLOB_TTL`,每个接收者的队列只记录它的key和下载地址
+    fn stash_blob(&self, con: &mut dyn StreamStore, message: &mut Activity) {
+        let blob = match message.blob.take() {
+            Some(blob) => blob,
+            None => return,
+        };
+        let blob_ref = format!("{:016x}", rand::random::<u64>());
+        let key = self.key_blob(&blob_ref);
+        if let Err(e) = con
+            .set(&key, &base64::encode(&blob))
+            .and_then(|_| con.expire(&key, BLOB_TTL))
+        {
+            return warn!("cannot keep an attachment, pushed without it: {}", e);
+        }
+        message.blob_key = Some(key);
+        message.blob_url = Some(format!("/blobs/{}", blob_ref));
+    }
+
+    /// 清理session在redis中的在线信息
+    fn clean_online(&self, con: &mut dyn StreamStore, id: usize) {
+        let id = id.to_string();
+        if let Ok(Some(username)) = con.hget(self.hset_online_users(), &id) {
+            let _ = con.hdel(self.hset_online_users(), &id);
+            let _ = con.hdel(&self.key_platform(&username), &id);
+            let _ = con.hdel(&self.key_identity_node(&username), &id);
+        }
+        if self.track_last_seen {
+            let _ = con.zrem(self.zset_last_seen(), &id);
+        }
+    }
+
+    /// 停止redis session,释放它的时间片
+    fn stop_session(&mut self, id: usize) {
+        self.trackers.remove(&id);
+        if let Some(entry) = self.sessions.remove(&id) {
+            entry.addr.do_send(RedisOffline);
+            if let (Some(scheduler), Some(slot)) = (&mut self.scheduler, entry.slot) {
+                scheduler.unregister(slot);
+            }
+        }
+    }
+
+    /// 通知当前时间片的session读取消息
+    fn dispatch_reads(&mut self) {
+        let slot = match &mut self.scheduler {
+            Some(scheduler) => scheduler.tick(),
+            None => return,
+        };
+        for entry in self.sessions.values() {
+            if entry.slot == Some(slot) {
+                entry.addr.do_send(ReadMessages);
+            }
+        }
+    }
+
+    /// 停止redis session并清理在线信息,redis session已经停止时同样清理
+    fn offline(&mut self, id: usize) {
+        self.stop_session(id);
+
+        let mut con = self.store();
+
+        self.clean_online(con.as_mut(), id);
+    }
+
+    /// 取消websocket session等待中的下线任务
+    fn cancel_pending(&mut self, id: usize, ctx: &mut Context<Self>) {
+        for pending in self.pending.values_mut() {
+            pending.retain(|(pending_id, handle)| {
+                if *pending_id == id {
+                    ctx.cancel_future(*handle);
+                }
+                *pending_id != id
+            });
+        }
+        self.pending.retain(|_, pending| !pending.is_empty());
+    }
+
+    /// 用户在等待期内重新上线,把还未下线的redis session转给新的websocket session
+    fn reattach(
+        &mut self,
+        con: &mut dyn StreamStore,
+        msg: &Online,
+        ctx: &mut Context<Self>,
+    ) -> bool {
+        let old_id = match self.pending.get_mut(&msg.name).and_then(Vec::pop) {
+            Some((old_id, handle)) => {
+                ctx.cancel_future(handle);
+                old_id
+            }
+            None => return false,
+        };
+        if self.pending.get(&msg.name).is_some_and(Vec::is_empty) {
+            self.pending.remove(&msg.name);
+        }
+        let mut entry = match self.sessions.remove(&old_id) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        if self.trackers.remove(&old_id) {
+            self.trackers.insert(msg.id);
+        }
+        entry.kick = msg.kick.clone();
+        entry.ask = msg.ask.clone();
+        info!(
+            "`{}` reconnected within grace period, session {} -> {}",
+            &msg.name, old_id, msg.id
+        );
+
+        entry.addr.do_send(Reattach {
+            id: msg.id,
+            websocket_addr: msg.addr.clone(),
+            backlog_summary: msg.backlog_summary,
+            framing: msg.framing,
+            batch_size: msg.batch_size,
+            capabilities: msg.capabilities.clone(),
+            delivery: msg.delivery,
+            fields: msg.fields.clone(),
+            echo: msg.echo,
+        });
+        self.sessions.insert(msg.id, entry);
+
+        let old_id = old_id.to_string();
+        let _ = con.hdel(self.hset_online_users(), &old_id);
+        let _ = con.hdel(&self.key_identity_node(&msg.name), &old_id);
+        let key_platforms = self.key_platform(&msg.name);
+        if let Ok(Some(platform)) = con.hget(&key_platforms, &old_id) {
+            let _ = con.hdel(&key_platforms, &old_id);
+            let _ = con.hset(&key_platforms, &msg.id.to_string(), &platform);
+        }
+        if self.track_last_seen {
+            let _ = con.zrem(self.zset_last_seen(), &old_id);
+        }
+        true
+    }
 }
 
 impl Handler<Online> for Redis {
     type Result = ();
 
-    fn handle(&mut self, msg: Online, _ctx: &mut Self::Context) -> Self::Result {
-        info!("start creating redis connection for `{}`", &msg.name);
+    fn handle(&mut self, msg: Online, ctx: &mut Self::Context) -> Self::Result {
+        // the websocket session changed its name, the old name goes offline right away
+        if self.sessions.contains_key(&msg.id) {
+            self.cancel_pending(msg.id, ctx);
+            self.offline(msg.id);
+        }
+        let mut con = self.store();
+
+        let id = msg.id.to_string();
+        let _ = con.hset(self.hset_online_users(), &id, &msg.name);
+        let _ = con.hset(
+            &self.key_identity_node(&msg.name),
+            &id,
+            &self.node_id.to_string(),
+        );
+        if self.track_last_seen {
+            let _ = con.zadd(self.zset_last_seen(), &id, Utc::now().timestamp());
+        }
+        if self.reattach(con.as_mut(), &msg, ctx) {
+            return;
+        }
 
-        let mut con = self
-            .cli
-            .get_connection()
-            .expect("get redis connection error");
+        info!("start creating redis connection for `{}`", &msg.name);
 
-        let _: RedisResult<String> = con.hset(self.hset_online_users(), msg.id, msg.name.clone());
+        let muted = if self.persist_mutes {
+            con.smembers(&self.key_muted(&msg.name)).unwrap_or_default()
+        } else {
+            vec![]
+        };
 
         let addr = RedisSession::new(
             msg.id,
             msg.name.clone(),
-            self.key_activity(&msg.name.as_str()),
+            self.key_activity(msg.name.as_str()),
             con,
             msg.addr,
         )
+        .backlog_summary(msg.backlog_summary)
+        .framing(msg.framing)
+        .batch_size(msg.batch_size)
+        .capabilities(msg.capabilities)
+        .delivery(msg.delivery)
+        .fields(msg.fields)
+        .echo(msg.echo)
+        .streams(self.keys_activity(&msg.name))
+        .muted(muted)
+        .receipts(ctx.address().recipient())
+        .dead_letter(self.key_dead_letter(&msg.name))
+        .delivery_rate(self.delivery_rate)
+        .max_frame_bytes(self.max_frame_bytes)
+        .retry(self.delivery_retry.clone())
+        .scheduled(self.scheduler.is_some())
+        .hooks(self.hooks.clone())
+        .poll_interval(self.poll_interval)
         .start();
 
-        self.sessions.insert(msg.id, addr.recipient());
+        let slot = self.scheduler.as_mut().map(ReadScheduler::register);
+        self.sessions.insert(
+            msg.id,
+            SessionEntry {
+                name: msg.name,
+                addr,
+                kick: msg.kick,
+                ask: msg.ask,
+                slot,
+            },
+        );
     }
 }
 
-impl Handler<PlatformOnline> for Redis {
+impl Handler<Join> for Redis {
     type Result = ();
 
-    fn handle(&mut self, msg: PlatformOnline, _ctx: &mut Self::Context) -> Self::Result {
-        info!("start creating redis connection for `{}`", &msg.name);
+    fn handle(&mut self, msg: Join, _: &mut Self::Context) -> Self::Result {
+        info!("`{}` joins room `{}`", &msg.name, msg.room.name());
+        let mut con = self.store();
+        let _ = con.sadd(&self.key_room_members(&msg.room), &msg.name);
 
-        let mut con = self
-            .cli
-            .get_connection()
-            .expect("get redis connection error");
-
-        let _: RedisResult<Platform> = con.hset(self.key_platform(&msg.name), msg.id, msg.platform);
+        if let Some(entry) = self.sessions.get(&msg.id) {
+            entry.addr.do_send(RoomSubscribe {
+                stream_name: self.key_room_activity(&msg.room),
+                room: msg.room.name().to_owned(),
+            });
+        }
     }
 }
 
-impl Handler<Offline> for Redis {
+impl Handler<Leave> for Redis {
     type Result = ();
 
-    fn handle(&mut self, msg: Offline, _: &mut Self::Context) -> Self::Result {
-        info!("name:{} disconnected, offline redis session", &msg.id);
-        if let Some(session_addr) = self.sessions.get(&msg.id) {
-            let _ = session_addr.do_send(RedisOffline);
-            self.sessions.remove(&msg.id);
+    fn handle(&mut self, msg: Leave, _: &mut Self::Context) -> Self::Result {
+        info!("`{}` leaves room `{}`", &msg.name, msg.room.name());
+        let mut con = self.store();
+        let _ = con.srem(&self.key_room_members(&msg.room), &msg.name);
+
+        if let Some(entry) = self.sessions.get(&msg.id) {
+            entry.addr.do_send(RoomUnsubscribe {
+                stream_name: self.key_room_activity(&msg.room),
+            });
+        }
+    }
+}
 
-            let mut con = self
-                .cli
-                .get_connection()
-                .expect("get redis connection error");
+impl Handler<Post> for Redis {
+    type Result = Option<String>;
 
-            let username: RedisResult<String> = con.hget(self.hset_online_users(), msg.id);
-            if let Ok(username) = username {
-                let _: RedisResult<String> = con.hdel(self.hset_online_users(), msg.id);
-                let key_platforms = self.key_platform(&username);
-                let _: RedisResult<Platform> = con.hdel(key_platforms, msg.id);
+    fn handle(&mut self, msg: Post, _: &mut Self::Context) -> Self::Result {
+        let mut con = self.store();
+        let sender = match msg.message.sender.as_deref() {
+            Some(sender) => sender,
+            None => {
+                warn!("a post to room `{}` has no sender", msg.room.name());
+                return None;
+            }
+        };
+        if !con
+            .sismember(&self.key_room_members(&msg.room), sender)
+            .unwrap_or(false)
+        {
+            warn!(
+                "`{}` cannot post to room `{}` without joining it",
+                sender,
+                msg.room.name()
+            );
+            return None;
+        }
+        let key = self.key_room_activity(&msg.room);
+        if let Some(reply_to) = &msg.message.reply_to {
+            let found = con.xrange(&key, reply_to, reply_to, 1).unwrap_or_default();
+            if found.is_empty() {
+                warn!(
+                    "cannot reply to `{}`, it is not in room `{}`",
+                    reply_to,
+                    msg.room.name()
+                );
+                return None;
             }
-
-            let _: RedisResult<Platform> = con.hget(self.hset_online_users(), msg.id);
         }
+        con.xadd(&key, &msg.message).ok()
     }
 }
 
-impl Handler<Trial> for Redis {
-    type Result = Vec<(String, String)>;
+impl Handler<Capabilities> for Redis {
+    type Result = ();
 
-    fn handle(&mut self, msg: Trial, _: &mut Self::Context) -> Self::Result {
-        let mut con = self
-            .cli
-            .get_connection()
-            .expect("get redis connection error");
-        let mut events = vec![];
+    fn handle(&mut self, msg: Capabilities, _: &mut Self::Context) -> Self::Result {
+        // not online yet, the capabilities are sent along with `Online`
+        if let Some(entry) = self.sessions.get(&msg.id) {
+            entry.addr.do_send(msg);
+        }
+    }
+}
 
-        for receiv in &msg.receivers {
-            let id: RedisResult<String> =
-                con.xadd_map(self.key_activity(receiv), "*", &msg.message);
+impl Handler<Fields> for Redis {
+    type Result = ();
 
-            if let Ok(id) = id {
-                events.push((receiv.to_string(), id));
-            }
+    fn handle(&mut self, msg: Fields, _: &mut Self::Context) -> Self::Result {
+        // not online yet, the fields are sent along with `Online`
+        if let Some(entry) = self.sessions.get(&msg.id) {
+            entry.addr.do_send(msg);
         }
-
-        events
     }
 }
 
-#[derive(Message)]
-#[rtype(result = "()")]
-pub struct RedisOffline;
-pub struct RedisSession {
-    pub id: usize,
-    pub name: String,
-    stream_name: String,
-    opts: StreamReadOptions,
-    pub session_addr: Connection,
-    pub websocket_addr: Recipient<WsMessage>,
+impl Handler<Echo> for Redis {
+    type Result = ();
+
+    fn handle(&mut self, msg: Echo, _: &mut Self::Context) -> Self::Result {
+        // not online yet, the setting is sent along with `Online`
+        if let Some(entry) = self.sessions.get(&msg.id) {
+            entry.addr.do_send(msg);
+        }
+    }
 }
 
-impl Actor for RedisSession {
-    type Context = Context<Self>;
+impl Handler<Track> for Redis {
+    type Result = ();
 
-    fn started(&mut self, ctx: &mut Self::Context) {
-        ctx.run_interval(MESSAGE_INTERVAL, |act, ctx| {
-            act.read_messages(ctx);
-        });
+    fn handle(&mut self, msg: Track, _: &mut Self::Context) -> Self::Result {
+        if msg.on {
+            self.trackers.insert(msg.id);
+        } else {
+            self.trackers.remove(&msg.id);
+        }
     }
 }
 
-impl Handler<RedisOffline> for RedisSession {
+impl Handler<Mute> for Redis {
     type Result = ();
 
-    fn handle(&mut self, _: RedisOffline, ctx: &mut Self::Context) -> Self::Result {
-        ctx.stop();
+    fn handle(&mut self, msg: Mute, _: &mut Self::Context) -> Self::Result {
+        let entry = match self.sessions.get(&msg.id) {
+            Some(entry) => entry,
+            None => return,
+        };
+        if self.persist_mutes {
+            let mut con = self.store();
+            let key = self.key_muted(&entry.name);
+            let _ = if msg.mute {
+                con.sadd(&key, &msg.sender)
+            } else {
+                con.srem(&key, &msg.sender)
+            };
+        }
+        entry.addr.do_send(msg);
     }
 }
 
-impl RedisSession {
-    pub fn new(
-        id: usize,
-        name: String,
-        stream_name: String,
-        connection: Connection,
-        websocket_addr: Recipient<WsMessage>,
-    ) -> Self {
-        Self {
-            id,
-            name,
-            stream_name,
-            opts: StreamReadOptions::default().block(BLOCK_MILLIS).count(10),
-            session_addr: connection,
-            websocket_addr,
+impl Handler<BatchSize> for Redis {
+    type Result = ();
+
+    fn handle(&mut self, msg: BatchSize, _: &mut Self::Context) -> Self::Result {
+        // not online yet, the size is sent along with `Online`
+        if let Some(entry) = self.sessions.get(&msg.id) {
+            entry.addr.do_send(msg);
         }
     }
 }
 
-impl RedisSession {
-    fn read_messages(&mut self, ctx: &mut Context<Self>) {
-        let inf: RedisResult<StreamInfoStreamReply> =
-            self.session_addr.xinfo_stream(&self.stream_name);
-        // if inf is Err(_), the xadd command have not been execute, no message
-        if let Ok(inf) = inf {
-            // no message in stream,keep pollings
-            if inf.length == 0 {
-                return;
-            }
+impl Handler<Priority> for Redis {
+    type Result = ();
 
-            // read all messages in the stream
-            let ssr: RedisResult<StreamReadReply> =
-                self.session_addr
-                    .xread_options(&[&self.stream_name], &["0"], &self.opts);
-            if let Ok(ssr) = ssr {
-                for StreamKey { key, ids } in ssr.keys {
-                    let items: Vec<Activity> = ids
-                        .iter()
-                        .map(|t| Activity {
-                            activity_type: t.get("activity_type").unwrap_or_default(),
-                            activity: t.get("activity").unwrap_or_default(),
-                        })
-                        .collect();
-                    let res = serde_json::to_string(&items);
-                    if let Ok(res) = res {
-                        self.websocket_addr
-                            .send(WsMessage(res))
-                            .into_actor(self)
-                            .then(move |res, act, ctx| {
-                                match res {
-                                    Ok(_) => {
-                                        // remove all the sended messages out from stream
-                                        let id_strs: &Vec<&String> =
-                                            &ids.iter().map(|StreamId { id, map: _ }| id).collect();
-                                        let _: RedisResult<()> =
-                                            act.session_addr.xdel(key, id_strs);
-                                    }
-                                    // something wrong with socket server
-                                    _ => ctx.stop(),
-                                }
-                                fut::ready(())
-                            })
-                            .wait(ctx);
-                    }
-                }
-            }
+    fn handle(&mut self, msg: Priority, _: &mut Self::Context) -> Self::Result {
+        if let Some(entry) = self.sessions.get(&msg.id) {
+            entry.addr.do_send(msg);
         }
     }
 }
 
-/// 用户上线消息,由websocket session发送到redis
-/// redis 接收到online
-#[derive(Message)]
-#[rtype(result = "()")]
-pub struct Online {
-    /// websocket session id
-    pub id: usize,
-    /// logined username
-    pub name: String,
-    /// `socket` session addr
-    pub addr: Recipient<WsMessage>,
+impl Handler<ReloadConfig> for Redis {
+    type Result = ();
+
+    fn handle(&mut self, mut msg: ReloadConfig, _: &mut Self::Context) -> Self::Result {
+        // the same as at start, so the reloaded config says what is applied
+        if msg.poll_interval.is_zero() {
+            msg.poll_interval = MESSAGE_INTERVAL;
+        }
+        if msg.poll_interval == self.poll_interval {
+            return;
+        }
+        info!(
+            "poll interval is now {:?}, was {:?}",
+            msg.poll_interval, self.poll_interval
+        );
+        self.poll_interval = msg.poll_interval;
+        for entry in self.sessions.values() {
+            entry.addr.do_send(msg.clone());
+        }
+    }
 }
 
-/// 用户上线消息,由websocket session发送到redis
-/// redis 接收到online
-#[derive(Message)]
-#[rtype(result = "()")]
-pub struct PlatformOnline {
-    /// websocket session id
-    pub id: usize,
-    /// logined username
-    pub name: String,
-    /// device
-    pub platform: Platform,
+impl Handler<ListSessions> for Redis {
+    type Result = ResponseFuture<Vec<SessionState>>;
+
+    fn handle(&mut self, _: ListSessions, _: &mut Self::Context) -> Self::Result {
+        let states: Vec<_> = self
+            .sessions
+            .values()
+            .map(|entry| entry.addr.send(DescribeSession))
+            .collect();
+        Box::pin(async move {
+            // sessions stopping meanwhile are left out
+            let mut states: Vec<SessionState> = futures::future::join_all(states)
+                .await
+                .into_iter()
+                .filter_map(Result::ok)
+                .collect();
+            states.sort_by_key(|state| state.id);
+            states
+        })
+    }
 }
 
-#[derive(Message)]
-#[rtype(result = "()")]
-pub struct Offline {
-    /// websocket session id
-    pub id: usize,
+impl Handler<Cursor> for Redis {
+    type Result = ResponseFuture<CursorPosition>;
+
+    fn handle(&mut self, msg: Cursor, _: &mut Self::Context) -> Self::Result {
+        let keys = self.keys_activity(&msg.name);
+        let stream_len = match xlen_all(self.store().as_mut(), &keys) {
+            Ok(len) => len,
+            Err(e) => {
+                warn!("cannot read the length of `{}`'s stream: {}", &msg.name, e);
+                0
+            }
+        };
+        let state = self
+            .sessions
+            .get(&msg.id)
+            .map(|entry| entry.addr.send(DescribeSession));
+        Box::pin(async move {
+            let last_id = match state {
+                Some(state) => state.await.ok().and_then(|state| state.last_delivered_id),
+                None => None,
+            };
+            CursorPosition {
+                last_id: last_id.unwrap_or_else(|| NO_CURSOR.to_owned()),
+                stream_len,
+            }
+        })
+    }
 }
 
-/// 审判
-#[derive(Message)]
-#[rtype(result = "Vec<(String, String)>")]
+impl Handler<Badges> for Redis {
+    type Result = ResponseFuture<BadgeCounts>;
+
+    fn handle(&mut self, msg: Badges, _: &mut Self::Context) -> Self::Result {
+        let counts = self
+            .sessions
+            .get(&msg.id)
+            .map(|entry| entry.addr.send(Badges { id: msg.id }));
+        Box::pin(async move {
+            match counts {
+                Some(counts) => counts.await.unwrap_or_default(),
+                None => BadgeCounts::default(),
+            }
+        })
+    }
+}
+
+impl Handler<PurgeUser> for Redis {
+    type Result = usize;
+
+    fn handle(&mut self, msg: PurgeUser, ctx: &mut Self::Context) -> Self::Result {
+        // sessions waiting out the grace period never come back
+        if let Some(pending) = self.pending.remove(&msg.username) {
+            for (_, handle) in pending {
+                ctx.cancel_future(handle);
+            }
+        }
+        let ids: Vec<usize> = self
+            .sessions
+            .iter()
+            .filter(|(_, entry)| entry.name == msg.username)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in &ids {
+            if let Some(entry) = self.sessions.get(id) {
+                let _ = entry.kick.do_send(Kick {
+                    code: CloseCode::Policy,
+                    reason: "account deleted".to_string(),
+                });
+            }
+            self.offline(*id);
+        }
+
+        let mut con = self.store();
+        let mut keys = self.keys_activity(&msg.username);
+        keys.extend([
+            self.key_platform(&msg.username),
+            self.key_dead_letter(&msg.username),
+            self.key_status(&msg.username),
+            self.key_followers(&msg.username),
+            self.key_public(&msg.username),
+            self.key_identity_node(&msg.username),
+            self.key_seen(&msg.username),
+            self.key_webhook(&msg.username),
+            self.key_last_seen(&msg.username),
+        ]);
+        self.last_seen_written.remove(&msg.username);
+        for key in keys.iter() {
+            if let Err(e) = con.del(key) {
+                warn!("cannot purge `{}` of `{}`: {}", key, &msg.username, e);
+            }
+        }
+        info!(
+            "purged `{}`, {} session(s) disconnected",
+            &msg.username,
+            ids.len()
+        );
+        ids.len()
+    }
+}
+
+impl Handler<SetStatus> for Redis {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetStatus, _: &mut Self::Context) -> Self::Result {
+        let mut con = self.store();
+        let key = self.key_status(&msg.name);
+        let _ = match &msg.status {
+            Some(status) => con.set(&key, status),
+            None => con.del(&key),
+        };
+    }
+}
+
+impl Handler<AskSession> for Redis {
+    type Result = ResponseFuture<Result<serde_json::Value, RequestError>>;
+
+    fn handle(&mut self, msg: AskSession, _: &mut Self::Context) -> Self::Result {
+        let ask = self.sessions.get(&msg.id).map(|entry| entry.ask.clone());
+        Box::pin(async move {
+            let ask = ask.ok_or(RequestError::UnknownSession)?;
+            ask.send(Ask {
+                payload: msg.payload,
+                timeout: msg.timeout,
+            })
+            .await
+            .unwrap_or(Err(RequestError::Closed))
+        })
+    }
+}
+
+impl Handler<DeadLetter> for Redis {
+    type Result = ();
+
+    fn handle(&mut self, msg: DeadLetter, _: &mut Self::Context) -> Self::Result {
+        let key = self.key_dead_letter(&msg.username);
+        if let Err(e) = self.store().xadd(&key, &msg.activity) {
+            warn!("cannot bury an activity of `{}`: {}", &msg.username, e);
+        }
+    }
+}
+
+impl Handler<ClientReport> for Redis {
+    type Result = ();
+
+    fn handle(&mut self, msg: ClientReport, _: &mut Self::Context) -> Self::Result {
+        let name = msg.name.as_deref().unwrap_or("-");
+        warn!(
+            "client error from session {} `{}`: {}",
+            msg.id, name, msg.report
+        );
+        if !self.store_client_reports {
+            return;
+        }
+        let report = Activity {
+            activity_type: "client_error".to_owned(),
+            activity: serde_json::json!({
+                "session": msg.id,
+                "name": msg.name,
+                "report": msg.report,
+            })
+            .to_string(),
+            ..Default::default()
+        };
+        if let Err(e) = self.store().xadd(self.stream_client_errors(), &report) {
+            warn!("cannot store the client error of session {}: {}", msg.id, e);
+        }
+    }
+}
+
+impl Handler<SetPublic> for Redis {
+    type Result = Publicity;
+
+    fn handle(&mut self, msg: SetPublic, _: &mut Self::Context) -> Self::Result {
+        let mut con = self.store();
+        let key = self.key_public(&msg.name);
+        let res = if msg.public {
+            con.set(&key, "1")
+        } else {
+            con.del(&key)
+        };
+        if let Err(e) = &res {
+            warn!("cannot set `{}` public: {}", &msg.name, e);
+        }
+        Publicity {
+            name: msg.name,
+            public: msg.public && res.is_ok(),
+        }
+    }
+}
+
+impl Handler<Follow> for Redis {
+    type Result = Following;
+
+    fn handle(&mut self, msg: Follow, _: &mut Self::Context) -> Self::Result {
+        let mut con = self.store();
+        let key = self.key_followers(&msg.username);
+        let following = if msg.follow {
+            // 只能关注公开的用户
+            let public = matches!(con.get(&self.key_public(&msg.username)), Ok(Some(_)));
+            public && msg.follower != msg.username && con.sadd(&key, &msg.follower).is_ok()
+        } else {
+            let _ = con.srem(&key, &msg.follower);
+            false
+        };
+        Following {
+            username: msg.username,
+            following,
+        }
+    }
+}
+
+impl Handler<Presence> for Redis {
+    type Result = PresenceInfo;
+
+    fn handle(&mut self, msg: Presence, _: &mut Self::Context) -> Self::Result {
+        let mut con = self.store();
+        let status = con.get(&self.key_status(&msg.name)).ok().flatten();
+        let online = self.sessions.values().any(|entry| entry.name == msg.name);
+        PresenceInfo {
+            name: msg.name,
+            online,
+            status,
+        }
+    }
+}
+
+impl Handler<Subscribe> for Redis {
+    type Result = Addr<RedisSession>;
+
+    fn handle(&mut self, msg: Subscribe, ctx: &mut Self::Context) -> Self::Result {
+        info!("`{}` subscribes without a websocket", &msg.name);
+        let sink = FrameSink { frames: msg.frames }.start();
+        RedisSession::new(
+            0,
+            msg.name.clone(),
+            self.key_activity(&msg.name),
+            self.store(),
+            sink.recipient(),
+        )
+        .streams(self.keys_activity(&msg.name))
+        .receipts(ctx.address().recipient())
+        .dead_letter(self.key_dead_letter(&msg.name))
+        .delivery_rate(self.delivery_rate)
+        .max_frame_bytes(self.max_frame_bytes)
+        .retry(self.delivery_retry.clone())
+        .hooks(self.hooks.clone())
+        .client_ack(true)
+        // 只投递消息,没有控制帧
+        .announce_caught_up(false)
+        .start()
+    }
+}
+
+/// 把redis session发送的消息转到channel,channel关闭后停止
+struct FrameSink {
+    frames: UnboundedSender<String>,
+}
+
+impl Actor for FrameSink {
+    type Context = Context<Self>;
+}
+
+impl Handler<WsMessage> for FrameSink {
+    type Result = ();
+
+    fn handle(&mut self, msg: WsMessage, ctx: &mut Self::Context) -> Self::Result {
+        if self.frames.unbounded_send(msg.0).is_err() {
+            ctx.stop();
+        }
+    }
+}
+
+impl Handler<ListMissed> for Redis {
+    type Result = Missed;
+
+    fn handle(&mut self, msg: ListMissed, _: &mut Self::Context) -> Self::Result {
+        let mut con = self.store();
+        let keys = self.keys_activity(&msg.name);
+        // every entry still in the stream is pending or delivered but not acked
+        let count = xlen_all(con.as_mut(), &keys).unwrap_or_default();
+        let ids = xrevrange_all(con.as_mut(), &keys, "+", "-", msg.limit.min(MISSED_LIMIT))
+            .map(|ids| ids.into_iter().map(|StreamId { id, map: _ }| id).collect())
+            .unwrap_or_default();
+        Missed { count, ids }
+    }
+}
+
+impl Handler<GetActivity> for Redis {
+    type Result = Fetched;
+
+    fn handle(&mut self, msg: GetActivity, _: &mut Self::Context) -> Self::Result {
+        let keys = self.keys_activity(&msg.name);
+        let activity = match xrange_all(self.store().as_mut(), &keys, &msg.id, &msg.id, 1) {
+            Ok(entries) => entries.first().map(Activity::from),
+            Err(e) => {
+                warn!("cannot read {} of `{}`: {}", &msg.id, &msg.name, e);
+                None
+            }
+        };
+        Fetched {
+            id: msg.id,
+            activity,
+        }
+    }
+}
+
+impl Handler<ListSince> for Redis {
+    type Result = Since;
+
+    fn handle(&mut self, msg: ListSince, _: &mut Self::Context) -> Self::Result {
+        let keys = self.keys_activity(&msg.name);
+        // stream ids start with the millisecond they were written in
+        let start = format!("{}-0", msg.since_ms);
+        let activities = match xrange_all(self.store().as_mut(), &keys, &start, "+", SINCE_LIMIT) {
+            Ok(entries) => entries
+                .iter()
+                .map(|entry| Activity {
+                    id: Some(entry.id.clone()),
+                    ..Activity::from(entry)
+                })
+                .collect(),
+            Err(e) => {
+                warn!("cannot read `{}` since {}: {}", &msg.name, msg.since_ms, e);
+                vec![]
+            }
+        };
+        Since {
+            since_ms: msg.since_ms,
+            activities,
+        }
+    }
+}
+
+impl Handler<ListHistory> for Redis {
+    type Result = HistoryPage;
+
+    fn handle(&mut self, msg: ListHistory, _: &mut Self::Context) -> Self::Result {
+        let count = msg.count.min(HISTORY_PAGE_LIMIT);
+        let keys = self.keys_activity(&msg.name);
+        // one more than asked tells whether another page follows
+        let entries = match prev_stream_id(&msg.before_id) {
+            Some(end) => xrevrange_all(self.store().as_mut(), &keys, &end, "-", count + 1)
+                .unwrap_or_else(|e| {
+                    warn!(
+                        "cannot read `{}` before {}: {}",
+                        &msg.name, &msg.before_id, e
+                    );
+                    vec![]
+                }),
+            None => vec![],
+        };
+        let end = entries.len() <= count;
+        let activities: Vec<Activity> = entries
+            .iter()
+            .take(count)
+            .map(|entry| Activity {
+                id: Some(entry.id.clone()),
+                ..Activity::from(entry)
+            })
+            .collect();
+        let next_before_id = if end {
+            None
+        } else {
+            activities.last().and_then(|item| item.id.clone())
+        };
+        HistoryPage {
+            before_id: msg.before_id,
+            activities,
+            next_before_id,
+            end,
+        }
+    }
+}
+
+impl Handler<GetBlob> for Redis {
+    type Result = Option<Vec<u8>>;
+
+    fn handle(&mut self, msg: GetBlob, _: &mut Self::Context) -> Self::Result {
+        let blob = match self.store().get(&self.key_blob(&msg.blob_ref)) {
+            Ok(blob) => blob?,
+            Err(e) => {
+                warn!("cannot read attachment {}: {}", &msg.blob_ref, e);
+                return None;
+            }
+        };
+        base64::decode(blob).ok()
+    }
+}
+
+impl Handler<Clear> for Redis {
+    type Result = Cleared;
+
+    fn handle(&mut self, msg: Clear, _: &mut Self::Context) -> Self::Result {
+        let mut con = self.store();
+        let mut cleared = 0;
+        for key in self.keys_activity(&msg.name) {
+            // only what is in the stream now, activities pushed meanwhile get newer ids and stay
+            let newest = match con.xrevrange(&key, "+", "-", 1) {
+                Ok(ids) => ids.into_iter().next(),
+                Err(e) => {
+                    warn!("cannot clear activities of `{}`: {}", &msg.name, e);
+                    None
+                }
+            };
+            cleared += newest
+                .and_then(|StreamId { id, map: _ }| {
+                    con.xtrim_minid(&key, &next_stream_id(&id))
+                        .map_err(|e| warn!("cannot clear activities of `{}`: {}", &msg.name, e))
+                        .ok()
+                })
+                .unwrap_or_default();
+        }
+        info!("`{}` cleared {} activities", &msg.name, cleared);
+        Cleared { cleared }
+    }
+}
+
+impl Handler<ExportUser> for Redis {
+    type Result = RedisResult<Vec<ExportedActivity>>;
+
+    fn handle(&mut self, msg: ExportUser, _: &mut Self::Context) -> Self::Result {
+        let mut con = self.store();
+        let start = msg
+            .after
+            .as_deref()
+            .map_or_else(|| "-".to_string(), next_stream_id);
+        let ids = xrange_all(
+            con.as_mut(),
+            &self.keys_activity(&msg.username),
+            &start,
+            "+",
+            msg.limit.min(EXPORT_LIMIT),
+        )?;
+        Ok(ids
+            .iter()
+            .map(|entry| {
+                let (millis, _) = parse_stream_id(&entry.id);
+                ExportedActivity {
+                    id: entry.id.clone(),
+                    timestamp: Utc
+                        .timestamp_millis_opt(millis as i64)
+                        .single()
+                        .unwrap_or_default(),
+                    activity: Activity::from(entry),
+                }
+            })
+            .collect())
+    }
+}
+
+/// FNV-1a 64 of the parts, each followed by 0xff which utf-8 never has.
+/// unlike `DefaultHasher` the same on every build, so entries stay in their shard across deploys
+fn fnv1a(parts: &[&[u8]]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325_u64;
+    for part in parts {
+        for byte in part.iter().chain(&[0xff]) {
+            hash ^= u64::from(*byte);
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+    hash
+}
+
+/// `(millis, sequence)` of a stream id, orders like the ids
+fn parse_stream_id(id: &str) -> (u64, u64) {
+    let (ms, seq) = id.split_once('-').unwrap_or((id, "0"));
+    (ms.parse().unwrap_or(0), seq.parse().unwrap_or(0))
+}
+
+/// bytes of the fields of a stream entry, about what it takes up in a frame
+fn entry_size(entry: &StreamId) -> usize {
+    entry
+        .map
+        .iter()
+        .map(|(field, value)| match value {
+            redis::Value::Data(data) => field.len() + data.len(),
+            _ => field.len(),
+        })
+        .sum()
+}
+
+/// the smallest stream id greater than `id`
+fn next_stream_id(id: &str) -> String {
+    match id.split_once('-') {
+        Some((ms, seq)) => match seq.parse::<u64>() {
+            Ok(seq) => format!("{}-{}", ms, seq + 1),
+            Err(_) => format!("{}-0", ms),
+        },
+        None => format!("{}-1", id),
+    }
+}
+
+/// entries read from the shards of one stream in id order, at most `count`,
+/// split into runs of consecutive entries of the same shard
+fn merge_shards(keys: Vec<StreamKey>, count: usize) -> Vec<(String, Vec<StreamId>)> {
+    // a shard that filled its count may hold entries older than the next ones of the others
+    let bound = keys
+        .iter()
+        .filter(|StreamKey { key: _, ids }| ids.len() >= count)
+        .filter_map(|StreamKey { key: _, ids }| ids.last())
+        .map(|entry| parse_stream_id(&entry.id))
+        .min();
+    let mut entries: Vec<(String, StreamId)> = keys
+        .into_iter()
+        .flat_map(|StreamKey { key, ids }| ids.into_iter().map(move |entry| (key.clone(), entry)))
+        .filter(|(_, entry)| bound.is_none_or(|bound| parse_stream_id(&entry.id) <= bound))
+        .collect();
+    entries.sort_by_key(|(_, entry)| parse_stream_id(&entry.id));
+    entries.truncate(count);
+    let mut runs: Vec<(String, Vec<StreamId>)> = vec![];
+    for (key, entry) in entries {
+        match runs.last_mut() {
+            Some((last, ids)) if *last == key => ids.push(entry),
+            _ => runs.push((key, vec![entry])),
+        }
+    }
+    runs
+}
+
+/// the largest stream id smaller than `id`, `None` for `0-0`
+fn prev_stream_id(id: &str) -> Option<String> {
+    match parse_stream_id(id) {
+        (0, 0) => None,
+        (ms, 0) => Some(format!("{}-{}", ms - 1, u64::MAX)),
+        (ms, seq) => Some(format!("{}-{}", ms, seq - 1)),
+    }
+}
+
+/// the total length of the streams `keys`
+fn xlen_all(con: &mut dyn StreamStore, keys: &[String]) -> RedisResult<usize> {
+    keys.iter().map(|key| con.xlen(key)).sum()
+}
+
+/// at most `count` entries of the streams `keys` from `start` to `end`, oldest first
+fn xrange_all(
+    con: &mut dyn StreamStore,
+    keys: &[String],
+    start: &str,
+    end: &str,
+    count: usize,
+) -> RedisResult<Vec<StreamId>> {
+    let mut entries = vec![];
+    for key in keys {
+        entries.extend(con.xrange(key, start, end, count)?);
+    }
+    entries.sort_by_key(|entry| parse_stream_id(&entry.id));
+    entries.truncate(count);
+    Ok(entries)
+}
+
+/// at most `count` entries of the streams `keys` from `end` back to `start`, newest first
+fn xrevrange_all(
+    con: &mut dyn StreamStore,
+    keys: &[String],
+    end: &str,
+    start: &str,
+    count: usize,
+) -> RedisResult<Vec<StreamId>> {
+    let mut entries = vec![];
+    for key in keys {
+        entries.extend(con.xrevrange(key, end, start, count)?);
+    }
+    entries.sort_by_key(|entry| Reverse(parse_stream_id(&entry.id)));
+    entries.truncate(count);
+    Ok(entries)
+}
+
+impl Handler<PlatformOnline> for Redis {
+    type Result = ();
+
+    fn handle(&mut self, msg: PlatformOnline, _ctx: &mut Self::Context) -> Self::Result {
+        info!("start creating redis connection for `{}`", &msg.name);
+
+        let mut con = self.store();
+
+        if let Ok(platform) = serde_json::to_string(&msg.platform) {
+            let _ = con.hset(
+                &self.key_platform(&msg.name),
+                &msg.id.to_string(),
+                &platform,
+            );
+        }
+        if let Some(entry) = self.sessions.get(&msg.id) {
+            entry.addr.do_send(UsePlatform {
+                platform: msg.platform.name(),
+                render: self.renders.get(msg.platform.name()).copied(),
+            });
+        }
+    }
+}
+
+impl Handler<Offline> for Redis {
+    type Result = ();
+
+    fn handle(&mut self, msg: Offline, ctx: &mut Self::Context) -> Self::Result {
+        let name = match self.sessions.get(&msg.id) {
+            Some(entry) if !self.offline_grace.is_zero() => entry.name.clone(),
+            _ => {
+                info!("name:{} disconnected, offline redis session", &msg.id);
+                return self.offline(msg.id);
+            }
+        };
+
+        info!(
+            "name:{} disconnected, offline redis session in {:?}",
+            &msg.id, self.offline_grace
+        );
+        let id = msg.id;
+        let pending_name = name.clone();
+        let handle = ctx.run_later(self.offline_grace, move |act, _| {
+            if let Some(pending) = act.pending.get_mut(&name) {
+                pending.retain(|(pending_id, _)| *pending_id != id);
+                if pending.is_empty() {
+                    act.pending.remove(&name);
+                }
+            }
+            act.offline(id);
+        });
+        self.pending
+            .entry(pending_name)
+            .or_default()
+            .push((msg.id, handle));
+    }
+}
+
+impl Handler<Heartbeat> for Redis {
+    type Result = ();
+
+    fn handle(&mut self, msg: Heartbeat, _: &mut Self::Context) -> Self::Result {
+        if !self.track_last_seen {
+            return;
+        }
+        let mut con = self.store();
+        let _ = con.zadd(
+            self.zset_last_seen(),
+            &msg.id.to_string(),
+            Utc::now().timestamp(),
+        );
+        let name = match self.sessions.get(&msg.id) {
+            Some(entry) => entry.name.clone(),
+            None => return,
+        };
+        // every session of the user beats, one write per interval is enough
+        let now = Instant::now();
+        if let Some(written) = self.last_seen_written.get(&name) {
+            if now.saturating_duration_since(*written) < self.last_seen_interval {
+                return;
+            }
+        }
+        let millis = Utc::now().timestamp_millis().to_string();
+        match con.set(&self.key_last_seen(&name), &millis) {
+            Ok(()) => {
+                self.last_seen_written.insert(name, now);
+            }
+            Err(e) => warn!("cannot refresh when `{}` was last seen: {}", name, e),
+        }
+    }
+}
+
+impl Handler<SweepStale> for Redis {
+    type Result = ();
+
+    fn handle(&mut self, _: SweepStale, _: &mut Self::Context) -> Self::Result {
+        let mut con = self.store();
+
+        let deadline = Utc::now().timestamp() - CLIENT_TIMEOUT.as_secs() as i64;
+        if let Ok(stale) = con.zrangebyscore(self.zset_last_seen(), deadline) {
+            for id in stale.iter().filter_map(|id| id.parse::<usize>().ok()) {
+                info!(
+                    "session:{} has not been seen since {}, offline it",
+                    id, deadline
+                );
+                self.stop_session(id);
+                self.clean_online(con.as_mut(), id);
+                let _ = con.zrem(self.zset_last_seen(), &id.to_string());
+            }
+        }
+    }
+}
+
+impl Handler<SampleBacklog> for Redis {
+    type Result = ();
+
+    fn handle(&mut self, _: SampleBacklog, _: &mut Self::Context) -> Self::Result {
+        let mut con = self.store();
+        // a user online on several devices has one stream
+        let names: BTreeSet<&str> = self
+            .sessions
+            .values()
+            .map(|entry| entry.name.as_str())
+            .collect();
+        let (mut streams, mut total, mut max, mut over) = (0, 0, 0, 0);
+        for name in names {
+            let len = match xlen_all(con.as_mut(), &self.keys_activity(name)) {
+                Ok(len) => len,
+                Err(e) => {
+                    warn!("cannot sample the backlog of `{}`: {}", name, e);
+                    continue;
+                }
+            };
+            if len > self.backlog_warn_len {
+                warn!("`{}` has {} undelivered activities", name, len);
+                over += 1;
+            }
+            streams += 1;
+            total += len;
+            max = max.max(len);
+        }
+        BACKLOG.set("streams", streams);
+        BACKLOG.set("total", total as u64);
+        BACKLOG.set("max", max as u64);
+        BACKLOG.set("over_threshold", over);
+    }
+}
+
+impl Handler<Trial> for Redis {
+    type Result = ResponseActFuture<Self, Vec<(String, String)>>;
+
+    fn handle(&mut self, msg: Trial, _: &mut Self::Context) -> Self::Result {
+        let chunk = self.trial_chunk_size;
+        let Trial {
+            mut message,
+            receivers,
+            quantity,
+        } = msg;
+        let mut con = self.store();
+        if let Some(at) = message.deliver_at {
+            if at as i64 > Utc::now().timestamp_millis() {
+                let scheduled = self.schedule(&mut *con, at, message, receivers, quantity);
+                return Box::pin(fut::ready(scheduled));
+            }
+        }
+        // 定时消息到时间写入时才计数
+        if let Some(rejected) = self.reject_over_quota(&mut *con, &message, &receivers) {
+            return Box::pin(fut::ready(rejected));
+        }
+        self.stash_blob(&mut *con, &mut message);
+        if let Some(quantity) = quantity {
+            self.limit_quantity(&mut *con, &mut message, quantity);
+        }
+        // other messages get a turn after every chunk
+        let starts = stream::iter((0..receivers.len()).step_by(chunk)).then(|start| async move {
+            tokio::task::yield_now().await;
+            start
+        });
+        let trial = fut::wrap_stream(starts).fold(
+            (con, Vec::with_capacity(receivers.len())),
+            move |(mut con, mut events): (Box<dyn StreamStore>, Vec<_>),
+                  start,
+                  act: &mut Self,
+                  _| {
+                let end = receivers.len().min(start + chunk);
+                for receiv in &receivers[start..end] {
+                    events.extend(act.push(&mut *con, receiv, &message, &receivers));
+                }
+                fut::ready((con, events))
+            },
+        );
+        Box::pin(
+            fut::wrap_future(self.trials.clone().lock_owned())
+                .then(move |turn, _, _| trial.map(move |(_, events), _, _| (turn, events)))
+                .map(|(_, events), _, _| events),
+        )
+    }
+}
+
+impl Handler<TrialBatch> for Redis {
+    type Result = ResponseActFuture<Self, Vec<Vec<(String, String)>>>;
+
+    fn handle(&mut self, msg: TrialBatch, _: &mut Self::Context) -> Self::Result {
+        // behind the pushes that are still being written
+        Box::pin(
+            fut::wrap_future(self.trials.clone().lock_owned())
+                .map(move |_turn, act: &mut Self, _| act.push_batch(msg)),
+        )
+    }
+}
+
+impl Handler<Receipt> for Redis {
+    type Result = ();
+
+    fn handle(&mut self, msg: Receipt, _: &mut Self::Context) -> Self::Result {
+        let mut con = self.store();
+        for push_id in &msg.push_ids {
+            self.record_receipt(&mut *con, push_id, &msg.receiver, msg.state);
+        }
+        if self.trackers.is_empty() || msg.state == ReceiptState::Pending {
+            return;
+        }
+        for push_id in msg.push_ids {
+            let sender = match con.get(&self.key_receipt_sender(&push_id)) {
+                Ok(Some(sender)) => sender,
+                _ => continue,
+            };
+            let acked = Acked {
+                event_id: push_id,
+                receiver: msg.receiver.clone(),
+                kind: msg.state,
+            };
+            for (_, entry) in self.tracking(&sender) {
+                entry.addr.do_send(acked.clone());
+            }
+        }
+    }
+}
+
+impl Handler<StatusOf> for Redis {
+    type Result = Receipts;
+
+    fn handle(&mut self, msg: StatusOf, _: &mut Self::Context) -> Self::Result {
+        let mut con = self.store();
+        let StatusOf { sender, push_ids } = msg;
+        let receipts = push_ids
+            .into_iter()
+            .map(|push_id| {
+                // pushes of others look like unknown ones
+                let sent = matches!(
+                    con.get(&self.key_receipt_sender(&push_id)),
+                    Ok(Some(sent_by)) if sent_by == sender
+                );
+                let receivers = if sent {
+                    con.hgetall(&self.key_receipts(&push_id))
+                        .unwrap_or_default()
+                        .into_iter()
+                        .filter_map(|(receiver, state)| Some((receiver, state.parse().ok()?)))
+                        .collect()
+                } else {
+                    BTreeMap::new()
+                };
+                (push_id, receivers)
+            })
+            .collect();
+        Receipts { receipts }
+    }
+}
+
+impl Handler<DeliverDue> for Redis {
+    type Result = ();
+
+    fn handle(&mut self, _: DeliverDue, ctx: &mut Self::Context) -> Self::Result {
+        let mut con = self.store();
+        let now = Utc::now().timestamp_millis();
+        let due = match con.zrangebyscore(self.zset_scheduled(), now) {
+            Ok(due) => due,
+            Err(_) => return,
+        };
+        for member in due {
+            // another node may have taken it between the two commands
+            match con.zrem(self.zset_scheduled(), &member) {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(e) => {
+                    warn!("cannot take a scheduled activity: {}", e);
+                    continue;
+                }
+            }
+            match serde_json::from_str::<Scheduled>(&member) {
+                Ok(Scheduled {
+                    message,
+                    receivers,
+                    quantity,
+                    ..
+                }) => ctx.notify(Trial {
+                    message,
+                    receivers,
+                    quantity,
+                }),
+                Err(e) => warn!("cannot read a scheduled activity, dropped: {}", e),
+            }
+        }
+    }
+}
+
+impl Redis {
+    /// 消息放入`scheduled`,到时间后由`DeliverDue`写入接收者的队列,
+    /// 返回的消息id为`scheduled`
+    fn schedule(
+        &self,
+        con: &mut dyn StreamStore,
+        at: u64,
+        message: Activity,
+        receivers: Vec<String>,
+        quantity: Option<u32>,
+    ) -> Vec<(String, String)> {
+        let scheduled = Scheduled {
+            nonce: rand::random(),
+            message,
+            receivers,
+            quantity,
+        };
+        let member = serde_json::to_string(&scheduled).unwrap_or_default();
+        if let Err(e) = con.zadd(self.zset_scheduled(), &member, at as i64) {
+            warn!("cannot schedule an activity: {}", e);
+            DELIVERIES.inc_by("push_failed", scheduled.receivers.len() as u64);
+            return vec![];
+        }
+        DELIVERIES.inc_by("scheduled", scheduled.receivers.len() as u64);
+        scheduled
+            .receivers
+            .into_iter()
+            .map(|receiv| (receiv, "scheduled".to_string()))
+            .collect()
+    }
+
+    /// 写入一个接收者的队列,返回接收者和消息id,写入失败时为`None`
+    fn push(
+        &self,
+        con: &mut dyn StreamStore,
+        receiv: &str,
+        message: &Activity,
+        receivers: &[String],
+    ) -> Option<(String, String)> {
+        let key = message.idempotency_key.as_deref();
+        if let Some(id) = key.and_then(|key| self.seen(con, receiv, key)) {
+            DELIVERIES.inc("duplicate");
+            return Some((receiv.to_string(), id));
+        }
+        let id = match con.xadd(&self.key_activity_of(receiv, message), message) {
+            Ok(id) => id,
+            Err(_) => {
+                DELIVERIES.inc("push_failed");
+                return None;
+            }
+        };
+        DELIVERIES.inc("pushed");
+        if let Some(correlation_id) = &message.correlation_id {
+            trace::hop(
+                correlation_id,
+                trace::XADD_DONE,
+                format_args!("receiver:{} id:{}", receiv, id),
+            );
+            self.record_receipt(con, correlation_id, receiv, ReceiptState::Pending);
+            self.record_sender(con, correlation_id, message);
+        }
+        if let Some(key) = key {
+            self.remember(con, receiv, key, &id);
+        }
+        self.fan_out(con, receiv, message, receivers);
+        self.post_webhook(con, receiv, message);
+        Some((receiv.to_string(), id))
+    }
+
+    /// 记录推送在一个接收者处的状态,`RECEIPT_TTL`后过期
+    fn record_receipt(
+        &self,
+        con: &mut dyn StreamStore,
+        push_id: &str,
+        receiver: &str,
+        state: ReceiptState,
+    ) {
+        let key = self.key_receipts(push_id);
+        if let Err(e) = con
+            .hset(&key, receiver, state.as_str())
+            .and_then(|_| con.expire(&key, RECEIPT_TTL))
+        {
+            warn!(
+                "cannot record that {} is {:?} for `{}`: {}",
+                push_id, state, receiver, e
+            );
+        }
+    }
+
+    /// 记录推送的发送者,回执由此找到发送者的session,`/status-of`由此只回答发送者
+    fn record_sender(&self, con: &mut dyn StreamStore, push_id: &str, message: &Activity) {
+        let sender = match message.sender.as_deref() {
+            Some(sender) => sender,
+            None => return,
+        };
+        let key = self.key_receipt_sender(push_id);
+        if let Err(e) = con
+            .set(&key, sender)
+            .and_then(|_| con.expire(&key, RECEIPT_TTL))
+        {
+            warn!("cannot record that `{}` sent {}: {}", sender, push_id, e);
+        }
+    }
+
+    /// 以`name`在线并且`/track on`的session
+    fn tracking<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> impl Iterator<Item = (&'a usize, &'a SessionEntry)> {
+        self.sessions
+            .iter()
+            .filter(move |(id, entry)| entry.name == name && self.trackers.contains(id))
+    }
+
+    fn push_batch(&self, msg: TrialBatch) -> Vec<Vec<(String, String)>> {
+        let mut con = self.store();
+        let now = Utc::now().timestamp_millis();
+        // activities due later wait in `scheduled`, as with `Trial`,
+        // and are counted against the quota when they are written
+        let mut held = HashMap::new();
+        let mut due = vec![];
+        for (index, (message, receivers)) in msg.events.into_iter().enumerate() {
+            match message.deliver_at {
+                Some(at) if at as i64 > now => {
+                    let ids = self.schedule(&mut *con, at, message, receivers, None);
+                    held.insert(index, ids);
+                }
+                _ => match self.reject_over_quota(&mut *con, &message, &receivers) {
+                    Some(rejected) => {
+                        held.insert(index, rejected);
+                    }
+                    None => due.push((message, receivers)),
+                },
+            }
+        }
+        let total = held.len() + due.len();
+        let mut pushed = self.push_due(&mut *con, due).into_iter();
+        (0..total)
+            .map(|index| {
+                held.remove(&index)
+                    .unwrap_or_else(|| pushed.next().unwrap_or_default())
+            })
+            .collect()
+    }
+
+    /// 一次往返写入各条消息,按消息的顺序返回各接收者的消息id
+    fn push_due(
+        &self,
+        con: &mut dyn StreamStore,
+        mut events: Vec<(Activity, Vec<String>)>,
+    ) -> Vec<Vec<(String, String)>> {
+        for (message, _) in &mut events {
+            self.stash_blob(&mut *con, message);
+        }
+        // the same idempotency check as `push`, repeats within the batch get the first id
+        let mut entries: Vec<(String, &Activity)> = vec![];
+        let mut written: HashMap<(&str, &str), usize> = HashMap::new();
+        let mut slots: Vec<Result<usize, String>> = vec![];
+        for (message, receivers) in &events {
+            let key = message.idempotency_key.as_deref();
+            for receiv in receivers {
+                let slot = match key {
+                    Some(key) => match written.get(&(receiv.as_str(), key)) {
+                        Some(index) => Ok(*index),
+                        None => match self.seen(&mut *con, receiv, key) {
+                            Some(id) => Err(id),
+                            None => {
+                                written.insert((receiv, key), entries.len());
+                                Ok(entries.len())
+                            }
+                        },
+                    },
+                    None => Ok(entries.len()),
+                };
+                if slot == Ok(entries.len()) {
+                    entries.push((self.key_activity_of(receiv, message), message));
+                }
+                slots.push(slot);
+            }
+        }
+        let ids = match con.xadd_batch(&entries) {
+            Ok(ids) => ids,
+            Err(e) => {
+                warn!("cannot push a batch of {} activities: {}", entries.len(), e);
+                DELIVERIES.inc_by("push_failed", entries.len() as u64);
+                return events.iter().map(|_| vec![]).collect();
+            }
+        };
+        DELIVERIES.inc_by("pushed", entries.len() as u64);
+
+        let mut slots = slots.into_iter();
+        let mut reported = HashSet::new();
+        let mut results = vec![];
+        for (message, receivers) in &events {
+            let mut pushed = vec![];
+            for (receiv, slot) in receivers.iter().zip(&mut slots) {
+                let (id, fresh) = match slot {
+                    Ok(index) => (ids[index].clone(), reported.insert(index)),
+                    Err(id) => (id, false),
+                };
+                if !fresh {
+                    DELIVERIES.inc("duplicate");
+                    pushed.push((receiv.to_string(), id));
+                    continue;
+                }
+                if let Some(correlation_id) = &message.correlation_id {
+                    trace::hop(
+                        correlation_id,
+                        trace::XADD_DONE,
+                        format_args!("receiver:{} id:{}", receiv, id),
+                    );
+                    self.record_receipt(&mut *con, correlation_id, receiv, ReceiptState::Pending);
+                    self.record_sender(&mut *con, correlation_id, message);
+                }
+                if let Some(key) = &message.idempotency_key {
+                    self.remember(&mut *con, receiv, key, &id);
+                }
+                self.fan_out(&mut *con, receiv, message, receivers);
+                self.post_webhook(&mut *con, receiv, message);
+                pushed.push((receiv.to_string(), id));
+            }
+            results.push(pushed);
+        }
+        results
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RedisOffline;
+
+/// 客户端确认收到的消息,从消息队列删除
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Ack {
+    pub ids: Vec<String>,
+}
+
+/// 轮到redis session读取消息,由`Redis`的scheduler发送
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ReadMessages;
+pub struct RedisSession {
+    pub id: usize,
+    pub name: String,
+    stream_name: String,
+    /// 用户的所有消息队列,分片时不止`stream_name`一个
+    streams: Vec<String>,
+    /// 每次最多读取的消息数量
+    count: usize,
+    /// 每一帧最多包含的消息数量
+    batch_size: usize,
+    /// 一帧消息的大致字节数上限,超出的消息放入下一帧
+    max_frame_bytes: usize,
+    /// 客户端能够处理的受限消息类型,见`GATED_TYPES`
+    capabilities: HashSet<String>,
+    /// 已加入房间的消息队列,以及最后读取到的消息id
+    rooms: HashMap<String, String>,
+    /// 已加入房间的消息队列,以及房间名称
+    room_names: HashMap<String, String>,
+    /// redis errors swallowed while delivering
+    errors: ErrorRate,
+    /// 开始投递前先发送积压消息的摘要
+    backlog_summary: bool,
+    /// 把一批消息编码为发给客户端的文本
+    encode: fn(&[Activity]) -> serde_json::Result<String>,
+    /// 一批消息写成一帧的方式,用于只包含部分字段的消息
+    framing: FramingMode,
+    /// 客户端需要的消息字段,`None`时包含所有字段
+    fields: Option<HashSet<String>>,
+    /// 是否投递自己发到房间的消息
+    echo: bool,
+    /// 客户端的平台,`Platform::name`,用于判断消息的`audience`
+    platform: Option<&'static str>,
+    /// 按客户端平台改写每条消息,`None`时原样发送
+    render: Option<Render>,
+    /// 投递前依次改写消息,可能丢弃
+    hooks: Vec<Arc<dyn DeliveryHook>>,
+    /// 这些用户发送的消息不投递,直接确认
+    muted: HashSet<String>,
+    /// 投递和确认了带有correlation id的消息后通知`Redis`
+    receipts: Option<Recipient<Receipt>>,
+    /// 无法编码的消息移到这里,`None`时直接删除
+    dead_letter: Option<String>,
+    /// 限制投递速度,超出的消息留在队列中
+    throttle: Option<Throttle>,
+    /// websocket session忙碌时重新发送的策略
+    retry: RetryPolicy,
+    /// 由`Redis`调度读取,不自行定时读取
+    scheduled: bool,
+    /// 客户端按id确认后才删除消息,否则发送成功即删除
+    client_ack: bool,
+    /// 至多一次时消息在发送前删除,发送失败不再投递
+    delivery: DeliveryMode,
+    /// 已发送但未确认的最后一条消息id
+    cursor: String,
+    // unacked.key: 已发送但未被客户端确认的消息id
+    // unacked.value: 消息所在的队列和发送的时刻
+    unacked: HashMap<String, (String, Instant)>,
+    /// 已发送的最大消息id,再次读到不大于它的消息即为重复投递
+    delivered_up_to: (u64, u64),
+    /// 连续没有读到消息的次数
+    idle_ticks: u64,
+    /// 积压消息投递完后发送`caught_up`
+    announce_caught_up: bool,
+    /// 上线时队列中有积压的消息,投递完、发送`caught_up`后为`false`
+    catching_up: bool,
+    /// 定时读取消息的间隔,由`/foreground`和`/background`调整
+    poll_interval: Duration,
+    /// 客户端指定过读取间隔,重新加载配置时保留
+    prioritized: bool,
+    /// 定时读取,改变间隔时取消后重新开始
+    poll: Option<SpawnHandle>,
+    pub store: Box<dyn StreamStore>,
+    pub websocket_addr: Recipient<WsMessage>,
+}
+
+impl Actor for RedisSession {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if self.backlog_summary {
+            self.send_backlog_summary();
+        }
+        if self.announce_caught_up {
+            match xlen_all(self.store.as_mut(), &self.streams) {
+                Ok(backlog) => self.catching_up = backlog > 0,
+                Err(e) => self.record_error("xlen", &e),
+            }
+        }
+        self.poll(ctx);
+        if self.client_ack {
+            ctx.run_interval(RECLAIM_INTERVAL, |_, ctx| {
+                ctx.notify(Reclaim { idle: RECLAIM_IDLE })
+            });
+        }
+    }
+}
+
+impl Handler<RedisOffline> for RedisSession {
+    type Result = ();
+
+    fn handle(&mut self, _: RedisOffline, ctx: &mut Self::Context) -> Self::Result {
+        ctx.stop();
+    }
+}
+
+impl Handler<ReadMessages> for RedisSession {
+    type Result = ();
+
+    fn handle(&mut self, _: ReadMessages, ctx: &mut Self::Context) -> Self::Result {
+        self.read_messages(ctx);
+    }
+}
+
+impl Handler<Ack> for RedisSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: Ack, _: &mut Self::Context) -> Self::Result {
+        if self.receipts.is_some() {
+            // the correlation ids are gone with the entries
+            let push_ids = msg
+                .ids
+                .iter()
+                .filter_map(|id| xrange_all(self.store.as_mut(), &self.streams, id, id, 1).ok())
+                .flatten()
+                .filter_map(|entry| entry.get("correlation_id"))
+                .collect();
+            self.receipt(push_ids, ReceiptState::Read);
+        }
+        for id in &msg.ids {
+            self.unacked.remove(id);
+        }
+        let store = &mut self.store;
+        let acked: RedisResult<usize> = self
+            .streams
+            .iter()
+            .map(|key| store.xdel(key, &msg.ids))
+            .sum();
+        match acked {
+            Ok(acked) => DELIVERIES.inc_by("acked", acked as u64),
+            Err(e) => self.record_error("xdel", &e),
+        }
+    }
+}
+
+impl Handler<DescribeSession> for RedisSession {
+    type Result = MessageResult<DescribeSession>;
+
+    fn handle(&mut self, _: DescribeSession, _: &mut Self::Context) -> Self::Result {
+        let (millis, sequence) = self.delivered_up_to;
+        MessageResult(SessionState {
+            id: self.id,
+            name: self.name.clone(),
+            stream_name: self.stream_name.clone(),
+            cursor: self.cursor.clone(),
+            last_delivered_id: (self.delivered_up_to != (0, 0))
+                .then(|| format!("{}-{}", millis, sequence)),
+            idle_ticks: self.idle_ticks,
+            poll_interval_ms: self.poll_interval.as_millis() as u64,
+        })
+    }
+}
+
+impl Handler<Reclaim> for RedisSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: Reclaim, ctx: &mut Self::Context) -> Self::Result {
+        if !self.client_ack {
+            return;
+        }
+        // only what was sent at least `idle` ago, the cursor stays where it is
+        let mut idle: Vec<(String, String)> = self
+            .unacked
+            .iter()
+            .filter(|(_, (_, sent))| sent.elapsed() >= msg.idle)
+            .map(|(id, (key, _))| (key.clone(), id.clone()))
+            .collect();
+        if idle.is_empty() {
+            return;
+        }
+        idle.sort_by_key(|(_, id)| parse_stream_id(id));
+        info!(
+            "redis session:{} reclaims {} activities of `{}` not acked for {:?}",
+            self.id,
+            idle.len(),
+            &self.name,
+            msg.idle
+        );
+        let mut runs: Vec<(String, Vec<StreamId>)> = vec![];
+        for (key, id) in idle {
+            let entry = match self.store.xrange(&key, &id, &id, 1) {
+                Ok(entries) => entries.into_iter().next(),
+                Err(e) => return self.record_error("xrange", &e),
+            };
+            let entry = match entry {
+                Some(entry) => entry,
+                // trimmed or deleted meanwhile
+                None => {
+                    self.unacked.remove(&id);
+                    continue;
+                }
+            };
+            match runs.last_mut() {
+                Some((last, entries)) if *last == key => entries.push(entry),
+                _ => runs.push((key, vec![entry])),
+            }
+        }
+        let frames: Vec<(String, Vec<StreamId>)> = runs
+            .iter()
+            .flat_map(|(key, ids)| {
+                self.frames(ids)
+                    .into_iter()
+                    .map(move |frame| (key.clone(), frame.to_vec()))
+            })
+            .collect();
+        // `ctx.wait` runs the future queued last first
+        for (key, frame) in frames.into_iter().rev() {
+            self.send_activities(&key, frame, ctx);
+        }
+    }
+}
+
+impl Handler<Badges> for RedisSession {
+    type Result = BadgeCounts;
+
+    fn handle(&mut self, _: Badges, _: &mut Self::Context) -> Self::Result {
+        let streams = self.streams.clone();
+        let cursor = if self.client_ack {
+            self.cursor.clone()
+        } else {
+            "0".to_string()
+        };
+        let dm = streams
+            .iter()
+            .map(|key| self.unread(key, &cursor))
+            .sum::<usize>()
+            .min(BADGE_COUNT);
+        let mut rooms: Vec<(String, String)> = self
+            .rooms
+            .iter()
+            .map(|(key, id)| (key.clone(), id.clone()))
+            .collect();
+        rooms.sort_by(|(a, _), (b, _)| self.room_names.get(a).cmp(&self.room_names.get(b)));
+        rooms.truncate(BADGE_ROOMS);
+        let rooms = rooms
+            .into_iter()
+            .filter_map(|(key, id)| {
+                let room = self.room_names.get(&key)?.clone();
+                Some((room, self.unread(&key, &id)))
+            })
+            .collect();
+        BadgeCounts { dm, rooms }
+    }
+}
+
+impl Handler<Reattach> for RedisSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: Reattach, _: &mut Self::Context) -> Self::Result {
+        self.id = msg.id;
+        self.websocket_addr = msg.websocket_addr;
+        self.encode = msg.framing.encoder();
+        self.framing = msg.framing;
+        self.fields = msg.fields;
+        self.echo = msg.echo;
+        if let Some(batch_size) = msg.batch_size {
+            self.batch_size = batch_size.max(1);
+        }
+        self.capabilities = msg.capabilities;
+        self.delivery = msg.delivery;
+        if msg.backlog_summary {
+            self.send_backlog_summary();
+        }
+    }
+}
+
+impl Handler<Capabilities> for RedisSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: Capabilities, _: &mut Self::Context) -> Self::Result {
+        self.capabilities = msg.capabilities;
+    }
+}
+
+impl Handler<Fields> for RedisSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: Fields, _: &mut Self::Context) -> Self::Result {
+        self.fields = msg.fields;
+    }
+}
+
+impl Handler<UsePlatform> for RedisSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: UsePlatform, _: &mut Self::Context) -> Self::Result {
+        self.platform = Some(msg.platform);
+        self.render = msg.render;
+    }
+}
+
+impl Handler<Acked> for RedisSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: Acked, _: &mut Self::Context) -> Self::Result {
+        if let Ok(acked) = serde_json::to_string(&msg) {
+            let _ = self.websocket_addr.do_send(WsMessage(acked));
+        }
+    }
+}
+
+impl Handler<Echo> for RedisSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: Echo, _: &mut Self::Context) -> Self::Result {
+        self.echo = msg.echo;
+    }
+}
+
+impl Handler<Mute> for RedisSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: Mute, _: &mut Self::Context) -> Self::Result {
+        if msg.mute {
+            self.muted.insert(msg.sender);
+        } else {
+            self.muted.remove(&msg.sender);
+        }
+    }
+}
+
+impl Handler<BatchSize> for RedisSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: BatchSize, _: &mut Self::Context) -> Self::Result {
+        self.batch_size = msg.size.max(1);
+    }
+}
+
+impl Handler<Priority> for RedisSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: Priority, ctx: &mut Self::Context) -> Self::Result {
+        self.poll_interval = if msg.foreground {
+            FOREGROUND_INTERVAL
+        } else {
+            BACKGROUND_INTERVAL
+        };
+        self.prioritized = true;
+        self.repoll(ctx);
+    }
+}
+
+impl Handler<ReloadConfig> for RedisSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: ReloadConfig, ctx: &mut Self::Context) -> Self::Result {
+        if !self.prioritized {
+            self.poll_interval = msg.poll_interval;
+            self.repoll(ctx);
+        }
+    }
+}
+
+impl Handler<RoomSubscribe> for RedisSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: RoomSubscribe, _: &mut Self::Context) -> Self::Result {
+        // only messages posted after joining are delivered
+        let last_id = match self.store.xinfo(&msg.stream_name) {
+            Ok(Some(inf)) => inf.last_generated_id,
+            _ => "0".to_string(),
+        };
+        self.room_names.insert(msg.stream_name.clone(), msg.room);
+        self.rooms.entry(msg.stream_name).or_insert(last_id);
+    }
+}
+
+impl Handler<RoomUnsubscribe> for RedisSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: RoomUnsubscribe, _: &mut Self::Context) -> Self::Result {
+        self.rooms.remove(&msg.stream_name);
+        self.room_names.remove(&msg.stream_name);
+    }
+}
+
+impl RedisSession {
+    pub fn new(
+        id: usize,
+        name: String,
+        stream_name: String,
+        store: Box<dyn StreamStore>,
+        websocket_addr: Recipient<WsMessage>,
+    ) -> Self {
+        Self {
+            id,
+            name,
+            streams: vec![stream_name.clone()],
+            stream_name,
+            count: READ_COUNT,
+            batch_size: READ_COUNT,
+            max_frame_bytes: MAX_FRAME_BYTES,
+            capabilities: HashSet::new(),
+            rooms: HashMap::new(),
+            room_names: HashMap::new(),
+            errors: ErrorRate::new(ERROR_RATE_WINDOW, ERROR_RATE_THRESHOLD),
+            backlog_summary: false,
+            encode: FramingMode::Array.encoder(),
+            framing: FramingMode::Array,
+            fields: None,
+            echo: true,
+            platform: None,
+            render: None,
+            hooks: vec![],
+            muted: HashSet::new(),
+            receipts: None,
+            dead_letter: None,
+            throttle: None,
+            retry: RetryPolicy::default(),
+            scheduled: false,
+            client_ack: false,
+            delivery: DeliveryMode::AtLeastOnce,
+            cursor: "0".to_string(),
+            unacked: HashMap::new(),
+            delivered_up_to: (0, 0),
+            idle_ticks: 0,
+            announce_caught_up: true,
+            catching_up: false,
+            poll_interval: MESSAGE_INTERVAL,
+            prioritized: false,
+            poll: None,
+            store,
+            websocket_addr,
+        }
+    }
+
+    pub fn announce_caught_up(mut self, announce_caught_up: bool) -> Self {
+        self.announce_caught_up = announce_caught_up;
+        self
+    }
+
+    pub fn backlog_summary(mut self, backlog_summary: bool) -> Self {
+        self.backlog_summary = backlog_summary;
+        self
+    }
+
+    #[cfg(test)]
+    pub fn encoder(mut self, encode: fn(&[Activity]) -> serde_json::Result<String>) -> Self {
+        self.encode = encode;
+        self
+    }
+
+    pub fn framing(mut self, framing: FramingMode) -> Self {
+        self.encode = framing.encoder();
+        self.framing = framing;
+        self
+    }
+
+    pub fn fields(mut self, fields: Option<HashSet<String>>) -> Self {
+        self.fields = fields;
+        self
+    }
+
+    pub fn echo(mut self, echo: bool) -> Self {
+        self.echo = echo;
+        self
+    }
+
+    pub fn streams(mut self, streams: Vec<String>) -> Self {
+        self.streams = streams;
+        self
+    }
+
+    pub fn hooks(mut self, hooks: Vec<Arc<dyn DeliveryHook>>) -> Self {
+        self.hooks = hooks;
+        self
+    }
+
+    pub fn receipts(mut self, receipts: Recipient<Receipt>) -> Self {
+        self.receipts = Some(receipts);
+        self
+    }
+
+    pub fn muted(mut self, muted: impl IntoIterator<Item = String>) -> Self {
+        self.muted = muted.into_iter().collect();
+        self
+    }
+
+    /// `None`时一帧最多包含`READ_COUNT`条消息
+    pub fn batch_size(mut self, batch_size: Option<usize>) -> Self {
+        self.batch_size = batch_size.unwrap_or(READ_COUNT).max(1);
+        self
+    }
+
+    pub fn max_frame_bytes(mut self, max_frame_bytes: usize) -> Self {
+        self.max_frame_bytes = max_frame_bytes;
+        self
+    }
+
+    pub fn capabilities(mut self, capabilities: HashSet<String>) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    pub fn dead_letter(mut self, stream_name: String) -> Self {
+        self.dead_letter = Some(stream_name);
+        self
+    }
+
+    pub fn client_ack(mut self, client_ack: bool) -> Self {
+        self.client_ack = client_ack;
+        self
+    }
+
+    pub fn delivery(mut self, delivery: DeliveryMode) -> Self {
+        self.delivery = delivery;
+        self
+    }
+
+    pub fn poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    pub fn scheduled(mut self, scheduled: bool) -> Self {
+        self.scheduled = scheduled;
+        self
+    }
+
+    /// at most `rate` activities per second, 0 for no limit
+    pub fn delivery_rate(mut self, rate: u32) -> Self {
+        self.throttle = (rate > 0).then(|| Throttle::new(rate));
+        self
+    }
+
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// 发送一帧消息,超时按重试策略重新发送,websocket session已关闭时直接失败
+    fn send_frame(&self, frame: String) -> impl Future<Output = Result<(), MailboxError>> {
+        let recipient = self.websocket_addr.clone();
+        let retry = self.retry.clone();
+        let id = self.id;
+        async move {
+            let mut attempt = 1;
+            loop {
+                let sent = match retry.timeout {
+                    Some(timeout) => {
+                        recipient
+                            .send(WsMessage(frame.clone()))
+                            .timeout(timeout)
+                            .await
+                    }
+                    None => recipient.send(WsMessage(frame.clone())).await,
+                };
+                match sent {
+                    Err(MailboxError::Timeout) if retry.retries_after(attempt) => {
+                        warn!(
+                            "websocket session:{} is busy, sending again after attempt {}",
+                            id, attempt
+                        );
+                        DELIVERIES.inc("retried");
+                        actix_web::rt::time::sleep(retry.backoff(attempt)).await;
+                        attempt += 1;
+                    }
+                    sent => return sent,
+                }
+            }
+        }
+    }
+
+    /// 把一批消息编码为一帧,客户端选择了字段时只包含这些字段,再按客户端平台改写
+    fn encode(&self, items: &[Activity]) -> serde_json::Result<String> {
+        if self.fields.is_none() && self.render.is_none() {
+            return (self.encode)(items);
+        }
+        let rendered = items
+            .iter()
+            .map(|item| {
+                let value = match &self.fields {
+                    Some(fields) => item.project(fields),
+                    None => serde_json::to_value(item)?,
+                };
+                Ok(match self.render {
+                    Some(render) => render(value),
+                    None => value,
+                })
+            })
+            .collect::<serde_json::Result<Vec<serde_json::Value>>>()?;
+        self.framing.encode(&rendered)
+    }
+
+    /// 队列中`after`之后的消息数量,最多数到`BADGE_COUNT`
+    fn unread(&mut self, key: &str, after: &str) -> usize {
+        match self.store.xrange(key, after, "+", BADGE_COUNT + 1) {
+            // `after` itself is already read
+            Ok(ids) => ids
+                .iter()
+                .filter(|StreamId { id, map: _ }| id != after)
+                .take(BADGE_COUNT)
+                .count(),
+            Err(e) => {
+                self.record_error("xrange", &e);
+                0
+            }
+        }
+    }
+
+    /// 相同compaction key的消息只保留最新的一条,被取代的消息不投递,直接删除
+    fn compact(&mut self, key: &str, ids: Vec<StreamId>) -> Vec<StreamId> {
+        let compaction_key = |entry: &StreamId| entry.get::<String>("compaction_key");
+        let latest: HashMap<String, usize> = ids
+            .iter()
+            .enumerate()
+            .filter_map(|(i, entry)| Some((compaction_key(entry)?, i)))
+            .collect();
+        if latest.is_empty() {
+            return ids;
+        }
+        let (kept, superseded): (Vec<_>, Vec<_>) =
+            ids.into_iter()
+                .enumerate()
+                .partition(|(i, entry)| match compaction_key(entry) {
+                    Some(compaction_key) => latest[&compaction_key] == *i,
+                    None => true,
+                });
+        if !superseded.is_empty() {
+            let superseded: Vec<String> = superseded
+                .into_iter()
+                .map(|(_, StreamId { id, map: _ })| id)
+                .collect();
+            DELIVERIES.inc_by("compacted", superseded.len() as u64);
+            if let Err(e) = self.store.xdel(key, &superseded) {
+                self.record_error("xdel", &e);
+            }
+        }
+        kept.into_iter().map(|(_, entry)| entry).collect()
+    }
+
+    /// 按`batch_size`和`max_frame_bytes`把消息分成多帧,
+    /// 客户端限制了帧的大小,一条超过上限的消息单独成为一帧
+    fn frames<'a>(&self, ids: &'a [StreamId]) -> Vec<&'a [StreamId]> {
+        let mut frames = vec![];
+        let (mut start, mut bytes) = (0, 0);
+        for (i, entry) in ids.iter().enumerate() {
+            let size = entry_size(entry);
+            if i > start && (i - start == self.batch_size || bytes + size > self.max_frame_bytes) {
+                frames.push(&ids[start..i]);
+                start = i;
+                bytes = 0;
+            }
+            bytes += size;
+        }
+        if start < ids.len() {
+            frames.push(&ids[start..]);
+        }
+        frames
+    }
+
+    /// 受限的消息类型只投递给声明了该能力的客户端
+    fn capable_of(&self, entry: &StreamId) -> bool {
+        match entry.get::<String>("activity_type") {
+            Some(activity_type) if GATED_TYPES.contains(&activity_type.as_str()) => {
+                self.capabilities.contains(&activity_type)
+            }
+            _ => true,
+        }
+    }
+
+    /// 带有`audience`的消息只投递给符合条件的session,无法解析的条件视为不符合
+    fn in_audience(&self, entry: &StreamId) -> bool {
+        match entry.get::<String>("audience") {
+            Some(audience) => serde_json::from_str::<Audience>(&audience)
+                .is_ok_and(|audience| audience.admits(self.platform, &self.capabilities)),
+            None => true,
+        }
+    }
+
+    /// 通知`Redis`这些推送在本用户处的状态
+    fn receipt(&self, push_ids: Vec<String>, state: ReceiptState) {
+        if let (Some(receipts), false) = (&self.receipts, push_ids.is_empty()) {
+            let _ = receipts.do_send(Receipt {
+                receiver: self.name.clone(),
+                push_ids,
+                state,
+            });
+        }
+    }
+
+    /// 消息的发送者是否被`/mute`
+    fn is_muted(&self, sender: Option<&str>) -> bool {
+        sender.is_some_and(|sender| self.muted.contains(sender))
+    }
+
+    /// 声明了`blob`能力的客户端直接收到附件,其他客户端只收到下载地址
+    fn attach_blob(&mut self, mut item: Activity) -> Activity {
+        let key = match &item.blob_key {
+            Some(key) if self.capabilities.contains(BLOB_CAPABILITY) => key,
+            _ => return item,
+        };
+        match self.store.get(key) {
+            Ok(Some(blob)) => {
+                item.blob = base64::decode(blob).ok();
+                item.blob_url = None;
+            }
+            // expired, the url tells the same
+            Ok(None) => {}
+            Err(e) => self.record_error("get", &e),
+        }
+        item
+    }
+
+    /// 不投递的消息当作已经投递,从消息队列删除
+    fn discard(&mut self, key: &str, entries: Vec<StreamId>, outcome: &str) {
+        if entries.is_empty() {
+            return;
+        }
+        let ids: Vec<String> = entries
+            .into_iter()
+            .map(|StreamId { id, map: _ }| id)
+            .collect();
+        info!(
+            "redis session:{} drops {:?} of {}, {}",
+            self.id, &ids, key, outcome
+        );
+        DELIVERIES.inc_by(outcome, ids.len() as u64);
+        if let Err(e) = self.store.xdel(key, &ids) {
+            self.record_error("xdel", &e);
+        }
+    }
+
+    /// 限量消息按读取的先后计数,领取到的接收者再次读取时仍然收到,
+    /// 已经发完的告诉客户端`sold_out`后删除,计数失败的留到下次读取
+    fn claim(&mut self, key: &str, ids: Vec<StreamId>) -> Vec<StreamId> {
+        let mut claimed = Vec::with_capacity(ids.len());
+        let mut sold_out = vec![];
+        for entry in ids {
+            let (claim_key, quantity) = match (
+                entry.get::<String>("claim_key"),
+                entry.get::<i64>("quantity"),
+            ) {
+                (Some(claim_key), Some(quantity)) => (claim_key, quantity),
+                _ => {
+                    claimed.push(entry);
+                    continue;
+                }
+            };
+            let winners = format!("{}:winners", claim_key);
+            let won = match self.store.smembers(&winners) {
+                Ok(winners) if winners.contains(&self.name) => Ok(true),
+                Ok(_) => self.store.incr(&claim_key).map(|count| count <= quantity),
+                Err(e) => Err(e),
+            };
+            match won {
+                Ok(true) => {
+                    if let Err(e) = self
+                        .store
+                        .sadd(&winners, &self.name)
+                        .and_then(|_| self.store.expire(&winners, CLAIM_TTL))
+                    {
+                        self.record_error("sadd", &e);
+                    }
+                    claimed.push(entry);
+                }
+                Ok(false) => sold_out.push(entry),
+                Err(e) => self.record_error("incr", &e),
+            }
+        }
+        for entry in &sold_out {
+            let signal = SoldOut {
+                activity_type: entry.get("activity_type").unwrap_or_default(),
+                correlation_id: entry.get("correlation_id"),
+            };
+            if let Ok(signal) = serde_json::to_string(&signal) {
+                let _ = self.websocket_addr.do_send(WsMessage(signal));
+            }
+        }
+        self.discard(key, sold_out, "sold_out");
+        claimed
+    }
+
+    /// 无法编码的消息不再投递,移到死信队列后从消息队列删除,避免反复读取
+    fn bury(&mut self, key: &str, ids: &[StreamId], items: &[Activity], err: &serde_json::Error) {
+        let id_strs: Vec<String> = ids
+            .iter()
+            .map(|StreamId { id, map: _ }| id.clone())
+            .collect();
+        warn!(
+            "redis session:{} cannot encode entries {:?} of {}: {}",
+            self.id, id_strs, key, err
+        );
+        if let Some(dead_letter) = &self.dead_letter {
+            for item in items {
+                if let Err(e) = self.store.xadd(dead_letter, item) {
+                    // keep the entries, they are retried with the next read
+                    return self.record_error("xadd", &e);
+                }
+            }
+        }
+        if let Err(e) = self.store.xdel(key, &id_strs) {
+            self.record_error("xdel", &e);
+        }
+    }
+
+    /// 告诉客户端有多少条积压的消息,没有积压时不发送
+    fn send_backlog_summary(&mut self) {
+        let count = match xlen_all(self.store.as_mut(), &self.streams) {
+            Ok(0) => return,
+            Ok(count) => count,
+            Err(e) => return self.record_error("xlen", &e),
+        };
+        let oldest = match xrange_all(self.store.as_mut(), &self.streams, "-", "+", 1) {
+            Ok(ids) => ids.into_iter().next(),
+            Err(e) => return self.record_error("xrange", &e),
+        };
+        let newest = match xrevrange_all(self.store.as_mut(), &self.streams, "+", "-", 1) {
+            Ok(ids) => ids.into_iter().next(),
+            Err(e) => return self.record_error("xrevrange", &e),
+        };
+        if let (Some(oldest), Some(newest)) = (oldest, newest) {
+            let summary = BacklogSummary {
+                count,
+                oldest_id: oldest.id,
+                newest_id: newest.id,
+            };
+            if let Ok(summary) = serde_json::to_string(&summary) {
+                let _ = self.websocket_addr.do_send(WsMessage(summary));
+            }
+        }
+    }
+
+    /// count a swallowed redis error, warn when the session keeps failing
+    fn record_error(&mut self, op: &str, err: &RedisError) {
+        debug!("redis session:{} `{}` error: {}", self.id, op, err);
+        REDIS_ERRORS.inc(op);
+        if self.errors.record() {
+            warn!(
+                "redis session:{} of `{}` reached {} errors in {:?}, {} in total",
+                self.id, self.name, ERROR_RATE_THRESHOLD, ERROR_RATE_WINDOW, self.errors.total
+            );
+        }
+    }
+}
+
+impl RedisSession {
+    /// 每隔`poll_interval`读取一次,由`Redis`调度时不自行读取
+    fn poll(&mut self, ctx: &mut Context<Self>) {
+        if !self.scheduled {
+            self.poll = Some(ctx.run_interval(self.poll_interval, |act, ctx| {
+                act.read_messages(ctx);
+            }));
+        }
+    }
+
+    /// 间隔变化后取消原来的定时读取,按新的间隔重新开始
+    fn repoll(&mut self, ctx: &mut Context<Self>) {
+        if let Some(poll) = self.poll.take() {
+            ctx.cancel_future(poll);
+        }
+        self.poll(ctx);
+    }
+
+    /// 间隔比`MESSAGE_INTERVAL`长时按比例多读,投递的速度不变
+    fn reads_per_tick(&self) -> usize {
+        let ratio = self.poll_interval.as_millis() / MESSAGE_INTERVAL.as_millis();
+        self.count * (ratio as usize).max(1)
+    }
+
+    fn read_messages(&mut self, ctx: &mut Context<Self>) {
+        let budget = match &mut self.throttle {
+            Some(throttle) => throttle.available(Instant::now()),
+            None => usize::MAX,
+        };
+        let delivered = self.read_activities(budget, ctx);
+        let delivered = delivered + self.read_rooms(budget - delivered, ctx);
+        if let Some(throttle) = &mut self.throttle {
+            throttle.take(delivered);
+        }
+        self.idle_ticks = if delivered == 0 {
+            self.idle_ticks + 1
+        } else {
+            0
+        };
+        if self.catching_up && delivered == 0 {
+            self.send_caught_up();
+        }
+    }
+
+    /// 第一次读完积压的消息,之后的消息都是实时投递的
+    fn send_caught_up(&mut self) {
+        self.catching_up = false;
+        if self.delivered_up_to == (0, 0) {
+            // 积压的消息一条也没有送达
+            return;
+        }
+        let (millis, sequence) = self.delivered_up_to;
+        let caught_up = CaughtUp {
+            last_id: format!("{}-{}", millis, sequence),
+        };
+        if let Ok(caught_up) = serde_json::to_string(&caught_up) {
+            let _ = self.websocket_addr.do_send(WsMessage(caught_up));
+        }
+    }
+
+    /// 读取个人消息,最多读取`budget`条,返回读取到的数量
+    fn read_activities(&mut self, budget: usize, ctx: &mut Context<Self>) -> usize {
+        if budget == 0 {
+            return 0;
+        }
+        let mut pending = false;
+        for i in 0..self.streams.len() {
+            match self.store.xinfo(&self.streams[i]) {
+                // no message in stream,keep pollings
+                Ok(Some(StreamInfo { length: 0, .. })) => {}
+                Ok(Some(_)) => pending = true,
+                // the xadd command have not been execute, no message
+                Ok(None) => {}
+                Err(e) => {
+                    self.record_error("xinfo", &e);
+                    return 0;
+                }
+            }
+        }
+        if !pending {
+            return 0;
+        }
+
+        // read all messages in the stream
+        // acknowledged by the client, unacknowledged ones are not sent again
+        let (cursor, block) = if self.client_ack {
+            (self.cursor.clone(), None)
+        } else {
+            ("0".to_string(), Some(BLOCK_MILLIS))
+        };
+        let count = self.reads_per_tick().min(budget);
+        let cursors = vec![cursor; self.streams.len()];
+        let ssr: RedisResult<StreamReadReply> =
+            self.store.xread(&self.streams, &cursors, count, block);
+        let ssr = match ssr {
+            Ok(ssr) => ssr,
+            Err(e) => {
+                self.record_error("xread", &e);
+                return 0;
+            }
+        };
+        let runs = merge_shards(ssr.keys, count);
+        let delivered = runs.iter().map(|(_, ids)| ids.len()).sum();
+        let runs: Vec<(String, Vec<StreamId>)> = runs
+            .into_iter()
+            .map(|(key, ids)| {
+                let ids = self.compact(&key, ids);
+                (key, ids)
+            })
+            .collect();
+        let frames: Vec<(&str, &[StreamId])> = runs
+            .iter()
+            .flat_map(|(key, ids)| {
+                self.frames(ids)
+                    .into_iter()
+                    .map(move |frame| (key.as_str(), frame))
+            })
+            .collect();
+        // `ctx.wait` runs the future queued last first
+        for (key, frame) in frames.into_iter().rev() {
+            self.send_activities(key, frame.to_vec(), ctx);
+        }
+        delivered
+    }
+
+    /// 把个人消息作为一帧发送,发送成功后删除,需要客户端确认时只移动游标,至多一次时发送前删除
+    fn send_activities(&mut self, key: &str, ids: Vec<StreamId>, ctx: &mut Context<Self>) {
+        let now = Utc::now().timestamp_millis() as u64;
+        let (ids, expired): (Vec<StreamId>, Vec<StreamId>) = ids
+            .into_iter()
+            .partition(|entry| entry.get::<u64>("expires_at").is_none_or(|at| at > now));
+        // too late to matter, a late reconnect does not get them
+        self.discard(key, expired, "expired");
+        let (ids, skipped): (Vec<StreamId>, Vec<StreamId>) =
+            ids.into_iter().partition(|entry| self.capable_of(entry));
+        // the client would not render them
+        self.discard(key, skipped, "skipped");
+        let (ids, outside): (Vec<StreamId>, Vec<StreamId>) =
+            ids.into_iter().partition(|entry| self.in_audience(entry));
+        self.discard(key, outside, "outside_audience");
+        let (muted, ids): (Vec<StreamId>, Vec<StreamId>) = ids
+            .into_iter()
+            .partition(|entry| self.is_muted(entry.get::<String>("sender").as_deref()));
+        self.discard(key, muted, "muted");
+        let ids = self.claim(key, ids);
+        if ids.is_empty() {
+            return;
+        }
+        let redelivered = ids
+            .iter()
+            .filter(|entry| parse_stream_id(&entry.id) <= self.delivered_up_to)
+            .count();
+        if redelivered > 0 {
+            DELIVERIES.inc_by("redelivered", redelivered as u64);
+        }
+        let mut kept = Vec::with_capacity(ids.len());
+        let mut items = Vec::with_capacity(ids.len());
+        let mut dropped = vec![];
+        for entry in ids {
+            let item = self.attach_blob(Activity {
+                id: self.client_ack.then(|| entry.id.clone()),
+                ..Activity::from(&entry)
+            });
+            match run_hooks(&self.hooks, item) {
+                Some(item) => {
+                    items.push(item);
+                    kept.push(entry);
+                }
+                None => dropped.push(entry),
+            }
+        }
+        // a hook decided the client does not get them
+        self.discard(key, dropped, "dropped");
+        let ids = kept;
+        if ids.is_empty() {
+            return;
+        }
+        let correlation_ids: Vec<String> = items
+            .iter()
+            .filter_map(|item| item.correlation_id.clone())
+            .collect();
+        for correlation_id in &correlation_ids {
+            trace::hop(
+                correlation_id,
+                trace::XREAD,
+                format_args!("session:{}", self.id),
+            );
+        }
+        let res = match self.encode(&items) {
+            Ok(res) => res,
+            Err(e) => return self.bury(key, &ids, &items, &e),
+        };
+        // the frame may still be lost, it is never read again
+        let at_most_once = self.delivery == DeliveryMode::AtMostOnce && !self.client_ack;
+        if at_most_once {
+            let id_strs: Vec<String> = ids
+                .iter()
+                .map(|StreamId { id, map: _ }| id.clone())
+                .collect();
+            match self.store.xdel(key, &id_strs) {
+                Ok(acked) => DELIVERIES.inc_by("acked", acked as u64),
+                // sending anyway could deliver them twice
+                Err(e) => return self.record_error("xdel", &e),
+            }
+        }
+        let key = key.to_owned();
+        self.send_frame(res)
+            .into_actor(self)
+            .then(move |res, act, ctx| {
+                match res {
+                    Ok(_) => {
+                        DELIVERIES.inc_by("delivered", ids.len() as u64);
+                        if let Some(StreamId { id, map: _ }) = ids.last() {
+                            act.delivered_up_to = act.delivered_up_to.max(parse_stream_id(id));
+                        }
+                        for correlation_id in &correlation_ids {
+                            trace::hop(
+                                correlation_id,
+                                trace::FRAME_SENT,
+                                format_args!("session:{}", act.id),
+                            );
+                        }
+                        act.receipt(correlation_ids, ReceiptState::Delivered);
+                        if act.client_ack {
+                            let sent = Instant::now();
+                            for StreamId { id, map: _ } in &ids {
+                                act.unacked.insert(id.clone(), (key.clone(), sent));
+                            }
+                            // a reclaimed frame is older than the cursor
+                            if let Some(StreamId { id, map: _ }) = ids.last() {
+                                if parse_stream_id(id) > parse_stream_id(&act.cursor) {
+                                    act.cursor = id.clone();
+                                }
+                            }
+                            return fut::ready(());
+                        }
+                        if at_most_once {
+                            return fut::ready(());
+                        }
+                        // remove all the sended messages out from stream
+                        let id_strs: Vec<String> =
+                            ids.into_iter().map(|StreamId { id, map: _ }| id).collect();
+                        match act.store.xdel(&key, &id_strs) {
+                            Ok(acked) => DELIVERIES.inc_by("acked", acked as u64),
+                            Err(e) => act.record_error("xdel", &e),
+                        }
+                    }
+                    // something wrong with socket server
+                    _ => {
+                        DELIVERIES.inc_by("failed", ids.len() as u64);
+                        ctx.stop()
+                    }
+                }
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// 读取已加入房间的消息,房间消息由所有成员共享,只移动游标不删除
+    /// 所有房间最多读取`budget`条,返回读取到的数量
+    fn read_rooms(&mut self, budget: usize, ctx: &mut Context<Self>) -> usize {
+        if self.rooms.is_empty() || budget == 0 {
+            return 0;
+        }
+        let (keys, ids): (Vec<String>, Vec<String>) = self
+            .rooms
+            .iter()
+            .map(|(key, id)| (key.clone(), id.clone()))
+            .unzip();
+
+        let ssr: RedisResult<StreamReadReply> =
+            self.store.xread(&keys, &ids, self.count.min(budget), None);
+        let ssr = match ssr {
+            Ok(ssr) => ssr,
+            Err(e) => {
+                self.record_error("xread", &e);
+                return 0;
+            }
+        };
+        let mut delivered = 0;
+        for StreamKey { key, mut ids } in ssr.keys {
+            ids.truncate(budget - delivered);
+            delivered += ids.len();
+            // `ctx.wait` runs the future queued last first
+            for frame in self.frames(&ids).into_iter().rev() {
+                self.send_room(&key, frame, ctx);
+            }
+        }
+        delivered
+    }
+
+    /// 房间的游标只向前移动,同一次读取的各帧不一定按顺序完成
+    fn advance_room(&mut self, key: &str, id: String) {
+        // the session may have left the room meanwhile
+        if let Some(cursor) = self.rooms.get_mut(key) {
+            if parse_stream_id(&id) > parse_stream_id(cursor) {
+                *cursor = id;
+            }
+        }
+    }
+
+    /// 把房间消息作为一帧发送,发送成功后移动游标
+    fn send_room(&mut self, key: &str, ids: &[StreamId], ctx: &mut Context<Self>) {
+        let last_id = match ids.last() {
+            Some(StreamId { id, map: _ }) => id.clone(),
+            None => return,
+        };
+        let now = Utc::now().timestamp_millis() as u64;
+        // room entries are shared, an expired one is only skipped
+        let live: Vec<&StreamId> = ids
+            .iter()
+            .filter(|entry| entry.get::<u64>("expires_at").is_none_or(|at| at > now))
+            .collect();
+        if live.len() < ids.len() {
+            DELIVERIES.inc_by("expired", (ids.len() - live.len()) as u64);
+        }
+        let mut items = Vec::with_capacity(live.len());
+        let mut skipped: BTreeMap<&str, usize> = BTreeMap::new();
+        for entry in live {
+            let reason = if !self.capable_of(entry) {
+                "not capable"
+            } else if !self.in_audience(entry) {
+                "outside the audience"
+            } else {
+                let item = Activity::from(entry);
+                // the client already shows what it posted
+                if !self.echo && item.sender.as_deref() == Some(self.name.as_str()) {
+                    "its own post"
+                } else if self.is_muted(item.sender.as_deref()) {
+                    "muted"
+                } else if let Some(item) = run_hooks(&self.hooks, item) {
+                    items.push(item);
+                    continue;
+                } else {
+                    "dropped by a hook"
+                }
+            };
+            *skipped.entry(reason).or_default() += 1;
+        }
+        for (reason, count) in skipped {
+            info!(
+                "redis session:{} skips {} entries of {} up to {}, {}",
+                self.id, count, key, last_id, reason
+            );
+            DELIVERIES.inc_by("skipped", count as u64);
+        }
+        if items.is_empty() {
+            self.advance_room(key, last_id);
+            return;
+        }
+        let items_len = items.len() as u64;
+        let res = match self.encode(&items) {
+            Ok(res) => res,
+            Err(e) => {
+                // room entries are shared, skip them without deleting
+                warn!(
+                    "redis session:{} skips {} entries of {} up to {}: {}",
+                    self.id,
+                    items.len(),
+                    key,
+                    last_id,
+                    e
+                );
+                self.advance_room(key, last_id);
+                return;
+            }
+        };
+        let key = key.to_owned();
+        self.send_frame(res)
+            .into_actor(self)
+            .then(move |res, act, ctx| {
+                match res {
+                    Ok(_) => {
+                        DELIVERIES.inc_by("delivered", items_len);
+                        act.advance_room(&key, last_id);
+                    }
+                    // something wrong with socket server
+                    _ => {
+                        DELIVERIES.inc_by("failed", items_len);
+                        ctx.stop()
+                    }
+                }
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+}
+
+/// 用户上线消息,由websocket session发送到redis
+/// redis 接收到online
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Online {
+    /// websocket session id
+    pub id: usize,
+    /// logined username
+    pub name: String,
+    /// `socket` session addr
+    pub addr: Recipient<WsMessage>,
+    /// 强制断开`socket` session
+    pub kick: Recipient<Kick>,
+    /// 向`socket` session的客户端发起请求
+    pub ask: Recipient<Ask>,
+    /// 投递积压消息前先发送摘要
+    pub backlog_summary: bool,
+    /// 一批消息写成一帧的方式
+    pub framing: FramingMode,
+    /// 每一帧最多包含的消息数量,`None`时使用默认值
+    pub batch_size: Option<usize>,
+    /// 客户端能够处理的受限消息类型
+    pub capabilities: HashSet<String>,
+    /// 投递失败的消息是否重新投递
+    pub delivery: DeliveryMode,
+    /// 客户端需要的消息字段,`None`时包含所有字段
+    pub fields: Option<HashSet<String>>,
+    /// 是否收到自己发到房间的消息
+    pub echo: bool,
+}
+
+/// 用户上线消息,由websocket session发送到redis
+/// redis 接收到online
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct PlatformOnline {
+    /// websocket session id
+    pub id: usize,
+    /// logined username
+    pub name: String,
+    /// device
+    pub platform: Platform,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Offline {
+    /// websocket session id
+    pub id: usize,
+}
+
+/// session心跳,刷新`last-seen`以及`last-seen:{username}`
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Heartbeat {
+    /// websocket session id
+    pub id: usize,
+}
+
+/// 统计在线用户队列中还没有投递的消息数量
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SampleBacklog;
+
+/// 把到时间的定时消息写入接收者的队列
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct DeliverDue;
+
+/// 等待投递的定时消息,`nonce`让内容相同的两次推送各占一个zset成员
+#[derive(Serialize, Deserialize)]
+struct Scheduled {
+    nonce: u64,
+    message: Activity,
+    receivers: Vec<String>,
+    #[serde(default)]
+    quantity: Option<u32>,
+}
+
+/// 清理长时间没有心跳的session
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SweepStale;
+
+/// 加入房间
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+pub struct Join {
+    /// websocket session id
+    pub id: usize,
+    /// logined username
+    pub name: String,
+    pub room: Room,
+}
+
+/// 离开房间
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+pub struct Leave {
+    /// websocket session id
+    pub id: usize,
+    /// logined username
+    pub name: String,
+    pub room: Room,
+}
+
+/// 向房间发送消息,返回消息id
+#[derive(Message)]
+#[rtype(result = "Option<String>")]
+pub struct Post {
+    pub room: Room,
+    pub message: Activity,
+}
+
+/// 按顺序导出用户队列中的消息,每次最多`EXPORT_LIMIT`条
+#[derive(Message)]
+#[rtype(result = "RedisResult<Vec<ExportedActivity>>")]
+pub struct ExportUser {
+    pub username: String,
+    /// 从这条消息之后开始导出,`None`时从头开始
+    pub after: Option<String>,
+    pub limit: usize,
+}
+
+/// 导出的消息,带有消息id以及写入时间
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ExportedActivity {
+    pub id: String,
+    /// 写入队列的时间,取自消息id
+    pub timestamp: DateTime<Utc>,
+    #[serde(flatten)]
+    pub activity: Activity,
+}
+
+/// 删除用户:断开用户的所有session并删除用户的所有数据,可以重复发送
+/// 返回被断开的session数量
+#[derive(Message)]
+#[rtype(result = "usize")]
+pub struct PurgeUser {
+    pub username: String,
+}
+
+/// 设置用户状态,`None`为清除
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SetStatus {
+    pub name: String,
+    pub status: Option<String>,
+}
+
+/// 客户端通过`/report`报告的错误,带上session的id和name
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ClientReport {
+    pub id: usize,
+    pub name: Option<String>,
+    pub report: serde_json::Value,
+}
+
+/// 公开或取消公开用户,公开用户的公开类型消息会复制给关注者
+#[derive(Message)]
+#[rtype(Publicity)]
+pub struct SetPublic {
+    pub name: String,
+    pub public: bool,
+}
+
+/// 用户是否公开
+#[derive(MessageResponse, Serialize, Debug, PartialEq)]
+pub struct Publicity {
+    pub name: String,
+    pub public: bool,
+}
+
+/// 关注或取消关注用户
+#[derive(Message)]
+#[rtype(Following)]
+pub struct Follow {
+    pub follower: String,
+    pub username: String,
+    /// `false`为取消关注
+    pub follow: bool,
+}
+
+/// 是否正在关注用户,用户不公开时无法关注
+#[derive(MessageResponse, Serialize, Debug, PartialEq)]
+pub struct Following {
+    pub username: String,
+    pub following: bool,
+}
+
+/// 查询用户是否在线及其状态
+#[derive(Message)]
+#[rtype(PresenceInfo)]
+pub struct Presence {
+    pub name: String,
+}
+
+/// 用户在线状态
+#[derive(MessageResponse, Serialize, Debug, PartialEq)]
+pub struct PresenceInfo {
+    pub name: String,
+    pub online: bool,
+    pub status: Option<String>,
+}
+
+/// 不通过websocket订阅用户的消息,消息由客户端按id确认,返回redis session用于确认和停止
+#[derive(Message)]
+#[rtype(result = "Addr<RedisSession>")]
+pub struct Subscribe {
+    pub name: String,
+    /// 每条为一批消息的json
+    pub frames: UnboundedSender<String>,
+}
+
+/// 查询还未确认的消息
+#[derive(Message)]
+#[rtype(Missed)]
+pub struct ListMissed {
+    pub name: String,
+    /// 最多返回的id数量,不超过`MISSED_LIMIT`
+    pub limit: usize,
+}
+
+/// 还未确认的消息数量以及id,最新的在前
+#[derive(MessageResponse, Serialize, Debug, PartialEq)]
+pub struct Missed {
+    pub count: usize,
+    pub ids: Vec<String>,
+}
+
+/// 读取用户队列中的一条消息,只读,不确认也不删除
+#[derive(Message)]
+#[rtype(Fetched)]
+pub struct GetActivity {
+    pub name: String,
+    /// stream entry id
+    pub id: String,
+}
+
+/// 读取到的消息,不在队列中时`activity`为空
+#[derive(MessageResponse, Serialize, Debug, PartialEq)]
+pub struct Fetched {
+    pub id: String,
+    pub activity: Option<Activity>,
+}
+
+/// 读取用户队列中某个时间及之后写入的消息,只读,不确认也不删除
+#[derive(Message)]
+#[rtype(Since)]
+pub struct ListSince {
+    pub name: String,
+    /// unix毫秒
+    pub since_ms: u64,
+}
+
+/// 按写入顺序排列,最多`SINCE_LIMIT`条,各条消息带有id
+#[derive(MessageResponse, Serialize, Debug, PartialEq)]
+pub struct Since {
+    pub since_ms: u64,
+    pub activities: Vec<Activity>,
+}
+
+/// 读取用户队列中某条消息之前的一页消息,只读,不确认也不删除
+#[derive(Message)]
+#[rtype(HistoryPage)]
+pub struct ListHistory {
+    pub name: String,
+    /// 只返回id小于它的消息
+    pub before_id: String,
+    /// 最多返回的数量,不超过`HISTORY_PAGE_LIMIT`
+    pub count: usize,
+}
+
+/// 最新的在前,各条消息带有id,`end`时没有更早的消息
+#[derive(MessageResponse, Serialize, Debug, PartialEq)]
+pub struct HistoryPage {
+    pub before_id: String,
+    pub activities: Vec<Activity>,
+    /// 用来读取下一页,`end`时为空
+    pub next_before_id: Option<String>,
+    pub end: bool,
+}
+
+/// 一次推送在接收者处的状态
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReceiptState {
+    /// 已写入接收者的队列
+    Pending,
+    /// 已发送给接收者的客户端
+    Delivered,
+    /// 客户端已按id确认
+    Read,
+}
+
+impl ReceiptState {
+    fn as_str(self) -> &'static str {
+        match self {
+            ReceiptState::Pending => "pending",
+            ReceiptState::Delivered => "delivered",
+            ReceiptState::Read => "read",
+        }
+    }
+}
+
+impl FromStr for ReceiptState {
+    type Err = ();
+
+    fn from_str(state: &str) -> Result<Self, Self::Err> {
+        match state {
+            "pending" => Ok(ReceiptState::Pending),
+            "delivered" => Ok(ReceiptState::Delivered),
+            "read" => Ok(ReceiptState::Read),
+            _ => Err(()),
+        }
+    }
+}
+
+/// redis session投递或者客户端确认了这些推送,由redis session发送
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Receipt {
+    pub receiver: String,
+    /// 推送时附加的correlation id
+    pub push_ids: Vec<String>,
+    pub state: ReceiptState,
+}
+
+/// 开始或停止实时收到自己发出的推送的回执,由websocket session发送到redis
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Track {
+    /// websocket session id
+    pub id: usize,
+    pub on: bool,
+}
+
+/// 推送已投递给接收者或者被接收者确认,发给`/track on`的发送者
+#[derive(Message, Serialize, Debug, Clone, PartialEq)]
+#[rtype(result = "()")]
+#[serde(tag = "type", rename = "ack")]
+pub struct Acked {
+    /// 推送时附加的correlation id
+    pub event_id: String,
+    pub receiver: String,
+    /// `delivered`或者`read`
+    pub kind: ReceiptState,
+}
+
+/// 查询推送在各接收者处的状态
+#[derive(Message)]
+#[rtype(Receipts)]
+pub struct StatusOf {
+    /// 只回答`sender`自己的推送
+    pub sender: String,
+    pub push_ids: HashSet<String>,
+}
+
+/// push id -> 接收者 -> 状态,过期或者未知的推送没有接收者
+#[derive(MessageResponse, Serialize, Debug, PartialEq)]
+pub struct Receipts {
+    pub receipts: BTreeMap<String, BTreeMap<String, ReceiptState>>,
+}
+
+/// 读取附件,过期或者不存在时为`None`
+#[derive(Message)]
+#[rtype(result = "Option<Vec<u8>>")]
+pub struct GetBlob {
+    pub blob_ref: String,
+}
+
+/// 清空用户的消息队列,不投递
+#[derive(Message)]
+#[rtype(Cleared)]
+pub struct Clear {
+    pub name: String,
+}
+
+/// 清空的消息数量
+#[derive(MessageResponse, Serialize, Debug, PartialEq)]
+pub struct Cleared {
+    pub cleared: usize,
+}
+
+/// 列出所有在线的redis session及其投递进度,用于排查投递卡住的问题
+#[derive(Message)]
+#[rtype(result = "Vec<SessionState>")]
+pub struct ListSessions;
+
+/// 查询session已投递到的消息id以及队列长度,客户端保存后可以从这里继续
+#[derive(Message)]
+#[rtype(CursorPosition)]
+pub struct Cursor {
+    /// websocket session id
+    pub id: usize,
+    pub name: String,
+}
+
+/// session已投递到的消息id
+#[derive(MessageResponse, Serialize, Debug, PartialEq)]
+pub struct CursorPosition {
+    /// 已投递的最大消息id,还没有投递过时为`NO_CURSOR`
+    pub last_id: String,
+    /// 队列中还有的消息数量
+    pub stream_len: usize,
+}
+
+/// 查询个人消息以及各房间的未读消息数量
+#[derive(Message)]
+#[rtype(BadgeCounts)]
+pub struct Badges {
+    /// websocket session id
+    pub id: usize,
+}
+
+/// 未读消息数量,每个队列最多数到`BADGE_COUNT`,最多包含`BADGE_ROOMS`个房间
+#[derive(MessageResponse, Serialize, Debug, Default, PartialEq)]
+pub struct BadgeCounts {
+    /// 个人消息
+    pub dm: usize,
+    /// 房间名称以及未读数量
+    pub rooms: BTreeMap<String, usize>,
+}
+
+/// 查询redis session的投递进度
+#[derive(Message)]
+#[rtype(result = "SessionState")]
+pub struct DescribeSession;
+
+/// redis session的投递进度
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SessionState {
+    /// websocket session id
+    pub id: usize,
+    pub name: String,
+    pub stream_name: String,
+    /// 由客户端确认时,已发送但未确认的最后一条消息id
+    pub cursor: String,
+    /// 已发送的最大消息id,还没有发送过时为`None`
+    pub last_delivered_id: Option<String>,
+    /// 连续没有读到消息的次数
+    pub idle_ticks: u64,
+    /// 定时读取消息的间隔
+    pub poll_interval_ms: u64,
+}
+
+/// 已发送超过`idle`仍未被客户端确认的消息重新投递,由客户端确认的redis session定时发送给自己,
+/// 客户端请求重新投递时`idle`为0
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Reclaim {
+    pub idle: Duration,
+}
+
+/// 用户重连,redis session改为向新的websocket session发送消息
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Reattach {
+    /// new websocket session id
+    pub id: usize,
+    pub websocket_addr: Recipient<WsMessage>,
+    /// 新的websocket session需要积压消息的摘要
+    pub backlog_summary: bool,
+    pub framing: FramingMode,
+    pub batch_size: Option<usize>,
+    pub capabilities: HashSet<String>,
+    pub delivery: DeliveryMode,
+    pub fields: Option<HashSet<String>>,
+    pub echo: bool,
+}
+
+/// 设置客户端能够处理的受限消息类型,由websocket session发送到redis
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+pub struct Capabilities {
+    /// websocket session id
+    pub id: usize,
+    pub capabilities: HashSet<String>,
+}
+
+/// 设置客户端需要的消息字段,由websocket session发送到redis
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+pub struct Fields {
+    /// websocket session id
+    pub id: usize,
+    /// `None`时包含所有字段
+    pub fields: Option<HashSet<String>>,
+}
+
+/// 客户端的平台以及对应的改写,由`Redis`在收到`PlatformOnline`后发送给redis session
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct UsePlatform {
+    pub platform: &'static str,
+    pub render: Option<Render>,
+}
+
+/// 设置是否收到自己发到房间的消息,由websocket session发送到redis
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+pub struct Echo {
+    /// websocket session id
+    pub id: usize,
+    pub echo: bool,
+}
+
+/// 不再收到或重新收到某个用户发送的消息,由websocket session发送到redis
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+pub struct Mute {
+    /// websocket session id
+    pub id: usize,
+    pub sender: String,
+    /// `false`为取消静音
+    pub mute: bool,
+}
+
+/// 运行时重新加载的配置,`Redis`把读取间隔转给没有指定过间隔的session,
+/// `Websocket`更新同名session的上限
+#[derive(Message, Debug, Clone)]
+#[rtype(result = "()")]
+pub struct ReloadConfig {
+    /// session读取消息的间隔,0为`MESSAGE_INTERVAL`
+    pub poll_interval: Duration,
+    /// 一个name最多被多少个session持有,0为不限制
+    pub max_sessions_per_user: usize,
+}
+
+/// 客户端的应用切换到前台或后台,前台时缩短读取间隔,后台时延长间隔并且每次读取更多消息,
+/// 由`Redis`调度读取时只影响每次读取的数量
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+pub struct Priority {
+    /// websocket session id
+    pub id: usize,
+    pub foreground: bool,
+}
+
+/// 修改每一帧最多包含的消息数量,由websocket session发送到redis
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+pub struct BatchSize {
+    /// websocket session id
+    pub id: usize,
+    pub size: usize,
+}
+
+/// 积压消息的摘要,在投递积压消息之前发送
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(tag = "type", rename = "backlog")]
+pub struct BacklogSummary {
+    pub count: usize,
+    pub oldest_id: String,
+    pub newest_id: String,
+}
+
+/// 积压的消息已经投递完,`last_id`为最后投递的消息id,每个session只发送一次
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(tag = "type", rename = "caught_up")]
+pub struct CaughtUp {
+    pub last_id: String,
+}
+
+/// 限量消息已经发完,代替消息发给没有领取到的接收者
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(tag = "type", rename = "sold_out")]
+pub struct SoldOut {
+    pub activity_type: String,
+    pub correlation_id: Option<String>,
+}
+
+/// 订阅房间消息队列,由redis发送到redis session
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RoomSubscribe {
+    pub stream_name: String,
+    /// 房间名称
+    pub room: String,
+}
+
+/// 取消订阅房间消息队列
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RoomUnsubscribe {
+    pub stream_name: String,
+}
+
+/// 审判,推送者超过推送配额时不写入,各接收者的消息id为`quota_exceeded`
+#[derive(Message)]
+#[rtype(result = "Vec<(String, String)>")]
 pub struct Trial {
     pub message: Activity,
     pub receivers: Vec<String>,
+    /// 限量消息,只有最先读取到的这么多个接收者收到,其他接收者收到`sold_out`
+    pub quantity: Option<u32>,
+}
+
+/// 一次写入多条消息,所有的xadd在一次往返中完成,按消息的顺序返回各接收者的消息id
+#[derive(Message)]
+#[rtype(result = "Vec<Vec<(String, String)>>")]
+pub struct TrialBatch {
+    pub events: Vec<(Activity, Vec<String>)>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc};
+
+    use super::*;
+    use crate::addr::{testing::Collector, MemoryStore};
+    use crate::{entity::apns, metrics};
+
+    fn activity(content: &str) -> Activity {
+        Activity {
+            activity_type: "event".to_string(),
+            activity: content.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// a websocket session that is gone before anything is delivered
+    struct Gone;
+
+    impl Actor for Gone {
+        type Context = Context<Self>;
+
+        fn started(&mut self, ctx: &mut Self::Context) {
+            ctx.stop();
+        }
+    }
+
+    impl Handler<WsMessage> for Gone {
+        type Result = ();
+
+        fn handle(&mut self, _: WsMessage, _: &mut Self::Context) {}
+    }
+
+    async fn online(addr: &Addr<Redis>, id: usize, name: &str) -> Collector {
+        let collector = Collector::default();
+        addr.send(Online {
+            id,
+            name: name.to_string(),
+            addr: collector.clone().start().recipient(),
+            kick: collector.clone().start().recipient(),
+            ask: collector.clone().start().recipient(),
+            backlog_summary: false,
+            framing: FramingMode::Array,
+            batch_size: None,
+            capabilities: HashSet::new(),
+            delivery: DeliveryMode::AtLeastOnce,
+            fields: None,
+            echo: true,
+        })
+        .await
+        .unwrap();
+        collector
+    }
+
+    #[actix_rt::test]
+    async fn attachments_reach_capable_clients_intact() {
+        let addr = Redis::new(MemoryStore::default()).start();
+        let capable = Collector::default();
+        addr.send(Online {
+            id: 1,
+            name: "ian".to_string(),
+            addr: capable.clone().start().recipient(),
+            kick: capable.clone().start().recipient(),
+            ask: capable.clone().start().recipient(),
+            backlog_summary: false,
+            framing: FramingMode::Array,
+            batch_size: None,
+            capabilities: vec![BLOB_CAPABILITY.to_string()].into_iter().collect(),
+            delivery: DeliveryMode::AtLeastOnce,
+            fields: None,
+            echo: true,
+        })
+        .await
+        .unwrap();
+        let text_only = online(&addr, 2, "linda").await;
+
+        let blob: Vec<u8> = (0..=255).collect();
+        addr.send(Trial {
+            message: Activity {
+                blob: Some(blob.clone()),
+                ..activity("patch")
+            },
+            receivers: vec!["ian".to_string(), "linda".to_string()],
+            quantity: None,
+        })
+        .await
+        .unwrap();
+        actix_rt::time::sleep(MESSAGE_INTERVAL * 2).await;
+
+        let delivered = |collector: &Collector| {
+            let frames = collector.received();
+            assert_eq!(frames.len(), 1);
+            let mut items: Vec<Activity> = serde_json::from_str(&frames[0]).unwrap();
+            items.remove(0)
+        };
+        let inline = delivered(&capable);
+        assert_eq!(inline.blob, Some(blob.clone()));
+        assert_eq!(inline.blob_url, None);
+
+        let linked = delivered(&text_only);
+        assert_eq!(linked.blob, None);
+        let blob_ref = linked
+            .blob_url
+            .as_deref()
+            .and_then(|url| url.strip_prefix("/blobs/"))
+            .unwrap()
+            .to_string();
+        assert_eq!(addr.send(GetBlob { blob_ref }).await.unwrap(), Some(blob));
+    }
+
+    #[actix_rt::test]
+    async fn get_reads_one_entry_without_acking_it() {
+        let store = MemoryStore::default();
+        let addr = Redis::new(store.clone()).start();
+        let pushed = Activity {
+            correlation_id: Some("00c0ffee".to_string()),
+            ..activity("recall")
+        };
+        let events = addr
+            .send(Trial {
+                message: pushed.clone(),
+                receivers: vec!["marina".to_string()],
+                quantity: None,
+            })
+            .await
+            .unwrap();
+        let id = events[0].1.clone();
+        let get = |id: &str| GetActivity {
+            name: "marina".to_string(),
+            id: id.to_string(),
+        };
+
+        assert_eq!(
+            addr.send(get(&id)).await.unwrap(),
+            Fetched {
+                id: id.clone(),
+                activity: Some(pushed),
+            }
+        );
+        assert_eq!(addr.send(get("9999-0")).await.unwrap().activity, None);
+        // still there to be delivered
+        assert_eq!(store.stream_ids("veda-activity:marina"), vec![id]);
+    }
+
+    #[actix_rt::test]
+    async fn since_ts_returns_what_was_written_from_then_on() {
+        let store = MemoryStore::default();
+        let addr = Redis::new(store.clone()).start();
+        let mut ids = vec![];
+        for text in ["veda", "trinity", "innovade", "ribbons"] {
+            let events = addr
+                .send(Trial {
+                    message: activity(text),
+                    receivers: vec!["aeolia".to_string()],
+                    quantity: None,
+                })
+                .await
+                .unwrap();
+            ids.push(events[0].1.clone());
+        }
+        // the millisecond the third entry was written in
+        let (boundary, _) = ids[2].split_once('-').unwrap();
+
+        let since = addr
+            .send(ListSince {
+                name: "aeolia".to_string(),
+                since_ms: boundary.parse().unwrap(),
+            })
+            .await
+            .unwrap();
+        let found: Vec<(Option<String>, String)> = since
+            .activities
+            .into_iter()
+            .map(|item| (item.id, item.activity))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (Some(ids[2].clone()), "innovade".to_string()),
+                (Some(ids[3].clone()), "ribbons".to_string()),
+            ]
+        );
+        // nothing is acked
+        assert_eq!(store.stream_ids("veda-activity:aeolia"), ids);
+    }
+
+    #[actix_rt::test]
+    async fn large_activities_are_split_across_frames() {
+        let addr = Redis::new(MemoryStore::default())
+            .max_frame_bytes(1000)
+            .start();
+        for size in [400, 400, 400, 1500] {
+            addr.send(Trial {
+                message: activity(&"0".repeat(size)),
+                receivers: vec!["ribbons".to_string()],
+                quantity: None,
+            })
+            .await
+            .unwrap();
+        }
+        let collector = online(&addr, 1, "ribbons").await;
+        actix_rt::time::sleep(MESSAGE_INTERVAL * 2).await;
+
+        let frames: Vec<Vec<usize>> = collector
+            .received()
+            .iter()
+            .map(|frame| {
+                serde_json::from_str::<Vec<Activity>>(frame)
+                    .unwrap()
+                    .iter()
+                    .map(|item| item.activity.len())
+                    .collect()
+            })
+            .collect();
+        assert_eq!(frames, vec![vec![400, 400], vec![400], vec![1500]]);
+        // only the activity that is too large by itself goes over
+        for frame in &collector.received()[..2] {
+            assert!(frame.len() <= 1000, "{}", frame.len());
+        }
+    }
+
+    #[actix_rt::test]
+    async fn history_pages_go_back_to_the_start() {
+        let store = MemoryStore::default();
+        let addr = Redis::new(store.clone()).start();
+        let mut ids = vec![];
+        for text in ["veda", "trinity", "innovade", "ribbons", "tieria"] {
+            let events = addr
+                .send(Trial {
+                    message: activity(text),
+                    receivers: vec!["regene".to_string()],
+                    quantity: None,
+                })
+                .await
+                .unwrap();
+            ids.push(events[0].1.clone());
+        }
+        let page = |before_id: &str| ListHistory {
+            name: "regene".to_string(),
+            before_id: before_id.to_string(),
+            count: 2,
+        };
+        let texts = |page: &HistoryPage| -> Vec<String> {
+            page.activities
+                .iter()
+                .map(|item| item.activity.clone())
+                .collect()
+        };
+
+        // the entry it starts before is not part of the page
+        let first = addr.send(page(&ids[4])).await.unwrap();
+        assert_eq!(texts(&first), vec!["ribbons", "innovade"]);
+        assert_eq!(first.activities[1].id, Some(ids[2].clone()));
+        assert_eq!(first.next_before_id, Some(ids[2].clone()));
+        assert!(!first.end);
+
+        let second = addr
+            .send(page(first.next_before_id.as_deref().unwrap()))
+            .await
+            .unwrap();
+        assert_eq!(texts(&second), vec!["trinity", "veda"]);
+        assert_eq!(second.next_before_id, None);
+        assert!(second.end);
+
+        let before_all = addr.send(page(&ids[0])).await.unwrap();
+        assert!(before_all.activities.is_empty());
+        assert!(before_all.end);
+        // nothing is acked
+        assert_eq!(store.stream_ids("veda-activity:regene"), ids);
+    }
+
+    #[actix_rt::test]
+    async fn sharded_streams_are_delivered_in_id_order() {
+        let store = MemoryStore::default();
+        let addr = Redis::new(store.clone()).stream_shards(4).start();
+        let mut ids = vec![];
+        for n in 0..25 {
+            let events = addr
+                .send(Trial {
+                    message: activity(&n.to_string()),
+                    receivers: vec!["setsuna".to_string()],
+                    quantity: None,
+                })
+                .await
+                .unwrap();
+            ids.push(events[0].1.clone());
+        }
+        assert!(store.stream_ids("veda-activity:setsuna").is_empty());
+        let lens: Vec<usize> = (0..4)
+            .map(|shard| {
+                store
+                    .stream_ids(&format!("veda-activity:setsuna:{}", shard))
+                    .len()
+            })
+            .collect();
+        assert_eq!(lens.iter().sum::<usize>(), 25);
+        assert!(
+            lens.iter().filter(|len| **len > 0).count() > 1,
+            "{:?}",
+            lens
+        );
+        let missed = addr
+            .send(ListMissed {
+                name: "setsuna".to_string(),
+                limit: 3,
+            })
+            .await
+            .unwrap();
+        assert_eq!(missed.count, 25);
+        assert_eq!(
+            missed.ids,
+            vec![ids[24].clone(), ids[23].clone(), ids[22].clone()]
+        );
+
+        // more than one read of `READ_COUNT` each
+        let collector = online(&addr, 1, "setsuna").await;
+        actix_rt::time::sleep(MESSAGE_INTERVAL * 4).await;
+
+        // `caught_up` follows once the backlog is drained
+        let received: Vec<String> = collector
+            .received()
+            .iter()
+            .filter_map(|frame| serde_json::from_str::<Vec<Activity>>(frame).ok())
+            .flatten()
+            .map(|item| item.activity)
+            .collect();
+        let expected: Vec<String> = (0..25).map(|n| n.to_string()).collect();
+        assert_eq!(received, expected);
+        for shard in 0..4 {
+            assert!(store
+                .stream_ids(&format!("veda-activity:setsuna:{}", shard))
+                .is_empty());
+        }
+    }
+
+    #[actix_rt::test]
+    async fn a_large_push_does_not_hold_up_online() {
+        let addr = Redis::new(MemoryStore::default())
+            .trial_chunk_size(8)
+            .start();
+        let receivers: Vec<String> = (0..2000).map(|n| format!("gn-{:04}", n)).collect();
+        let trial = addr.send(Trial {
+            message: activity("trans-am"),
+            receivers: receivers.clone(),
+            quantity: None,
+        });
+        let done = Rc::new(Cell::new(false));
+        let pushed = actix_rt::spawn({
+            let done = done.clone();
+            async move {
+                let events = trial.await.unwrap();
+                done.set(true);
+                events
+            }
+        });
+
+        online(&addr, 1, "setsuna").await;
+        assert!(!done.get());
+        assert_eq!(pushed.await.unwrap().len(), receivers.len());
+    }
+
+    #[actix_rt::test]
+    async fn followers_receive_public_posts() {
+        let store = MemoryStore::default();
+        let addr = Redis::new(store.clone()).start();
+        let follow = |follow| Follow {
+            follower: "allelujah".to_string(),
+            username: "tieria".to_string(),
+            follow,
+        };
+        // tieria is not public yet
+        assert!(!addr.send(follow(true)).await.unwrap().following);
+        addr.send(SetPublic {
+            name: "tieria".to_string(),
+            public: true,
+        })
+        .await
+        .unwrap();
+        assert!(addr.send(follow(true)).await.unwrap().following);
+        let follower = online(&addr, 1, "allelujah").await;
+
+        let trial = |activity_type: &str, content: &str| Trial {
+            message: Activity {
+                activity_type: activity_type.to_string(),
+                ..activity(content)
+            },
+            receivers: vec!["tieria".to_string()],
+            quantity: None,
+        };
+        addr.send(trial("post", "veda")).await.unwrap();
+        addr.send(trial("message", "private")).await.unwrap();
+        actix_rt::time::sleep(MESSAGE_INTERVAL + Duration::from_millis(200)).await;
+
+        let received: Vec<Activity> = follower
+            .received()
+            .iter()
+            .flat_map(|frame| serde_json::from_str::<Vec<Activity>>(frame).unwrap())
+            .collect();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].activity, "veda");
+
+        addr.send(follow(false)).await.unwrap();
+        addr.send(trial("post", "nadleeh")).await.unwrap();
+        assert!(store.stream_ids("veda-activity:allelujah").is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn identity_node_follows_the_sessions() {
+        let store = MemoryStore::default();
+        let addr = Redis::new(store.clone()).node_id(7).start();
+        online(&addr, 1, "setsuna").await;
+        online(&addr, 2, "setsuna").await;
+        assert_eq!(
+            store.hget("identity-node:setsuna", "1").as_deref(),
+            Some("7")
+        );
+        assert_eq!(
+            store.hget("identity-node:setsuna", "2").as_deref(),
+            Some("7")
+        );
+
+        addr.send(Offline { id: 1 }).await.unwrap();
+        assert_eq!(store.hget("identity-node:setsuna", "1"), None);
+        assert_eq!(
+            store.hget("identity-node:setsuna", "2").as_deref(),
+            Some("7")
+        );
+    }
+
+    #[actix_rt::test]
+    async fn purge_user_removes_keys_and_disconnects() {
+        let mut store = MemoryStore::default();
+        store
+            .xadd("veda-activity:graham", &activity("flag"))
+            .unwrap();
+        store
+            .xadd("veda-dead-letter:graham", &activity("poison"))
+            .unwrap();
+        store.set("status:graham", "busy").unwrap();
+        store
+            .xadd("veda-activity:billy", &activity("custom"))
+            .unwrap();
+
+        let addr = Redis::new(store.clone()).track_last_seen(true).start();
+        let first = online(&addr, 1, "graham").await;
+        let second = online(&addr, 2, "graham").await;
+        let other = online(&addr, 3, "billy").await;
+        store.hset("platforms:graham", "1", "{}").unwrap();
+
+        let purge = || PurgeUser {
+            username: "graham".to_string(),
+        };
+        assert_eq!(addr.send(purge()).await.unwrap(), 2);
+        actix_rt::time::sleep(Duration::from_millis(100)).await;
+
+        for collector in &[&first, &second] {
+            assert!(collector
+                .received()
+                .contains(&"kicked: account deleted".to_string()));
+        }
+        assert!(!other.received().iter().any(|m| m.starts_with("kicked")));
+        {
+            let memory = store.lock();
+            for key in &[
+                "veda-activity:graham",
+                "veda-dead-letter:graham",
+                "platforms:graham",
+            ] {
+                assert!(!memory.streams.contains_key(*key), "{}", key);
+                assert!(!memory.hashes.contains_key(*key), "{}", key);
+            }
+            assert!(!memory.strings.contains_key("status:graham"));
+            let online = &memory.hashes["online-users"];
+            assert_eq!(online.values().collect::<Vec<_>>(), vec!["billy"]);
+            assert!(!memory.zsets["last-seen"].contains_key("1"));
+        }
+        assert_eq!(store.stream_ids("veda-activity:billy").len(), 1);
+
+        // purging again is a no-op
+        assert_eq!(addr.send(purge()).await.unwrap(), 0);
+    }
+
+    #[actix_rt::test]
+    async fn heartbeats_advance_when_the_user_was_last_seen() {
+        let store = MemoryStore::default();
+        let addr = Redis::new(store.clone())
+            .track_last_seen(true)
+            .last_seen_interval(Duration::from_millis(50))
+            .start();
+        let _first = online(&addr, 1, "alleluia").await;
+        let _second = online(&addr, 2, "alleluia").await;
+        let last_seen = || -> Option<i64> {
+            let memory = store.lock();
+            memory
+                .strings
+                .get("last-seen:alleluia")
+                .map(|millis| millis.parse().unwrap())
+        };
+        assert_eq!(last_seen(), None);
+
+        addr.send(Heartbeat { id: 1 }).await.unwrap();
+        let first = last_seen().unwrap();
+        // within the interval the beats of both sessions are not written
+        actix_rt::time::sleep(Duration::from_millis(5)).await;
+        addr.send(Heartbeat { id: 2 }).await.unwrap();
+        addr.send(Heartbeat { id: 1 }).await.unwrap();
+        assert_eq!(last_seen(), Some(first));
+
+        actix_rt::time::sleep(Duration::from_millis(60)).await;
+        addr.send(Heartbeat { id: 2 }).await.unwrap();
+        let second = last_seen().unwrap();
+        assert!(second >= first + 50, "{} {}", first, second);
+        assert!(second <= Utc::now().timestamp_millis());
+    }
+
+    #[actix_rt::test]
+    async fn sweep_stale_offlines_sessions_not_seen() {
+        let mut store = MemoryStore::default();
+        store.hset("online-users", "101", "stale-meister").unwrap();
+        store.zadd("last-seen", "101", 0).unwrap();
+
+        let addr = Redis::new(store.clone()).track_last_seen(true).start();
+        addr.send(SweepStale).await.unwrap();
+
+        assert_eq!(store.hget("online-users", "101"), None);
+        assert!(store
+            .zrangebyscore("last-seen", i64::MAX)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn trial_fans_out_in_order_and_acks_delivered() {
+        let store = MemoryStore::default();
+        let addr = Redis::new(store.clone()).start();
+        let setsuna = online(&addr, 1, "setsuna").await;
+        let lockon = online(&addr, 2, "lockon").await;
+
+        for content in ["exia", "dynames"] {
+            let ids = addr
+                .send(Trial {
+                    message: activity(content),
+                    receivers: vec!["setsuna".to_string(), "lockon".to_string()],
+                    quantity: None,
+                })
+                .await
+                .unwrap();
+            assert_eq!(ids.len(), 2);
+        }
+        actix_rt::time::sleep(MESSAGE_INTERVAL * 2).await;
+
+        for collector in [setsuna, lockon] {
+            let received: Vec<Activity> = collector
+                .received()
+                .iter()
+                .flat_map(|frame| serde_json::from_str::<Vec<Activity>>(frame).unwrap())
+                .collect();
+            assert_eq!(received, vec![activity("exia"), activity("dynames")]);
+        }
+        // delivered activities are removed from the streams
+        assert!(store.stream_ids("veda-activity:setsuna").is_empty());
+        assert!(store.stream_ids("veda-activity:lockon").is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn at_most_once_does_not_redeliver() {
+        let mut store = MemoryStore::default();
+        store
+            .xadd("veda-activity:tieria", &activity("virtue"))
+            .unwrap();
+        let failed = metrics::DELIVERIES.get("failed");
+        let redelivered = metrics::DELIVERIES.get("redelivered");
+
+        let gone = RedisSession::new(
+            1,
+            "tieria".to_string(),
+            "veda-activity:tieria".to_string(),
+            Box::new(store.clone()),
+            Gone.start().recipient(),
+        )
+        .delivery(DeliveryMode::AtMostOnce)
+        .start();
+        actix_rt::time::sleep(MESSAGE_INTERVAL * 2).await;
+        assert!(!gone.connected());
+        assert!(metrics::DELIVERIES.get("failed") > failed);
+        // the lost frame is not read again by the next session
+        assert!(store.stream_ids("veda-activity:tieria").is_empty());
+
+        let collector = Collector::default();
+        let _session = RedisSession::new(
+            2,
+            "tieria".to_string(),
+            "veda-activity:tieria".to_string(),
+            Box::new(store.clone()),
+            collector.clone().start().recipient(),
+        )
+        .delivery(DeliveryMode::AtMostOnce)
+        .start();
+        actix_rt::time::sleep(MESSAGE_INTERVAL * 2).await;
+        assert!(collector.received().is_empty());
+        assert_eq!(metrics::DELIVERIES.get("redelivered"), redelivered);
+    }
+
+    #[actix_rt::test]
+    async fn projected_activities_leave_the_other_fields_out() {
+        let store = MemoryStore::default();
+        let addr = Redis::new(store.clone()).start();
+        let collector = online(&addr, 1, "regene").await;
+        addr.send(Fields {
+            id: 1,
+            fields: Some(vec!["activity_type".to_string()].into_iter().collect()),
+        })
+        .await
+        .unwrap();
+        addr.send(Trial {
+            message: Activity {
+                correlation_id: Some("00c0ffee".to_string()),
+                ..activity("veda")
+            },
+            receivers: vec!["regene".to_string()],
+            quantity: None,
+        })
+        .await
+        .unwrap();
+        actix_rt::time::sleep(MESSAGE_INTERVAL + Duration::from_millis(200)).await;
+
+        let received = collector.received();
+        assert_eq!(received.len(), 1);
+        let delivered: serde_json::Value = serde_json::from_str(&received[0]).unwrap();
+        assert_eq!(delivered, serde_json::json!([{"activity_type": "event"}]));
+    }
+
+    /// an http server answering every request with `status`, handing out what it was sent
+    fn webhook_server(status: &'static str) -> (String, std::sync::mpsc::Receiver<String>) {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut received = vec![];
+                let mut buf = [0u8; 1024];
+                // headers, then as much body as they announce
+                loop {
+                    let n = stream.read(&mut buf).unwrap();
+                    received.extend_from_slice(&buf[..n]);
+                    let text = String::from_utf8_lossy(&received).into_owned();
+                    if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                        let length = head
+                            .lines()
+                            .find_map(|line| {
+                                line.to_lowercase()
+                                    .strip_prefix("content-length: ")
+                                    .map(str::to_owned)
+                            })
+                            .and_then(|length| length.trim().parse().ok())
+                            .unwrap_or(0);
+                        if n == 0 || body.len() >= length {
+                            break;
+                        }
+                    }
+                }
+                let reply = format!("HTTP/1.1 {}\r\ncontent-length: 0\r\n\r\n", status);
+                stream.write_all(reply.as_bytes()).unwrap();
+                let _ = tx.send(String::from_utf8_lossy(&received).into_owned());
+            }
+        });
+        (url, rx)
+    }
+
+    #[actix_rt::test]
+    async fn webhooks_get_the_activities_posted() {
+        let mut store = MemoryStore::default();
+        let (ok_url, posted) = webhook_server("200 OK");
+        let (failing_url, failed) = webhook_server("500 Internal Server Error");
+        store.set("webhook:ribbons", &ok_url).unwrap();
+        store.set("webhook:regene", &failing_url).unwrap();
+        let addr = Redis::new(store.clone())
+            .webhook_retry(RetryPolicy {
+                max_attempts: 2,
+                backoff: Duration::from_millis(10),
+                timeout: Some(Duration::from_secs(1)),
+            })
+            .start();
+
+        let message = Activity {
+            correlation_id: Some("veda-1".to_string()),
+            ..activity("innovade")
+        };
+        addr.send(Trial {
+            message: message.clone(),
+            receivers: vec!["ribbons".to_string(), "regene".to_string()],
+            quantity: None,
+        })
+        .await
+        .unwrap();
+
+        let request = actix_web::rt::task::spawn_blocking(move || {
+            posted.recv_timeout(Duration::from_secs(3)).unwrap()
+        })
+        .await
+        .unwrap();
+        assert!(request.starts_with("POST /hook "), "{}", request);
+        assert!(
+            request.to_lowercase().contains("x-veda-receiver: ribbons"),
+            "{}",
+            request
+        );
+        let body = request.split_once("\r\n\r\n").unwrap().1;
+        assert_eq!(serde_json::from_str::<Activity>(body).unwrap(), message);
+        // the stream keeps its copy for the websocket sessions
+        assert_eq!(store.stream_ids("veda-activity:ribbons").len(), 1);
+
+        // every attempt fails, the activity ends up in the dead letters
+        actix_web::rt::task::spawn_blocking(move || {
+            for _ in 0..2 {
+                failed.recv_timeout(Duration::from_secs(3)).unwrap();
+            }
+        })
+        .await
+        .unwrap();
+        actix_rt::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(store.stream_ids("veda-dead-letter:regene").len(), 1);
+        assert!(store.stream_ids("veda-dead-letter:ribbons").is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn webhooks_get_the_batch_pushes() {
+        let mut store = MemoryStore::default();
+        let (url, posted) = webhook_server("200 OK");
+        store.set("webhook:ribbons", &url).unwrap();
+        let addr = Redis::new(store.clone()).start();
+
+        addr.send(TrialBatch {
+            events: vec![
+                (activity("innovade"), vec!["ribbons".to_string()]),
+                (activity("veda"), vec!["tieria".to_string()]),
+            ],
+        })
+        .await
+        .unwrap();
+
+        let request = actix_web::rt::task::spawn_blocking(move || {
+            let request = posted.recv_timeout(Duration::from_secs(3)).unwrap();
+            // only one receiver has a webhook
+            assert!(posted.recv_timeout(Duration::from_millis(300)).is_err());
+            request
+        })
+        .await
+        .unwrap();
+        assert!(
+            request.to_lowercase().contains("x-veda-receiver: ribbons"),
+            "{}",
+            request
+        );
+        let body = request.split_once("\r\n\r\n").unwrap().1;
+        assert_eq!(
+            serde_json::from_str::<Activity>(body).unwrap(),
+            activity("innovade")
+        );
+    }
+
+    #[actix_rt::test]
+    async fn client_reports_are_stored_with_the_session() {
+        let store = MemoryStore::default();
+        let report = |id| ClientReport {
+            id,
+            name: Some("tieria".to_string()),
+            report: serde_json::json!({"kind": "parse", "frame": 3}),
+        };
+        // only logged by default
+        let addr = Redis::new(store.clone()).start();
+        addr.send(report(1)).await.unwrap();
+        assert!(store.stream_ids("client-errors").is_empty());
+
+        let addr = Redis::new(store.clone()).store_client_reports(true).start();
+        addr.send(report(2)).await.unwrap();
+
+        let mut con = store.connect().unwrap();
+        let entries = con.xrange("client-errors", "-", "+", 10).unwrap();
+        assert_eq!(entries.len(), 1);
+        let stored = Activity::from(&entries[0]);
+        assert_eq!(stored.activity_type, "client_error");
+        let stored: serde_json::Value = serde_json::from_str(&stored.activity).unwrap();
+        assert_eq!(
+            stored,
+            serde_json::json!({
+                "session": 2,
+                "name": "tieria",
+                "report": {"kind": "parse", "frame": 3},
+            })
+        );
+    }
+
+    #[actix_rt::test]
+    async fn pushes_over_the_sender_quota_are_rejected_until_the_window_ends() {
+        let store = MemoryStore::default();
+        let window = Duration::from_millis(500);
+        let addr = Redis::new(store.clone()).push_quota(2, window).start();
+        let push = |sender: Option<&str>| Trial {
+            message: Activity {
+                sender: sender.map(str::to_string),
+                ..activity("trans-am")
+            },
+            receivers: vec!["tieria".to_string()],
+            quantity: None,
+        };
+
+        // start with a fresh window so every push lands in it
+        let millis = window.as_millis() as i64;
+        let into = Utc::now().timestamp_millis() % millis;
+        actix_rt::time::sleep(Duration::from_millis((millis - into) as u64)).await;
+        let mut ids = vec![];
+        for _ in 0..3 {
+            ids.push(addr.send(push(Some("ribbons"))).await.unwrap().remove(0).1);
+        }
+        assert_ne!(ids[0], QUOTA_EXCEEDED);
+        assert_ne!(ids[1], QUOTA_EXCEEDED);
+        assert_eq!(ids[2], QUOTA_EXCEEDED);
+        assert_eq!(store.stream_ids("veda-activity:tieria").len(), 2);
+
+        // other senders are not held to it, pushes without one share a quota
+        let (_, other) = addr.send(push(Some("regene"))).await.unwrap().remove(0);
+        assert_ne!(other, QUOTA_EXCEEDED);
+        let mut anonymous = vec![];
+        for _ in 0..3 {
+            anonymous.push(addr.send(push(None)).await.unwrap().remove(0).1);
+        }
+        assert_ne!(anonymous[1], QUOTA_EXCEEDED);
+        assert_eq!(anonymous[2], QUOTA_EXCEEDED);
+
+        // a batch is counted the same way
+        let batch = addr
+            .send(TrialBatch {
+                events: vec![
+                    (push(Some("ribbons")).message, vec!["tieria".to_string()]),
+                    (push(Some("regene")).message, vec!["tieria".to_string()]),
+                ],
+            })
+            .await
+            .unwrap();
+        assert_eq!(batch[0][0].1, QUOTA_EXCEEDED);
+        assert_ne!(batch[1][0].1, QUOTA_EXCEEDED);
+        assert_eq!(store.stream_ids("veda-activity:tieria").len(), 6);
+
+        actix_rt::time::sleep(window).await;
+        let (_, id) = addr.send(push(Some("ribbons"))).await.unwrap().remove(0);
+        assert_ne!(id, QUOTA_EXCEEDED);
+        let (_, anonymous) = addr.send(push(None)).await.unwrap().remove(0);
+        assert_ne!(anonymous, QUOTA_EXCEEDED);
+        assert_eq!(store.stream_ids("veda-activity:tieria").len(), 8);
+    }
+
+    #[actix_rt::test]
+    async fn a_retried_push_is_written_once() {
+        let store = MemoryStore::default();
+        let addr = Redis::new(store.clone()).start();
+        let trial = |receivers: &[&str]| Trial {
+            message: Activity {
+                idempotency_key: Some("push-00".to_string()),
+                ..activity("exia")
+            },
+            receivers: receivers.iter().map(|name| name.to_string()).collect(),
+            quantity: None,
+        };
+        let duplicates = metrics::DELIVERIES.get("duplicate");
+
+        let first = addr.send(trial(&["setsuna"])).await.unwrap();
+        let retried = addr.send(trial(&["setsuna", "lockon"])).await.unwrap();
+
+        assert_eq!(store.stream_ids("veda-activity:setsuna").len(), 1);
+        assert_eq!(store.stream_ids("veda-activity:lockon").len(), 1);
+        // the retry answers with the entry of the first push
+        assert_eq!(retried[0], first[0]);
+        assert!(metrics::DELIVERIES.get("duplicate") > duplicates);
+        assert_eq!(
+            store.lock().ttls.get("seen:setsuna"),
+            Some(&crate::constants::IDEMPOTENCY_TTL)
+        );
+
+        // without a key every push is written
+        addr.send(Trial {
+            message: activity("exia"),
+            receivers: vec!["setsuna".to_string()],
+            quantity: None,
+        })
+        .await
+        .unwrap();
+        assert_eq!(store.stream_ids("veda-activity:setsuna").len(), 2);
+    }
+
+    #[actix_rt::test]
+    async fn trial_batch_fans_out_to_every_receiver() {
+        let store = MemoryStore::default();
+        let addr = Redis::new(store.clone()).start();
+        let receivers = |names: &[&str]| names.iter().map(|name| name.to_string()).collect();
+        let pushed = addr
+            .send(TrialBatch {
+                events: vec![
+                    (activity("exia"), receivers(&["setsuna", "lockon"])),
+                    (activity("dynames"), receivers(&["lockon"])),
+                    (activity("kyrios"), receivers(&["allelujah", "setsuna"])),
+                ],
+            })
+            .await
+            .unwrap();
+
+        let names: Vec<Vec<&str>> = pushed
+            .iter()
+            .map(|ids| ids.iter().map(|(name, _)| name.as_str()).collect())
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                vec!["setsuna", "lockon"],
+                vec!["lockon"],
+                vec!["allelujah", "setsuna"]
+            ]
+        );
+        assert_eq!(
+            store.stream_ids("veda-activity:setsuna"),
+            vec![pushed[0][0].1.clone(), pushed[2][1].1.clone()]
+        );
+        assert_eq!(
+            store.stream_ids("veda-activity:lockon"),
+            vec![pushed[0][1].1.clone(), pushed[1][0].1.clone()]
+        );
+        assert_eq!(
+            store.stream_ids("veda-activity:allelujah"),
+            vec![pushed[2][0].1.clone()]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn a_retried_batch_is_written_once() {
+        let store = MemoryStore::default();
+        let addr = Redis::new(store.clone()).start();
+        let keyed = Activity {
+            idempotency_key: Some("order-00".to_string()),
+            ..activity("exia")
+        };
+        let batch = || TrialBatch {
+            events: vec![(keyed.clone(), vec!["setsuna".to_string()])],
+        };
+        let first = addr.send(batch()).await.unwrap();
+        let retried = addr.send(batch()).await.unwrap();
+        assert_eq!(retried, first);
+
+        // the same key twice in one batch
+        let twice = addr
+            .send(TrialBatch {
+                events: vec![
+                    (keyed.clone(), vec!["lockon".to_string()]),
+                    (keyed, vec!["lockon".to_string()]),
+                ],
+            })
+            .await
+            .unwrap();
+        assert_eq!(twice[0], twice[1]);
+        assert_eq!(store.stream_ids("veda-activity:setsuna").len(), 1);
+        assert_eq!(store.stream_ids("veda-activity:lockon").len(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn badges_count_the_unread_activities() {
+        let mut store = MemoryStore::default();
+        for content in &["ptolemy", "trinity"] {
+            store
+                .xadd("veda-activity:sumeragi", &activity(content))
+                .unwrap();
+        }
+        // read by `Redis` on its schedule, which never comes here
+        let session = RedisSession::new(
+            1,
+            "sumeragi".to_string(),
+            "veda-activity:sumeragi".to_string(),
+            Box::new(store.clone()),
+            Collector::default().start().recipient(),
+        )
+        .scheduled(true)
+        .start();
+        for room in &["bridge", "hangar"] {
+            store
+                .xadd(&format!("veda-activity:room:{}", room), &activity("before"))
+                .unwrap();
+            session
+                .send(RoomSubscribe {
+                    stream_name: format!("veda-activity:room:{}", room),
+                    room: room.to_string(),
+                })
+                .await
+                .unwrap();
+        }
+        for content in &["exia", "dynames", "kyrios"] {
+            store
+                .xadd("veda-activity:room:bridge", &activity(content))
+                .unwrap();
+        }
+        store
+            .xadd("veda-activity:room:hangar", &activity("virtue"))
+            .unwrap();
+
+        let counts = session.send(Badges { id: 1 }).await.unwrap();
+        assert_eq!(counts.dm, 2);
+        assert_eq!(
+            counts.rooms,
+            vec![("bridge".to_string(), 3), ("hangar".to_string(), 1)]
+                .into_iter()
+                .collect()
+        );
+    }
+
+    #[actix_rt::test]
+    async fn only_the_latest_of_a_compaction_key_is_delivered() {
+        let mut store = MemoryStore::default();
+        let update = |content: &str, compaction_key: Option<&str>| Activity {
+            compaction_key: compaction_key.map(str::to_owned),
+            ..activity(content)
+        };
+        for x in 1..=3 {
+            store
+                .xadd(
+                    "veda-activity:leo",
+                    &update(&format!("x={}", x), Some("cursor:x")),
+                )
+                .unwrap();
+        }
+        store
+            .xadd("veda-activity:leo", &update("y=1", Some("cursor:y")))
+            .unwrap();
+        store
+            .xadd("veda-activity:leo", &update("hello", None))
+            .unwrap();
+        let compacted = metrics::DELIVERIES.get("compacted");
+
+        let addr = Redis::new(store.clone()).start();
+        let collector = online(&addr, 1, "leo").await;
+        actix_rt::time::sleep(MESSAGE_INTERVAL + Duration::from_millis(200)).await;
+
+        let received: Vec<String> = collector
+            .received()
+            .iter()
+            .flat_map(|frame| serde_json::from_str::<Vec<Activity>>(frame).unwrap())
+            .map(|activity| activity.activity)
+            .collect();
+        assert_eq!(received, vec!["x=3", "y=1", "hello"]);
+        assert!(metrics::DELIVERIES.get("compacted") >= compacted + 2);
+        assert!(store.stream_ids("veda-activity:leo").is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn expired_activities_are_acked_without_delivery() {
+        let store = MemoryStore::default();
+        let addr = Redis::new(store.clone()).start();
+        let now = Utc::now().timestamp_millis() as u64;
+        let call = |content: &str, expires_at: u64| Trial {
+            message: Activity {
+                expires_at: Some(expires_at),
+                ..activity(content)
+            },
+            receivers: vec!["graham".to_string()],
+            quantity: None,
+        };
+        addr.send(call("missed call", now - 1_000)).await.unwrap();
+        addr.send(call("ringing", now + 30_000)).await.unwrap();
+        addr.send(Trial {
+            message: activity("hello"),
+            receivers: vec!["graham".to_string()],
+            quantity: None,
+        })
+        .await
+        .unwrap();
+        let expired = metrics::DELIVERIES.get("expired");
+
+        let collector = online(&addr, 1, "graham").await;
+        actix_rt::time::sleep(MESSAGE_INTERVAL + Duration::from_millis(200)).await;
+
+        let received: Vec<String> = collector
+            .received()
+            .iter()
+            .flat_map(|frame| serde_json::from_str::<Vec<Activity>>(frame).unwrap())
+            .map(|activity| activity.activity)
+            .collect();
+        assert_eq!(received, vec!["ringing", "hello"]);
+        assert!(metrics::DELIVERIES.get("expired") > expired);
+        assert!(store.stream_ids("veda-activity:graham").is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn cursor_follows_the_delivery() {
+        let store = MemoryStore::default();
+        let addr = Redis::new(store.clone()).start();
+        let collector = online(&addr, 1, "ribbons").await;
+        let cursor = || Cursor {
+            id: 1,
+            name: "ribbons".to_string(),
+        };
+        assert_eq!(
+            addr.send(cursor()).await.unwrap(),
+            CursorPosition {
+                last_id: NO_CURSOR.to_string(),
+                stream_len: 0,
+            }
+        );
+
+        let pushed = addr
+            .send(Trial {
+                message: activity("veda"),
+                receivers: vec!["ribbons".to_string()],
+                quantity: None,
+            })
+            .await
+            .unwrap();
+        actix_rt::time::sleep(MESSAGE_INTERVAL + Duration::from_millis(200)).await;
+        assert_eq!(collector.received().len(), 1);
+
+        assert_eq!(
+            addr.send(cursor()).await.unwrap(),
+            CursorPosition {
+                last_id: pushed[0].1.clone(),
+                stream_len: 0,
+            }
+        );
+    }
+
+    #[actix_rt::test]
+    async fn undelivered_activities_are_kept() {
+        let store = MemoryStore::default();
+        let addr = Redis::new(store.clone()).start();
+        addr.send(Online {
+            id: 1,
+            name: "tieria".to_string(),
+            addr: Gone.start().recipient(),
+            kick: Collector::default().start().recipient(),
+            ask: Collector::default().start().recipient(),
+            backlog_summary: false,
+            framing: FramingMode::Array,
+            batch_size: None,
+            capabilities: HashSet::new(),
+            delivery: DeliveryMode::AtLeastOnce,
+            fields: None,
+            echo: true,
+        })
+        .await
+        .unwrap();
+        actix_rt::time::sleep(MESSAGE_INTERVAL / 2).await;
+        addr.send(Trial {
+            message: activity("virtue"),
+            receivers: vec!["tieria".to_string()],
+            quantity: None,
+        })
+        .await
+        .unwrap();
+        actix_rt::time::sleep(MESSAGE_INTERVAL * 2).await;
+
+        assert_eq!(store.stream_ids("veda-activity:tieria").len(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn backlog_is_sampled_for_online_users() {
+        let addr = Redis::new(MemoryStore::default())
+            .backlog_sampling(Duration::from_secs(3600), 1000)
+            .start();
+        addr.send(Online {
+            id: 1,
+            name: "tieria".to_string(),
+            addr: Gone.start().recipient(),
+            kick: Collector::default().start().recipient(),
+            ask: Collector::default().start().recipient(),
+            backlog_summary: false,
+            framing: FramingMode::Array,
+            batch_size: None,
+            capabilities: HashSet::new(),
+            delivery: DeliveryMode::AtLeastOnce,
+            fields: None,
+            echo: true,
+        })
+        .await
+        .unwrap();
+        online(&addr, 2, "lockon").await;
+        addr.send(TrialBatch {
+            events: (0..1500)
+                .map(|_| (activity("virtue"), vec!["tieria".to_string()]))
+                .collect(),
+        })
+        .await
+        .unwrap();
+
+        addr.send(SampleBacklog).await.unwrap();
+        assert_eq!(BACKLOG.get("streams"), 2);
+        assert_eq!(BACKLOG.get("total"), 1500);
+        assert_eq!(BACKLOG.get("max"), 1500);
+        assert_eq!(BACKLOG.get("over_threshold"), 1);
+        assert!(metrics::render().contains("veda_stream_backlog{stat=\"max\"} 1500"));
+    }
+
+    #[actix_rt::test]
+    async fn a_limited_activity_reaches_only_the_first_receivers() {
+        let store = MemoryStore::default();
+        let addr = Redis::new(store.clone()).start();
+        let names = ["setsuna", "lockon", "allelujah"];
+        let mut collectors = vec![];
+        for (id, name) in names.iter().enumerate() {
+            collectors.push(online(&addr, id + 1, name).await);
+        }
+        let pushed = addr
+            .send(Trial {
+                message: Activity {
+                    correlation_id: Some("00c0ffee".to_string()),
+                    ..activity("first one wins")
+                },
+                receivers: names.iter().map(|name| name.to_string()).collect(),
+                quantity: Some(1),
+            })
+            .await
+            .unwrap();
+        assert_eq!(pushed.len(), 3);
+        actix_rt::time::sleep(MESSAGE_INTERVAL * 2).await;
+
+        let (winners, others): (Vec<Vec<String>>, Vec<Vec<String>>) = collectors
+            .iter()
+            .map(|collector| collector.received())
+            .partition(|received| {
+                received
+                    .iter()
+                    .any(|frame| frame.contains("first one wins"))
+            });
+        assert_eq!(winners.len(), 1, "{:?}", winners);
+        assert_eq!(winners[0].len(), 1);
+        for received in &others {
+            let frames: Vec<serde_json::Value> = received
+                .iter()
+                .map(|frame| serde_json::from_str(frame).unwrap())
+                .collect();
+            assert_eq!(
+                frames,
+                vec![serde_json::json!({
+                    "type": "sold_out",
+                    "activity_type": "event",
+                    "correlation_id": "00c0ffee",
+                })]
+            );
+        }
+        // sold out or not, nothing stays behind
+        for name in names {
+            assert!(store
+                .stream_ids(&format!("veda-activity:{}", name))
+                .is_empty());
+        }
+    }
+
+    #[actix_rt::test]
+    async fn each_platform_gets_its_own_rendering() {
+        let addr = Redis::new(MemoryStore::default())
+            .render("IPhone", apns)
+            .start();
+        let web = online(&addr, 1, "setsuna").await;
+        let iphone = online(&addr, 2, "lockon").await;
+        for (id, name, platform) in [(1, "setsuna", "Web"), (2, "lockon", "IPhone")] {
+            let device = format!(
+                r#"{{"platform":"{}","device":{{"device_name":"{}"}}}}"#,
+                platform, name
+            );
+            addr.send(PlatformOnline {
+                id,
+                name: name.to_string(),
+                platform: serde_json::from_str(&device).unwrap(),
+            })
+            .await
+            .unwrap();
+        }
+        addr.send(Trial {
+            message: activity("trans-am"),
+            receivers: vec!["setsuna".to_string(), "lockon".to_string()],
+            quantity: None,
+        })
+        .await
+        .unwrap();
+        actix_rt::time::sleep(MESSAGE_INTERVAL * 2).await;
+
+        let frames = |collector: &Collector| -> serde_json::Value {
+            let received = collector.received();
+            assert_eq!(received.len(), 1, "{:?}", received);
+            serde_json::from_str(&received[0]).unwrap()
+        };
+        let expected = serde_json::json!({"activity_type": "event", "activity": "trans-am"});
+        assert_eq!(frames(&web), serde_json::json!([expected]));
+        assert_eq!(
+            frames(&iphone),
+            serde_json::json!([{
+                "aps": {"alert": {"title": "event", "body": "trans-am"}},
+                "data": expected,
+            }])
+        );
+    }
+
+    #[actix_rt::test]
+    async fn an_ios_only_activity_skips_the_other_sessions() {
+        let store = MemoryStore::default();
+        let addr = Redis::new(store.clone()).start();
+        let web = online(&addr, 1, "setsuna").await;
+        let iphone = online(&addr, 2, "lockon").await;
+        for (id, name, platform) in [(1, "setsuna", "Web"), (2, "lockon", "IPhone")] {
+            let device = format!(
+                r#"{{"platform":"{}","device":{{"device_name":"{}"}}}}"#,
+                platform, name
+            );
+            addr.send(PlatformOnline {
+                id,
+                name: name.to_string(),
+                platform: serde_json::from_str(&device).unwrap(),
+            })
+            .await
+            .unwrap();
+        }
+        addr.send(Trial {
+            message: Activity {
+                audience: Some(Audience {
+                    platforms: vec!["IPhone".to_string(), "IPad".to_string()],
+                    capabilities: vec![],
+                }),
+                ..activity("testflight")
+            },
+            receivers: vec!["setsuna".to_string(), "lockon".to_string()],
+            quantity: None,
+        })
+        .await
+        .unwrap();
+        actix_rt::time::sleep(MESSAGE_INTERVAL * 2).await;
+
+        assert!(web.received().is_empty(), "{:?}", web.received());
+        let received = iphone.received();
+        assert_eq!(received.len(), 1, "{:?}", received);
+        assert!(received[0].contains("testflight"));
+        // the audience stays on the server
+        assert!(!received[0].contains("IPad"));
+        // skipped is as good as delivered
+        assert!(store.stream_ids("veda-activity:setsuna").is_empty());
+        assert!(store.stream_ids("veda-activity:lockon").is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn a_scheduled_activity_waits_for_its_time() {
+        let mut store = MemoryStore::default();
+        let addr = Redis::new(store.clone()).start();
+        let deliver_at = Utc::now().timestamp_millis() as u64 + 100;
+        let pushed = addr
+            .send(Trial {
+                message: Activity {
+                    deliver_at: Some(deliver_at),
+                    ..activity("trans-am")
+                },
+                receivers: vec!["setsuna".to_string()],
+                quantity: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            pushed,
+            vec![("setsuna".to_string(), "scheduled".to_string())]
+        );
+
+        actix::clock::sleep(Duration::from_millis(50)).await;
+        assert!(store.stream_ids("veda-activity:setsuna").is_empty());
+
+        actix::clock::sleep(Duration::from_millis(50) + SCHEDULE_TICK * 2).await;
+        assert!(Utc::now().timestamp_millis() as u64 >= deliver_at);
+        assert_eq!(store.stream_ids("veda-activity:setsuna").len(), 1);
+        assert!(store
+            .zrangebyscore("scheduled", i64::MAX)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn a_batch_schedules_its_later_activities() {
+        let store = MemoryStore::default();
+        let addr = Redis::new(store.clone()).start();
+        let deliver_at = Utc::now().timestamp_millis() as u64 + 100;
+        let pushed = addr
+            .send(TrialBatch {
+                events: vec![
+                    (
+                        Activity {
+                            deliver_at: Some(deliver_at),
+                            ..activity("trans-am")
+                        },
+                        vec!["setsuna".to_string()],
+                    ),
+                    (activity("ptolemy"), vec!["setsuna".to_string()]),
+                ],
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            pushed[0],
+            vec![("setsuna".to_string(), "scheduled".to_string())]
+        );
+        assert_eq!(pushed[1].len(), 1);
+        assert_eq!(
+            store.stream_ids("veda-activity:setsuna"),
+            vec![pushed[1][0].1.clone()]
+        );
+
+        actix::clock::sleep(Duration::from_millis(100) + SCHEDULE_TICK * 2).await;
+        assert_eq!(store.stream_ids("veda-activity:setsuna").len(), 2);
+    }
+
+    #[actix_rt::test]
+    async fn room_post_reaches_every_member() {
+        let addr = Redis::new(MemoryStore::default()).start();
+        let room = Room("gundam-00".to_string());
+
+        let mut collectors = vec![];
+        for (id, name) in [(1_usize, "setsuna"), (2, "lockon")] {
+            collectors.push(online(&addr, id, name).await);
+            addr.send(Join {
+                id,
+                name: name.to_string(),
+                room: room.clone(),
+            })
+            .await
+            .unwrap();
+        }
+
+        let posted = addr
+            .send(Post {
+                room: room.clone(),
+                message: Activity {
+                    sender: Some("setsuna".to_string()),
+                    ..activity("trans-am")
+                },
+            })
+            .await
+            .unwrap();
+        assert!(posted.is_some());
+
+        actix_rt::time::sleep(MESSAGE_INTERVAL * 2).await;
+        for collector in collectors {
+            let received = collector.received();
+            assert_eq!(received.len(), 1);
+            assert!(received[0].contains("trans-am"));
+        }
+    }
+
+    #[actix_rt::test]
+    async fn expired_room_posts_are_skipped() {
+        let addr = Redis::new(MemoryStore::default()).start();
+        let room = Room("trinity".to_string());
+        let collector = online(&addr, 1, "johann").await;
+        let _sender = online(&addr, 2, "neena").await;
+        for (id, name) in [(1_usize, "johann"), (2, "neena")] {
+            addr.send(Join {
+                id,
+                name: name.to_string(),
+                room: room.clone(),
+            })
+            .await
+            .unwrap();
+        }
+        let now = Utc::now().timestamp_millis() as u64;
+        for (content, expires_at) in [("missed call", now - 1_000), ("ringing", now + 30_000)] {
+            addr.send(Post {
+                room: room.clone(),
+                message: Activity {
+                    sender: Some("neena".to_string()),
+                    expires_at: Some(expires_at),
+                    ..activity(content)
+                },
+            })
+            .await
+            .unwrap();
+        }
+        let expired = metrics::DELIVERIES.get("expired");
+
+        actix_rt::time::sleep(MESSAGE_INTERVAL * 2).await;
+        let received: Vec<String> = collector
+            .received()
+            .iter()
+            .flat_map(|frame| serde_json::from_str::<Vec<Activity>>(frame).unwrap())
+            .map(|activity| activity.activity)
+            .collect();
+        assert_eq!(received, vec!["ringing"]);
+        assert!(metrics::DELIVERIES.get("expired") > expired);
+    }
+
+    #[actix_rt::test]
+    async fn only_members_post_to_a_room() {
+        let store = MemoryStore::default();
+        let addr = Redis::new(store.clone()).start();
+        let room = Room("ptolemy".to_string());
+        addr.send(Join {
+            id: 1,
+            name: "sumeragi".to_string(),
+            room: room.clone(),
+        })
+        .await
+        .unwrap();
+        let post = |sender: Option<&str>| Post {
+            room: room.clone(),
+            message: Activity {
+                sender: sender.map(str::to_owned),
+                ..activity("mission-plan")
+            },
+        };
+
+        assert!(addr.send(post(None)).await.unwrap().is_none());
+        assert!(addr.send(post(Some("graham"))).await.unwrap().is_none());
+        assert!(addr.send(post(Some("sumeragi"))).await.unwrap().is_some());
+        assert_eq!(store.stream_ids("veda-activity:room:ptolemy").len(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn echo_off_leaves_out_what_the_member_posted() {
+        let addr = Redis::new(MemoryStore::default()).start();
+        let room = Room("ptolemy".to_string());
+        let mut collectors = vec![];
+        for (id, name) in [(1_usize, "sumeragi"), (2, "lasse")] {
+            collectors.push(online(&addr, id, name).await);
+            addr.send(Join {
+                id,
+                name: name.to_string(),
+                room: room.clone(),
+            })
+            .await
+            .unwrap();
+        }
+        addr.send(Echo { id: 1, echo: false }).await.unwrap();
+
+        for (sender, text) in [("sumeragi", "mission-plan"), ("lasse", "roger")] {
+            addr.send(Post {
+                room: room.clone(),
+                message: Activity {
+                    sender: Some(sender.to_string()),
+                    ..activity(text)
+                },
+            })
+            .await
+            .unwrap();
+        }
+        actix_rt::time::sleep(MESSAGE_INTERVAL * 2).await;
+
+        let received = |collector: &Collector| -> Vec<String> {
+            collector
+                .received()
+                .iter()
+                .flat_map(|frame| serde_json::from_str::<Vec<Activity>>(frame).unwrap())
+                .map(|item| item.activity)
+                .collect()
+        };
+        assert_eq!(received(&collectors[0]), vec!["roger"]);
+        assert_eq!(received(&collectors[1]), vec!["mission-plan", "roger"]);
+    }
+
+    #[actix_rt::test]
+    async fn muted_senders_are_acked_but_not_delivered() {
+        let store = MemoryStore::default();
+        let addr = Redis::new(store.clone()).persist_mutes(true).start();
+        let room = Room("ptolemy".to_string());
+        let collector = online(&addr, 1, "feldt").await;
+        addr.send(Join {
+            id: 1,
+            name: "feldt".to_string(),
+            room: room.clone(),
+        })
+        .await
+        .unwrap();
+        addr.send(Mute {
+            id: 1,
+            sender: "graham".to_string(),
+            mute: true,
+        })
+        .await
+        .unwrap();
+        for (id, name) in [(2_usize, "graham"), (3, "lockon")] {
+            addr.send(Join {
+                id,
+                name: name.to_string(),
+                room: room.clone(),
+            })
+            .await
+            .unwrap();
+        }
+
+        for (sender, text) in [("graham", "princess"), ("lockon", "roger")] {
+            let message = Activity {
+                sender: Some(sender.to_string()),
+                ..activity(text)
+            };
+            addr.send(Post {
+                room: room.clone(),
+                message: message.clone(),
+            })
+            .await
+            .unwrap();
+            addr.send(Trial {
+                message,
+                receivers: vec!["feldt".to_string()],
+                quantity: None,
+            })
+            .await
+            .unwrap();
+        }
+        actix_rt::time::sleep(MESSAGE_INTERVAL * 2).await;
+
+        let received: Vec<String> = collector
+            .received()
+            .iter()
+            .flat_map(|frame| serde_json::from_str::<Vec<Activity>>(frame).unwrap())
+            .map(|item| item.activity)
+            .collect();
+        assert_eq!(received, vec!["roger", "roger"]);
+        assert!(store.stream_ids("veda-activity:feldt").is_empty());
+        // kept for the next time feldt comes online
+        let mut store = store;
+        assert_eq!(store.smembers("muted:feldt").unwrap(), vec!["graham"]);
+    }
+
+    #[actix_rt::test]
+    async fn a_reply_carries_the_entry_it_answers() {
+        let addr = Redis::new(MemoryStore::default()).start();
+        let room = Room("ptolemy".to_string());
+        let collector = online(&addr, 1, "sumeragi").await;
+        addr.send(Join {
+            id: 1,
+            name: "sumeragi".to_string(),
+            room: room.clone(),
+        })
+        .await
+        .unwrap();
+
+        let post = |reply_to: Option<String>| Post {
+            room: room.clone(),
+            message: Activity {
+                reply_to,
+                sender: Some("sumeragi".to_string()),
+                ..activity("mission-plan")
+            },
+        };
+        let original = addr.send(post(None)).await.unwrap().unwrap();
+        assert!(addr
+            .send(post(Some(original.clone())))
+            .await
+            .unwrap()
+            .is_some());
+        // nothing to answer in the room
+        assert!(addr
+            .send(post(Some("9999-0".to_string())))
+            .await
+            .unwrap()
+            .is_none());
+
+        actix_rt::time::sleep(MESSAGE_INTERVAL * 2).await;
+        let delivered: Vec<Activity> = collector
+            .received()
+            .iter()
+            .flat_map(|frame| serde_json::from_str::<Vec<Activity>>(frame).unwrap())
+            .collect();
+        assert_eq!(delivered.len(), 2);
+        assert_eq!(delivered[0].reply_to, None);
+        assert_eq!(delivered[1].reply_to, Some(original));
+    }
+
+    #[actix_rt::test]
+    async fn redis_errors_are_counted() {
+        let store = MemoryStore::default();
+        let before = REDIS_ERRORS.get("xinfo");
+        // the first fails the backlog check on start
+        store.fail_next(3);
+        RedisSession::new(
+            1,
+            "wrong-type".to_string(),
+            "veda-activity:wrong-type".to_string(),
+            Box::new(store),
+            Collector::default().start().recipient(),
+        )
+        .start();
+        actix_rt::time::sleep(MESSAGE_INTERVAL * 3).await;
+
+        assert!(REDIS_ERRORS.get("xinfo") >= before + 2);
+    }
+
+    #[actix_rt::test]
+    async fn correlation_id_is_logged_from_push_to_delivery() {
+        crate::trace::tests::captured();
+        let addr = Redis::new(MemoryStore::default()).start();
+        let collector = online(&addr, 1, "hallelujah").await;
+
+        let correlation_id = trace::new_correlation_id();
+        trace::hop(&correlation_id, trace::PUSH_RECEIVED, "test");
+        addr.send(Trial {
+            message: Activity {
+                correlation_id: Some(correlation_id.clone()),
+                ..activity("quantum burst")
+            },
+            receivers: vec!["hallelujah".to_string()],
+            quantity: None,
+        })
+        .await
+        .unwrap();
+        actix_rt::time::sleep(MESSAGE_INTERVAL * 2).await;
+
+        assert_eq!(
+            crate::trace::tests::hops_of(&correlation_id),
+            vec![
+                trace::PUSH_RECEIVED,
+                trace::XADD_DONE,
+                trace::XREAD,
+                trace::FRAME_SENT
+            ]
+        );
+        assert!(collector.received()[0].contains(&correlation_id));
+    }
+
+    #[actix_rt::test]
+    async fn reconnect_within_grace_keeps_the_session() {
+        let store = MemoryStore::default();
+        let grace = Duration::from_secs(2);
+        let addr = Redis::new(store.clone()).offline_grace(grace).start();
+
+        let before = online(&addr, 1, "lockon").await;
+        addr.send(Offline { id: 1 }).await.unwrap();
+        let after = online(&addr, 2, "lockon").await;
+
+        assert_eq!(store.hget("online-users", "1"), None);
+        assert_eq!(store.hget("online-users", "2").as_deref(), Some("lockon"));
+
+        // the scheduled offline was cancelled, the session still delivers
+        actix_rt::time::sleep(grace + MESSAGE_INTERVAL).await;
+        addr.send(Trial {
+            message: activity("ha-ro"),
+            receivers: vec!["lockon".to_string()],
+            quantity: None,
+        })
+        .await
+        .unwrap();
+        actix_rt::time::sleep(MESSAGE_INTERVAL * 2).await;
+
+        assert!(before.received().is_empty());
+        assert_eq!(after.received().len(), 1);
+        assert_eq!(store.hget("online-users", "2").as_deref(), Some("lockon"));
+    }
+
+    #[actix_rt::test]
+    async fn switch_offlines_the_old_name_and_drains_the_new_one() {
+        let mut store = MemoryStore::default();
+        store
+            .xadd("veda-activity:bob", &activity("for bob"))
+            .unwrap();
+
+        let addr = Redis::new(store.clone())
+            .offline_grace(Duration::from_secs(60))
+            .start();
+        let collector = Collector::default();
+        let switch = |name: &str| Online {
+            id: 1,
+            name: name.to_string(),
+            addr: collector.clone().start().recipient(),
+            kick: collector.clone().start().recipient(),
+            ask: collector.clone().start().recipient(),
+            backlog_summary: false,
+            framing: FramingMode::Array,
+            batch_size: None,
+            capabilities: HashSet::new(),
+            delivery: DeliveryMode::AtLeastOnce,
+            fields: None,
+            echo: true,
+        };
+        addr.send(switch("alice")).await.unwrap();
+        assert_eq!(store.hget("online-users", "1").as_deref(), Some("alice"));
+
+        addr.send(switch("bob")).await.unwrap();
+        // alice does not linger in the grace period
+        assert_eq!(store.hget("online-users", "1").as_deref(), Some("bob"));
+        store
+            .xadd("veda-activity:alice", &activity("for alice"))
+            .unwrap();
+        actix_rt::time::sleep(MESSAGE_INTERVAL + Duration::from_millis(200)).await;
+
+        let received = collector.received();
+        assert_eq!(received.len(), 1);
+        let delivered: Vec<Activity> = serde_json::from_str(&received[0]).unwrap();
+        assert_eq!(delivered[0].activity, "for bob");
+        assert_eq!(store.stream_ids("veda-activity:alice").len(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn status_is_returned_by_presence() {
+        let store = MemoryStore::default();
+        let addr = Redis::new(store.clone()).start();
+        let presence = || Presence {
+            name: "lockon".to_string(),
+        };
+        assert_eq!(
+            addr.send(presence()).await.unwrap(),
+            PresenceInfo {
+                name: "lockon".to_string(),
+                online: false,
+                status: None,
+            }
+        );
+
+        let _collector = online(&addr, 1, "lockon").await;
+        addr.send(SetStatus {
+            name: "lockon".to_string(),
+            status: Some("sniping".to_string()),
+        })
+        .await
+        .unwrap();
+        let info = addr.send(presence()).await.unwrap();
+        assert!(info.online);
+        assert_eq!(info.status.as_deref(), Some("sniping"));
+        assert_eq!(
+            store
+                .lock()
+                .strings
+                .get("status:lockon")
+                .map(String::as_str),
+            Some("sniping")
+        );
+
+        addr.send(SetStatus {
+            name: "lockon".to_string(),
+            status: None,
+        })
+        .await
+        .unwrap();
+        assert_eq!(addr.send(presence()).await.unwrap().status, None);
+    }
+
+    #[actix_rt::test]
+    async fn offline_cleans_keys_without_a_session() {
+        let mut store = MemoryStore::default();
+        store.hset("online-users", "7", "neil").unwrap();
+        store.hset("platforms:neil", "7", "{}").unwrap();
+
+        let addr = Redis::new(store.clone()).start();
+        addr.send(Offline { id: 7 }).await.unwrap();
+
+        assert_eq!(store.hget("online-users", "7"), None);
+        assert_eq!(store.hget("platforms:neil", "7"), None);
+    }
+
+    #[actix_rt::test]
+    async fn a_drained_backlog_is_marked_caught_up_once() {
+        let mut store = MemoryStore::default();
+        let ids: Vec<String> = ["exia", "dynames", "kyrios", "virtue"]
+            .iter()
+            .map(|content| {
+                store
+                    .xadd("veda-activity:setsuna", &activity(content))
+                    .unwrap()
+            })
+            .collect();
+        let addr = Redis::new(store.clone()).start();
+        let collector = online(&addr, 1, "setsuna").await;
+        actix_rt::time::sleep(MESSAGE_INTERVAL * 3).await;
+
+        // live pushes after the backlog are not followed by another marker
+        addr.send(Trial {
+            message: activity("00 raiser"),
+            receivers: vec!["setsuna".to_string()],
+            quantity: None,
+        })
+        .await
+        .unwrap();
+        actix_rt::time::sleep(MESSAGE_INTERVAL * 3).await;
+
+        let received = collector.received();
+        let markers: Vec<usize> = received
+            .iter()
+            .enumerate()
+            .filter(|(_, frame)| frame.contains("caught_up"))
+            .map(|(at, _)| at)
+            .collect();
+        assert_eq!(markers.len(), 1, "{:?}", received);
+        let marker: serde_json::Value = serde_json::from_str(&received[markers[0]]).unwrap();
+        assert_eq!(
+            marker,
+            serde_json::json!({"type": "caught_up", "last_id": ids[3]})
+        );
+        assert!(received[..markers[0]].concat().contains("virtue"));
+        assert!(received[markers[0] + 1..].concat().contains("00 raiser"));
+    }
+
+    #[actix_rt::test]
+    async fn backlog_summary_precedes_delivery() {
+        let mut store = MemoryStore::default();
+        let ids: Vec<String> = (0..100)
+            .map(|i| {
+                store
+                    .xadd("veda-activity:hallelujah", &activity(&i.to_string()))
+                    .unwrap()
+            })
+            .collect();
+
+        let addr = Redis::new(store.clone()).start();
+        let collector = Collector::default();
+        addr.send(Online {
+            id: 1,
+            name: "hallelujah".to_string(),
+            addr: collector.clone().start().recipient(),
+            kick: collector.clone().start().recipient(),
+            ask: collector.clone().start().recipient(),
+            backlog_summary: true,
+            framing: FramingMode::Array,
+            batch_size: None,
+            capabilities: HashSet::new(),
+            delivery: DeliveryMode::AtLeastOnce,
+            fields: None,
+            echo: true,
+        })
+        .await
+        .unwrap();
+        actix_rt::time::sleep(MESSAGE_INTERVAL + Duration::from_millis(200)).await;
+
+        let received = collector.received();
+        let summary = serde_json::to_string(&BacklogSummary {
+            count: 100,
+            oldest_id: ids[0].clone(),
+            newest_id: ids[99].clone(),
+        })
+        .unwrap();
+        assert_eq!(
+            summary,
+            format!(
+                r#"{{"type":"backlog","count":100,"oldest_id":"{}","newest_id":"{}"}}"#,
+                ids[0], ids[99]
+            )
+        );
+        assert_eq!(received[0], summary);
+        let first: Vec<Activity> = serde_json::from_str(&received[1]).unwrap();
+        assert_eq!(first[0].activity, "0");
+    }
+
+    #[actix_rt::test]
+    async fn batch_size_caps_activities_per_frame() {
+        let mut store = MemoryStore::default();
+        for i in 0..25 {
+            store
+                .xadd("veda-activity:exia", &activity(&i.to_string()))
+                .unwrap();
+        }
+
+        let addr = Redis::new(store.clone()).start();
+        let collector = Collector::default();
+        addr.send(Online {
+            id: 1,
+            name: "exia".to_string(),
+            addr: collector.clone().start().recipient(),
+            kick: collector.clone().start().recipient(),
+            ask: collector.clone().start().recipient(),
+            backlog_summary: false,
+            framing: FramingMode::Array,
+            batch_size: Some(5),
+            capabilities: HashSet::new(),
+            delivery: DeliveryMode::AtLeastOnce,
+            fields: None,
+            echo: true,
+        })
+        .await
+        .unwrap();
+        actix_rt::time::sleep(MESSAGE_INTERVAL * 3 + Duration::from_millis(200)).await;
+
+        let frames: Vec<Vec<Activity>> = collector
+            .received()
+            .iter()
+            .map(|frame| serde_json::from_str(frame).unwrap())
+            .collect();
+        assert_eq!(frames.len(), 5);
+        assert!(frames.iter().all(|frame| frame.len() == 5));
+        assert_eq!(frames[4][4].activity, "24");
+
+        // `/batch-size` changes it while online
+        addr.send(BatchSize { id: 1, size: 2 }).await.unwrap();
+        for i in 25..29 {
+            store
+                .xadd("veda-activity:exia", &activity(&i.to_string()))
+                .unwrap();
+        }
+        actix_rt::time::sleep(MESSAGE_INTERVAL + Duration::from_millis(200)).await;
+        let received = collector.received();
+        assert_eq!(received.len(), 7);
+        let last: Vec<Activity> = serde_json::from_str(&received[6]).unwrap();
+        assert_eq!(last.len(), 2);
+    }
+
+    #[actix_rt::test]
+    async fn unencodable_batches_are_dead_lettered_once() {
+        let mut store = MemoryStore::default();
+        store
+            .xadd("veda-activity:ribbons", &activity("poison"))
+            .unwrap();
+
+        let collector = Collector::default();
+        let session = RedisSession::new(
+            1,
+            "ribbons".to_string(),
+            "veda-activity:ribbons".to_string(),
+            Box::new(store.clone()),
+            collector.clone().start().recipient(),
+        )
+        .encoder(|_| serde_json::from_str::<String>("poison"))
+        .dead_letter("veda-dead-letter:ribbons".to_string())
+        .start();
+        actix_rt::time::sleep(MESSAGE_INTERVAL * 2 + Duration::from_millis(200)).await;
+
+        assert!(collector.received().is_empty());
+        assert!(store.stream_ids("veda-activity:ribbons").is_empty());
+        assert_eq!(store.stream_ids("veda-dead-letter:ribbons").len(), 1);
+        drop(session);
+    }
+
+    #[actix_rt::test]
+    async fn delivery_respects_the_rate() {
+        let mut store = MemoryStore::default();
+        for i in 0..100 {
+            store
+                .xadd("veda-activity:trinity", &activity(&i.to_string()))
+                .unwrap();
+        }
+
+        let addr = Redis::new(store.clone()).delivery_rate(4).start();
+        let collector = online(&addr, 1, "trinity").await;
+        // two reads, one full bucket and one refill
+        actix_rt::time::sleep(MESSAGE_INTERVAL * 2 + Duration::from_millis(300)).await;
+
+        let delivered: usize = collector
+            .received()
+            .iter()
+            .map(|frame| serde_json::from_str::<Vec<Activity>>(frame).unwrap().len())
+            .sum();
+        assert!((4..=8).contains(&delivered), "delivered {}", delivered);
+        assert_eq!(
+            store.stream_ids("veda-activity:trinity").len(),
+            100 - delivered
+        );
+    }
+
+    #[actix_rt::test]
+    async fn clear_drains_the_stream_without_delivery() {
+        let mut store = MemoryStore::default();
+        for i in 0..25 {
+            store
+                .xadd("veda-activity:lyle", &activity(&i.to_string()))
+                .unwrap();
+        }
+
+        let addr = Redis::new(store.clone()).start();
+        let cleared = addr
+            .send(Clear {
+                name: "lyle".to_string(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(cleared, Cleared { cleared: 25 });
+        assert!(store.stream_ids("veda-activity:lyle").is_empty());
+
+        let collector = online(&addr, 1, "lyle").await;
+        actix_rt::time::sleep(MESSAGE_INTERVAL + Duration::from_millis(200)).await;
+        assert!(collector.received().is_empty());
+    }
+
+    #[test]
+    fn shards_stay_put_across_builds() {
+        assert_eq!(fnv1a(&[b"a"]), 0x089b_c907_b544_c769);
+
+        let redis = Redis::new(MemoryStore::default()).stream_shards(4);
+        let compacted = Activity {
+            compaction_key: Some("gn-drive".to_string()),
+            ..activity("trans-am")
+        };
+        assert_eq!(
+            redis.key_activity_of("tieria", &compacted),
+            format!("{}:2", redis.key_activity("tieria"))
+        );
+        assert_eq!(
+            redis.key_activity_of("tieria", &activity("trans-am")),
+            format!("{}:1", redis.key_activity("tieria"))
+        );
+    }
+
+    #[test]
+    fn next_stream_id_follows_the_id() {
+        assert_eq!(next_stream_id("1526919030474-55"), "1526919030474-56");
+        assert_eq!(next_stream_id("7"), "7-1");
+    }
+
+    #[actix_rt::test]
+    async fn trial_round_trips_the_activity() {
+        let store = MemoryStore::default();
+        let addr = Redis::new(store.clone()).start();
+        let pushed = Activity {
+            activity_type: "mission".to_string(),
+            activity: r#"{"target":"moralia","eta":"04:00"}"#.to_string(),
+            correlation_id: Some("0123456789abcdef".to_string()),
+            compaction_key: None,
+            idempotency_key: None,
+            expires_at: None,
+            deliver_at: None,
+            reply_to: None,
+            sender: None,
+            blob: None,
+            blob_url: None,
+            blob_key: None,
+            claim_key: None,
+            quantity: None,
+            audience: None,
+            extra: Default::default(),
+            id: None,
+        };
+        addr.send(Trial {
+            message: pushed.clone(),
+            receivers: vec!["setsuna".to_string()],
+            quantity: None,
+        })
+        .await
+        .unwrap();
+
+        let collector = online(&addr, 1, "setsuna").await;
+        actix_rt::time::sleep(MESSAGE_INTERVAL + Duration::from_millis(200)).await;
+
+        let received = collector.received();
+        let delivered: Vec<Activity> = serde_json::from_str(&received[0]).unwrap();
+        assert_eq!(delivered, vec![pushed]);
+    }
+
+    #[actix_rt::test]
+    async fn staggered_sessions_still_receive() {
+        let mut store = MemoryStore::default();
+        let addr = Redis::new(store.clone()).stagger_reads(true).start();
+        let collectors = [
+            online(&addr, 1, "kati").await,
+            online(&addr, 2, "patrick").await,
+        ];
+        for name in ["kati", "patrick"] {
+            store
+                .xadd(&format!("veda-activity:{}", name), &activity(name))
+                .unwrap();
+        }
+        actix_rt::time::sleep(MESSAGE_INTERVAL + Duration::from_millis(200)).await;
+
+        for collector in &collectors {
+            assert_eq!(collector.received().len(), 1);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn missed_reports_unacked_activities() {
+        let mut store = MemoryStore::default();
+        let ids: Vec<String> = (0..3)
+            .map(|i| {
+                store
+                    .xadd("veda-activity:feldt", &activity(&i.to_string()))
+                    .unwrap()
+            })
+            .collect();
+
+        let addr = Redis::new(store.clone()).start();
+        let (frames, mut received) = futures::channel::mpsc::unbounded();
+        let session = addr
+            .send(Subscribe {
+                name: "feldt".to_string(),
+                frames,
+            })
+            .await
+            .unwrap();
+        // delivered, only the first one is acked
+        futures::StreamExt::next(&mut received).await.unwrap();
+        session
+            .send(Ack {
+                ids: vec![ids[0].clone()],
+            })
+            .await
+            .unwrap();
+
+        let missed = addr
+            .send(ListMissed {
+                name: "feldt".to_string(),
+                limit: 1,
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            missed,
+            Missed {
+                count: 2,
+                ids: vec![ids[2].clone()],
+            }
+        );
+    }
+
+    #[actix_rt::test]
+    async fn the_sender_sees_who_read_a_push() {
+        let addr = Redis::new(MemoryStore::default()).start();
+        let ids = addr
+            .send(Trial {
+                message: Activity {
+                    correlation_id: Some("00c0ffee".to_string()),
+                    sender: Some("sumeragi".to_string()),
+                    ..activity("operation-fallen-angels")
+                },
+                receivers: vec![
+                    "setsuna".to_string(),
+                    "lockon".to_string(),
+                    "allelujah".to_string(),
+                ],
+                quantity: None,
+            })
+            .await
+            .unwrap();
+        let (frames, mut received) = futures::channel::mpsc::unbounded();
+        let session = addr
+            .send(Subscribe {
+                name: "setsuna".to_string(),
+                frames,
+            })
+            .await
+            .unwrap();
+        futures::StreamExt::next(&mut received).await.unwrap();
+        session
+            .send(Ack {
+                ids: vec![ids[0].1.clone()],
+            })
+            .await
+            .unwrap();
+        actix_rt::time::sleep(Duration::from_millis(50)).await;
+
+        let status_of = |sender: &str| StatusOf {
+            sender: sender.to_string(),
+            push_ids: ["00c0ffee", "unknown"]
+                .iter()
+                .map(|id| id.to_string())
+                .collect(),
+        };
+        let status = addr.send(status_of("sumeragi")).await.unwrap();
+        let expected: BTreeMap<String, ReceiptState> = [
+            ("setsuna", ReceiptState::Read),
+            ("lockon", ReceiptState::Pending),
+            ("allelujah", ReceiptState::Pending),
+        ]
+        .iter()
+        .map(|(receiver, state)| (receiver.to_string(), *state))
+        .collect();
+        assert_eq!(status.receipts["00c0ffee"], expected);
+        assert!(status.receipts["unknown"].is_empty());
+
+        // a receiver, or anyone else, learns nothing of the push
+        let status = addr.send(status_of("setsuna")).await.unwrap();
+        assert!(status.receipts["00c0ffee"].is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn a_tracking_sender_hears_of_deliveries_and_reads() {
+        let addr = Redis::new(MemoryStore::default()).start();
+        let sender = online(&addr, 1, "sumeragi").await;
+        addr.send(Track { id: 1, on: true }).await.unwrap();
+        let ids = addr
+            .send(Trial {
+                message: Activity {
+                    correlation_id: Some("00c0ffee".to_string()),
+                    sender: Some("sumeragi".to_string()),
+                    ..activity("mission-plan")
+                },
+                receivers: vec!["setsuna".to_string()],
+                quantity: None,
+            })
+            .await
+            .unwrap();
+        let (frames, mut received) = futures::channel::mpsc::unbounded();
+        let session = addr
+            .send(Subscribe {
+                name: "setsuna".to_string(),
+                frames,
+            })
+            .await
+            .unwrap();
+        futures::StreamExt::next(&mut received).await.unwrap();
+        session
+            .send(Ack {
+                ids: vec![ids[0].1.clone()],
+            })
+            .await
+            .unwrap();
+        actix_rt::time::sleep(Duration::from_millis(50)).await;
+
+        let acks: Vec<serde_json::Value> = sender
+            .received()
+            .iter()
+            .map(|frame| serde_json::from_str(frame).unwrap())
+            .collect();
+        let ack = |kind: &str| {
+            serde_json::json!({
+                "type": "ack",
+                "event_id": "00c0ffee",
+                "receiver": "setsuna",
+                "kind": kind,
+            })
+        };
+        assert_eq!(acks, vec![ack("delivered"), ack("read")]);
+    }
+
+    #[actix_rt::test]
+    async fn delivered_and_acked_are_counted() {
+        let outcomes = ["pushed", "delivered", "acked"];
+        let before: Vec<u64> = outcomes.iter().map(|o| DELIVERIES.get(o)).collect();
+
+        let addr = Redis::new(MemoryStore::default()).start();
+        let collector = online(&addr, 1, "billy").await;
+        addr.send(Trial {
+            message: activity("custom flag"),
+            receivers: vec!["billy".to_string(); 3],
+            quantity: None,
+        })
+        .await
+        .unwrap();
+        actix_rt::time::sleep(MESSAGE_INTERVAL + Duration::from_millis(200)).await;
+        assert_eq!(collector.received().len(), 1);
+
+        // other tests count too, ours are at least there
+        for (outcome, before) in outcomes.iter().zip(before) {
+            assert!(DELIVERIES.get(outcome) >= before + 3, "{}", outcome);
+        }
+        assert!(metrics::render().contains("veda_deliveries_total{outcome=\"acked\"}"));
+    }
+
+    #[actix_rt::test]
+    async fn unacked_activities_are_reclaimed() {
+        let mut store = MemoryStore::default();
+        let pushed = store
+            .xadd("veda-activity:sergei", &activity("tieren"))
+            .unwrap();
+        let addr = Redis::new(store.clone()).start();
+        let subscribe = || {
+            let (frames, received) = futures::channel::mpsc::unbounded();
+            let session = addr.send(Subscribe {
+                name: "sergei".to_string(),
+                frames,
+            });
+            (session, received)
+        };
+        let wait = || actix_rt::time::sleep(MESSAGE_INTERVAL + Duration::from_millis(200));
+
+        // a consumer crashes after receiving the activity, before acking it
+        let (crashed, mut crashed_frames) = subscribe();
+        let crashed = crashed.await.unwrap();
+        wait().await;
+        assert!(crashed_frames.try_recv().is_ok());
+        crashed.send(RedisOffline).await.unwrap();
+
+        // a live consumer gets it too but never acks it either
+        let (live, mut frames) = subscribe();
+        let live = live.await.unwrap();
+        wait().await;
+        assert!(frames.try_recv().unwrap().contains(&pushed));
+        wait().await;
+        assert!(frames.try_recv().is_err());
+
+        // just delivered, however old the entry itself is
+        live.send(Reclaim { idle: RECLAIM_IDLE }).await.unwrap();
+        wait().await;
+        assert!(frames.try_recv().is_err());
+
+        let redelivered = DELIVERIES.get("redelivered");
+        live.send(Reclaim {
+            idle: MESSAGE_INTERVAL,
+        })
+        .await
+        .unwrap();
+        wait().await;
+        assert!(frames.try_recv().unwrap().contains(&pushed));
+        assert!(DELIVERIES.get("redelivered") > redelivered);
+        // sent once more, not on every tick after
+        wait().await;
+        assert!(frames.try_recv().is_err());
+        assert_eq!(
+            store.stream_ids("veda-activity:sergei"),
+            vec![pushed.clone()]
+        );
+
+        // acked, nothing left to reclaim
+        live.send(Ack { ids: vec![pushed] }).await.unwrap();
+        live.send(Reclaim {
+            idle: Duration::ZERO,
+        })
+        .await
+        .unwrap();
+        wait().await;
+        assert!(frames.try_recv().is_err());
+    }
+
+    /// takes its first `slow` frames too late for the sender
+    struct Sluggish {
+        slow: usize,
+        received: Collector,
+    }
+
+    impl Actor for Sluggish {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<WsMessage> for Sluggish {
+        type Result = ResponseActFuture<Self, ()>;
+
+        fn handle(&mut self, msg: WsMessage, _: &mut Self::Context) -> Self::Result {
+            self.received.0.lock().unwrap().push(msg.0);
+            let delay = if self.slow > 0 {
+                self.slow -= 1;
+                Duration::from_millis(300)
+            } else {
+                Duration::ZERO
+            };
+            Box::pin(fut::wrap_future(actix_rt::time::sleep(delay)))
+        }
+    }
+
+    #[actix_rt::test]
+    async fn busy_sessions_are_retried_before_stopping() {
+        let mut store = MemoryStore::default();
+        store
+            .xadd("veda-activity:kati", &activity("mobile suit"))
+            .unwrap();
+        let addr = Redis::new(store.clone())
+            .delivery_retry(RetryPolicy {
+                max_attempts: 3,
+                backoff: Duration::from_millis(50),
+                timeout: Some(Duration::from_millis(100)),
+            })
+            .start();
+        let received = Collector::default();
+        let retried = DELIVERIES.get("retried");
+        addr.send(Online {
+            id: 1,
+            name: "kati".to_string(),
+            addr: Sluggish {
+                slow: 2,
+                received: received.clone(),
+            }
+            .start()
+            .recipient(),
+            kick: received.clone().start().recipient(),
+            ask: received.clone().start().recipient(),
+            backlog_summary: false,
+            framing: FramingMode::Array,
+            batch_size: None,
+            capabilities: HashSet::new(),
+            delivery: DeliveryMode::AtLeastOnce,
+            fields: None,
+            echo: true,
+        })
+        .await
+        .unwrap();
+        actix_rt::time::sleep(MESSAGE_INTERVAL + Duration::from_millis(800)).await;
+
+        // taken on the third attempt
+        assert_eq!(received.received().len(), 3);
+        assert!(DELIVERIES.get("retried") >= retried + 2);
+        assert!(store.stream_ids("veda-activity:kati").is_empty());
+
+        // the session is still delivering
+        store
+            .xadd("veda-activity:kati", &activity("mobile armor"))
+            .unwrap();
+        actix_rt::time::sleep(MESSAGE_INTERVAL).await;
+        assert_eq!(received.received().len(), 4);
+        assert!(store.stream_ids("veda-activity:kati").is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn list_sessions_reports_the_delivery_progress() {
+        let mut store = MemoryStore::default();
+        let pushed = store
+            .xadd("veda-activity:allelujah", &activity("kyrios"))
+            .unwrap();
+        let addr = Redis::new(store).start();
+        online(&addr, 2, "soma").await;
+        online(&addr, 1, "allelujah").await;
+        actix_rt::time::sleep(MESSAGE_INTERVAL * 2 + Duration::from_millis(200)).await;
+
+        let sessions = addr.send(ListSessions).await.unwrap();
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions[0].id, 1);
+        assert_eq!(sessions[0].name, "allelujah");
+        assert_eq!(sessions[0].stream_name, "veda-activity:allelujah");
+        assert_eq!(sessions[0].last_delivered_id, Some(pushed));
+        assert_eq!(sessions[0].idle_ticks, 1);
+        assert_eq!(sessions[1].name, "soma");
+        assert_eq!(sessions[1].last_delivered_id, None);
+        assert_eq!(sessions[1].idle_ticks, 2);
+    }
+
+    /// stamps what it lets through, drops what is secret
+    struct Stamp;
+
+    impl DeliveryHook for Stamp {
+        fn apply(&self, mut activity: Activity) -> Option<Activity> {
+            if activity.activity == "secret" {
+                return None;
+            }
+            activity
+                .extra
+                .insert("region".to_string(), "lagrange-3".into());
+            Some(activity)
+        }
+    }
+
+    #[actix_rt::test]
+    async fn hooks_change_activities_on_the_way_out() {
+        let store = MemoryStore::default();
+        let addr = Redis::new(store.clone()).hook(Stamp).start();
+        let collector = online(&addr, 1, "ian").await;
+        for content in ["gn-x", "secret"] {
+            addr.send(Trial {
+                message: activity(content),
+                receivers: vec!["ian".to_string()],
+                quantity: None,
+            })
+            .await
+            .unwrap();
+        }
+        actix_rt::time::sleep(MESSAGE_INTERVAL * 2).await;
+
+        let delivered: Vec<serde_json::Value> = collector
+            .received()
+            .iter()
+            .flat_map(|frame| serde_json::from_str::<Vec<serde_json::Value>>(frame).unwrap())
+            .collect();
+        assert_eq!(delivered.len(), 1, "{:?}", delivered);
+        assert_eq!(delivered[0]["activity"], "gn-x");
+        assert_eq!(delivered[0]["region"], "lagrange-3");
+        // dropped for good, not read again
+        assert!(store.stream_ids("veda-activity:ian").is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn a_foreground_session_polls_more_often() {
+        let addr = Redis::new(MemoryStore::default()).start();
+        let collector = online(&addr, 1, "feldt").await;
+        let poll_interval = || async { addr.send(ListSessions).await.unwrap()[0].poll_interval_ms };
+        assert_eq!(poll_interval().await, MESSAGE_INTERVAL.as_millis() as u64);
+
+        addr.send(Priority {
+            id: 1,
+            foreground: true,
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            poll_interval().await,
+            FOREGROUND_INTERVAL.as_millis() as u64
+        );
+        addr.send(Trial {
+            message: activity("haro"),
+            receivers: vec!["feldt".to_string()],
+            quantity: None,
+        })
+        .await
+        .unwrap();
+        // well before the next tick at the normal pace
+        actix_rt::time::sleep(FOREGROUND_INTERVAL * 2).await;
+        assert_eq!(collector.received().len(), 1);
+
+        addr.send(Priority {
+            id: 1,
+            foreground: false,
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            poll_interval().await,
+            BACKGROUND_INTERVAL.as_millis() as u64
+        );
+    }
+
+    #[actix_rt::test]
+    async fn sessions_adopt_a_reloaded_poll_interval() {
+        let addr = Redis::new(MemoryStore::default()).start();
+        let collector = online(&addr, 1, "lasse").await;
+        online(&addr, 2, "chall").await;
+        addr.send(Priority {
+            id: 2,
+            foreground: true,
+        })
+        .await
+        .unwrap();
+        let poll_intervals = || async {
+            let mut sessions = addr.send(ListSessions).await.unwrap();
+            sessions.sort_by_key(|session| session.id);
+            sessions
+                .iter()
+                .map(|session| session.poll_interval_ms)
+                .collect::<Vec<_>>()
+        };
+        let normal = MESSAGE_INTERVAL.as_millis() as u64;
+        let foreground = FOREGROUND_INTERVAL.as_millis() as u64;
+        assert_eq!(poll_intervals().await, vec![normal, foreground]);
+
+        let slow = MESSAGE_INTERVAL * 3;
+        addr.send(ReloadConfig {
+            poll_interval: slow,
+            max_sessions_per_user: 0,
+        })
+        .await
+        .unwrap();
+        online(&addr, 3, "kinkeadu").await;
+        let slow_ms = slow.as_millis() as u64;
+        // the session that asked for its own interval keeps it
+        assert_eq!(poll_intervals().await, vec![slow_ms, foreground, slow_ms]);
+
+        addr.send(Trial {
+            message: activity("trans-am"),
+            receivers: vec!["lasse".to_string()],
+            quantity: None,
+        })
+        .await
+        .unwrap();
+        // a tick at the old pace reads nothing any more
+        actix_rt::time::sleep(MESSAGE_INTERVAL * 2).await;
+        assert!(collector.received().is_empty());
+        actix_rt::time::sleep(slow).await;
+        assert_eq!(collector.received().len(), 1);
+
+        // 0 is `MESSAGE_INTERVAL` again, as at start
+        addr.send(ReloadConfig {
+            poll_interval: Duration::ZERO,
+            max_sessions_per_user: 0,
+        })
+        .await
+        .unwrap();
+        assert_eq!(poll_intervals().await, vec![normal, foreground, normal]);
+    }
+
+    #[actix_rt::test]
+    async fn gated_types_need_the_capability() {
+        let mut store = MemoryStore::default();
+        let typed = |activity_type: &str, content: &str| Activity {
+            activity_type: activity_type.to_string(),
+            ..activity(content)
+        };
+        store
+            .xadd("veda-activity:marie", &typed("rich", "video"))
+            .unwrap();
+        store
+            .xadd("veda-activity:marie", &typed("message", "text"))
+            .unwrap();
+        let addr = Redis::new(store.clone()).start();
+        let collector = online(&addr, 1, "marie").await;
+        actix_rt::time::sleep(MESSAGE_INTERVAL + Duration::from_millis(200)).await;
+
+        let received = collector.received();
+        assert_eq!(received.len(), 1);
+        let delivered: Vec<Activity> = serde_json::from_str(&received[0]).unwrap();
+        assert_eq!(delivered, vec![typed("message", "text")]);
+        // skipped, but acked all the same
+        assert!(store.stream_ids("veda-activity:marie").is_empty());
+
+        addr.send(Capabilities {
+            id: 1,
+            capabilities: vec!["rich".to_string()].into_iter().collect(),
+        })
+        .await
+        .unwrap();
+        store
+            .xadd("veda-activity:marie", &typed("rich", "video"))
+            .unwrap();
+        actix_rt::time::sleep(MESSAGE_INTERVAL).await;
+        let received = collector.received();
+        assert_eq!(received.len(), 2);
+        assert!(received[1].contains("video"));
+    }
+
+    #[test]
+    fn stream_ids_order_numerically() {
+        assert!(parse_stream_id("10-0") > parse_stream_id("9-5"));
+        assert!(parse_stream_id("9-10") > parse_stream_id("9-9"));
+    }
 }
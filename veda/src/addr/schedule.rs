@@ -0,0 +1,78 @@
+use std::time::Duration;
+
+use rand::seq::IteratorRandom;
+
+/// 把redis session的读取分散到`interval`内的各个时间片,避免所有session同时读取redis
+pub struct ReadScheduler {
+    interval: Duration,
+    // load.index: 时间片
+    // load.value: 在该时间片读取的session数量
+    load: Vec<usize>,
+    current: usize,
+}
+
+impl ReadScheduler {
+    pub fn new(interval: Duration, slots: usize) -> Self {
+        Self {
+            interval,
+            load: vec![0; slots.max(1)],
+            current: 0,
+        }
+    }
+
+    /// 两个时间片之间的间隔
+    pub fn tick_interval(&self) -> Duration {
+        self.interval / self.load.len() as u32
+    }
+
+    /// 为新的session分配负载最小的时间片,负载相同时随机选择
+    pub fn register(&mut self) -> usize {
+        let min = self.load.iter().copied().min().unwrap_or_default();
+        let slot = (0..self.load.len())
+            .filter(|slot| self.load[*slot] == min)
+            .choose(&mut rand::thread_rng())
+            .unwrap_or_default();
+        self.load[slot] += 1;
+        slot
+    }
+
+    pub fn unregister(&mut self, slot: usize) {
+        if let Some(load) = self.load.get_mut(slot) {
+            *load = load.saturating_sub(1);
+        }
+    }
+
+    /// 进入下一个时间片,返回该时间片
+    pub fn tick(&mut self) -> usize {
+        self.current = (self.current + 1) % self.load.len();
+        self.current
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_are_spread_across_the_interval() {
+        let mut scheduler = ReadScheduler::new(Duration::from_secs(1), 10);
+        assert_eq!(scheduler.tick_interval(), Duration::from_millis(100));
+        let slots: Vec<usize> = (0..100).map(|_| scheduler.register()).collect();
+
+        // one interval worth of ticks reads every session exactly once, ten at a time
+        let mut reads = vec![0; slots.len()];
+        for _ in 0..10 {
+            let slot = scheduler.tick();
+            let due: Vec<usize> = (0..slots.len()).filter(|i| slots[*i] == slot).collect();
+            assert_eq!(due.len(), 10);
+            for i in due {
+                reads[i] += 1;
+            }
+        }
+        assert!(reads.iter().all(|reads| *reads == 1));
+
+        // a leaving session frees its slot for the next one
+        scheduler.unregister(slots[0]);
+        assert_eq!(scheduler.register(), slots[0]);
+    }
+}
@@ -1,27 +1,86 @@
-use std::net::SocketAddr;
+use std::{net::SocketAddr, pin::Pin, time::Duration};
 
-use actix::{Actor, Context, Recipient};
+use actix::{Actor, Addr, Context};
 use chrono::Utc;
+use futures::{channel::mpsc, stream, Stream, StreamExt};
+use log::{info, warn};
 use tonic::Code;
 
-use super::Trial;
+use super::{Ack, Reclaim, Redis, RedisOffline, Subscribe, Trial};
 use crate::{
-    activity::{self, activity_source_server::ActivitySource},
-    entity::Activity,
+    activity::{
+        self, activity_source_server::ActivitySource, subscribe_request::Request, SubscribeRequest,
+    },
+    constants::{MAX_BLOB_SIZE, QUOTA_EXCEEDED},
+    entity::{Activity, Audience},
+    trace,
 };
 
-impl Into<Activity> for activity::Activity {
-    fn into(self) -> Activity {
+impl From<activity::Activity> for Activity {
+    fn from(activity: activity::Activity) -> Self {
         Activity {
-            activity_type: self.activity_type,
-            activity: self.content,
+            activity_type: activity.activity_type,
+            activity: activity.content,
+            correlation_id: None,
+            compaction_key: Some(activity.compaction_key).filter(|key| !key.is_empty()),
+            idempotency_key: Some(activity.idempotency_key).filter(|key| !key.is_empty()),
+            expires_at: Some(activity.expires_at).filter(|at| *at > 0),
+            deliver_at: Some(activity.deliver_at).filter(|at| *at > 0),
+            reply_to: Some(activity.reply_to).filter(|id| !id.is_empty()),
+            sender: Some(activity.sender).filter(|sender| !sender.is_empty()),
+            blob: Some(activity.blob).filter(|blob| !blob.is_empty()),
+            blob_url: None,
+            blob_key: None,
+            claim_key: None,
+            quantity: None,
+            audience: activity
+                .audience
+                .map(|audience| Audience {
+                    platforms: audience.platforms,
+                    capabilities: audience.capabilities,
+                })
+                .filter(|audience| !audience.is_empty()),
+            extra: Default::default(),
+            id: None,
         }
     }
 }
+
+impl From<Activity> for activity::Delivery {
+    fn from(activity: Activity) -> Self {
+        activity::Delivery {
+            id: activity.id.unwrap_or_default(),
+            activity: Some(activity::Activity {
+                activity_type: activity.activity_type,
+                content: activity.activity,
+                compaction_key: activity.compaction_key.unwrap_or_default(),
+                idempotency_key: activity.idempotency_key.unwrap_or_default(),
+                expires_at: activity.expires_at.unwrap_or_default(),
+                deliver_at: activity.deliver_at.unwrap_or_default(),
+                reply_to: activity.reply_to.unwrap_or_default(),
+                blob: activity.blob.unwrap_or_default(),
+                blob_url: activity.blob_url.unwrap_or_default(),
+                // sessions only see what they are in the audience of
+                audience: None,
+                sender: activity.sender.unwrap_or_default(),
+            }),
+        }
+    }
+}
+
+/// 一批消息的json转为逐条投递
+#[allow(clippy::result_large_err)]
+fn deliveries(frame: String) -> Vec<Result<activity::Delivery, tonic::Status>> {
+    match serde_json::from_str::<Vec<Activity>>(&frame) {
+        Ok(items) => items.into_iter().map(|item| Ok(item.into())).collect(),
+        Err(e) => vec![Err(tonic::Status::internal(e.to_string()))],
+    }
+}
 #[derive(Clone)]
 pub struct Seravee {
+    #[allow(dead_code)]
     pub addr: SocketAddr,
-    pub redis_addr: Recipient<Trial>,
+    pub redis_addr: Addr<Redis>,
 }
 
 impl Actor for Seravee {
@@ -36,21 +95,40 @@ impl ActivitySource for Seravee {
     ) -> Result<tonic::Response<activity::States>, tonic::Status> {
         let msg = request.into_inner();
         let content = msg.message.unwrap();
+        if content.blob.len() > MAX_BLOB_SIZE {
+            return Err(tonic::Status::invalid_argument(format!(
+                "blob is larger than {} bytes",
+                MAX_BLOB_SIZE
+            )));
+        }
+        let mut message: Activity = content.into();
+        let correlation_id = trace::new_correlation_id();
+        trace::hop(
+            &correlation_id,
+            trace::PUSH_RECEIVED,
+            format_args!("receivers:{:?}", msg.receivers),
+        );
+        message.correlation_id = Some(correlation_id.clone());
         let trail = Trial {
-            message: content.into(),
+            message,
             receivers: msg.receivers,
+            quantity: Some(msg.quantity).filter(|quantity| *quantity > 0),
         };
 
         let ids = &self.redis_addr.send(trail).await;
         match ids {
+            Ok(ids) if !ids.is_empty() && ids.iter().all(|(_, id)| id == QUOTA_EXCEEDED) => {
+                Err(tonic::Status::resource_exhausted("push quota exceeded"))
+            }
             Ok(ids) => {
                 let msgs: Vec<activity::Status> = ids
-                    .into_iter()
+                    .iter()
                     .map(|str| activity::Status {
                         message: str.to_owned().1,
                         receiver: str.to_owned().0,
                         action: 0,
                         expire_at: Utc::now().timestamp(),
+                        push_id: correlation_id.clone(),
                     })
                     .collect();
 
@@ -63,12 +141,221 @@ impl ActivitySource for Seravee {
         }
     }
 
+    type SubscribeStream =
+        Pin<Box<dyn Stream<Item = Result<activity::Delivery, tonic::Status>> + Send + Sync>>;
+
+    async fn subscribe(
+        &self,
+        request: tonic::Request<tonic::Streaming<SubscribeRequest>>,
+    ) -> Result<tonic::Response<Self::SubscribeStream>, tonic::Status> {
+        let mut requests = request.into_inner();
+        let name = match requests.message().await? {
+            Some(SubscribeRequest {
+                request: Some(Request::Username(name)),
+            }) => name,
+            _ => {
+                return Err(tonic::Status::invalid_argument(
+                    "the first message must be the username",
+                ))
+            }
+        };
+
+        let (frames, received) = mpsc::unbounded();
+        let session = self
+            .redis_addr
+            .send(Subscribe {
+                name: name.clone(),
+                frames,
+            })
+            .await
+            .map_err(|e| tonic::Status::unavailable(e.to_string()))?;
+
+        // acks arrive on the request stream until the client hangs up
+        tokio::spawn(async move {
+            loop {
+                match requests.message().await {
+                    Ok(Some(SubscribeRequest {
+                        request: Some(Request::Ack(id)),
+                    })) => session.do_send(Ack { ids: vec![id] }),
+                    Ok(Some(SubscribeRequest {
+                        request: Some(Request::Redeliver(true)),
+                    })) => session.do_send(Reclaim {
+                        idle: Duration::ZERO,
+                    }),
+                    Ok(Some(_)) => warn!(
+                        "`{}` sent something other than an ack or a redelivery",
+                        &name
+                    ),
+                    Ok(None) | Err(_) => break,
+                }
+            }
+            info!("`{}` unsubscribed", &name);
+            session.do_send(RedisOffline);
+        });
+
+        let stream = received.flat_map(|frame| stream::iter(deliveries(frame)));
+        Ok(tonic::Response::new(Box::pin(stream)))
+    }
+
     async fn act_flow(
         &self,
         _request: tonic::Request<activity::Status>,
     ) -> Result<tonic::Response<activity::Status>, tonic::Status> {
         todo!()
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use tonic::transport::{Channel, Server};
+
+    use super::*;
+    use crate::{
+        activity::{
+            activity_source_client::ActivitySourceClient,
+            activity_source_server::ActivitySourceServer,
+        },
+        addr::{store::StreamStore, MemoryStore},
+        constants::MESSAGE_INTERVAL,
+    };
 
-  
+    async fn connect(addr: SocketAddr) -> ActivitySourceClient<Channel> {
+        for _ in 0..50 {
+            if let Ok(client) = ActivitySourceClient::connect(format!("http://{}", addr)).await {
+                return client;
+            }
+            actix_rt::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("grpc server at {} is not up", addr);
+    }
+
+    /// a grpc server on a free port in front of `store`
+    async fn serve(store: &MemoryStore) -> ActivitySourceClient<Channel> {
+        let redis_addr = Redis::new(store.clone()).start();
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let seravee = Seravee { addr, redis_addr };
+        actix_web::rt::spawn(
+            Server::builder()
+                .add_service(ActivitySourceServer::new(seravee))
+                .serve(addr),
+        );
+        connect(addr).await
+    }
+
+    #[actix_rt::test]
+    async fn subscribe_and_ack_over_grpc() {
+        let mut store = MemoryStore::default();
+        let mut client = serve(&store).await;
+
+        let pushed = store
+            .xadd(
+                "veda-activity:ian",
+                &Activity {
+                    activity_type: "maintenance".to_string(),
+                    activity: "gn-drive".to_string(),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        let (requests, outbound) = mpsc::unbounded();
+        requests
+            .unbounded_send(SubscribeRequest {
+                request: Some(Request::Username("ian".to_string())),
+            })
+            .unwrap();
+        let mut deliveries = client.subscribe(outbound).await.unwrap().into_inner();
+
+        let delivery = deliveries.message().await.unwrap().unwrap();
+        assert_eq!(delivery.id, pushed);
+        assert_eq!(delivery.activity.unwrap().content, "gn-drive");
+        // delivered but not acknowledged yet
+        assert_eq!(store.stream_ids("veda-activity:ian"), vec![pushed.clone()]);
+
+        requests
+            .unbounded_send(SubscribeRequest {
+                request: Some(Request::Ack(pushed)),
+            })
+            .unwrap();
+        actix_rt::time::sleep(MESSAGE_INTERVAL).await;
+        assert!(store.stream_ids("veda-activity:ian").is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn a_limited_push_over_grpc() {
+        let mut store = MemoryStore::default();
+        let mut client = serve(&store).await;
+        let push = |content: &str, quantity| activity::Message {
+            receivers: vec!["lockon".to_string(), "tieria".to_string()],
+            message: Some(activity::Activity {
+                activity_type: "event".to_string(),
+                content: content.to_string(),
+                ..Default::default()
+            }),
+            quantity,
+        };
+        client.active(push("first one wins", 1)).await.unwrap();
+        client.active(push("for everyone", 0)).await.unwrap();
+
+        for receiver in ["lockon", "tieria"] {
+            let entries = store
+                .xrange(&format!("veda-activity:{}", receiver), "-", "+", 10)
+                .unwrap();
+            let quantities: Vec<Option<u32>> =
+                entries.iter().map(|entry| entry.get("quantity")).collect();
+            assert_eq!(quantities, vec![Some(1), None]);
+            assert!(entries[0].get::<String>("claim_key").is_some());
+        }
+    }
+
+    #[actix_rt::test]
+    async fn unacked_deliveries_come_back_on_request() {
+        let mut store = MemoryStore::default();
+        let mut client = serve(&store).await;
+        let pushed: Vec<String> = ["gn-tau", "gn-drive"]
+            .iter()
+            .map(|content| {
+                store
+                    .xadd(
+                        "veda-activity:graham",
+                        &Activity {
+                            activity_type: "event".to_string(),
+                            activity: content.to_string(),
+                            ..Default::default()
+                        },
+                    )
+                    .unwrap()
+            })
+            .collect();
+
+        let (requests, outbound) = mpsc::unbounded();
+        requests
+            .unbounded_send(SubscribeRequest {
+                request: Some(Request::Username("graham".to_string())),
+            })
+            .unwrap();
+        let mut deliveries = client.subscribe(outbound).await.unwrap().into_inner();
+        let mut ids = vec![];
+        for _ in 0..2 {
+            ids.push(deliveries.message().await.unwrap().unwrap().id);
+        }
+        assert_eq!(ids, pushed);
+
+        // the client was suspended and lost them before acking
+        requests
+            .unbounded_send(SubscribeRequest {
+                request: Some(Request::Redeliver(true)),
+            })
+            .unwrap();
+        let mut again = vec![];
+        for _ in 0..2 {
+            again.push(deliveries.message().await.unwrap().unwrap().id);
+        }
+        assert_eq!(again, pushed);
+    }
 }
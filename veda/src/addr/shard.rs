@@ -0,0 +1,471 @@
+use std::collections::{HashMap, VecDeque};
+
+use actix::prelude::*;
+use actix_web_actors::ws::CloseCode;
+use chrono::{DateTime, Utc};
+use log::info;
+use rand::Rng;
+use serde::Serialize;
+
+use super::{DrainConnections, Kick, SessionId, WsMessage};
+use crate::{
+    config::OverflowPolicy, constants::DRAIN_CLOSE_DELAY, entity::Origin, metrics::DELIVERIES,
+};
+
+/// 接入时记录的session信息
+#[derive(Clone, Debug)]
+pub struct Connection {
+    pub origin: Origin,
+    pub connected_since: DateTime<Utc>,
+    pub platform: Option<String>,
+}
+
+/// the frame that tells a drained client to reconnect elsewhere
+#[derive(Serialize)]
+struct ReconnectFrame {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    after_ms: u64,
+}
+
+/// 发给一个session的消息队列,同一时间只有一条消息在发送
+struct Outbox {
+    addr: Recipient<WsMessage>,
+    kick: Recipient<Kick>,
+    queue: VecDeque<String>,
+    sending: bool,
+}
+
+/// 一部分websocket session的连接,`Websocket`按id把session分到各个shard,
+/// 各shard在自己的线程里注册session和发送消息
+pub struct SessionShard {
+    // sessions.key: websocket session的id
+    // sessions.value: websocket 接受参数地址以及等待发送的消息
+    sessions: HashMap<SessionId, Outbox>,
+    // connections.key: websocket session的id
+    // connections.value: 客户端的来源、接入时间以及平台
+    connections: HashMap<SessionId, Connection>,
+    /// 每个session最多等待发送的消息数量
+    depth: usize,
+    /// 队列满时如何处理新的消息
+    overflow: OverflowPolicy,
+}
+
+impl SessionShard {
+    /// 队列长度至少为1
+    pub fn new(depth: usize, overflow: OverflowPolicy) -> Self {
+        Self {
+            sessions: HashMap::new(),
+            connections: HashMap::new(),
+            depth: depth.max(1),
+            overflow,
+        }
+    }
+
+    /// 把消息放入session的队列,队列满时按`overflow`处理
+    fn enqueue(&mut self, id: SessionId, message: String, ctx: &mut Context<Self>) {
+        let outbox = match self.sessions.get_mut(&id) {
+            Some(outbox) => outbox,
+            None => return,
+        };
+        if outbox.queue.len() >= self.depth {
+            DELIVERIES.inc("overflowed");
+            match self.overflow {
+                OverflowPolicy::DropOldest => {
+                    outbox.queue.pop_front();
+                }
+                OverflowPolicy::DropNewest => return,
+                OverflowPolicy::DisconnectClient => {
+                    info!("session:{} cannot keep up, disconnected", id);
+                    let _ = outbox.kick.do_send(Kick {
+                        code: CloseCode::Policy,
+                        reason: "too many pending messages".to_string(),
+                    });
+                    // it unregisters once it stops
+                    outbox.queue.clear();
+                    return;
+                }
+            }
+        }
+        outbox.queue.push_back(message);
+        self.flush(id, ctx);
+    }
+
+    /// 上一条消息送达后发送队列中的下一条
+    fn flush(&mut self, id: SessionId, ctx: &mut Context<Self>) {
+        let outbox = match self.sessions.get_mut(&id) {
+            Some(outbox) if !outbox.sending => outbox,
+            _ => return,
+        };
+        let message = match outbox.queue.pop_front() {
+            Some(message) => message,
+            None => return,
+        };
+        outbox.sending = true;
+        outbox
+            .addr
+            .send(WsMessage(message))
+            .into_actor(self)
+            .then(move |res, act, ctx| {
+                if let Some(outbox) = act.sessions.get_mut(&id) {
+                    outbox.sending = false;
+                    match res {
+                        Ok(_) => act.flush(id, ctx),
+                        // the session is gone
+                        Err(_) => outbox.queue.clear(),
+                    }
+                }
+                fut::ready(())
+            })
+            .spawn(ctx);
+    }
+}
+
+impl Actor for SessionShard {
+    type Context = Context<Self>;
+}
+
+/// 把session注册到shard
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Register {
+    pub id: SessionId,
+    pub addr: Recipient<WsMessage>,
+    pub kick: Recipient<Kick>,
+    pub origin: Origin,
+}
+
+/// 从shard移除session
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Unregister {
+    pub id: SessionId,
+}
+
+/// 记录session所在的平台
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SetPlatform {
+    pub id: SessionId,
+    pub platform: String,
+}
+
+/// 发送消息给shard中的一部分session,`ids`为`None`时发给所有session
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Deliver {
+    pub ids: Option<Vec<SessionId>>,
+    pub message: String,
+}
+
+/// 断开shard中的一个session
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Evict {
+    pub id: SessionId,
+    pub reason: String,
+}
+
+/// 查询session的连接信息
+#[derive(Message)]
+#[rtype(result = "Option<Connection>")]
+pub struct ConnectionOf {
+    pub id: SessionId,
+}
+
+/// session等待发送的帧数量,包括正在发送的一帧
+#[derive(Message)]
+#[rtype(result = "usize")]
+pub struct QueueDepth {
+    pub id: SessionId,
+}
+
+/// 立即发出session队列中等待的帧,`discard`时丢弃,返回发出或丢弃的帧数量
+#[derive(Message)]
+#[rtype(result = "usize")]
+pub struct FlushQueue {
+    pub id: SessionId,
+    pub discard: bool,
+}
+
+/// 列出shard中所有session的连接信息
+#[derive(Message)]
+#[rtype(result = "Vec<(SessionId, Connection)>")]
+pub struct ListConnections;
+
+impl Handler<Register> for SessionShard {
+    type Result = ();
+
+    fn handle(&mut self, msg: Register, _: &mut Self::Context) -> Self::Result {
+        self.sessions.insert(
+            msg.id,
+            Outbox {
+                addr: msg.addr,
+                kick: msg.kick,
+                queue: VecDeque::new(),
+                sending: false,
+            },
+        );
+        self.connections.insert(
+            msg.id,
+            Connection {
+                origin: msg.origin,
+                connected_since: Utc::now(),
+                platform: None,
+            },
+        );
+    }
+}
+
+impl Handler<Unregister> for SessionShard {
+    type Result = ();
+
+    fn handle(&mut self, msg: Unregister, _: &mut Self::Context) -> Self::Result {
+        self.sessions.remove(&msg.id);
+        if let Some(connection) = self.connections.remove(&msg.id) {
+            info!(
+                "session:{} from {:?} unregistered",
+                msg.id, connection.origin
+            );
+        }
+    }
+}
+
+impl Handler<SetPlatform> for SessionShard {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetPlatform, _: &mut Self::Context) -> Self::Result {
+        if let Some(connection) = self.connections.get_mut(&msg.id) {
+            connection.platform = Some(msg.platform);
+        }
+    }
+}
+
+impl Handler<Deliver> for SessionShard {
+    type Result = ();
+
+    fn handle(&mut self, msg: Deliver, ctx: &mut Self::Context) -> Self::Result {
+        let ids = match msg.ids {
+            Some(ids) => ids,
+            None => self.sessions.keys().copied().collect(),
+        };
+        for id in ids {
+            self.enqueue(id, msg.message.clone(), ctx);
+        }
+    }
+}
+
+impl Handler<DrainConnections> for SessionShard {
+    type Result = ();
+
+    fn handle(&mut self, msg: DrainConnections, ctx: &mut Self::Context) -> Self::Result {
+        let ids: Vec<SessionId> = self.sessions.keys().copied().collect();
+        let mut rng = rand::thread_rng();
+        for id in &ids {
+            // not everyone reconnects at the same moment
+            let frame = ReconnectFrame {
+                kind: "reconnect",
+                after_ms: rng.gen_range(0..=msg.reconnect_after_ms),
+            };
+            if let Ok(frame) = serde_json::to_string(&frame) {
+                self.enqueue(*id, frame, ctx);
+            }
+        }
+        ctx.run_later(DRAIN_CLOSE_DELAY, move |act, _| {
+            for id in ids {
+                if let Some(outbox) = act.sessions.get(&id) {
+                    let _ = outbox.kick.do_send(Kick {
+                        code: CloseCode::Restart,
+                        reason: "draining, reconnect elsewhere".to_string(),
+                    });
+                }
+            }
+        });
+    }
+}
+
+impl Handler<Evict> for SessionShard {
+    type Result = ();
+
+    fn handle(&mut self, msg: Evict, _: &mut Self::Context) -> Self::Result {
+        if let Some(outbox) = self.sessions.get(&msg.id) {
+            info!("session:{} evicted: {}", msg.id, msg.reason);
+            // it unregisters once it stops
+            let _ = outbox.kick.do_send(Kick {
+                code: CloseCode::Policy,
+                reason: msg.reason,
+            });
+        }
+    }
+}
+
+impl Handler<ConnectionOf> for SessionShard {
+    type Result = Option<Connection>;
+
+    fn handle(&mut self, msg: ConnectionOf, _: &mut Self::Context) -> Self::Result {
+        self.connections.get(&msg.id).cloned()
+    }
+}
+
+impl Handler<QueueDepth> for SessionShard {
+    type Result = usize;
+
+    fn handle(&mut self, msg: QueueDepth, _: &mut Self::Context) -> Self::Result {
+        self.sessions
+            .get(&msg.id)
+            .map_or(0, |outbox| outbox.queue.len() + usize::from(outbox.sending))
+    }
+}
+
+impl Handler<FlushQueue> for SessionShard {
+    type Result = usize;
+
+    fn handle(&mut self, msg: FlushQueue, _: &mut Self::Context) -> Self::Result {
+        let outbox = match self.sessions.get_mut(&msg.id) {
+            Some(outbox) => outbox,
+            None => return 0,
+        };
+        let frames: Vec<String> = outbox.queue.drain(..).collect();
+        if msg.discard {
+            DELIVERIES.inc_by("discarded", frames.len() as u64);
+            return frames.len();
+        }
+        // behind the frame in flight, without waiting for each other
+        for frame in &frames {
+            let _ = outbox.addr.do_send(WsMessage(frame.clone()));
+        }
+        frames.len()
+    }
+}
+
+impl Handler<ListConnections> for SessionShard {
+    type Result = MessageResult<ListConnections>;
+
+    fn handle(&mut self, _: ListConnections, _: &mut Self::Context) -> Self::Result {
+        MessageResult(
+            self.connections
+                .iter()
+                .map(|(id, connection)| (*id, connection.clone()))
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use super::*;
+
+    /// a session taking 50ms for every message
+    #[derive(Clone, Default)]
+    struct Slow(Arc<Mutex<Vec<String>>>);
+
+    impl Actor for Slow {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<WsMessage> for Slow {
+        type Result = ResponseFuture<()>;
+
+        fn handle(&mut self, msg: WsMessage, _: &mut Self::Context) -> Self::Result {
+            let received = self.0.clone();
+            Box::pin(async move {
+                actix::clock::sleep(Duration::from_millis(50)).await;
+                received.lock().unwrap().push(msg.0);
+            })
+        }
+    }
+
+    impl Handler<Kick> for Slow {
+        type Result = ();
+
+        fn handle(&mut self, _: Kick, _: &mut Self::Context) {
+            self.0.lock().unwrap().push("kicked".to_string());
+        }
+    }
+
+    /// flood a slow session with five messages through a queue of two
+    async fn flood(overflow: OverflowPolicy) -> Vec<String> {
+        let shard = SessionShard::new(2, overflow).start();
+        let slow = Slow::default();
+        let addr = slow.clone().start();
+        shard
+            .send(Register {
+                id: 1,
+                addr: addr.clone().recipient(),
+                kick: addr.recipient(),
+                origin: Origin::default(),
+            })
+            .await
+            .unwrap();
+        for i in 1..=5 {
+            shard
+                .send(Deliver {
+                    ids: Some(vec![1]),
+                    message: i.to_string(),
+                })
+                .await
+                .unwrap();
+        }
+        actix::clock::sleep(Duration::from_millis(400)).await;
+        let received = slow.0.lock().unwrap().clone();
+        received
+    }
+
+    #[actix_rt::test]
+    async fn flush_sends_the_waiting_frames_at_once() {
+        for discard in [false, true] {
+            let shard = SessionShard::new(10, OverflowPolicy::default()).start();
+            let slow = Slow::default();
+            let addr = slow.clone().start();
+            shard
+                .send(Register {
+                    id: 1,
+                    addr: addr.clone().recipient(),
+                    kick: addr.recipient(),
+                    origin: Origin::default(),
+                })
+                .await
+                .unwrap();
+            for i in 1..=5 {
+                shard
+                    .send(Deliver {
+                        ids: Some(vec![1]),
+                        message: i.to_string(),
+                    })
+                    .await
+                    .unwrap();
+            }
+            // the first frame is in flight
+            let flushed = shard.send(FlushQueue { id: 1, discard }).await.unwrap();
+            assert_eq!(flushed, 4);
+            assert_eq!(shard.send(QueueDepth { id: 1 }).await.unwrap(), 1);
+
+            actix::clock::sleep(Duration::from_millis(400)).await;
+            let mut received = slow.0.lock().unwrap().clone();
+            // `Slow` sleeps on every frame side by side
+            received.sort();
+            if discard {
+                assert_eq!(received, vec!["1"]);
+            } else {
+                assert_eq!(received, vec!["1", "2", "3", "4", "5"]);
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn a_full_queue_applies_the_overflow_policy() {
+        let overflowed = DELIVERIES.get("overflowed");
+        // the first message is on its way while the others queue up
+        assert_eq!(flood(OverflowPolicy::DropOldest).await, vec!["1", "4", "5"]);
+        assert_eq!(flood(OverflowPolicy::DropNewest).await, vec!["1", "2", "3"]);
+        let disconnected = flood(OverflowPolicy::DisconnectClient).await;
+        assert_eq!(disconnected[0], "kicked");
+        assert!(disconnected.len() <= 3, "{:?}", disconnected);
+        assert!(DELIVERIES.get("overflowed") >= overflowed + 5);
+    }
+}
@@ -0,0 +1,237 @@
+use std::{collections::HashMap, time::Duration};
+
+use redis::{
+    streams::{
+        StreamId, StreamInfoStreamReply, StreamRangeReply, StreamReadOptions, StreamReadReply,
+    },
+    Client, Commands, Connection, ErrorKind, RedisError, RedisResult,
+};
+
+use crate::entity::Activity;
+
+/// length and last id of a stream
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamInfo {
+    pub length: usize,
+    pub last_generated_id: String,
+}
+
+/// the redis commands `Redis` and `RedisSession` rely on
+pub trait StreamStore: Send {
+    /// append an activity to a stream, returns the entry id
+    fn xadd(&mut self, key: &str, activity: &Activity) -> RedisResult<String>;
+    /// append every activity to its stream in one round-trip, returns the entry ids in order
+    fn xadd_batch(&mut self, entries: &[(String, &Activity)]) -> RedisResult<Vec<String>>;
+    /// `None` when the stream has never been written
+    fn xinfo(&mut self, key: &str) -> RedisResult<Option<StreamInfo>>;
+    /// read entries after `ids` from each stream, at most `count` per stream
+    fn xread(
+        &mut self,
+        keys: &[String],
+        ids: &[String],
+        count: usize,
+        block: Option<usize>,
+    ) -> RedisResult<StreamReadReply>;
+    fn xdel(&mut self, key: &str, ids: &[String]) -> RedisResult<usize>;
+    fn xlen(&mut self, key: &str) -> RedisResult<usize>;
+    /// atomically delete every entry with an id lower than `min_id`
+    fn xtrim_minid(&mut self, key: &str, min_id: &str) -> RedisResult<usize>;
+    /// at most `count` entries from `start` to `end`, oldest first
+    fn xrange(
+        &mut self,
+        key: &str,
+        start: &str,
+        end: &str,
+        count: usize,
+    ) -> RedisResult<Vec<StreamId>>;
+    /// at most `count` entries from `end` back to `start`, newest first
+    fn xrevrange(
+        &mut self,
+        key: &str,
+        end: &str,
+        start: &str,
+        count: usize,
+    ) -> RedisResult<Vec<StreamId>>;
+
+    fn get(&mut self, key: &str) -> RedisResult<Option<String>>;
+    fn set(&mut self, key: &str, value: &str) -> RedisResult<()>;
+    fn del(&mut self, key: &str) -> RedisResult<()>;
+    /// add one to the counter `key`, returns the new count
+    fn incr(&mut self, key: &str) -> RedisResult<i64>;
+    /// drop `key` once `ttl` has passed
+    fn expire(&mut self, key: &str, ttl: Duration) -> RedisResult<()>;
+
+    fn hset(&mut self, key: &str, field: &str, value: &str) -> RedisResult<()>;
+    fn hget(&mut self, key: &str, field: &str) -> RedisResult<Option<String>>;
+    fn hdel(&mut self, key: &str, field: &str) -> RedisResult<()>;
+    fn hgetall(&mut self, key: &str) -> RedisResult<HashMap<String, String>>;
+
+    fn sadd(&mut self, key: &str, member: &str) -> RedisResult<()>;
+    fn srem(&mut self, key: &str, member: &str) -> RedisResult<()>;
+    fn smembers(&mut self, key: &str) -> RedisResult<Vec<String>>;
+    fn sismember(&mut self, key: &str, member: &str) -> RedisResult<bool>;
+
+    fn zadd(&mut self, key: &str, member: &str, score: i64) -> RedisResult<()>;
+    /// whether `member` was there to remove
+    fn zrem(&mut self, key: &str, member: &str) -> RedisResult<bool>;
+    /// members scored at most `max`
+    fn zrangebyscore(&mut self, key: &str, max: i64) -> RedisResult<Vec<String>>;
+}
+
+/// hands out a `StreamStore` to every actor that needs one
+pub trait Connector: Send {
+    fn connect(&self) -> RedisResult<Box<dyn StreamStore>>;
+}
+
+impl Connector for Client {
+    fn connect(&self) -> RedisResult<Box<dyn StreamStore>> {
+        Ok(Box::new(self.get_connection()?))
+    }
+}
+
+/// xinfo on a stream that has never been written is not a failure
+fn is_missing_stream(err: &RedisError) -> bool {
+    err.kind() == ErrorKind::ResponseError && err.to_string().contains("no such key")
+}
+
+impl StreamStore for Connection {
+    fn xadd(&mut self, key: &str, activity: &Activity) -> RedisResult<String> {
+        self.xadd_map(key, "*", activity)
+    }
+
+    fn xadd_batch(&mut self, entries: &[(String, &Activity)]) -> RedisResult<Vec<String>> {
+        let mut pipe = redis::pipe();
+        for (key, activity) in entries {
+            pipe.xadd_map(key, "*", *activity);
+        }
+        pipe.query(self)
+    }
+
+    fn xinfo(&mut self, key: &str) -> RedisResult<Option<StreamInfo>> {
+        let inf: RedisResult<StreamInfoStreamReply> = self.xinfo_stream(key);
+        match inf {
+            Ok(inf) => Ok(Some(StreamInfo {
+                length: inf.length,
+                last_generated_id: inf.last_generated_id,
+            })),
+            Err(e) if is_missing_stream(&e) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn xread(
+        &mut self,
+        keys: &[String],
+        ids: &[String],
+        count: usize,
+        block: Option<usize>,
+    ) -> RedisResult<StreamReadReply> {
+        let mut opts = StreamReadOptions::default().count(count);
+        if let Some(block) = block {
+            opts = opts.block(block);
+        }
+        self.xread_options(keys, ids, &opts)
+    }
+
+    fn xdel(&mut self, key: &str, ids: &[String]) -> RedisResult<usize> {
+        Commands::xdel(self, key, ids)
+    }
+
+    fn xlen(&mut self, key: &str) -> RedisResult<usize> {
+        Commands::xlen(self, key)
+    }
+
+    fn xtrim_minid(&mut self, key: &str, min_id: &str) -> RedisResult<usize> {
+        redis::cmd("XTRIM")
+            .arg(key)
+            .arg("MINID")
+            .arg(min_id)
+            .query(self)
+    }
+
+    fn xrange(
+        &mut self,
+        key: &str,
+        start: &str,
+        end: &str,
+        count: usize,
+    ) -> RedisResult<Vec<StreamId>> {
+        let reply: StreamRangeReply = self.xrange_count(key, start, end, count)?;
+        Ok(reply.ids)
+    }
+
+    fn xrevrange(
+        &mut self,
+        key: &str,
+        end: &str,
+        start: &str,
+        count: usize,
+    ) -> RedisResult<Vec<StreamId>> {
+        let reply: StreamRangeReply = self.xrevrange_count(key, end, start, count)?;
+        Ok(reply.ids)
+    }
+
+    fn get(&mut self, key: &str) -> RedisResult<Option<String>> {
+        Commands::get(self, key)
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> RedisResult<()> {
+        Commands::set(self, key, value)
+    }
+
+    fn del(&mut self, key: &str) -> RedisResult<()> {
+        Commands::del(self, key)
+    }
+
+    fn incr(&mut self, key: &str) -> RedisResult<i64> {
+        Commands::incr(self, key, 1)
+    }
+
+    fn expire(&mut self, key: &str, ttl: Duration) -> RedisResult<()> {
+        Commands::expire(self, key, ttl.as_secs() as usize)
+    }
+
+    fn hset(&mut self, key: &str, field: &str, value: &str) -> RedisResult<()> {
+        Commands::hset(self, key, field, value)
+    }
+
+    fn hget(&mut self, key: &str, field: &str) -> RedisResult<Option<String>> {
+        Commands::hget(self, key, field)
+    }
+
+    fn hdel(&mut self, key: &str, field: &str) -> RedisResult<()> {
+        Commands::hdel(self, key, field)
+    }
+
+    fn hgetall(&mut self, key: &str) -> RedisResult<HashMap<String, String>> {
+        Commands::hgetall(self, key)
+    }
+
+    fn sadd(&mut self, key: &str, member: &str) -> RedisResult<()> {
+        Commands::sadd(self, key, member)
+    }
+
+    fn srem(&mut self, key: &str, member: &str) -> RedisResult<()> {
+        Commands::srem(self, key, member)
+    }
+
+    fn smembers(&mut self, key: &str) -> RedisResult<Vec<String>> {
+        Commands::smembers(self, key)
+    }
+
+    fn sismember(&mut self, key: &str, member: &str) -> RedisResult<bool> {
+        Commands::sismember(self, key, member)
+    }
+
+    fn zadd(&mut self, key: &str, member: &str, score: i64) -> RedisResult<()> {
+        Commands::zadd(self, key, member, score)
+    }
+
+    fn zrem(&mut self, key: &str, member: &str) -> RedisResult<bool> {
+        Commands::zrem(self, key, member)
+    }
+
+    fn zrangebyscore(&mut self, key: &str, max: i64) -> RedisResult<Vec<String>> {
+        Commands::zrangebyscore(self, key, "-inf", max)
+    }
+}
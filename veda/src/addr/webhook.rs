@@ -0,0 +1,92 @@
+use actix::prelude::*;
+use log::warn;
+
+use crate::{entity::Activity, metrics::DELIVERIES, retry::RetryPolicy};
+
+/// 把消息POST到用户配置的webhook,失败时按`retry`重试,最终失败的消息交给`dead_letter`
+pub struct WebhookSink {
+    client: awc::Client,
+    retry: RetryPolicy,
+    dead_letter: Recipient<DeadLetter>,
+}
+
+impl WebhookSink {
+    pub fn new(retry: RetryPolicy, dead_letter: Recipient<DeadLetter>) -> Self {
+        Self {
+            client: awc::Client::default(),
+            retry,
+            dead_letter,
+        }
+    }
+}
+
+impl Actor for WebhookSink {
+    type Context = Context<Self>;
+}
+
+/// POST一条消息到`url`
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct PostActivity {
+    pub username: String,
+    pub url: String,
+    pub activity: Activity,
+}
+
+/// 无法投递的消息,写入用户的死信队列
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct DeadLetter {
+    pub username: String,
+    pub activity: Activity,
+}
+
+impl Handler<PostActivity> for WebhookSink {
+    type Result = ();
+
+    fn handle(&mut self, msg: PostActivity, ctx: &mut Self::Context) -> Self::Result {
+        let client = self.client.clone();
+        let retry = self.retry.clone();
+        let PostActivity {
+            username,
+            url,
+            activity,
+        } = msg;
+        let body = activity.clone();
+        let receiver = username.clone();
+        async move {
+            let mut attempt = 1;
+            loop {
+                let mut req = client
+                    .post(&url)
+                    .insert_header(("X-Veda-Receiver", receiver.as_str()));
+                if let Some(timeout) = retry.timeout {
+                    req = req.timeout(timeout);
+                }
+                let err = match req.send_json(&body).await {
+                    Ok(res) if res.status().is_success() => return Ok(()),
+                    Ok(res) => format!("status {}", res.status()),
+                    Err(e) => e.to_string(),
+                };
+                if !retry.retries_after(attempt) {
+                    return Err(err);
+                }
+                actix::clock::sleep(retry.backoff(attempt)).await;
+                attempt += 1;
+            }
+        }
+        .into_actor(self)
+        .map(move |res, act, _| match res {
+            Ok(()) => DELIVERIES.inc("webhook_posted"),
+            Err(e) => {
+                warn!(
+                    "cannot post an activity of `{}` to its webhook: {}",
+                    username, e
+                );
+                DELIVERIES.inc("webhook_failed");
+                let _ = act.dead_letter.do_send(DeadLetter { username, activity });
+            }
+        })
+        .spawn(ctx);
+    }
+}
@@ -1,25 +1,75 @@
 use actix::prelude::*;
 use actix_web_actors::ws;
-use log::{debug, info};
-use rand::{prelude::ThreadRng, Rng};
+use chrono::{DateTime, Utc};
+use log::{debug, info, warn};
+use serde::Serialize;
+use serde_json::Value;
 
-use std::{collections::HashMap, time::Instant};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    future::Future,
+    hash::{Hash, Hasher},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use crate::{
     addr::PlatformOnline,
-    constants::{CLIENT_TIMEOUT, HEARTBEAT_INTERVAL},
+    compress::gzip,
+    config::{FrameLogMode, LiveConfig, OverflowPolicy, SessionLimitPolicy},
+    constants::{
+        COMPRESS_THRESHOLD, GZIP_CAPABILITY, MAX_NAME_LEN, OUTBOUND_QUEUE_DEPTH, PROTOCOL_VERSION,
+        READ_COUNT, SELFTEST_TYPE,
+    },
+    entity::{Activity, DeliveryMode, FramingMode, Origin, ProtocolVersion, Room, ACTIVITY_FIELDS},
+    heartbeat::AdaptiveHeartbeat,
+    throttle::Throttle,
+    trace,
 };
 
-use super::{Offline, Online, Redis, Seravee};
+use super::{
+    authz::{CommandAuthorizer, Role},
+    command::{deprecated, Command, ALIASES, COMMANDS},
+    id::{IdGenerator, Random, SessionId},
+    request::{Ask, PendingRequests, RequestError},
+    shard::{
+        ConnectionOf, Deliver, Evict, FlushQueue, ListConnections, QueueDepth, Register,
+        SessionShard, SetPlatform, Unregister,
+    },
+    Badges, BatchSize, Capabilities, Clear, ClientReport, Cursor, Echo, Fields, Follow,
+    GetActivity, Heartbeat, Join, Leave, ListHistory, ListMissed, ListSince, Mute, Offline, Online,
+    Post, Presence, Priority, Redis, ReloadConfig, Seravee, SetPublic, SetStatus, StatusOf, Track,
+    Trial,
+};
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct WsMessage(pub String);
 
+/// 强制断开websocket session,`code`和`reason`随close帧发给客户端
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct Kick {
+    pub code: ws::CloseCode,
+    pub reason: String,
+}
+
+/// 滚动部署前通知所有session到其他节点重连,随后关闭连接
+#[derive(Message, Debug, Clone, Copy)]
+#[rtype(result = "()")]
+pub struct DrainConnections {
+    /// 客户端最多等待这么久再重连,各session随机错开
+    pub reconnect_after_ms: u64,
+}
+
 /// 接入websocket服务
 #[derive(Message, Debug)]
-#[rtype(usize)]
+#[rtype(SessionId)]
 pub struct Connect {
     pub addr: Recipient<WsMessage>,
+    /// 消息积压过多时断开session
+    pub kick: Recipient<Kick>,
+    /// 客户端的ip和user-agent
+    pub origin: Origin,
 }
 
 /// 断开websocket服务
@@ -28,12 +78,60 @@ pub struct Connect {
 pub struct Disconnect {
     pub id: usize,
 }
-/// 告诉Studio当前session的name
+/// 告诉Studio当前session的name,可以重复发送以更换name
 #[derive(Message, Debug)]
-#[rtype(result = "()")]
+#[rtype(IdentityResult)]
 pub struct IdentitySession {
     pub id: usize,
     pub name: String,
+    /// 连接所属的租户,不同租户的同名用户互不相干
+    pub tenant: Option<String>,
+}
+
+/// 设置session name的结果
+#[derive(MessageResponse, Serialize, Debug, PartialEq)]
+#[serde(tag = "identity", rename_all = "snake_case")]
+pub enum IdentityResult {
+    /// name设置成功,`previous`为被释放的旧name
+    Set {
+        name: String,
+        previous: Option<String>,
+    },
+    /// 与当前name相同
+    Unchanged { name: String },
+    /// name已被其他session占用
+    Taken { name: String },
+    /// name过长或含有不允许的字符,不会被设置
+    Invalid { name: String, reason: String },
+    /// name已被允许的最多session持有
+    TooManySessions { name: String },
+}
+
+/// 检查name或房间名能否安全地作为redis key的一部分,只允许字母、数字以及`-_.@`,
+/// `what`为错误信息中的称呼
+pub(super) fn validate_name(name: &str, what: &str, max_len: usize) -> Result<(), String> {
+    if name.is_empty() {
+        return Err(format!("{} is empty", what));
+    }
+    if name.chars().count() > max_len {
+        return Err(format!("{} is longer than {} characters", what, max_len));
+    }
+    match name
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || "-_.@".contains(*c)))
+    {
+        Some(c) => Err(format!("`{}` is not allowed in a {}", c, what)),
+        None => Ok(()),
+    }
+}
+
+/// `t/{tenant}/{name}`, names and rooms never have a `/`, so a scoped name
+/// cannot run into a plain one or into the keys redis builds from them
+fn scoped(tenant: Option<&str>, name: String) -> String {
+    match tenant {
+        Some(tenant) => format!("t/{}/{}", tenant, name),
+        None => name,
+    }
 }
 
 /// 告诉Studio当前session的name
@@ -44,51 +142,455 @@ pub struct RedisMessage {
     pub msg: String,
 }
 
-/// 显示在线的names
-pub struct ListNames;
+/// 查询session订阅的房间等信息
+#[derive(Message, Debug)]
+#[rtype(Subscriptions)]
+pub struct ListSubscriptions {
+    pub id: usize,
+}
+
+/// session的订阅信息
+#[derive(MessageResponse, Serialize, Debug, Default, PartialEq)]
+pub struct Subscriptions {
+    /// 已加入的房间
+    pub rooms: Vec<String>,
+    /// 关注的用户,暂不支持
+    pub watching: Vec<String>,
+    /// 过滤的事件类型,暂不支持
+    pub event_filter: Vec<String>,
+}
+
+/// `/diag`返回的诊断报告,汇总session的各项状态
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Diagnostics {
+    pub id: usize,
+    pub name: Option<String>,
+    /// 一批消息写成一帧的方式
+    pub framing: FramingMode,
+    /// 每一帧最多包含的消息数量
+    pub batch_size: usize,
+    /// 最近一次ping到pong的耗时,还没有收到pong时为`None`
+    pub rtt_ms: Option<u64>,
+    /// 队列中尚未确认的消息数量
+    pub pending: usize,
+    pub subscriptions: Subscriptions,
+    pub server_time: DateTime<Utc>,
+}
+
+/// 定时发给客户端的连接质量,客户端可以据此在较差的网络上降低更新频率
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Quality {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    /// 最近一次ping到pong的耗时,还没有收到pong时为`None`
+    pub rtt_ms: Option<u64>,
+    /// 等待发送给这个session的帧数量
+    pub queue: usize,
+}
+
+/// `/flush`的结果,`type`为`flushed`或`discarded`
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Flushed {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    /// 发出或丢弃的帧数量,不包括正在发送的一帧
+    pub count: usize,
+}
+
+/// `/keepalive`的结果,`interval_s`为服务器ping的间隔,不ping时为`None`
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Keepalive {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub interval_s: Option<u64>,
+}
+
+lazy_static! {
+    /// `ServerTime::monotonic`从这里开始计时
+    static ref STARTED: Instant = Instant::now();
+}
+
+/// `/time`返回的服务器时间,客户端据此计算与本地时钟的偏差
+#[derive(Serialize, Debug)]
+pub struct ServerTime {
+    /// unix毫秒时间戳
+    pub server_ts: i64,
+    /// 单调递增的纳秒数,不受系统时钟调整影响
+    pub monotonic: u128,
+}
+
+impl ServerTime {
+    pub fn now() -> Self {
+        Self {
+            server_ts: Utc::now().timestamp_millis(),
+            monotonic: STARTED.elapsed().as_nanos(),
+        }
+    }
+}
+
+/// `/hello`返回的欢迎信息,恢复连接后客户端据此重建状态
+#[derive(Serialize, Debug)]
+pub struct Hello {
+    pub id: usize,
+    pub name: Option<String>,
+    pub server_time: ServerTime,
+    /// 连接时协商的帧格式、投递方式以及每帧的消息数量
+    pub framing: FramingMode,
+    pub delivery: DeliveryMode,
+    pub batch_size: usize,
+    pub subscriptions: Subscriptions,
+}
+
+/// `/help`返回的命令列表以及支持的格式,客户端据此判断服务器的功能
+#[derive(Serialize, Debug)]
+pub struct Help {
+    pub protocol: u32,
+    pub commands: &'static [&'static str],
+    /// 改名的命令以及新的名称,旧名称仍然可用
+    pub deprecated: &'static [(&'static str, &'static str)],
+    /// `/fields`可以选择的字段
+    pub fields: &'static [&'static str],
+    /// 连接时可以选择的帧格式以及投递方式
+    pub framings: [FramingMode; 2],
+    pub deliveries: [DeliveryMode; 2],
+}
+
+impl Help {
+    pub fn new() -> Self {
+        Self {
+            protocol: PROTOCOL_VERSION,
+            commands: COMMANDS,
+            deprecated: ALIASES,
+            fields: ACTIVITY_FIELDS,
+            framings: [FramingMode::Array, FramingMode::Ndjson],
+            deliveries: [DeliveryMode::AtLeastOnce, DeliveryMode::AtMostOnce],
+        }
+    }
+}
+
+/// 发送消息给所有连接中的session
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct Broadcast {
+    pub message: String,
+}
+
+/// 用户状态变更,通知与该session同房间的所有session
+#[derive(Message, Serialize, Debug, Clone)]
+#[rtype(result = "()")]
+#[serde(tag = "event", rename = "presence-status")]
+pub struct StatusChanged {
+    /// websocket session id
+    #[serde(skip)]
+    pub id: usize,
+    pub name: String,
+    pub status: Option<String>,
+}
+
+/// 查询session的name以及来源
+#[derive(Message, Debug)]
+#[rtype(result = "Option<SessionDetails>")]
+pub struct SessionInfo {
+    pub id: usize,
+}
+
+/// session的name以及来源
+#[derive(Serialize, Debug, PartialEq)]
+pub struct SessionDetails {
+    pub id: usize,
+    pub name: Option<String>,
+    pub origin: Origin,
+}
+
+/// session所在的平台,由`/platform`设置
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct SessionPlatform {
+    pub id: usize,
+    pub platform: String,
+}
+
+/// 列出与`id`登录了同一name的所有session,包括它自己
+#[derive(Debug)]
+pub struct OwnSessions {
+    pub id: usize,
+}
+
+impl actix::Message for OwnSessions {
+    type Result = Vec<OwnSession>;
+}
+
+/// 用户自己的一个websocket session
+#[derive(Serialize, Debug, PartialEq)]
+pub struct OwnSession {
+    pub id: usize,
+    pub platform: Option<String>,
+    pub connected_since: DateTime<Utc>,
+    pub ip: Option<String>,
+    /// 是否为查询的session
+    pub current: bool,
+}
+
+/// 断开与`id`登录了同一name的另一个session
+#[derive(Message, Debug)]
+#[rtype(Revoked)]
+pub struct RevokeSession {
+    pub id: usize,
+    pub target: usize,
+}
+
+/// `revoked`为`false`时不是自己的session,或者已经断开
+#[derive(MessageResponse, Serialize, Debug, PartialEq)]
+pub struct Revoked {
+    pub id: usize,
+    pub revoked: bool,
+}
+
+/// 列出所有websocket session
+#[derive(Debug)]
+pub struct ListNames {
+    /// 管理员总能看到identity,否则在隐私模式下隐藏
+    pub admin: bool,
+}
 
 impl actix::Message for ListNames {
-    type Result = Vec<String>;
+    type Result = Vec<NamedSession>;
+}
+
+/// 一个websocket session
+#[derive(Serialize, Debug, PartialEq)]
+pub struct NamedSession {
+    pub id: usize,
+    /// 登录的name,未登录或被隐藏时为`None`
+    pub identity: Option<String>,
+    pub platform: Option<String>,
+    pub connected_since: DateTime<Utc>,
 }
 
 pub struct Websocket {
-    //链接信息
-    // soc_sessions.key: websocket session的id
-    // soc_sessions.value: websocket 接受参数地址
-    sessions: HashMap<usize, Recipient<WsMessage>>,
-    // red_sessions.key: redis steam session的id
-    rng: ThreadRng,
+    /// 各session的连接由这些shard保存,按id分配
+    shards: Vec<Addr<SessionShard>>,
+    /// shard各自运行的线程
+    arbiters: Vec<Arbiter>,
+    /// shard的数量
+    shard_count: usize,
+    /// 每个session最多等待发送的消息数量
+    outbound_depth: usize,
+    /// session的消息队列满时如何处理新的消息
+    overflow: OverflowPolicy,
+    // rooms.key: 房间
+    // rooms.value: 已加入房间的websocket session id
+    rooms: HashMap<Room, HashSet<usize>>,
+    // names.key: websocket session的id
+    // names.value: session name
+    names: HashMap<usize, String>,
+    /// name是否只能被一个session持有
+    exclusive_names: bool,
+    /// 一个name最多被多少session持有,0为不限制
+    max_sessions_per_user: usize,
+    /// 超出`max_sessions_per_user`时如何处理
+    session_limit: SessionLimitPolicy,
+    /// 非管理员列出session时隐藏identity
+    private_names: bool,
+    /// name的最大长度
+    max_name_len: usize,
+    /// 生成websocket session的id
+    ids: Box<dyn IdGenerator>,
+    /// 已经接入的session数量
+    connects: u64,
+    // connected.key: websocket session的id
+    // connected.value: 该session是第几个接入的
+    connected: HashMap<usize, u64>,
+    /// 状态变更在这段时间内合并为一次通知,0为立即通知
+    presence_debounce: Duration,
+    // presence.key: websocket session的id
+    // presence.value: 等待通知的最新状态
+    presence: HashMap<usize, StatusChanged>,
 }
 
 impl Default for Websocket {
     fn default() -> Self {
         Self {
-            sessions: HashMap::with_capacity(1),
-            rng: rand::thread_rng(),
+            shards: vec![],
+            arbiters: vec![],
+            shard_count: 1,
+            outbound_depth: OUTBOUND_QUEUE_DEPTH,
+            overflow: OverflowPolicy::default(),
+            rooms: HashMap::new(),
+            names: HashMap::new(),
+            exclusive_names: false,
+            max_sessions_per_user: 0,
+            session_limit: SessionLimitPolicy::default(),
+            private_names: false,
+            max_name_len: MAX_NAME_LEN,
+            ids: Box::new(Random::default()),
+            connects: 0,
+            connected: HashMap::new(),
+            presence_debounce: Duration::ZERO,
+            presence: HashMap::new(),
         }
     }
 }
 
 impl Websocket {
-    /// 发送消息到指定name的所有客户端
-    fn send_message(&self, id: usize, message: &str) {
-        if let Some(addr) = self.sessions.get(&id) {
-            let _ = addr.do_send(WsMessage(message.to_owned()));
+    pub fn exclusive_names(mut self, exclusive_names: bool) -> Self {
+        self.exclusive_names = exclusive_names;
+        self
+    }
+
+    /// 一个name最多被`max`个session持有,0为不限制,超出时按`policy`处理
+    pub fn max_sessions_per_user(mut self, max: usize, policy: SessionLimitPolicy) -> Self {
+        self.max_sessions_per_user = max;
+        self.session_limit = policy;
+        self
+    }
+
+    pub fn private_names(mut self, private_names: bool) -> Self {
+        self.private_names = private_names;
+        self
+    }
+
+    /// 0时使用`MAX_NAME_LEN`
+    pub fn max_name_len(mut self, max_name_len: usize) -> Self {
+        self.max_name_len = if max_name_len == 0 {
+            MAX_NAME_LEN
+        } else {
+            max_name_len
+        };
+        self
+    }
+
+    pub fn id_generator(mut self, ids: impl IdGenerator + 'static) -> Self {
+        self.ids = Box::new(ids);
+        self
+    }
+
+    /// 每个session最多`depth`条消息等待发送,0为`OUTBOUND_QUEUE_DEPTH`,超出时按`overflow`处理
+    pub fn outbound_queue(mut self, depth: usize, overflow: OverflowPolicy) -> Self {
+        self.outbound_depth = match depth {
+            0 => OUTBOUND_QUEUE_DEPTH,
+            depth => depth,
+        };
+        self.overflow = overflow;
+        self
+    }
+
+    /// 状态变更等待`presence_debounce`后只通知最后的状态,网络不稳定时房间成员不会被反复通知
+    pub fn presence_debounce(mut self, presence_debounce: Duration) -> Self {
+        self.presence_debounce = presence_debounce;
+        self
+    }
+
+    /// 至少一个shard
+    pub fn shards(mut self, shard_count: usize) -> Self {
+        self.shard_count = shard_count.max(1);
+        self
+    }
+
+    /// 其他session已经持有`max_sessions_per_user`次`name`时,
+    /// 按`session_limit`拒绝`id`或者断开最早接入的session,拒绝时返回`false`
+    fn make_room(&mut self, id: usize, name: &str) -> bool {
+        if self.max_sessions_per_user == 0 {
+            return true;
+        }
+        let mut holders: Vec<usize> = self
+            .names
+            .iter()
+            .filter(|(other, n)| **other != id && n.as_str() == name)
+            .map(|(other, _)| *other)
+            .collect();
+        if holders.len() < self.max_sessions_per_user {
+            return true;
+        }
+        if self.session_limit == SessionLimitPolicy::Reject {
+            return false;
+        }
+        holders.sort_unstable_by_key(|other| self.connected.get(other).copied());
+        let excess = holders.len() + 1 - self.max_sessions_per_user;
+        for oldest in holders.into_iter().take(excess) {
+            // the name is free for the new session before the old one is gone
+            self.names.remove(&oldest);
+            self.shard(oldest).do_send(Evict {
+                id: oldest,
+                reason: format!("`{}` is open on too many sessions", name),
+            });
+        }
+        true
+    }
+
+    /// session所在的shard
+    fn shard(&self, id: usize) -> &Addr<SessionShard> {
+        &self.shards[shard_index(id, self.shards.len())]
+    }
+
+    /// 发送消息到指定的客户端,每个shard只发送一次
+    fn send_message(&self, ids: impl IntoIterator<Item = usize>, message: &str) {
+        let mut by_shard: HashMap<usize, Vec<usize>> = HashMap::new();
+        for id in ids {
+            by_shard
+                .entry(shard_index(id, self.shards.len()))
+                .or_default()
+                .push(id);
+        }
+        for (shard, ids) in by_shard {
+            self.shards[shard].do_send(Deliver {
+                ids: Some(ids),
+                message: message.to_owned(),
+            });
         }
     }
 }
 
+/// 按id的hash分配shard,snowflake id的低位是序号,直接取模分布不均
+fn shard_index(id: SessionId, shard_count: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    id.hash(&mut hasher);
+    hasher.finish() as usize % shard_count
+}
+
 impl Actor for Websocket {
     type Context = Context<Self>;
+
+    fn started(&mut self, _: &mut Self::Context) {
+        let (depth, overflow) = (self.outbound_depth, self.overflow);
+        for _ in 0..self.shard_count {
+            let arbiter = Arbiter::new();
+            self.shards.push(SessionShard::start_in_arbiter(
+                &arbiter.handle(),
+                move |_| SessionShard::new(depth, overflow),
+            ));
+            self.arbiters.push(arbiter);
+        }
+    }
+
+    fn stopped(&mut self, _: &mut Self::Context) {
+        for arbiter in &self.arbiters {
+            arbiter.stop();
+        }
+    }
 }
 
 impl Handler<Connect> for Websocket {
-    type Result = usize;
+    type Result = SessionId;
 
     fn handle(&mut self, msg: Connect, _: &mut Self::Context) -> Self::Result {
-        let id = self.rng.gen::<usize>();
-        info!("websocket connection {} connected", id);
-        self.sessions.insert(id, msg.addr);
+        let id = self.ids.next_id();
+        // the session stopped waiting and is gone
+        if !msg.addr.connected() {
+            return id;
+        }
+        info!(
+            "websocket connection {} connected from {:?}",
+            id, &msg.origin
+        );
+        self.connects += 1;
+        self.connected.insert(id, self.connects);
+        self.shard(id).do_send(Register {
+            id,
+            addr: msg.addr,
+            kick: msg.kick,
+            origin: msg.origin,
+        });
         // 新的连接会增加连接数量,不一定会引起用户数量增加
         id
     }
@@ -98,8 +600,263 @@ impl Handler<Disconnect> for Websocket {
     type Result = ();
 
     fn handle(&mut self, msg: Disconnect, _: &mut Self::Context) -> Self::Result {
-        self.sessions.remove(&msg.id);
-        info!("name:{:?} disconnected", &msg.id);
+        self.shard(msg.id).do_send(Unregister { id: msg.id });
+        self.connected.remove(&msg.id);
+        let name = self.names.remove(&msg.id);
+        self.rooms.retain(|_, members| {
+            members.remove(&msg.id);
+            !members.is_empty()
+        });
+        info!("session:{} name:{:?} disconnected", &msg.id, name);
+    }
+}
+
+impl Handler<IdentitySession> for Websocket {
+    type Result = IdentityResult;
+
+    fn handle(&mut self, msg: IdentitySession, _: &mut Self::Context) -> Self::Result {
+        let IdentitySession { id, name, tenant } = msg;
+        if let Err(reason) = validate_name(&name, "name", self.max_name_len) {
+            return IdentityResult::Invalid { name, reason };
+        }
+        let name = scoped(tenant.as_deref(), name);
+        if self.names.get(&id) == Some(&name) {
+            return IdentityResult::Unchanged { name };
+        }
+        if self.exclusive_names && self.names.values().any(|n| n == &name) {
+            return IdentityResult::Taken { name };
+        }
+        if !self.make_room(id, &name) {
+            return IdentityResult::TooManySessions { name };
+        }
+        let previous = self.names.insert(id, name.clone());
+        info!(
+            "websocket session {} is now `{}`, was {:?}",
+            id, name, previous
+        );
+        IdentityResult::Set { name, previous }
+    }
+}
+
+impl Handler<ReloadConfig> for Websocket {
+    type Result = ();
+
+    fn handle(&mut self, msg: ReloadConfig, _: &mut Self::Context) -> Self::Result {
+        info!("sessions per user are now {}", msg.max_sessions_per_user);
+        self.max_sessions_per_user = msg.max_sessions_per_user;
+    }
+}
+
+impl Handler<QueueDepth> for Websocket {
+    type Result = ResponseFuture<usize>;
+
+    fn handle(&mut self, msg: QueueDepth, _: &mut Self::Context) -> Self::Result {
+        let depth = self.shard(msg.id).send(msg);
+        Box::pin(async move { depth.await.unwrap_or_default() })
+    }
+}
+
+impl Handler<FlushQueue> for Websocket {
+    type Result = ResponseFuture<usize>;
+
+    fn handle(&mut self, msg: FlushQueue, _: &mut Self::Context) -> Self::Result {
+        let count = self.shard(msg.id).send(msg);
+        Box::pin(async move { count.await.unwrap_or_default() })
+    }
+}
+
+impl Handler<SessionInfo> for Websocket {
+    type Result = ResponseFuture<Option<SessionDetails>>;
+
+    fn handle(&mut self, msg: SessionInfo, _: &mut Self::Context) -> Self::Result {
+        let name = self.names.get(&msg.id).cloned();
+        let connection = self.shard(msg.id).send(ConnectionOf { id: msg.id });
+        Box::pin(async move {
+            let origin = connection.await.ok()??.origin;
+            Some(SessionDetails {
+                id: msg.id,
+                name,
+                origin,
+            })
+        })
+    }
+}
+
+impl Handler<SessionPlatform> for Websocket {
+    type Result = ();
+
+    fn handle(&mut self, msg: SessionPlatform, _: &mut Self::Context) -> Self::Result {
+        self.shard(msg.id).do_send(SetPlatform {
+            id: msg.id,
+            platform: msg.platform,
+        });
+    }
+}
+
+impl Handler<ListNames> for Websocket {
+    type Result = ResponseFuture<Vec<NamedSession>>;
+
+    fn handle(&mut self, msg: ListNames, _: &mut Self::Context) -> Self::Result {
+        let redact = self.private_names && !msg.admin;
+        let names = if redact {
+            HashMap::new()
+        } else {
+            self.names.clone()
+        };
+        let connections: Vec<_> = self
+            .shards
+            .iter()
+            .map(|shard| shard.send(ListConnections))
+            .collect();
+        Box::pin(async move {
+            let mut sessions: Vec<NamedSession> = futures::future::join_all(connections)
+                .await
+                .into_iter()
+                .filter_map(Result::ok)
+                .flatten()
+                .map(|(id, connection)| NamedSession {
+                    id,
+                    identity: names.get(&id).cloned(),
+                    platform: connection.platform,
+                    connected_since: connection.connected_since,
+                })
+                .collect();
+            sessions.sort_by_key(|session| (session.connected_since, session.id));
+            sessions
+        })
+    }
+}
+
+impl Handler<OwnSessions> for Websocket {
+    type Result = ResponseFuture<Vec<OwnSession>>;
+
+    fn handle(&mut self, msg: OwnSessions, _: &mut Self::Context) -> Self::Result {
+        let name = self.names.get(&msg.id);
+        let connections: Vec<_> = self
+            .names
+            .iter()
+            .filter(|(_, other)| Some(*other) == name)
+            .map(|(id, _)| {
+                let id = *id;
+                let connection = self.shard(id).send(ConnectionOf { id });
+                async move { (id, connection.await) }
+            })
+            .collect();
+        Box::pin(async move {
+            let mut sessions: Vec<OwnSession> = futures::future::join_all(connections)
+                .await
+                .into_iter()
+                .filter_map(|(id, connection)| Some((id, connection.ok()??)))
+                .map(|(id, connection)| OwnSession {
+                    id,
+                    platform: connection.platform,
+                    connected_since: connection.connected_since,
+                    ip: connection.origin.ip,
+                    current: id == msg.id,
+                })
+                .collect();
+            sessions.sort_by_key(|session| (session.connected_since, session.id));
+            sessions
+        })
+    }
+}
+
+impl Handler<RevokeSession> for Websocket {
+    type Result = Revoked;
+
+    fn handle(&mut self, msg: RevokeSession, _: &mut Self::Context) -> Self::Result {
+        let own = msg.id != msg.target
+            && self.names.contains_key(&msg.id)
+            && self.names.get(&msg.id) == self.names.get(&msg.target);
+        if own {
+            self.names.remove(&msg.target);
+            self.shard(msg.target).do_send(Evict {
+                id: msg.target,
+                reason: format!("revoked by session {}", msg.id),
+            });
+        }
+        Revoked {
+            id: msg.target,
+            revoked: own,
+        }
+    }
+}
+
+impl Handler<ListSubscriptions> for Websocket {
+    type Result = Subscriptions;
+
+    fn handle(&mut self, msg: ListSubscriptions, _: &mut Self::Context) -> Self::Result {
+        let mut rooms: Vec<String> = self
+            .rooms
+            .iter()
+            .filter(|(_, members)| members.contains(&msg.id))
+            .map(|(room, _)| room.name().to_owned())
+            .collect();
+        rooms.sort();
+        Subscriptions {
+            rooms,
+            ..Default::default()
+        }
+    }
+}
+
+impl Handler<Join> for Websocket {
+    type Result = ();
+
+    fn handle(&mut self, msg: Join, _: &mut Self::Context) -> Self::Result {
+        self.rooms.entry(msg.room).or_default().insert(msg.id);
+    }
+}
+
+impl Handler<Leave> for Websocket {
+    type Result = ();
+
+    fn handle(&mut self, msg: Leave, _: &mut Self::Context) -> Self::Result {
+        if let Some(members) = self.rooms.get_mut(&msg.room) {
+            members.remove(&msg.id);
+            if members.is_empty() {
+                self.rooms.remove(&msg.room);
+            }
+        }
+    }
+}
+
+impl Handler<StatusChanged> for Websocket {
+    type Result = ();
+
+    fn handle(&mut self, msg: StatusChanged, ctx: &mut Self::Context) -> Self::Result {
+        if self.presence_debounce.is_zero() {
+            return self.announce(&msg);
+        }
+        // 窗口内的变更只通知最后的状态
+        let id = msg.id;
+        if self.presence.insert(id, msg).is_none() {
+            ctx.run_later(self.presence_debounce, move |act, _| {
+                if let Some(msg) = act.presence.remove(&id) {
+                    act.announce(&msg);
+                }
+            });
+        }
+    }
+}
+
+impl Websocket {
+    /// 通知同房间的session以及自己
+    fn announce(&self, msg: &StatusChanged) {
+        let message = match serde_json::to_string(msg) {
+            Ok(message) => message,
+            Err(_) => return,
+        };
+        // 自己也会收到,作为设置成功的确认
+        let mut watchers: HashSet<usize> = self
+            .rooms
+            .values()
+            .filter(|members| members.contains(&msg.id))
+            .flatten()
+            .copied()
+            .collect();
+        watchers.insert(msg.id);
+        self.send_message(watchers, &message);
     }
 }
 
@@ -107,7 +864,34 @@ impl Handler<RedisMessage> for Websocket {
     type Result = ();
 
     fn handle(&mut self, msg: RedisMessage, _: &mut Self::Context) -> Self::Result {
-        self.send_message(msg.id, &msg.msg);
+        self.send_message(Some(msg.id), &msg.msg);
+    }
+}
+
+impl Handler<DrainConnections> for Websocket {
+    type Result = ();
+
+    fn handle(&mut self, msg: DrainConnections, _: &mut Self::Context) -> Self::Result {
+        info!(
+            "draining every session, reconnect within {}ms",
+            msg.reconnect_after_ms
+        );
+        for shard in &self.shards {
+            shard.do_send(msg);
+        }
+    }
+}
+
+impl Handler<Broadcast> for Websocket {
+    type Result = ();
+
+    fn handle(&mut self, msg: Broadcast, _: &mut Self::Context) -> Self::Result {
+        for shard in &self.shards {
+            shard.do_send(Deliver {
+                ids: None,
+                message: msg.message.clone(),
+            });
+        }
     }
 }
 
@@ -117,10 +901,141 @@ pub struct WebsocketSession {
     pub name: Option<String>,
     /// session内部计时器,用于定时向客户端ping
     pub hb: Instant,
+    /// 最近一次发送ping的时间
+    pub ping_at: Instant,
+    /// 最近一次ping到pong的耗时
+    pub rtt: Option<Duration>,
+    /// 按rtt调整的心跳间隔
+    pub heartbeat: AdaptiveHeartbeat,
     /// websocket addr
     pub redis_addr: Addr<Redis>,
     pub websocket_addr: Addr<Websocket>,
+    #[allow(dead_code)]
     pub grpc_addr: Addr<Seravee>,
+    /// 上线时先收到积压消息的摘要
+    pub backlog_summary: bool,
+    /// 一批消息写成一帧的方式
+    pub framing: FramingMode,
+    /// 每一帧最多包含的消息数量
+    pub batch_size: Option<usize>,
+    /// 客户端能够处理的受限消息类型,由`/caps`设置
+    pub capabilities: HashSet<String>,
+    /// 投递失败的消息是否重新投递
+    pub delivery: DeliveryMode,
+    /// 客户端需要的消息字段,由`/fields`设置,`None`时包含所有字段
+    pub fields: Option<HashSet<String>>,
+    /// 是否收到自己发到房间的消息,由`/echo`设置
+    pub echo: bool,
+    /// 客户端的ip和user-agent
+    pub origin: Origin,
+    /// 客户端发来的帧记录到日志的方式
+    pub frame_logging: FrameLogMode,
+    /// 发送连接质量的间隔,`None`时不发送
+    pub quality_interval: Option<Duration>,
+    /// 限制`/report`的频率
+    pub reports: Throttle,
+    /// 运行时的配置,每条命令按其中的`command_rate`限制
+    pub config: Arc<LiveConfig>,
+    /// 命令的配额和它按照的`command_rate`,`command_rate`改变时重新计算
+    pub commands: Option<(u32, Throttle)>,
+    /// 服务器发给客户端还没有回复的请求
+    pub requests: PendingRequests,
+    /// panic时代替`stopping`通知下线
+    pub guard: SessionGuard,
+    /// 决定session能否执行命令
+    pub authorizer: Arc<dyn CommandAuthorizer>,
+    /// 握手请求带有admin token时为`Admin`
+    pub role: Role,
+    /// 等待websocket服务器登记的最长时间,超时后关闭连接
+    pub register_timeout: Duration,
+    /// 握手时协商的协议版本,决定错误帧的格式
+    pub protocol: ProtocolVersion,
+    /// 连接时带上的租户,name、房间和redis key都限定在租户内
+    pub tenant: Option<String>,
+}
+
+/// 处理消息时panic不会调用`stopping`,session被drop时由它通知redis和websocket服务器下线
+pub struct SessionGuard {
+    id: usize,
+    redis_addr: Addr<Redis>,
+    websocket_addr: Addr<Websocket>,
+    armed: bool,
+}
+
+impl SessionGuard {
+    pub fn new(redis_addr: Addr<Redis>, websocket_addr: Addr<Websocket>) -> Self {
+        Self {
+            id: 0,
+            redis_addr,
+            websocket_addr,
+            armed: false,
+        }
+    }
+
+    /// 接入websocket服务器后开始守护
+    fn arm(&mut self, id: usize) {
+        self.id = id;
+        self.armed = true;
+    }
+
+    /// `stopping`已经通知过下线
+    fn disarm(&mut self) {
+        self.armed = false;
+    }
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+        warn!(
+            "websocket session {} dropped without stopping, cleaning up",
+            self.id
+        );
+        self.redis_addr.do_send(Offline { id: self.id });
+        self.websocket_addr.do_send(Disconnect { id: self.id });
+    }
+}
+
+/// the frame warning a client that it used the old name of a renamed command
+#[derive(Serialize)]
+struct DeprecatedFrame {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    command: &'static str,
+    replaced_by: &'static str,
+}
+
+/// `None` unless `text` runs a command by its deprecated name
+fn deprecation_notice(text: &str) -> Option<String> {
+    let (command, replaced_by) = deprecated(text)?;
+    serde_json::to_string(&DeprecatedFrame {
+        kind: "deprecated",
+        command,
+        replaced_by,
+    })
+    .ok()
+}
+
+/// what `mode` lets the log know of a frame a client sent
+fn frame_log(mode: FrameLogMode, id: usize, msg: &ws::Message) -> Option<String> {
+    let (kind, size) = match msg {
+        ws::Message::Text(text) => ("text", text.len()),
+        ws::Message::Binary(bytes) => ("binary", bytes.len()),
+        ws::Message::Continuation(_) => ("continuation", 0),
+        ws::Message::Ping(bytes) => ("ping", bytes.len()),
+        ws::Message::Pong(bytes) => ("pong", bytes.len()),
+        ws::Message::Close(_) => ("close", 0),
+        ws::Message::Nop => ("nop", 0),
+    };
+    match mode {
+        FrameLogMode::Off => None,
+        FrameLogMode::Metadata => {
+            Some(format!("websocket message: {}-{} {} bytes", id, kind, size))
+        }
+        FrameLogMode::Full => Some(format!("websocket message: {}-{:?}", id, msg)),
+    }
 }
 
 impl Actor for WebsocketSession {
@@ -131,6 +1046,12 @@ impl Actor for WebsocketSession {
     fn started(&mut self, ctx: &mut Self::Context) {
         // we'll start heartbeat process on session start.
         self.hb(ctx);
+        if let Some(interval) = self.quality_interval {
+            ctx.run_interval(interval, |act, ctx| {
+                let quality = act.quality();
+                act.respond(quality, ctx);
+            });
+        }
 
         // register self in socket server. `AsyncContext::wait` register
         // future within context, but context waits until this future resolves
@@ -140,12 +1061,30 @@ impl Actor for WebsocketSession {
         let addr = ctx.address();
         self.websocket_addr
             .send(Connect {
-                addr: addr.recipient(),
+                addr: addr.clone().recipient(),
+                kick: addr.recipient(),
+                origin: self.origin.clone(),
             })
+            .timeout(self.register_timeout)
             .into_actor(self)
             .then(|res, act, ctx| {
                 match res {
-                    Ok(res) => act.id = res,
+                    Ok(res) => {
+                        act.id = res;
+                        act.guard.arm(res);
+                    }
+                    // the socket server is too busy, the client may try again later
+                    Err(MailboxError::Timeout) => {
+                        warn!(
+                            "websocket session not registered within {:?}",
+                            act.register_timeout
+                        );
+                        ctx.close(Some(ws::CloseReason {
+                            code: ws::CloseCode::Again,
+                            description: Some("registration timed out".to_string()),
+                        }));
+                        ctx.stop();
+                    }
                     // something is wrong with socket server
                     _ => ctx.stop(),
                 }
@@ -155,10 +1094,11 @@ impl Actor for WebsocketSession {
     }
 
     fn stopping(&mut self, _: &mut Self::Context) -> Running {
+        self.guard.disarm();
         // notify redis server
-        &self.redis_addr.do_send(Offline { id: self.id });
+        self.redis_addr.do_send(Offline { id: self.id });
         // notify socket server
-        &self.websocket_addr.do_send(Disconnect { id: self.id });
+        self.websocket_addr.do_send(Disconnect { id: self.id });
         Running::Stop
     }
 }
@@ -172,10 +1112,44 @@ impl Handler<WsMessage> for WebsocketSession {
     }
 }
 
-/// WebSocket message handler
-impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebsocketSession {
-    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
-        let msg = match msg {
+impl Handler<Kick> for WebsocketSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: Kick, ctx: &mut Self::Context) {
+        info!("websocket session {} is kicked: {}", self.id, &msg.reason);
+        ctx.close(Some(ws::CloseReason {
+            code: msg.code,
+            description: Some(msg.reason),
+        }));
+        ctx.stop();
+    }
+}
+
+impl Handler<Ask> for WebsocketSession {
+    type Result = ResponseActFuture<Self, Result<Value, RequestError>>;
+
+    fn handle(&mut self, msg: Ask, ctx: &mut Self::Context) -> Self::Result {
+        let (req_id, frame, reply) = self.requests.open(&msg.payload);
+        ctx.text(frame);
+        Box::pin(
+            actix::clock::timeout(msg.timeout, reply)
+                .into_actor(self)
+                .map(move |res, act, _| match res {
+                    Ok(Ok(reply)) => Ok(reply),
+                    Ok(Err(_)) => Err(RequestError::Closed),
+                    Err(_) => {
+                        act.requests.cancel(&req_id);
+                        Err(RequestError::Timeout)
+                    }
+                }),
+        )
+    }
+}
+
+/// WebSocket message handler
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebsocketSession {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        let msg = match msg {
             Err(_) => {
                 ctx.stop();
                 return;
@@ -183,50 +1157,37 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebsocketSession
             Ok(msg) => msg,
         };
 
-        debug!("websocket message: {:?}-{:?}", self.id, msg);
+        if let Some(line) = frame_log(self.frame_logging, self.id, &msg) {
+            debug!("{}", line);
+        }
         match msg {
             ws::Message::Ping(msg) => {
                 self.hb = Instant::now();
+                self.redis_addr.do_send(Heartbeat { id: self.id });
                 ctx.pong(&msg);
             }
             ws::Message::Pong(_) => {
                 self.hb = Instant::now();
+                let rtt = self.hb.saturating_duration_since(self.ping_at);
+                self.rtt = Some(rtt);
+                self.heartbeat.observe(rtt);
+                self.redis_addr.do_send(Heartbeat { id: self.id });
             }
             ws::Message::Text(text) => {
                 let m = text.trim();
                 // we check for /sss type of messages
-                if m.starts_with('/') {
-                    let v: Vec<&str> = m.splitn(2, ' ').collect();
-                    if let "/login" = v[0] {
-                        if v.len() == 2 {
-                            let name = v[1].to_owned();
-                            self.name = Some(name.clone());
-                            self.redis_addr.do_send(Online {
-                                id: self.id,
-                                name,
-                                addr: ctx.address().recipient(),
-                            });
-                        } else {
-                            ctx.text("!!! name is required");
-                        }
-                    } else if let "/platform" = v[0] {
-                        if v.len() == 2 {
-                            let device = serde_json::from_str(v[1]).expect("error device info");
-                            if let Some(username) = &self.name {
-                                self.redis_addr.do_send(PlatformOnline {
-                                    id: self.id,
-                                    name: username.to_string(),
-                                    platform: device,
-                                });
-                            }
-                        } else {
-                            ctx.text("!!! platform is required");
+                if !m.starts_with('/') {
+                    ctx.text(self.protocol.error(&format!("unknown command: {:?}", m)));
+                    return;
+                }
+                match m.parse::<Command>() {
+                    Ok(command) => {
+                        if let Some(notice) = deprecation_notice(m) {
+                            ctx.text(notice);
                         }
-                    } else {
-                        ctx.text(format!("!!! unknown command: {:?}", m))
+                        self.command(command, ctx)
                     }
-                } else {
-                    ctx.text(format!("!!! unknown command: {:?}", m));
+                    Err(e) => ctx.text(self.protocol.error(&e.to_string())),
                 }
             }
             ws::Message::Binary(_) => info!("Unexpected binary"),
@@ -243,12 +1204,585 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebsocketSession
 }
 
 impl WebsocketSession {
-    /// helper method that sends ping to client every second.
+    /// spend one command of the quota, a session sending too many gets an error
+    /// instead of flooding the actors its commands go to
+    fn admit(&mut self) -> bool {
+        let rate = self.config.load().command_rate;
+        if rate == 0 {
+            self.commands = None;
+            return true;
+        }
+        let quota = match &mut self.commands {
+            Some((at, quota)) if *at == rate => quota,
+            commands => &mut commands.insert((rate, Throttle::new(rate))).1,
+        };
+        if quota.available(Instant::now()) == 0 {
+            debug!(
+                "websocket session {} is throttled, command dropped",
+                self.id
+            );
+            return false;
+        }
+        quota.take(1);
+        true
+    }
+
+    /// the error sent back instead of running a command the session may not run
+    fn denied(&self, command: &Command) -> Option<String> {
+        (!self.authorizer.permits(self.role, command)).then(|| {
+            self.protocol
+                .error(&format!("permission denied: {}", command.name()))
+        })
+    }
+
+    /// run a parsed command, answering the client directly or through the actor that handles it
+    fn command(&mut self, command: Command, ctx: &mut ws::WebsocketContext<Self>) {
+        debug!("websocket session {} runs {}", self.id, command.name());
+        if !self.admit() {
+            ctx.text(self.protocol.error("too many commands"));
+            return;
+        }
+        if let Some(denied) = self.denied(&command) {
+            ctx.text(denied);
+            return;
+        }
+        match command {
+            Command::Login(name) => self.identify(name, ctx),
+            Command::Switch(name) => match &self.name {
+                Some(_) => self.identify(name, ctx),
+                None => ctx.text(self.protocol.error("login is required")),
+            },
+            Command::Platform(device) => {
+                self.websocket_addr.do_send(SessionPlatform {
+                    id: self.id,
+                    platform: device.name().to_owned(),
+                });
+                if let Some(username) = &self.name {
+                    self.redis_addr.do_send(PlatformOnline {
+                        id: self.id,
+                        name: username.to_string(),
+                        platform: device,
+                    });
+                }
+            }
+            Command::Join(room) => match &self.name {
+                Some(name) => {
+                    let join = Join {
+                        id: self.id,
+                        name: name.clone(),
+                        room: Room(self.scoped(room)),
+                    };
+                    self.websocket_addr.do_send(join.clone());
+                    self.redis_addr.do_send(join);
+                }
+                None => ctx.text(self.protocol.error("login is required")),
+            },
+            Command::Leave(room) => match &self.name {
+                Some(name) => {
+                    let leave = Leave {
+                        id: self.id,
+                        name: name.clone(),
+                        room: Room(self.scoped(room)),
+                    };
+                    self.websocket_addr.do_send(leave.clone());
+                    self.redis_addr.do_send(leave);
+                }
+                None => ctx.text(self.protocol.error("login is required")),
+            },
+            Command::Post {
+                room,
+                text,
+                reply_to,
+            } => match &self.name {
+                Some(name) => {
+                    self.redis_addr
+                        .send(Post {
+                            room: Room(self.scoped(room)),
+                            message: Activity {
+                                activity_type: "message".to_owned(),
+                                activity: text,
+                                reply_to,
+                                sender: Some(name.clone()),
+                                ..Default::default()
+                            },
+                        })
+                        .into_actor(self)
+                        .map(|posted, act: &mut Self, ctx| {
+                            // e.g. not a member, or the entry it replies to is not in the room
+                            if !matches!(posted, Ok(Some(_))) {
+                                ctx.text(act.protocol.error("message was not posted"));
+                            }
+                        })
+                        .wait(ctx);
+                }
+                None => ctx.text(self.protocol.error("login is required")),
+            },
+            Command::Status(status) => match &self.name {
+                Some(name) => {
+                    self.redis_addr.do_send(SetStatus {
+                        name: name.clone(),
+                        status: status.clone(),
+                    });
+                    self.websocket_addr.do_send(StatusChanged {
+                        id: self.id,
+                        name: name.clone(),
+                        status,
+                    });
+                }
+                None => ctx.text(self.protocol.error("login is required")),
+            },
+            Command::Clear => match &self.name {
+                Some(name) => {
+                    let addr = self.redis_addr.clone();
+                    let name = name.clone();
+                    self.relay(&addr, Clear { name }, ctx);
+                }
+                None => ctx.text(self.protocol.error("login is required")),
+            },
+            Command::Missed(limit) => match &self.name {
+                Some(name) => {
+                    let addr = self.redis_addr.clone();
+                    let name = name.clone();
+                    self.relay(&addr, ListMissed { name, limit }, ctx);
+                }
+                None => ctx.text(self.protocol.error("login is required")),
+            },
+            Command::Get(entry) => match &self.name {
+                Some(name) => {
+                    let addr = self.redis_addr.clone();
+                    let name = name.clone();
+                    self.relay(&addr, GetActivity { name, id: entry }, ctx);
+                }
+                None => ctx.text(self.protocol.error("login is required")),
+            },
+            Command::SinceTs(since_ms) => match &self.name {
+                Some(name) => {
+                    let addr = self.redis_addr.clone();
+                    let name = name.clone();
+                    self.relay_large(&addr, ListSince { name, since_ms }, ctx);
+                }
+                None => ctx.text(self.protocol.error("login is required")),
+            },
+            Command::HistoryPage { before_id, count } => match &self.name {
+                Some(name) => {
+                    let addr = self.redis_addr.clone();
+                    let name = name.clone();
+                    let list = ListHistory {
+                        name,
+                        before_id,
+                        count,
+                    };
+                    self.relay_large(&addr, list, ctx);
+                }
+                None => ctx.text(self.protocol.error("login is required")),
+            },
+            Command::StatusOf(push_ids) => match &self.name {
+                Some(name) => {
+                    let addr = self.redis_addr.clone();
+                    let sender = name.clone();
+                    self.relay(&addr, StatusOf { sender, push_ids }, ctx);
+                }
+                None => ctx.text(self.protocol.error("login is required")),
+            },
+            Command::Track(on) => match &self.name {
+                Some(_) => self.redis_addr.do_send(Track { id: self.id, on }),
+                None => ctx.text(self.protocol.error("login is required")),
+            },
+            Command::Badges => match &self.name {
+                Some(_) => {
+                    let addr = self.redis_addr.clone();
+                    self.relay(&addr, Badges { id: self.id }, ctx);
+                }
+                None => ctx.text(self.protocol.error("login is required")),
+            },
+            Command::Cursor => match &self.name {
+                Some(name) => {
+                    let addr = self.redis_addr.clone();
+                    let cursor = Cursor {
+                        id: self.id,
+                        name: name.clone(),
+                    };
+                    self.relay(&addr, cursor, ctx);
+                }
+                None => ctx.text(self.protocol.error("login is required")),
+            },
+            Command::BatchSize(size) => {
+                self.batch_size = Some(size);
+                self.redis_addr.do_send(BatchSize { id: self.id, size });
+            }
+            Command::Foreground => self.redis_addr.do_send(Priority {
+                id: self.id,
+                foreground: true,
+            }),
+            Command::Background => self.redis_addr.do_send(Priority {
+                id: self.id,
+                foreground: false,
+            }),
+            Command::Caps(capabilities) => {
+                self.capabilities = capabilities.clone();
+                self.redis_addr.do_send(Capabilities {
+                    id: self.id,
+                    capabilities,
+                });
+            }
+            Command::Fields(fields) => {
+                self.fields = fields.clone();
+                self.redis_addr.do_send(Fields {
+                    id: self.id,
+                    fields,
+                });
+            }
+            Command::Echo(echo) => {
+                self.echo = echo;
+                self.redis_addr.do_send(Echo { id: self.id, echo });
+            }
+            Command::Public(public) => match &self.name {
+                Some(name) => {
+                    let addr = self.redis_addr.clone();
+                    let name = name.clone();
+                    self.relay(&addr, SetPublic { name, public }, ctx);
+                }
+                None => ctx.text(self.protocol.error("login is required")),
+            },
+            Command::Follow(username) => self.follow(self.scoped(username), true, ctx),
+            Command::Mute(sender) => self.redis_addr.do_send(Mute {
+                id: self.id,
+                sender: self.scoped(sender),
+                mute: true,
+            }),
+            Command::Unmute(sender) => self.redis_addr.do_send(Mute {
+                id: self.id,
+                sender: self.scoped(sender),
+                mute: false,
+            }),
+            Command::Unfollow(username) => self.follow(self.scoped(username), false, ctx),
+            Command::Presence(name) => {
+                let addr = self.redis_addr.clone();
+                let name = self.scoped(name);
+                self.relay(&addr, Presence { name }, ctx);
+            }
+            Command::List => {
+                let admin = self.role == Role::Admin;
+                let addr = self.websocket_addr.clone();
+                self.relay(&addr, ListNames { admin }, ctx);
+            }
+            Command::Broadcast(message) => self.websocket_addr.do_send(Broadcast { message }),
+            Command::Sessions if self.name.is_some() => {
+                let addr = self.websocket_addr.clone();
+                self.relay(&addr, OwnSessions { id: self.id }, ctx);
+            }
+            Command::Revoke(target) if self.name.is_some() => {
+                let addr = self.websocket_addr.clone();
+                self.relay(
+                    &addr,
+                    RevokeSession {
+                        id: self.id,
+                        target,
+                    },
+                    ctx,
+                );
+            }
+            Command::Sessions | Command::Revoke(_) => {
+                ctx.text(self.protocol.error("login is required"))
+            }
+            Command::Whoami => {
+                let addr = self.websocket_addr.clone();
+                self.relay(&addr, SessionInfo { id: self.id }, ctx);
+            }
+            Command::Time => {
+                if let Ok(time) = serde_json::to_string(&ServerTime::now()) {
+                    ctx.text(time);
+                }
+            }
+            Command::Help => {
+                if let Ok(help) = serde_json::to_string(&Help::new()) {
+                    ctx.text(help);
+                }
+            }
+            Command::Diag => self.respond(self.diagnostics(), ctx),
+            Command::Flush(discard) => self.respond(self.flush(discard), ctx),
+            Command::Keepalive(interval) => {
+                let keepalive = Keepalive {
+                    kind: "keepalive",
+                    interval_s: self.heartbeat.pin(interval).map(|i| i.as_secs()),
+                };
+                // 旧间隔的心跳循环在下一次触发时结束
+                self.hb(ctx);
+                if let Ok(keepalive) = serde_json::to_string(&keepalive) {
+                    ctx.text(keepalive);
+                }
+            }
+            Command::SelfTest => match &self.name {
+                Some(name) => {
+                    let addr = self.redis_addr.clone();
+                    let trial = self.self_test(name);
+                    self.relay(&addr, trial, ctx);
+                }
+                None => ctx.text(self.protocol.error("login is required")),
+            },
+            Command::Hello => self.respond(self.hello(), ctx),
+            Command::Subscriptions => {
+                let addr = self.websocket_addr.clone();
+                self.relay(&addr, ListSubscriptions { id: self.id }, ctx);
+            }
+            Command::Reply { req_id, reply } => {
+                if !self.requests.resolve(&req_id, reply) {
+                    ctx.text(self.protocol.error(&format!("unknown request {}", req_id)));
+                }
+            }
+            Command::Report(report) => {
+                if self.reports.available(Instant::now()) == 0 {
+                    ctx.text(self.protocol.error("too many reports"));
+                    return;
+                }
+                self.reports.take(1);
+                self.redis_addr.do_send(ClientReport {
+                    id: self.id,
+                    name: self.name.clone(),
+                    report,
+                });
+            }
+        }
+    }
+
+    /// follow or unfollow `username`, relaying the outcome to the client
+    fn follow(&mut self, username: String, follow: bool, ctx: &mut ws::WebsocketContext<Self>) {
+        match &self.name {
+            Some(name) => {
+                let addr = self.redis_addr.clone();
+                let follow = Follow {
+                    follower: name.clone(),
+                    username,
+                    follow,
+                };
+                self.relay(&addr, follow, ctx);
+            }
+            None => ctx.text(self.protocol.error("login is required")),
+        }
+    }
+
+    /// send the outcome of `fut` to the client as json
+    fn respond<T>(
+        &mut self,
+        fut: impl Future<Output = Result<T, MailboxError>> + 'static,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) where
+        T: Serialize,
+    {
+        fut.into_actor(self)
+            .then(|res, _, ctx| {
+                match res {
+                    Ok(res) => {
+                        if let Ok(res) = serde_json::to_string(&res) {
+                            ctx.text(res);
+                        }
+                    }
+                    // something is wrong with the actor
+                    _ => ctx.stop(),
+                }
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// ask an actor and relay its answer to the client as json
+    fn relay<A, M>(&mut self, addr: &Addr<A>, msg: M, ctx: &mut ws::WebsocketContext<Self>)
+    where
+        A: Handler<M>,
+        A::Context: dev::ToEnvelope<A, M>,
+        M: actix::Message + Send + 'static,
+        M::Result: Serialize + Send,
+    {
+        self.respond(addr.send(msg), ctx);
+    }
+
+    /// like `relay`, for answers that can be large, see `large_frame`
+    fn relay_large<A, M>(&mut self, addr: &Addr<A>, msg: M, ctx: &mut ws::WebsocketContext<Self>)
+    where
+        A: Handler<M>,
+        A::Context: dev::ToEnvelope<A, M>,
+        M: actix::Message + Send + 'static,
+        M::Result: Serialize + Send,
+    {
+        addr.send(msg)
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(res) => {
+                        if let Ok(res) = serde_json::to_string(&res) {
+                            ctx.write_raw(act.large_frame(res));
+                        }
+                    }
+                    // something is wrong with the actor
+                    _ => ctx.stop(),
+                }
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// a text frame, gzipped into a binary frame when it is over `COMPRESS_THRESHOLD`
+    /// and the client has the `gzip` capability
+    fn large_frame(&self, text: String) -> ws::Message {
+        if text.len() <= COMPRESS_THRESHOLD || !self.capabilities.contains(GZIP_CAPABILITY) {
+            return ws::Message::Text(text.into());
+        }
+        match gzip(text.as_bytes()) {
+            Ok(gzipped) => ws::Message::Binary(gzipped.into()),
+            Err(e) => {
+                warn!(
+                    "websocket session {} cannot gzip a response: {}",
+                    self.id, e
+                );
+                ws::Message::Text(text.into())
+            }
+        }
+    }
+
+    /// the `/selftest` event, pushed to the caller's own stream and delivered back like any other
+    fn self_test(&self, name: &str) -> Trial {
+        Trial {
+            message: Activity {
+                activity_type: SELFTEST_TYPE.to_string(),
+                activity: serde_json::json!({ "session_id": self.id }).to_string(),
+                correlation_id: Some(trace::new_correlation_id()),
+                ..Default::default()
+            },
+            receivers: vec![name.to_owned()],
+            quantity: None,
+        }
+    }
+
+    /// gather the `/diag` report from this session, the websocket server and redis
+    fn diagnostics(&self) -> impl Future<Output = Result<Diagnostics, MailboxError>> {
+        let subscriptions = self.websocket_addr.send(ListSubscriptions { id: self.id });
+        let pending = self
+            .name
+            .clone()
+            .map(|name| self.redis_addr.send(ListMissed { name, limit: 0 }));
+        let mut report = Diagnostics {
+            id: self.id,
+            name: self.name.clone(),
+            framing: self.framing,
+            batch_size: self.batch_size.unwrap_or(READ_COUNT),
+            rtt_ms: self.rtt.map(|rtt| rtt.as_millis() as u64),
+            pending: 0,
+            subscriptions: Subscriptions::default(),
+            server_time: Utc::now(),
+        };
+        async move {
+            report.subscriptions = subscriptions.await?;
+            if let Some(pending) = pending {
+                report.pending = pending.await?.count;
+            }
+            Ok(report)
+        }
+    }
+
+    /// `/flush`: the frames waiting for the client sent at once, or dropped
+    fn flush(&self, discard: bool) -> impl Future<Output = Result<Flushed, MailboxError>> {
+        let count = self.websocket_addr.send(FlushQueue {
+            id: self.id,
+            discard,
+        });
+        async move {
+            Ok(Flushed {
+                kind: if discard { "discarded" } else { "flushed" },
+                count: count.await?,
+            })
+        }
+    }
+
+    /// the rtt and the frames waiting for the client, sent every `quality_interval`
+    fn quality(&self) -> impl Future<Output = Result<Quality, MailboxError>> {
+        let queue = self.websocket_addr.send(QueueDepth { id: self.id });
+        let rtt_ms = self.rtt.map(|rtt| rtt.as_millis() as u64);
+        async move {
+            Ok(Quality {
+                kind: "quality",
+                rtt_ms,
+                queue: queue.await?,
+            })
+        }
+    }
+
+    /// the welcome as a freshly connected client would get it, with the current subscriptions
+    fn hello(&self) -> impl Future<Output = Result<Hello, MailboxError>> {
+        let subscriptions = self.websocket_addr.send(ListSubscriptions { id: self.id });
+        let mut hello = Hello {
+            id: self.id,
+            name: self.name.clone(),
+            server_time: ServerTime::now(),
+            framing: self.framing,
+            delivery: self.delivery,
+            batch_size: self.batch_size.unwrap_or(READ_COUNT),
+            subscriptions: Subscriptions::default(),
+        };
+        async move {
+            hello.subscriptions = subscriptions.await?;
+            Ok(hello)
+        }
+    }
+
+    /// qualify a name or room with the tenant of the connection
+    fn scoped(&self, name: String) -> String {
+        scoped(self.tenant.as_deref(), name)
+    }
+
+    /// set or change the name of the session, relaying the result to the client
+    fn identify(&mut self, name: String, ctx: &mut ws::WebsocketContext<Self>) {
+        self.websocket_addr
+            .send(IdentitySession {
+                id: self.id,
+                name,
+                tenant: self.tenant.clone(),
+            })
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(result) => {
+                        if let IdentityResult::Set { name, .. } = &result {
+                            // redis offlines the old name right away, without waiting for the grace
+                            // period, before onlining the new one
+                            act.name = Some(name.clone());
+                            act.redis_addr.do_send(Online {
+                                id: act.id,
+                                name: name.clone(),
+                                addr: ctx.address().recipient(),
+                                kick: ctx.address().recipient(),
+                                ask: ctx.address().recipient(),
+                                backlog_summary: act.backlog_summary,
+                                framing: act.framing,
+                                batch_size: act.batch_size,
+                                capabilities: act.capabilities.clone(),
+                                delivery: act.delivery,
+                                fields: act.fields.clone(),
+                                echo: act.echo,
+                            });
+                        }
+                        if let Ok(result) = serde_json::to_string(&result) {
+                            ctx.text(result);
+                        }
+                    }
+                    // something is wrong with socket server
+                    _ => ctx.stop(),
+                }
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// helper method that sends ping to client, the interval follows the rtt of the pongs.
     /// also this method checks pongs from client
     fn hb(&self, ctx: &mut ws::WebsocketContext<Self>) {
-        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
+        let epoch = self.heartbeat.epoch();
+        ctx.run_later(self.heartbeat.interval(), move |act, ctx| {
+            if act.heartbeat.epoch() != epoch {
+                // `/keepalive` started another loop
+                return;
+            }
             // check client heartbeats
-            if Instant::now().duration_since(act.hb) > CLIENT_TIMEOUT {
+            if Instant::now().duration_since(act.hb) > act.heartbeat.timeout() {
                 // heartbeat timed out
                 info!("websocket client heartbeat failed, disconnecting!");
 
@@ -260,7 +1794,1267 @@ impl WebsocketSession {
                 return;
             }
 
-            ctx.ping(b"");
+            if act.heartbeat.pings() {
+                act.ping_at = Instant::now();
+                ctx.ping(b"");
+            }
+            act.hb(ctx);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::{
+        addr::{
+            authz::AdminCommands, store::StreamStore, testing::Collector, ListSessions, MemoryStore,
+        },
+        config::{Config, CONFIG},
+        constants::{
+            DRAIN_CLOSE_DELAY, HEARTBEAT_INTERVAL, MESSAGE_INTERVAL, REGISTER_TIMEOUT, REPORT_RATE,
+        },
+    };
+
+    #[actix_rt::test]
+    async fn set_and_reset_identity() {
+        let addr = Websocket::default().start();
+        let set = addr
+            .send(IdentitySession {
+                id: 1,
+                name: "setsuna".to_string(),
+                tenant: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            set,
+            IdentityResult::Set {
+                name: "setsuna".to_string(),
+                previous: None
+            }
+        );
+
+        let same = addr
+            .send(IdentitySession {
+                id: 1,
+                name: "setsuna".to_string(),
+                tenant: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            same,
+            IdentityResult::Unchanged {
+                name: "setsuna".to_string()
+            }
+        );
+
+        let changed = addr
+            .send(IdentitySession {
+                id: 1,
+                name: "f.seiei".to_string(),
+                tenant: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            changed,
+            IdentityResult::Set {
+                name: "f.seiei".to_string(),
+                previous: Some("setsuna".to_string())
+            }
+        );
+    }
+
+    #[actix_rt::test]
+    async fn invalid_names_are_rejected() {
+        let addr = Websocket::default().max_name_len(8).start();
+        let identity = |name: &str| IdentitySession {
+            id: 1,
+            name: name.to_string(),
+            tenant: None,
+        };
+        let valid = addr.send(identity("lyle_d.2")).await.unwrap();
+        assert!(matches!(valid, IdentityResult::Set { .. }));
+
+        let too_long = addr.send(identity("lockon-stratos")).await.unwrap();
+        assert!(matches!(
+            too_long,
+            IdentityResult::Invalid { ref reason, .. } if reason.contains("longer than 8")
+        ));
+        for name in ["a b", "room:x", "", "名字"] {
+            let illegal = addr.send(identity(name)).await.unwrap();
+            assert!(
+                matches!(illegal, IdentityResult::Invalid { .. }),
+                "{:?}",
+                name
+            );
+        }
+        // a rejected name leaves the current one in place
+        let same = addr.send(identity("lyle_d.2")).await.unwrap();
+        assert!(matches!(same, IdentityResult::Unchanged { .. }));
+    }
+
+    #[actix_rt::test]
+    async fn conflicting_identity() {
+        let addr = Websocket::default().exclusive_names(true).start();
+        let identity = |id: usize, name: &str| IdentitySession {
+            id,
+            name: name.to_string(),
+            tenant: None,
+        };
+        addr.send(identity(1, "tieria")).await.unwrap();
+
+        let taken = addr.send(identity(2, "tieria")).await.unwrap();
+        assert_eq!(
+            taken,
+            IdentityResult::Taken {
+                name: "tieria".to_string()
+            }
+        );
+
+        // the old name is released once the session changes it
+        addr.send(identity(1, "veda")).await.unwrap();
+        let set = addr.send(identity(2, "tieria")).await.unwrap();
+        assert!(matches!(set, IdentityResult::Set { .. }));
+    }
+
+    #[actix_rt::test]
+    async fn sessions_over_the_cap_are_rejected_or_evict_the_oldest() {
+        for policy in [SessionLimitPolicy::Reject, SessionLimitPolicy::EvictOldest] {
+            let addr = Websocket::default()
+                .max_sessions_per_user(2, policy)
+                .start();
+            let mut sessions = vec![];
+            for _ in 0..3 {
+                let collector = Collector::default();
+                let id = addr
+                    .send(Connect {
+                        addr: collector.clone().start().recipient(),
+                        kick: collector.clone().start().recipient(),
+                        origin: Origin::default(),
+                    })
+                    .await
+                    .unwrap();
+                let result = addr
+                    .send(IdentitySession {
+                        id,
+                        name: "hallelujah".to_string(),
+                        tenant: None,
+                    })
+                    .await
+                    .unwrap();
+                sessions.push((collector, result));
+            }
+            actix_rt::time::sleep(Duration::from_millis(50)).await;
+
+            assert!(matches!(sessions[0].1, IdentityResult::Set { .. }));
+            assert!(matches!(sessions[1].1, IdentityResult::Set { .. }));
+            match policy {
+                SessionLimitPolicy::Reject => {
+                    assert_eq!(
+                        sessions[2].1,
+                        IdentityResult::TooManySessions {
+                            name: "hallelujah".to_string()
+                        }
+                    );
+                    assert!(sessions[0].0.received().is_empty());
+                }
+                SessionLimitPolicy::EvictOldest => {
+                    assert!(matches!(sessions[2].1, IdentityResult::Set { .. }));
+                    assert_eq!(
+                        sessions[0].0.received(),
+                        vec!["kicked: `hallelujah` is open on too many sessions"]
+                    );
+                    assert!(sessions[1].0.received().is_empty());
+                }
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn a_user_lists_and_revokes_its_other_sessions() {
+        let addr = Websocket::default().start();
+        let mut sessions = vec![];
+        for (name, ip) in [
+            ("tieria", "10.0.0.1"),
+            ("tieria", "10.0.0.2"),
+            ("regene", "10.0.0.3"),
+        ] {
+            let collector = Collector::default();
+            let id = addr
+                .send(Connect {
+                    addr: collector.clone().start().recipient(),
+                    kick: collector.clone().start().recipient(),
+                    origin: Origin {
+                        ip: Some(ip.to_string()),
+                        user_agent: None,
+                    },
+                })
+                .await
+                .unwrap();
+            addr.send(IdentitySession {
+                id,
+                name: name.to_string(),
+                tenant: None,
+            })
+            .await
+            .unwrap();
+            sessions.push((id, collector));
+        }
+        let (laptop, phone, other) = (sessions[0].0, sessions[1].0, sessions[2].0);
+
+        let own = addr.send(OwnSessions { id: laptop }).await.unwrap();
+        let listed: Vec<(usize, Option<&str>, bool)> = own
+            .iter()
+            .map(|session| (session.id, session.ip.as_deref(), session.current))
+            .collect();
+        assert_eq!(
+            listed,
+            vec![
+                (laptop, Some("10.0.0.1"), true),
+                (phone, Some("10.0.0.2"), false),
+            ]
+        );
+
+        // someone else's session is out of reach
+        let denied = addr
+            .send(RevokeSession {
+                id: laptop,
+                target: other,
+            })
+            .await
+            .unwrap();
+        assert!(!denied.revoked);
+        let revoked = addr
+            .send(RevokeSession {
+                id: laptop,
+                target: phone,
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            revoked,
+            Revoked {
+                id: phone,
+                revoked: true
+            }
+        );
+        actix_rt::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(
+            sessions[1].1.received(),
+            vec![format!("kicked: revoked by session {}", laptop)]
+        );
+        assert!(sessions[2].1.received().is_empty());
+        let own = addr.send(OwnSessions { id: laptop }).await.unwrap();
+        assert_eq!(own.len(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn shared_identity_when_not_exclusive() {
+        let addr = Websocket::default().start();
+        for id in 1..=2 {
+            let set = addr
+                .send(IdentitySession {
+                    id,
+                    name: "allelujah".to_string(),
+                    tenant: None,
+                })
+                .await
+                .unwrap();
+            assert!(matches!(set, IdentityResult::Set { .. }));
+        }
+    }
+
+    #[actix_rt::test]
+    async fn subscriptions_reflect_joined_rooms() {
+        let addr = Websocket::default().start();
+        let fresh = addr.send(ListSubscriptions { id: 1 }).await.unwrap();
+        assert_eq!(fresh, Subscriptions::default());
+        assert_eq!(
+            serde_json::to_string(&fresh).unwrap(),
+            r#"{"rooms":[],"watching":[],"event_filter":[]}"#
+        );
+
+        for room in ["ptolemy", "celestial-being"] {
+            addr.send(Join {
+                id: 1,
+                name: "sumeragi".to_string(),
+                room: Room(room.to_string()),
+            })
+            .await
+            .unwrap();
+        }
+        addr.send(Leave {
+            id: 1,
+            name: "sumeragi".to_string(),
+            room: Room("ptolemy".to_string()),
+        })
+        .await
+        .unwrap();
+
+        let subscriptions = addr.send(ListSubscriptions { id: 1 }).await.unwrap();
+        assert_eq!(subscriptions.rooms, vec!["celestial-being"]);
+        let other = addr.send(ListSubscriptions { id: 2 }).await.unwrap();
+        assert!(other.rooms.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn status_reaches_room_mates() {
+        let addr = Websocket::default().start();
+        let mut collectors = vec![];
+        for id in 1..=3 {
+            let collector = Collector::default();
+            let connected = addr
+                .send(Connect {
+                    addr: collector.clone().start().recipient(),
+                    kick: collector.clone().start().recipient(),
+                    origin: Origin::default(),
+                })
+                .await
+                .unwrap();
+            // the last session stays out of the room
+            if id < 3 {
+                addr.send(Join {
+                    id: connected,
+                    name: format!("meister-{}", id),
+                    room: Room("trinity".to_string()),
+                })
+                .await
+                .unwrap();
+            }
+            collectors.push((connected, collector));
+        }
+
+        addr.send(StatusChanged {
+            id: collectors[0].0,
+            name: "meister-1".to_string(),
+            status: Some("in a meeting".to_string()),
+        })
+        .await
+        .unwrap();
+        actix_rt::time::sleep(Duration::from_millis(50)).await;
+
+        let expected = r#"{"event":"presence-status","name":"meister-1","status":"in a meeting"}"#;
+        assert_eq!(collectors[0].1.received(), vec![expected]);
+        assert_eq!(collectors[1].1.received(), vec![expected]);
+        assert!(collectors[2].1.received().is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn flapping_status_is_announced_once() {
+        let addr = Websocket::default()
+            .presence_debounce(Duration::from_millis(100))
+            .start();
+        let mut collectors = vec![];
+        for id in 1..=2 {
+            let collector = Collector::default();
+            let connected = addr
+                .send(Connect {
+                    addr: collector.clone().start().recipient(),
+                    kick: collector.clone().start().recipient(),
+                    origin: Origin::default(),
+                })
+                .await
+                .unwrap();
+            addr.send(Join {
+                id: connected,
+                name: format!("meister-{}", id),
+                room: Room("trinity".to_string()),
+            })
+            .await
+            .unwrap();
+            collectors.push((connected, collector));
+        }
+
+        for status in [Some("online"), None, Some("online"), None, Some("away")] {
+            addr.send(StatusChanged {
+                id: collectors[0].0,
+                name: "meister-1".to_string(),
+                status: status.map(str::to_string),
+            })
+            .await
+            .unwrap();
+        }
+        actix_rt::time::sleep(Duration::from_millis(50)).await;
+        assert!(collectors[1].1.received().is_empty());
+
+        actix_rt::time::sleep(Duration::from_millis(100)).await;
+        let expected = r#"{"event":"presence-status","name":"meister-1","status":"away"}"#;
+        assert_eq!(collectors[0].1.received(), vec![expected]);
+        assert_eq!(collectors[1].1.received(), vec![expected]);
+    }
+
+    #[actix_rt::test]
+    async fn drained_sessions_are_told_to_reconnect_then_closed() {
+        let addr = Websocket::default().shards(2).start();
+        let mut collectors = vec![];
+        for _ in 0..2 {
+            let collector = Collector::default();
+            addr.send(Connect {
+                addr: collector.clone().start().recipient(),
+                kick: collector.clone().start().recipient(),
+                origin: Origin::default(),
+            })
+            .await
+            .unwrap();
+            collectors.push(collector);
+        }
+
+        addr.send(DrainConnections {
+            reconnect_after_ms: 5000,
+        })
+        .await
+        .unwrap();
+        actix_rt::time::sleep(Duration::from_millis(50)).await;
+        for collector in &collectors {
+            let received = collector.received();
+            assert_eq!(received.len(), 1);
+            let frame: Value = serde_json::from_str(&received[0]).unwrap();
+            assert_eq!(frame["type"], "reconnect");
+            assert!(frame["after_ms"].as_u64().unwrap() <= 5000);
+        }
+
+        actix_rt::time::sleep(DRAIN_CLOSE_DELAY).await;
+        for collector in &collectors {
+            let received = collector.received();
+            assert_eq!(received.len(), 2);
+            assert_eq!(received[1], "kicked: draining, reconnect elsewhere");
+        }
+    }
+
+    #[actix_rt::test]
+    async fn broadcast_reaches_every_shard() {
+        let addr = Websocket::default().shards(4).start();
+        let mut collectors = vec![];
+        for _ in 0..16 {
+            let collector = Collector::default();
+            let id = addr
+                .send(Connect {
+                    addr: collector.clone().start().recipient(),
+                    kick: collector.clone().start().recipient(),
+                    origin: Origin::default(),
+                })
+                .await
+                .unwrap();
+            collectors.push((id, collector));
+        }
+        let shards: HashSet<usize> = collectors
+            .iter()
+            .map(|(id, _)| shard_index(*id, 4))
+            .collect();
+        assert!(shards.len() > 1);
+
+        addr.send(Broadcast {
+            message: "all hands".to_string(),
+        })
+        .await
+        .unwrap();
+        actix_rt::time::sleep(Duration::from_millis(50)).await;
+
+        for (_, collector) in &collectors {
+            assert_eq!(collector.received(), vec!["all hands"]);
+        }
+        let listed = addr.send(ListNames { admin: true }).await.unwrap();
+        assert_eq!(listed.len(), collectors.len());
+    }
+
+    #[actix_rt::test]
+    async fn a_flooding_session_does_not_starve_others() {
+        let redis_addr = Redis::new(MemoryStore::default()).start();
+        let websocket_addr = Websocket::default().start();
+        let config = Arc::new(LiveConfig::new(Config {
+            command_rate: 5,
+            ..CONFIG.clone()
+        }));
+        let mut noisy = WebsocketSession {
+            config: config.clone(),
+            ..session(1, "hixen", redis_addr.clone(), websocket_addr.clone())
+        };
+        let mut quiet = WebsocketSession {
+            config: config.clone(),
+            ..session(2, "halleluja", redis_addr, websocket_addr)
+        };
+        // the rest never reaches the actors
+        assert_eq!((0..1000).filter(|_| noisy.admit()).count(), 5);
+        assert!(quiet.admit());
+
+        // a reloaded rate applies right away
+        config.store(Config {
+            command_rate: 0,
+            ..CONFIG.clone()
+        });
+        assert!((0..1000).all(|_| noisy.admit()));
+    }
+
+    #[actix_rt::test]
+    async fn session_info_returns_the_origin() {
+        let addr = Websocket::default().start();
+        let origin = Origin {
+            ip: Some("203.0.113.7".to_string()),
+            user_agent: Some("veda-test".to_string()),
+        };
+        let id = addr
+            .send(Connect {
+                addr: Collector::default().start().recipient(),
+                kick: Collector::default().start().recipient(),
+                origin: origin.clone(),
+            })
+            .await
+            .unwrap();
+        addr.send(IdentitySession {
+            id,
+            name: "marina".to_string(),
+            tenant: None,
+        })
+        .await
+        .unwrap();
+
+        let info = addr.send(SessionInfo { id }).await.unwrap();
+        assert_eq!(
+            info,
+            Some(SessionDetails {
+                id,
+                name: Some("marina".to_string()),
+                origin,
+            })
+        );
+
+        addr.send(Disconnect { id }).await.unwrap();
+        assert_eq!(addr.send(SessionInfo { id }).await.unwrap(), None);
+    }
+
+    #[actix_rt::test]
+    async fn list_names_returns_structured_sessions() {
+        let addr = Websocket::default().private_names(true).start();
+        let before = Utc::now();
+        let mut ids = vec![];
+        for _ in 0..2 {
+            let id = addr
+                .send(Connect {
+                    addr: Collector::default().start().recipient(),
+                    kick: Collector::default().start().recipient(),
+                    origin: Origin::default(),
+                })
+                .await
+                .unwrap();
+            ids.push(id);
+        }
+        addr.send(IdentitySession {
+            id: ids[0],
+            name: "christina".to_string(),
+            tenant: None,
+        })
+        .await
+        .unwrap();
+        addr.send(SessionPlatform {
+            id: ids[0],
+            platform: "Web".to_string(),
+        })
+        .await
+        .unwrap();
+
+        let sessions = addr.send(ListNames { admin: true }).await.unwrap();
+        assert_eq!(
+            sessions.iter().map(|s| s.id).collect::<Vec<_>>(),
+            ids.clone()
+        );
+        assert_eq!(sessions[0].identity.as_deref(), Some("christina"));
+        assert_eq!(sessions[0].platform.as_deref(), Some("Web"));
+        assert_eq!(sessions[1].identity, None);
+        assert!(sessions
+            .iter()
+            .all(|s| s.connected_since >= before && s.connected_since <= Utc::now()));
+
+        // identities are hidden from everyone else
+        let redacted = addr.send(ListNames { admin: false }).await.unwrap();
+        assert!(redacted.iter().all(|s| s.identity.is_none()));
+        assert_eq!(redacted[0].platform.as_deref(), Some("Web"));
+    }
+
+    /// a logged in session that is not started
+    fn session(
+        id: usize,
+        name: &str,
+        redis_addr: Addr<Redis>,
+        websocket_addr: Addr<Websocket>,
+    ) -> WebsocketSession {
+        WebsocketSession {
+            id,
+            name: Some(name.to_string()),
+            hb: Instant::now(),
+            ping_at: Instant::now(),
+            rtt: None,
+            heartbeat: AdaptiveHeartbeat::new(HEARTBEAT_INTERVAL, HEARTBEAT_INTERVAL),
+            redis_addr: redis_addr.clone(),
+            websocket_addr: websocket_addr.clone(),
+            grpc_addr: Seravee {
+                addr: "127.0.0.1:0".parse().unwrap(),
+                redis_addr: redis_addr.clone(),
+            }
+            .start(),
+            backlog_summary: false,
+            framing: FramingMode::Array,
+            batch_size: None,
+            capabilities: HashSet::new(),
+            delivery: DeliveryMode::AtLeastOnce,
+            fields: None,
+            echo: true,
+            origin: Origin::default(),
+            frame_logging: FrameLogMode::Off,
+            quality_interval: None,
+            reports: Throttle::new(REPORT_RATE),
+            config: Arc::new(LiveConfig::new(CONFIG.clone())),
+            commands: None,
+            requests: PendingRequests::default(),
+            guard: SessionGuard::new(redis_addr, websocket_addr),
+            authorizer: Arc::new(AdminCommands),
+            role: Role::User,
+            register_timeout: REGISTER_TIMEOUT,
+            protocol: ProtocolVersion::default(),
+            tenant: None,
+        }
+    }
+
+    #[actix_rt::test]
+    async fn a_panicking_session_still_goes_offline() {
+        let redis_addr = Redis::new(MemoryStore::default()).start();
+        let websocket_addr = Websocket::default().start();
+        let collector = Collector::default();
+        let id = websocket_addr
+            .send(Connect {
+                addr: collector.clone().start().recipient(),
+                kick: collector.clone().start().recipient(),
+                origin: Origin::default(),
+            })
+            .await
+            .unwrap();
+        redis_addr
+            .send(Online {
+                id,
+                name: "hallelujah".to_string(),
+                addr: collector.clone().start().recipient(),
+                kick: collector.clone().start().recipient(),
+                ask: collector.clone().start().recipient(),
+                backlog_summary: false,
+                framing: FramingMode::default(),
+                batch_size: None,
+                capabilities: HashSet::new(),
+                delivery: DeliveryMode::default(),
+                fields: None,
+                echo: true,
+            })
+            .await
+            .unwrap();
+        let mut session = session(id, "hallelujah", redis_addr.clone(), websocket_addr.clone());
+        session.guard.arm(id);
+
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(move || {
+            let _session = session;
+            panic!("a bug in a handler");
+        }));
+        assert!(panicked.is_err());
+
+        actix_rt::time::sleep(Duration::from_millis(50)).await;
+        assert!(redis_addr.send(ListSessions).await.unwrap().is_empty());
+        let sessions = websocket_addr
+            .send(ListNames { admin: true })
+            .await
+            .unwrap();
+        assert!(sessions.iter().all(|session| session.id != id));
+    }
+
+    #[actix_rt::test]
+    async fn hello_repeats_the_welcome_and_subscriptions() {
+        let redis_addr = Redis::new(MemoryStore::default()).start();
+        let websocket_addr = Websocket::default().start();
+        let id = websocket_addr
+            .send(Connect {
+                addr: Collector::default().start().recipient(),
+                kick: Collector::default().start().recipient(),
+                origin: Origin::default(),
+            })
+            .await
+            .unwrap();
+        for room in &["ptolemy", "trinity"] {
+            websocket_addr
+                .send(Join {
+                    id,
+                    name: "sumeragi".to_string(),
+                    room: Room(room.to_string()),
+                })
+                .await
+                .unwrap();
+        }
+        let session = WebsocketSession {
+            delivery: DeliveryMode::AtMostOnce,
+            batch_size: Some(5),
+            ..session(id, "sumeragi", redis_addr, websocket_addr)
+        };
+
+        let before = Utc::now().timestamp_millis();
+        let hello = serde_json::to_value(session.hello().await.unwrap()).unwrap();
+        assert_eq!(hello["id"], id);
+        assert_eq!(hello["name"], "sumeragi");
+        assert!(hello["server_time"]["server_ts"].as_i64().unwrap() >= before);
+        assert_eq!(hello["framing"], "array");
+        assert_eq!(hello["delivery"], "at_most_once");
+        assert_eq!(hello["batch_size"], 5);
+        assert_eq!(
+            hello["subscriptions"]["rooms"],
+            serde_json::json!(["ptolemy", "trinity"])
+        );
+    }
+
+    #[actix_rt::test]
+    async fn a_large_history_page_is_gzipped_for_clients_that_take_it() {
+        let mut store = MemoryStore::default();
+        let mut ids = vec![];
+        for n in 0..20 {
+            let activity = Activity {
+                activity: format!("{}{}", n, "0".repeat(2000)),
+                ..Default::default()
+            };
+            ids.push(store.xadd("veda-activity:tieria", &activity).unwrap());
+        }
+        let redis_addr = Redis::new(store).start();
+        let websocket_addr = Websocket::default().start();
+        let page = redis_addr
+            .send(ListHistory {
+                name: "tieria".to_string(),
+                before_id: format!("{}-0", u64::MAX),
+                count: 20,
+            })
+            .await
+            .unwrap();
+        let text = serde_json::to_string(&page).unwrap();
+        assert!(text.len() > COMPRESS_THRESHOLD);
+
+        let plain = session(1, "tieria", redis_addr.clone(), websocket_addr.clone());
+        assert!(matches!(
+            plain.large_frame(text.clone()),
+            ws::Message::Text(_)
+        ));
+
+        let session = WebsocketSession {
+            capabilities: vec![GZIP_CAPABILITY.to_string()].into_iter().collect(),
+            ..session(2, "tieria", redis_addr, websocket_addr)
+        };
+        assert!(matches!(
+            session.large_frame("{}".to_string()),
+            ws::Message::Text(_)
+        ));
+        let gzipped = match session.large_frame(text) {
+            ws::Message::Binary(gzipped) => gzipped,
+            other => panic!("not gzipped: {:?}", other),
+        };
+        assert!(gzipped.len() < COMPRESS_THRESHOLD);
+        let mut decompressed = String::new();
+        GzDecoder::new(&gzipped[..])
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        let decompressed: serde_json::Value = serde_json::from_str(&decompressed).unwrap();
+        let activities = decompressed["activities"].as_array().unwrap();
+        assert_eq!(activities.len(), 20);
+        assert_eq!(activities[0]["id"], ids[19].as_str());
+        assert!(activities[0]["activity"]
+            .as_str()
+            .unwrap()
+            .starts_with("19"));
+        assert_eq!(decompressed["end"], true);
+        assert_eq!(page.activities.len(), 20);
+    }
+
+    /// takes a frame and never finishes sending it, keeps what it took
+    #[derive(Clone, Default)]
+    struct Stalled(Arc<std::sync::Mutex<Vec<String>>>);
+
+    impl Actor for Stalled {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<WsMessage> for Stalled {
+        type Result = ResponseFuture<()>;
+
+        fn handle(&mut self, msg: WsMessage, _: &mut Self::Context) -> Self::Result {
+            self.0.lock().unwrap().push(msg.0);
+            Box::pin(futures::future::pending())
+        }
+    }
+
+    #[actix_rt::test]
+    async fn flush_sends_the_frames_stuck_behind_a_slow_one() {
+        let redis_addr = Redis::new(MemoryStore::default()).start();
+        let websocket_addr = Websocket::default().start();
+        let stalled = Stalled::default();
+        let id = websocket_addr
+            .send(Connect {
+                addr: stalled.clone().start().recipient(),
+                kick: Collector::default().start().recipient(),
+                origin: Origin::default(),
+            })
+            .await
+            .unwrap();
+        for n in 0..3 {
+            websocket_addr
+                .send(Broadcast {
+                    message: n.to_string(),
+                })
+                .await
+                .unwrap();
+        }
+        actix_rt::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(*stalled.0.lock().unwrap(), vec!["0"]);
+
+        let session = session(id, "tieria", redis_addr, websocket_addr);
+        assert_eq!(
+            session.flush(false).await.unwrap(),
+            Flushed {
+                kind: "flushed",
+                count: 2
+            }
+        );
+        actix_rt::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(*stalled.0.lock().unwrap(), vec!["0", "1", "2"]);
+        // nothing is left to discard
+        let discarded = serde_json::to_value(session.flush(true).await.unwrap()).unwrap();
+        assert_eq!(
+            discarded,
+            serde_json::json!({"type": "discarded", "count": 0})
+        );
+    }
+
+    #[actix_rt::test]
+    async fn quality_reports_the_rtt_and_the_frames_waiting() {
+        let redis_addr = Redis::new(MemoryStore::default()).start();
+        let websocket_addr = Websocket::default().start();
+        let id = websocket_addr
+            .send(Connect {
+                addr: Stalled::default().start().recipient(),
+                kick: Collector::default().start().recipient(),
+                origin: Origin::default(),
+            })
+            .await
+            .unwrap();
+        let session = WebsocketSession {
+            quality_interval: Some(Duration::from_secs(5)),
+            ..session(id, "tieria", redis_addr.clone(), websocket_addr.clone())
+        };
+        let quality = session.quality().await.unwrap();
+        assert_eq!(
+            quality,
+            Quality {
+                kind: "quality",
+                rtt_ms: None,
+                queue: 0
+            }
+        );
+
+        for n in 0..3 {
+            websocket_addr
+                .send(Broadcast {
+                    message: n.to_string(),
+                })
+                .await
+                .unwrap();
+        }
+        actix_rt::time::sleep(Duration::from_millis(50)).await;
+        let session = WebsocketSession {
+            rtt: Some(Duration::from_millis(42)),
+            ..session
+        };
+        let quality = serde_json::to_value(session.quality().await.unwrap()).unwrap();
+        // one frame is being sent, two wait behind it
+        assert_eq!(
+            quality,
+            serde_json::json!({"type": "quality", "rtt_ms": 42, "queue": 3})
+        );
+    }
+
+    #[actix_rt::test]
+    async fn diag_reports_the_session_state() {
+        let mut store = MemoryStore::default();
+        for _ in 0..3 {
+            store
+                .xadd("veda-activity:lockon", &Activity::default())
+                .unwrap();
+        }
+        let redis_addr = Redis::new(store).start();
+        let websocket_addr = Websocket::default().start();
+        let id = websocket_addr
+            .send(Connect {
+                addr: Collector::default().start().recipient(),
+                kick: Collector::default().start().recipient(),
+                origin: Origin::default(),
+            })
+            .await
+            .unwrap();
+        websocket_addr
+            .send(Join {
+                id,
+                name: "lockon".to_string(),
+                room: Room("dynames".to_string()),
+            })
+            .await
+            .unwrap();
+
+        let session = WebsocketSession {
+            rtt: Some(Duration::from_millis(42)),
+            framing: FramingMode::Ndjson,
+            ..session(id, "lockon", redis_addr, websocket_addr)
+        };
+        let before = Utc::now();
+        let report = session.diagnostics().await.unwrap();
+
+        assert_eq!(report.id, id);
+        assert_eq!(report.name.as_deref(), Some("lockon"));
+        assert_eq!(report.framing, FramingMode::Ndjson);
+        assert_eq!(report.batch_size, READ_COUNT);
+        assert_eq!(report.rtt_ms, Some(42));
+        assert_eq!(report.pending, 3);
+        assert_eq!(report.subscriptions.rooms, vec!["dynames".to_string()]);
+        assert!(report.server_time >= before);
+
+        let json: serde_json::Value = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["framing"], "ndjson");
+        assert_eq!(json["subscriptions"]["rooms"][0], "dynames");
+    }
+
+    #[test]
+    fn help_lists_every_command_and_format() {
+        let help = serde_json::to_value(Help::new()).unwrap();
+        assert_eq!(help["protocol"], PROTOCOL_VERSION);
+        let commands: Vec<&str> = help["commands"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|command| command.as_str().unwrap())
+            .collect();
+        assert_eq!(commands, COMMANDS);
+        assert!(commands.contains(&"/help"));
+        assert_eq!(help["framings"], serde_json::json!(["array", "ndjson"]));
+        assert_eq!(
+            help["deliveries"],
+            serde_json::json!(["at_least_once", "at_most_once"])
+        );
+        assert_eq!(help["fields"][0], "activity_type");
+        assert_eq!(
+            help["deprecated"][0],
+            serde_json::json!(["/batchsize", "/batch-size"])
+        );
+    }
+
+    #[test]
+    fn an_old_command_name_is_answered_with_a_deprecation_notice() {
+        let notice: serde_json::Value =
+            serde_json::from_str(&deprecation_notice("/batchsize 2").unwrap()).unwrap();
+        assert_eq!(
+            notice,
+            serde_json::json!({
+                "type": "deprecated",
+                "command": "/batchsize",
+                "replaced_by": "/batch-size",
+            })
+        );
+        assert_eq!(deprecation_notice("/batch-size 2"), None);
+        // the old name runs the same command
+        assert_eq!("/batchsize 2".parse::<Command>(), Ok(Command::BatchSize(2)));
+    }
+
+    #[actix_rt::test]
+    async fn a_slow_server_closes_the_connection_instead_of_hanging() {
+        let redis_addr = Redis::new(MemoryStore::default()).start();
+        // the thread of the socket server is busy for longer than the session waits
+        let busy = Arbiter::new();
+        let websocket_addr = Websocket::start_in_arbiter(&busy.handle(), |_| Websocket::default());
+        let busy_for = Duration::from_secs(5);
+        let (blocked, is_blocked) = futures::channel::oneshot::channel();
+        busy.spawn(async move {
+            let _ = blocked.send(());
+            std::thread::sleep(busy_for)
+        });
+        is_blocked.await.unwrap();
+        let session = WebsocketSession {
+            register_timeout: Duration::from_millis(100),
+            ..session(0, "setsuna", redis_addr, websocket_addr)
+        };
+
+        let started = Instant::now();
+        let frames: Vec<_> = ws::WebsocketContext::create(session, futures::stream::pending())
+            .map(|frame| frame.unwrap())
+            .collect()
+            .await;
+        assert!(started.elapsed() < busy_for);
+        let frames = frames.concat();
+        // a close frame: fin and opcode 8, the length, then the code and the reason
+        assert_eq!(frames[0], 0x88);
+        assert_eq!(u16::from_be_bytes([frames[2], frames[3]]), 1013);
+        assert_eq!(&frames[4..], b"registration timed out");
+        busy.stop();
+    }
+
+    /// a masked client frame, the mask is all zeros so the payload goes as is
+    fn client_frame(opcode: u8, payload: &[u8]) -> actix_web::web::Bytes {
+        let mut frame = vec![0x80 | opcode, 0x80 | payload.len() as u8, 0, 0, 0, 0];
+        frame.extend_from_slice(payload);
+        frame.into()
+    }
+
+    #[actix_rt::test]
+    async fn the_server_pings_at_the_keepalive_the_client_asked_for() {
+        let redis_addr = Redis::new(MemoryStore::default()).start();
+        let websocket_addr = Websocket::default().start();
+        let session = WebsocketSession {
+            heartbeat: AdaptiveHeartbeat::new(Duration::from_secs(1), HEARTBEAT_INTERVAL),
+            ..session(0, "lockon", redis_addr, websocket_addr)
+        };
+        // the client answers every ping in time
+        let pongs = futures::stream::unfold((), |_| async {
+            actix_rt::time::sleep(Duration::from_millis(300)).await;
+            Some((Ok(client_frame(0xA, b"")), ()))
+        });
+        let input =
+            futures::stream::iter(vec![Ok(client_frame(0x1, b"/keepalive 1"))]).chain(pongs);
+
+        let frames: Vec<_> = ws::WebsocketContext::create(session, input)
+            .take_until(actix_rt::time::sleep(Duration::from_millis(3500)))
+            .map(|frame| frame.unwrap())
+            .collect()
+            .await;
+        let frames = server_frames(&frames.concat());
+        let pings = frames.iter().filter(|(opcode, _)| *opcode == 0x89).count();
+        let texts: Vec<&str> = frames
+            .iter()
+            .filter(|(opcode, _)| *opcode == 0x81)
+            .map(|(_, text)| text.as_str())
+            .collect();
+        assert_eq!(texts, vec![r#"{"type":"keepalive","interval_s":1}"#]);
+        assert_eq!(pings, 3);
+    }
+
+    /// the opcodes and payloads of server frames: not masked, and short here
+    fn server_frames(bytes: &[u8]) -> Vec<(u8, String)> {
+        let (mut frames, mut at) = (vec![], 0);
+        while at < bytes.len() {
+            let len = bytes[at + 1] as usize;
+            let payload = String::from_utf8_lossy(&bytes[at + 2..at + 2 + len]).to_string();
+            frames.push((bytes[at], payload));
+            at += 2 + len;
+        }
+        frames
+    }
+
+    #[actix_rt::test]
+    async fn errors_take_the_shape_of_the_negotiated_protocol() {
+        let redis_addr = Redis::new(MemoryStore::default()).start();
+        let websocket_addr = Websocket::default().start();
+        let mut errors = vec![];
+        for protocol in [ProtocolVersion::V1, ProtocolVersion::V2] {
+            let session = WebsocketSession {
+                protocol,
+                ..session(0, "ribbons", redis_addr.clone(), websocket_addr.clone())
+            };
+            let input = futures::stream::iter(vec![Ok(client_frame(0x1, b"/flush all"))])
+                .chain(futures::stream::pending());
+            let frames: Vec<_> = ws::WebsocketContext::create(session, input)
+                .take_until(actix_rt::time::sleep(Duration::from_millis(200)))
+                .map(|frame| frame.unwrap())
+                .collect()
+                .await;
+            errors.extend(server_frames(&frames.concat()));
+        }
+        assert_eq!(
+            errors,
+            vec![
+                (0x81, "!!! only discard is allowed".to_string()),
+                (
+                    0x81,
+                    r#"{"type":"error","message":"only discard is allowed"}"#.to_string()
+                ),
+            ]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn same_names_in_two_tenants_stay_apart() {
+        let websocket_addr = Websocket::default().exclusive_names(true).start();
+        let mut names = vec![];
+        for (id, tenant) in [(1, "celestial"), (2, "innovade")] {
+            let result = websocket_addr
+                .send(IdentitySession {
+                    id,
+                    name: "alice".to_string(),
+                    tenant: Some(tenant.to_string()),
+                })
+                .await
+                .unwrap();
+            match result {
+                IdentityResult::Set { name, .. } => names.push(name),
+                other => panic!("{:?}", other),
+            }
+        }
+        assert_eq!(names, vec!["t/celestial/alice", "t/innovade/alice"]);
+
+        let mut store = MemoryStore::default();
+        store
+            .xadd("veda-activity:t/celestial/alice", &Activity::default())
+            .unwrap();
+        let redis_addr = Redis::new(store).start();
+        let mut collectors = vec![];
+        for (id, name) in names.iter().enumerate() {
+            let collector = Collector::default();
+            redis_addr
+                .send(Online {
+                    id,
+                    name: name.clone(),
+                    addr: collector.clone().start().recipient(),
+                    kick: collector.clone().start().recipient(),
+                    ask: collector.clone().start().recipient(),
+                    backlog_summary: false,
+                    framing: FramingMode::default(),
+                    batch_size: None,
+                    capabilities: HashSet::new(),
+                    delivery: DeliveryMode::default(),
+                    fields: None,
+                    echo: true,
+                })
+                .await
+                .unwrap();
+            collectors.push(collector);
+        }
+        redis_addr.do_send(SetStatus {
+            name: "t/celestial/alice".to_string(),
+            status: Some("away".to_string()),
         });
+        actix_rt::time::sleep(MESSAGE_INTERVAL * 2).await;
+        // the backlog, maybe followed by `caught_up`
+        assert!(collectors[0].received()[0].starts_with("[{"));
+        assert!(collectors[1].received().is_empty());
+
+        // `/presence alice` asks within the tenant of the session
+        let innovade = WebsocketSession {
+            tenant: Some("innovade".to_string()),
+            ..session(2, "t/innovade/alice", redis_addr.clone(), websocket_addr)
+        };
+        let name = innovade.scoped("alice".to_string());
+        let presence = redis_addr.send(Presence { name }).await.unwrap();
+        assert_eq!(presence.name, "t/innovade/alice");
+        assert!(presence.online);
+        assert_eq!(presence.status, None);
+        let presence = redis_addr
+            .send(Presence {
+                name: "t/celestial/alice".to_string(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(presence.status.as_deref(), Some("away"));
+    }
+
+    #[test]
+    fn tenants_cannot_reach_the_keys_of_plain_names() {
+        let redis = Redis::new(MemoryStore::default()).stream_shards(4);
+        // tenant `room` with user `lobby` against the room `lobby`
+        assert_ne!(
+            redis.key_activity(&scoped(Some("room"), "lobby".to_string())),
+            redis.key_room_activity(&Room("lobby".to_string()))
+        );
+        // tenant `alice` with user `0` against the shard 0 of `alice`
+        let alice = redis.keys_activity("alice");
+        for key in redis.keys_activity(&scoped(Some("alice"), "0".to_string())) {
+            assert!(!alice.contains(&key), "{}", key);
+        }
+        // a name cannot spell out a scoped one
+        assert!(validate_name("t/alice/0", "name", MAX_NAME_LEN).is_err());
+    }
+
+    #[actix_rt::test]
+    async fn a_selftest_comes_back_to_the_session() {
+        let redis_addr = Redis::new(MemoryStore::default()).start();
+        let websocket_addr = Websocket::default().start();
+        let collector = Collector::default();
+        redis_addr
+            .send(Online {
+                id: 4,
+                name: "tieria".to_string(),
+                addr: collector.clone().start().recipient(),
+                kick: collector.clone().start().recipient(),
+                ask: collector.clone().start().recipient(),
+                backlog_summary: false,
+                framing: FramingMode::default(),
+                batch_size: None,
+                capabilities: HashSet::new(),
+                delivery: DeliveryMode::default(),
+                fields: None,
+                echo: true,
+            })
+            .await
+            .unwrap();
+        assert_eq!("/selftest".parse::<Command>(), Ok(Command::SelfTest));
+        let session = session(4, "tieria", redis_addr.clone(), websocket_addr);
+        let pushed = redis_addr.send(session.self_test("tieria")).await.unwrap();
+        assert_eq!(pushed.len(), 1);
+        actix_rt::time::sleep(MESSAGE_INTERVAL * 2).await;
+
+        let received = collector.received();
+        assert_eq!(received.len(), 1, "{:?}", received);
+        let frame: serde_json::Value = serde_json::from_str(&received[0]).unwrap();
+        assert_eq!(frame[0]["activity_type"], SELFTEST_TYPE);
+        let activity: serde_json::Value =
+            serde_json::from_str(frame[0]["activity"].as_str().unwrap()).unwrap();
+        assert_eq!(activity["session_id"], 4);
+        assert!(frame[0]["correlation_id"].is_string());
+    }
+
+    #[actix_rt::test]
+    async fn only_admins_broadcast() {
+        let redis_addr = Redis::new(MemoryStore::default()).start();
+        let websocket_addr = Websocket::default().start();
+        let broadcast = Command::Broadcast("all hands".to_string());
+
+        // the name does not make an admin, only the token of the upgrade request does
+        let crew = session(1, "sumeragi", redis_addr.clone(), websocket_addr.clone());
+        assert_eq!(
+            crew.denied(&broadcast).as_deref(),
+            Some("!!! permission denied: /broadcast")
+        );
+        assert_eq!(crew.denied(&Command::List), None);
+
+        let captain = WebsocketSession {
+            role: Role::Admin,
+            ..session(2, "sumeragi", redis_addr, websocket_addr)
+        };
+        assert_eq!(captain.denied(&broadcast), None);
+    }
+
+    #[test]
+    fn metadata_logging_leaves_the_content_out() {
+        let msg = ws::Message::Text("/login hunter2".into());
+
+        assert_eq!(frame_log(FrameLogMode::Off, 7, &msg), None);
+        let metadata = frame_log(FrameLogMode::Metadata, 7, &msg).unwrap();
+        assert!(metadata.contains("text 14 bytes"), "{}", metadata);
+        assert!(!metadata.contains("hunter2"), "{}", metadata);
+        let full = frame_log(FrameLogMode::Full, 7, &msg).unwrap();
+        assert!(full.contains("hunter2"), "{}", full);
+    }
+
+    #[test]
+    fn server_time_is_current() {
+        let before = Utc::now().timestamp_millis();
+        let first = ServerTime::now();
+        let second = ServerTime::now();
+        let after = Utc::now().timestamp_millis();
+
+        assert!(first.server_ts >= before && first.server_ts <= after);
+        assert!(second.monotonic >= first.monotonic);
+        let json: serde_json::Value = serde_json::to_value(&first).unwrap();
+        assert_eq!(json["server_ts"], first.server_ts);
+        assert!(json["monotonic"].is_u64());
     }
 }
@@ -0,0 +1,74 @@
+use std::{
+    io::{self, Write},
+    mem,
+};
+
+use flate2::{write::GzEncoder, Compression};
+
+/// gzip `body`, for large one-shot responses sent apart from the delivery framing
+pub fn gzip(body: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::with_capacity(body.len() / 4), Compression::fast());
+    encoder.write_all(body)?;
+    encoder.finish()
+}
+
+/// gzip a body written one chunk at a time, each chunk is flushed out as soon as it is written
+pub struct GzipChunks(GzEncoder<Vec<u8>>);
+
+impl Default for GzipChunks {
+    fn default() -> Self {
+        Self(GzEncoder::new(Vec::new(), Compression::fast()))
+    }
+}
+
+impl GzipChunks {
+    /// the compressed bytes of `chunk`, the client inflates them without waiting for the rest
+    pub fn chunk(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>> {
+        self.0.write_all(chunk)?;
+        self.0.flush()?;
+        Ok(mem::take(self.0.get_mut()))
+    }
+
+    /// the gzip trailer, after the last chunk
+    pub fn finish(self) -> io::Result<Vec<u8>> {
+        self.0.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+
+    use super::*;
+
+    #[test]
+    fn gzipped_bodies_decompress_to_themselves() {
+        let body = "trans-am ".repeat(1000);
+        let gzipped = gzip(body.as_bytes()).unwrap();
+        assert!(gzipped.len() < body.len());
+
+        let mut decompressed = String::new();
+        GzDecoder::new(&gzipped[..])
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, body);
+    }
+
+    #[test]
+    fn gzipped_chunks_decompress_to_the_whole_body() {
+        let mut chunks = GzipChunks::default();
+        let mut gzipped = vec![];
+        for chunk in &["trans-am\n", "gn-drive\n", "quanta\n"] {
+            gzipped.extend(chunks.chunk(chunk.as_bytes()).unwrap());
+        }
+        gzipped.extend(chunks.finish().unwrap());
+
+        let mut decompressed = String::new();
+        GzDecoder::new(&gzipped[..])
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, "trans-am\ngn-drive\nquanta\n");
+    }
+}
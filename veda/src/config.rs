@@ -1,12 +1,210 @@
 use dotenv::dotenv;
 use serde::Deserialize;
+use std::sync::{Arc, RwLock};
 #[derive(Clone, Deserialize, Debug)]
 pub struct Config {
     pub redis_url: String,
+    /// sent with `AUTH` instead of being part of `redis_url`
+    #[serde(default)]
+    pub redis_username: Option<String>,
+    #[serde(default)]
+    pub redis_password: Option<String>,
+    /// connect to redis over tls, like a `rediss://` url
+    #[serde(default)]
+    pub redis_tls: bool,
     pub grpc_url: String,
+    #[allow(dead_code)]
     pub backtrace: u8,
     pub log: String,
     pub server: String,
+    /// maintain the `last-seen` sorted set and sweep stale sessions
+    #[serde(default)]
+    pub track_last_seen: bool,
+    /// seconds between two heartbeat writes of `last-seen:{username}`, 0 for `LAST_SEEN_WRITE_INTERVAL`
+    #[serde(default)]
+    pub last_seen_write_secs: u64,
+    /// a name can only be held by one websocket session
+    #[serde(default)]
+    pub exclusive_names: bool,
+    /// sessions one name may be held by, 0 for no limit
+    #[serde(default)]
+    pub max_sessions_per_user: usize,
+    /// what happens to a session that would go over `max_sessions_per_user`
+    #[serde(default)]
+    pub session_limit: SessionLimitPolicy,
+    /// hide identities when non-admins list the sessions
+    #[serde(default)]
+    pub private_names: bool,
+    /// keep the errors clients send with `/report` in the `client-errors` stream
+    #[serde(default)]
+    pub store_client_reports: bool,
+    /// keep what users `/mute` in redis, so it outlasts their sessions
+    #[serde(default)]
+    pub persist_mutes: bool,
+    /// a webhook that did not take an activity is posted this many more times before it is buried
+    #[serde(default)]
+    pub webhook_retries: u32,
+    /// milliseconds before the first webhook retry, doubled for every further retry
+    #[serde(default)]
+    pub webhook_backoff_ms: u64,
+    /// bytes of activities bundled into one frame, 0 for `MAX_FRAME_BYTES`
+    #[serde(default)]
+    pub max_frame_bytes: usize,
+    /// streams a user's activities are spread over, 0 counts as 1
+    #[serde(default)]
+    pub stream_shards: usize,
+    /// receivers a push writes before other redis messages get a turn, 0 for `TRIAL_CHUNK_SIZE`
+    #[serde(default)]
+    pub trial_chunk_size: usize,
+    /// seconds between two samples of the stream backlog, 0 for `BACKLOG_SAMPLE_INTERVAL`
+    #[serde(default)]
+    pub backlog_sample_secs: u64,
+    /// a sampled stream longer than this is logged, 0 for `BACKLOG_WARN_LEN`
+    #[serde(default)]
+    pub backlog_warn_len: usize,
+    /// commands per second a websocket session runs, 0 for no limit
+    #[serde(default)]
+    pub command_rate: u32,
+    /// shortest heartbeat interval in seconds when the rtt rises, 0 for `HEARTBEAT_INTERVAL`
+    #[serde(default)]
+    pub heartbeat_min_secs: u64,
+    /// longest heartbeat interval in seconds on a fast link, 0 for `HEARTBEAT_INTERVAL`
+    #[serde(default)]
+    pub heartbeat_max_secs: u64,
+    /// max length of a session name, 0 for `MAX_NAME_LEN`
+    #[serde(default)]
+    pub max_name_len: usize,
+    /// seconds to wait for a reconnect before a disconnected user goes offline
+    #[serde(default)]
+    pub offline_grace_secs: u64,
+    /// take the client ip from `X-Forwarded-For`, only behind a trusted proxy
+    #[serde(default)]
+    pub trust_forwarded_for: bool,
+    /// activities delivered to a session per second, 0 for no limit
+    #[serde(default)]
+    pub delivery_rate: u32,
+    /// milliseconds a session has to take a frame, 0 waits forever and never retries
+    #[serde(default)]
+    pub delivery_timeout_ms: u64,
+    /// frames a session did not take in time are sent this many more times before it is stopped
+    #[serde(default)]
+    pub delivery_retries: u32,
+    /// milliseconds before the first retry, doubled for every further retry
+    #[serde(default)]
+    pub delivery_backoff_ms: u64,
+    /// spread the reads of all sessions over the polling interval
+    #[serde(default)]
+    pub stagger_reads: bool,
+    /// seconds a connection stays idle before tcp keepalive probes start, 0 for the os default
+    #[serde(default)]
+    pub tcp_keepalive_secs: u64,
+    /// what is logged of every frame a client sends
+    #[serde(default)]
+    pub frame_logging: FrameLogMode,
+    /// seconds between two connection quality frames sent to every client, 0 for none
+    #[serde(default)]
+    pub quality_interval_secs: u64,
+    /// distinguishes the nodes of a cluster in session ids
+    #[serde(default)]
+    pub node_id: u16,
+    /// how session ids are handed out
+    #[serde(default)]
+    pub session_ids: SessionIdMode,
+    /// actors the websocket sessions are spread over, 0 counts as 1
+    #[serde(default)]
+    pub session_shards: usize,
+    /// frames waiting for one websocket session, 0 for `OUTBOUND_QUEUE_DEPTH`
+    #[serde(default)]
+    pub outbound_queue_depth: usize,
+    /// what happens to a frame that finds the queue of its session full
+    #[serde(default)]
+    pub outbound_overflow: OverflowPolicy,
+    /// milliseconds status changes are collected before room members hear the last one, 0 announces each
+    #[serde(default)]
+    pub presence_debounce_ms: u64,
+    /// bearer token of the `/admin` and `/export` routes, they refuse everyone without it.
+    /// a websocket upgrade carrying it runs admin commands like `/broadcast`
+    #[serde(default)]
+    pub admin_token: Option<String>,
+    /// IPhone and IPad sessions get activities in the APNs payload shape
+    #[serde(default)]
+    pub apns_payloads: bool,
+    /// milliseconds a new connection waits to be registered before it is closed, 0 for `REGISTER_TIMEOUT`
+    #[serde(default)]
+    pub register_timeout_ms: u64,
+    /// `id=name` pairs, activities from these senders are delivered with a `sender_name`
+    #[serde(default)]
+    pub display_names: Vec<String>,
+    /// pushes one sender makes per window before the rest are rejected, 0 for no quota,
+    /// pushes without a sender share one
+    #[serde(default)]
+    pub push_quota: u32,
+    /// seconds over which pushes are counted against `push_quota`, 0 for `PUSH_QUOTA_WINDOW`
+    #[serde(default)]
+    pub push_quota_window_secs: u64,
+    /// milliseconds between two reads of a session's streams, 0 for `MESSAGE_INTERVAL`
+    #[serde(default)]
+    pub poll_interval_ms: u64,
+    /// serve wss with these cert and key, plain http without them
+    #[serde(flatten, default)]
+    pub tls: Option<TlsConfig>,
+}
+
+/// `full` logs what clients send, which may be sensitive, only turn it on in development
+#[derive(Clone, Copy, Deserialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FrameLogMode {
+    #[default]
+    Off,
+    /// the frame type and its size
+    Metadata,
+    /// the whole frame
+    Full,
+}
+
+/// where the id of a new websocket session comes from
+#[derive(Clone, Copy, Deserialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionIdMode {
+    #[default]
+    Random,
+    /// 1, 2, 3... from every start
+    Sequential,
+    /// time and `node_id`, unique across restarts and nodes
+    Snowflake,
+}
+
+/// what a full outbound queue does with one more frame
+#[derive(Clone, Copy, Deserialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// make room by dropping the frame that waited longest
+    #[default]
+    DropOldest,
+    /// drop the frame that does not fit
+    DropNewest,
+    /// close the connection of a client that cannot keep up
+    DisconnectClient,
+}
+
+/// what a name already held by `max_sessions_per_user` sessions does with one more
+#[derive(Clone, Copy, Deserialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionLimitPolicy {
+    /// the new session keeps its old name
+    #[default]
+    Reject,
+    /// the session that connected first is closed
+    EvictOldest,
+}
+
+/// pem encoded certificate chain and private key
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+pub struct TlsConfig {
+    #[serde(rename = "tls_cert_path")]
+    pub cert_path: String,
+    #[serde(rename = "tls_key_path")]
+    pub key_path: String,
 }
 
 lazy_static! {
@@ -21,6 +219,24 @@ fn get_config() -> Config {
     }
 }
 
+/// the config handlers read per request, swapped whole by `/admin/reload`
+#[derive(Debug)]
+pub struct LiveConfig(RwLock<Arc<Config>>);
+
+impl LiveConfig {
+    pub fn new(config: Config) -> Self {
+        Self(RwLock::new(Arc::new(config)))
+    }
+
+    pub fn load(&self) -> Arc<Config> {
+        self.0.read().unwrap().clone()
+    }
+
+    pub fn store(&self, config: Config) {
+        *self.0.write().unwrap() = Arc::new(config);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -35,4 +251,63 @@ mod tests {
         let config = &CONFIG;
         assert_ne!(config.server, "".to_string());
     }
+
+    #[test]
+    fn a_stored_config_replaces_the_loaded_one() {
+        let live = LiveConfig::new(get_config());
+        let before = live.load();
+        live.store(Config {
+            poll_interval_ms: before.poll_interval_ms + 500,
+            ..get_config()
+        });
+        assert_eq!(live.load().poll_interval_ms, before.poll_interval_ms + 500);
+        // a request that loaded the old config keeps it until it is done
+        assert_eq!(before.poll_interval_ms, get_config().poll_interval_ms);
+    }
+
+    #[test]
+    fn tls_is_optional() {
+        let vars = |tls: &[(&str, &str)]| {
+            let mut vars: Vec<(String, String)> = [
+                ("REDIS_URL", "redis://127.0.0.1:6379"),
+                ("GRPC_URL", "[::1]:50051"),
+                ("BACKTRACE", "1"),
+                ("LOG", "actix_web=info"),
+                ("SERVER", "127.0.0.1:3000"),
+                ("NODE_ID", "3"),
+            ]
+            .iter()
+            .chain(tls)
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+            vars.sort();
+            vars
+        };
+
+        let plain = envy::from_iter::<_, Config>(vars(&[])).unwrap();
+        assert_eq!(plain.tls, None);
+        assert_eq!(plain.node_id, 3);
+        assert_eq!(plain.session_ids, SessionIdMode::Random);
+
+        let snowflake =
+            envy::from_iter::<_, Config>(vars(&[("SESSION_IDS", "snowflake")])).unwrap();
+        assert_eq!(snowflake.session_ids, SessionIdMode::Snowflake);
+        assert_eq!(plain.frame_logging, FrameLogMode::Off);
+
+        let logged = envy::from_iter::<_, Config>(vars(&[("FRAME_LOGGING", "metadata")])).unwrap();
+        assert_eq!(logged.frame_logging, FrameLogMode::Metadata);
+
+        let tls = envy::from_iter::<_, Config>(vars(&[
+            ("TLS_CERT_PATH", "cert.pem"),
+            ("TLS_KEY_PATH", "key.pem"),
+        ]))
+        .unwrap();
+        assert_eq!(
+            tls.tls,
+            Some(TlsConfig {
+                cert_path: "cert.pem".to_string(),
+                key_path: "key.pem".to_string(),
+            })
+        );
+    }
 }
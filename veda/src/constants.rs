@@ -1,16 +1,106 @@
 use std::time::Duration;
 
-/// js toISOString() in test suit can't handle chrono's default precision
+// js toISOString() in test suit can't handle chrono's default precision
 //pub const DATE_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.3fZ";
 
-/// max len of redis stream for each key is 1000
+// max len of redis stream for each key is 1000
 //pub const MAXLEN: StreamMaxlen = StreamMaxlen::Approx(1000);
 
 /// blocking message time milliseconds
 pub const BLOCK_MILLIS: usize = 600;
+/// max activities read from a stream at once
+pub const READ_COUNT: usize = 10;
 /// polling message time interval
 pub const MESSAGE_INTERVAL: Duration = Duration::from_millis(1000);
+/// polling interval of a session whose app is in the foreground
+pub const FOREGROUND_INTERVAL: Duration = Duration::from_millis(250);
+/// polling interval of a session whose app is in the background
+pub const BACKGROUND_INTERVAL: Duration = Duration::from_millis(4000);
+/// max ids `/missed` returns
+pub const MISSED_LIMIT: usize = 100;
+/// activity types only delivered to sessions that declared them with `/caps`
+pub const GATED_TYPES: &[&str] = &["rich"];
+/// activity type of the event `/selftest` pushes to the caller
+pub const SELFTEST_TYPE: &str = "selftest";
+/// activity types copied to the followers of a public user
+pub const PUBLIC_TYPES: &[&str] = &["post"];
+/// `last_id` of `/cursor` before anything is delivered
+pub const NO_CURSOR: &str = "0-0";
+/// max rooms `/badges` reports
+pub const BADGE_ROOMS: usize = 50;
+/// `/badges` stops counting a stream at this many unread activities
+pub const BADGE_COUNT: usize = 99;
+/// default max length of a session name, names become part of redis keys
+pub const MAX_NAME_LEN: usize = 64;
+/// max activities `/since-ts` returns
+pub const SINCE_LIMIT: usize = 1000;
+/// max activities one page of `/history-page` returns
+pub const HISTORY_PAGE_LIMIT: usize = 100;
+/// max activities one page of an export returns
+pub const EXPORT_LIMIT: usize = 1000;
+/// Slots the polling interval is split into when reads are staggered
+pub const READ_SLOTS: usize = 10;
 /// How often heartbeat pings are sent
 pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+/// Round-trip times up to this count as a fast link, the heartbeat slows down on it
+pub const LOW_RTT: Duration = Duration::from_millis(200);
+/// Error reports a session may send with `/report` per second
+pub const REPORT_RATE: u32 = 1;
+/// How long an idempotency key keeps a retried push from being written again
+pub const IDEMPOTENCY_TTL: Duration = Duration::from_secs(600);
+/// Version of the websocket protocol `/help` reports
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Frames waiting for one websocket session before the overflow policy applies
+pub const OUTBOUND_QUEUE_DEPTH: usize = 256;
+/// Bytes the activities bundled into one frame stay under, unless one alone is larger
+pub const MAX_FRAME_BYTES: usize = 64 * 1024;
+/// Receivers a push writes before the other messages of `Redis` get a turn
+pub const TRIAL_CHUNK_SIZE: usize = 64;
+/// How often the backlog of the streams of online users is sampled
+pub const BACKLOG_SAMPLE_INTERVAL: Duration = Duration::from_secs(60);
+/// Undelivered activities in one stream before its user is logged
+pub const BACKLOG_WARN_LEN: usize = 1000;
+/// How often scheduled activities that are due are moved into their streams
+pub const SCHEDULE_TICK: Duration = Duration::from_millis(100);
+/// How long a drained session stays open after it is told to reconnect
+pub const DRAIN_CLOSE_DELAY: Duration = Duration::from_secs(1);
+/// Largest attachment an activity may carry
+pub const MAX_BLOB_SIZE: usize = 64 * 1024;
+/// How long the delivery state of a push is kept for `/status-of`
+pub const RECEIPT_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// How long an attachment is kept after it is pushed
+pub const BLOB_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// How long the counter of a limited-quantity activity is kept after it is pushed
+pub const CLAIM_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// Window over which the pushes of one sender are counted against the push quota
+pub const PUSH_QUOTA_WINDOW: Duration = Duration::from_secs(60);
+/// Message id reported to receivers of a push rejected by the push quota
+pub const QUOTA_EXCEEDED: &str = "quota_exceeded";
+/// Capability of clients that take attachments inline instead of a download url
+pub const BLOB_CAPABILITY: &str = "blob";
+/// Capability of clients that take large one-shot responses gzipped in a binary frame
+pub const GZIP_CAPABILITY: &str = "gzip";
+/// Header naming the tenant a websocket connection belongs to
+pub const TENANT_HEADER: &str = "x-tenant";
+/// How large a `/history-page`, `/since-ts` or export response grows before it is gzipped
+pub const COMPRESS_THRESHOLD: usize = 16 * 1024;
+/// How long a webhook has to answer a post
+pub const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long the server waits for a client to reply to a request
+pub const ASK_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a new websocket session waits to be registered before it closes the connection
+pub const REGISTER_TIMEOUT: Duration = Duration::from_secs(5);
 /// How long before lack of client response causes a timeout
 pub const CLIENT_TIMEOUT: Duration = Duration::from_secs(60);
+/// Window in which the redis errors of a session are counted
+pub const ERROR_RATE_WINDOW: Duration = Duration::from_secs(60);
+/// Redis errors within the window before a session is reported
+pub const ERROR_RATE_THRESHOLD: u64 = 10;
+/// How often the `last-seen` set is swept for stale sessions
+pub const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+/// How often a heartbeat writes `last-seen:{username}` at most
+pub const LAST_SEEN_WRITE_INTERVAL: Duration = Duration::from_secs(30);
+/// How often sessions acknowledged by the client look for abandoned activities
+pub const RECLAIM_INTERVAL: Duration = Duration::from_secs(30);
+/// How long a delivered activity may stay unacknowledged before it is delivered again
+pub const RECLAIM_IDLE: Duration = Duration::from_secs(300);
@@ -1,10 +1,194 @@
-use redis::{FromRedisValue, ToRedisArgs};
+use std::collections::HashSet;
+
+use log::warn;
+use redis::{streams::StreamId, FromRedisValue, ToRedisArgs};
 use serde::{Deserialize, Serialize};
-#[derive(Deserialize, Serialize)]
+
+/// version of the stream entry layout written by `ToRedisArgs`,
+/// entries without a `v` field were written before it was versioned and share the layout of 1
+pub const ACTIVITY_VERSION: u32 = 1;
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
 pub struct Activity {
     /// event message
     pub activity_type: String,
     pub activity: String,
+    /// attached when the activity is pushed, follows it until it reaches the client
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+    /// of several activities with the same key only the latest is delivered
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compaction_key: Option<String>,
+    /// a retried push with the same key is written only once per receiver
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idempotency_key: Option<String>,
+    /// unix milliseconds after which the activity is dropped instead of delivered
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    /// unix milliseconds before which the activity is held back instead of pushed,
+    /// never written into a stream
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deliver_at: Option<u64>,
+    /// id of the entry this one answers, lets clients render threads
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<String>,
+    /// who posted it to a room
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender: Option<String>,
+    /// a small attachment, base64 in json, at most `MAX_BLOB_SIZE` bytes
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "base64_blob")]
+    pub blob: Option<Vec<u8>>,
+    /// where a client without the `blob` capability downloads the attachment
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blob_url: Option<String>,
+    /// redis key the attachment is kept under, apart from the stream
+    #[serde(skip)]
+    pub blob_key: Option<String>,
+    /// redis counter of the receivers that got a limited-quantity activity
+    #[serde(skip)]
+    pub claim_key: Option<String>,
+    /// how many receivers get a limited-quantity activity, the others are told it is sold out
+    #[serde(skip)]
+    pub quantity: Option<u32>,
+    /// the sessions it is delivered to, the others drop it, never sent to clients
+    #[serde(default, skip_serializing)]
+    pub audience: Option<Audience>,
+    /// fields a `DeliveryHook` adds on the way to the client, never written into a stream
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+    /// stream entry id, only sent to clients that acknowledge by id
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+}
+
+/// constraints a session has to meet to get an activity, empty ones always hold
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Audience {
+    /// `Platform::name` of the session, any of them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub platforms: Vec<String>,
+    /// what the session declared with `/caps`, all of them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub capabilities: Vec<String>,
+}
+
+impl Audience {
+    pub fn is_empty(&self) -> bool {
+        self.platforms.is_empty() && self.capabilities.is_empty()
+    }
+
+    /// whether a session on `platform` with `capabilities` is in the audience
+    pub fn admits(&self, platform: Option<&str>, capabilities: &HashSet<String>) -> bool {
+        let on_platform = self.platforms.is_empty()
+            || platform.is_some_and(|platform| self.platforms.iter().any(|p| p == platform));
+        on_platform
+            && self
+                .capabilities
+                .iter()
+                .all(|capability| capabilities.contains(capability))
+    }
+}
+
+/// how a batch of activities is written into one websocket frame
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FramingMode {
+    /// one json array
+    #[default]
+    Array,
+    /// one json activity per line
+    Ndjson,
+}
+
+/// what happens to activities that are read from a stream but not delivered
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryMode {
+    /// removed once delivered, read again after a failure
+    #[default]
+    AtLeastOnce,
+    /// removed before they are sent, lost when the frame is not delivered
+    AtMostOnce,
+}
+
+impl FramingMode {
+    pub fn encoder(self) -> fn(&[Activity]) -> serde_json::Result<String> {
+        match self {
+            FramingMode::Array => |items| FramingMode::Array.encode(items),
+            FramingMode::Ndjson => |items| FramingMode::Ndjson.encode(items),
+        }
+    }
+
+    /// write a batch of anything serializable, e.g. projected activities
+    pub fn encode<T: Serialize>(self, items: &[T]) -> serde_json::Result<String> {
+        match self {
+            FramingMode::Array => serde_json::to_string(items),
+            FramingMode::Ndjson => {
+                let lines = items
+                    .iter()
+                    .map(serde_json::to_string)
+                    .collect::<serde_json::Result<Vec<String>>>()?;
+                Ok(lines.join("\n"))
+            }
+        }
+    }
+}
+
+/// fields of an activity a client can ask for with `/fields`, `id` is always sent
+pub const ACTIVITY_FIELDS: &[&str] = &[
+    "activity_type",
+    "activity",
+    "correlation_id",
+    "compaction_key",
+    "idempotency_key",
+    "expires_at",
+    "reply_to",
+    "sender",
+    "blob",
+    "blob_url",
+];
+
+impl Activity {
+    /// the activity as json with only `fields` and the `id`
+    pub fn project(&self, fields: &HashSet<String>) -> serde_json::Value {
+        let mut value = serde_json::to_value(self).unwrap_or_default();
+        if let serde_json::Value::Object(map) = &mut value {
+            map.retain(|field, _| field == "id" || fields.contains(field));
+        }
+        value
+    }
+}
+
+impl From<&StreamId> for Activity {
+    fn from(entry: &StreamId) -> Self {
+        let version: u32 = entry.get("v").unwrap_or(1);
+        if version > ACTIVITY_VERSION {
+            warn!(
+                "stream entry {} has layout v{}, newer than v{}",
+                entry.id, version, ACTIVITY_VERSION
+            );
+        }
+        Activity {
+            activity_type: entry.get("activity_type").unwrap_or_default(),
+            activity: entry.get("activity").unwrap_or_default(),
+            correlation_id: entry.get("correlation_id"),
+            compaction_key: entry.get("compaction_key"),
+            idempotency_key: entry.get("idempotency_key"),
+            expires_at: entry.get("expires_at"),
+            deliver_at: None,
+            reply_to: entry.get("reply_to"),
+            sender: entry.get("sender"),
+            blob: None,
+            blob_url: entry.get("blob_url"),
+            blob_key: entry.get("blob_key"),
+            claim_key: entry.get("claim_key"),
+            quantity: entry.get("quantity"),
+            audience: entry
+                .get::<String>("audience")
+                .and_then(|audience| serde_json::from_str(&audience).ok()),
+            extra: Default::default(),
+            id: None,
+        }
+    }
 }
 
 impl ToRedisArgs for &Activity {
@@ -12,10 +196,74 @@ impl ToRedisArgs for &Activity {
     where
         W: ?Sized + redis::RedisWrite,
     {
+        "v".write_redis_args(out);
+        ACTIVITY_VERSION.write_redis_args(out);
         "activity_type".write_redis_args(out);
         self.activity_type.write_redis_args(out);
         "activity".write_redis_args(out);
         self.activity.write_redis_args(out);
+        if let Some(correlation_id) = &self.correlation_id {
+            "correlation_id".write_redis_args(out);
+            correlation_id.write_redis_args(out);
+        }
+        if let Some(compaction_key) = &self.compaction_key {
+            "compaction_key".write_redis_args(out);
+            compaction_key.write_redis_args(out);
+        }
+        if let Some(idempotency_key) = &self.idempotency_key {
+            "idempotency_key".write_redis_args(out);
+            idempotency_key.write_redis_args(out);
+        }
+        if let Some(expires_at) = self.expires_at {
+            "expires_at".write_redis_args(out);
+            expires_at.write_redis_args(out);
+        }
+        if let Some(reply_to) = &self.reply_to {
+            "reply_to".write_redis_args(out);
+            reply_to.write_redis_args(out);
+        }
+        if let Some(sender) = &self.sender {
+            "sender".write_redis_args(out);
+            sender.write_redis_args(out);
+        }
+        if let Some(blob_key) = &self.blob_key {
+            "blob_key".write_redis_args(out);
+            blob_key.write_redis_args(out);
+        }
+        if let Some(blob_url) = &self.blob_url {
+            "blob_url".write_redis_args(out);
+            blob_url.write_redis_args(out);
+        }
+        if let (Some(claim_key), Some(quantity)) = (&self.claim_key, self.quantity) {
+            "claim_key".write_redis_args(out);
+            claim_key.write_redis_args(out);
+            "quantity".write_redis_args(out);
+            quantity.write_redis_args(out);
+        }
+        if let Some(audience) = &self.audience {
+            if let Ok(audience) = serde_json::to_string(audience) {
+                "audience".write_redis_args(out);
+                audience.write_redis_args(out);
+            }
+        }
+    }
+}
+
+/// attachments travel as base64 strings in json
+mod base64_blob {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(blob: &Option<Vec<u8>>, s: S) -> Result<S::Ok, S::Error> {
+        match blob {
+            Some(blob) => s.serialize_str(&base64::encode(blob)),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Vec<u8>>, D::Error> {
+        Option::<String>::deserialize(d)?
+            .map(|blob| base64::decode(blob).map_err(D::Error::custom))
+            .transpose()
     }
 }
 
@@ -23,13 +271,13 @@ impl FromRedisValue for Activity {
     fn from_redis_value(v: &redis::Value) -> redis::RedisResult<Activity> {
         match *v {
             redis::Value::Data(ref val) => match serde_json::from_slice(val) {
-                Err(_) => Err(((redis::ErrorKind::TypeError, "Can't unjson value")).into()),
+                Err(_) => Err((redis::ErrorKind::TypeError, "Can't unjson value").into()),
                 Ok(v) => Ok(v),
             },
-            _ => Err(((
+            _ => Err((
                 redis::ErrorKind::ResponseError,
                 "Response type not Dashboard compatible.",
-            ))
+            )
                 .into()),
         }
     }
@@ -41,3 +289,154 @@ impl FromRedisValue for Activity {
             .collect())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use redis::Value;
+
+    use super::*;
+
+    #[test]
+    fn correlation_id_survives_the_stream_entry() {
+        let activity = Activity {
+            activity_type: "event".to_string(),
+            activity: "trans-am".to_string(),
+            correlation_id: Some("00c0ffee".to_string()),
+            compaction_key: None,
+            idempotency_key: None,
+            expires_at: None,
+            deliver_at: None,
+            reply_to: None,
+            sender: None,
+            blob: None,
+            blob_url: None,
+            blob_key: None,
+            claim_key: None,
+            quantity: None,
+            audience: None,
+            extra: Default::default(),
+            id: None,
+        };
+        let args = (&activity).to_redis_args();
+        let map: HashMap<String, Value> = args
+            .chunks(2)
+            .map(|kv| {
+                (
+                    String::from_utf8(kv[0].clone()).unwrap(),
+                    Value::Data(kv[1].clone()),
+                )
+            })
+            .collect();
+        let entry = StreamId {
+            id: "0-1".to_string(),
+            map,
+        };
+
+        assert_eq!(entry.get::<u32>("v"), Some(ACTIVITY_VERSION));
+        assert_eq!(Activity::from(&entry), activity);
+    }
+
+    #[test]
+    fn an_audience_admits_sessions_meeting_every_constraint() {
+        let rich: HashSet<String> = vec!["rich".to_string()].into_iter().collect();
+        let audience = Audience {
+            platforms: vec!["IPhone".to_string(), "IPad".to_string()],
+            capabilities: vec!["rich".to_string()],
+        };
+        assert!(audience.admits(Some("IPad"), &rich));
+        assert!(!audience.admits(Some("Web"), &rich));
+        assert!(!audience.admits(None, &rich));
+        assert!(!audience.admits(Some("IPhone"), &HashSet::new()));
+        assert!(Audience::default().admits(None, &HashSet::new()));
+    }
+
+    #[test]
+    fn projection_leaves_the_other_fields_out() {
+        let activity = Activity {
+            activity_type: "launch".to_string(),
+            activity: "exia".to_string(),
+            correlation_id: Some("00c0ffee".to_string()),
+            compaction_key: None,
+            idempotency_key: None,
+            expires_at: None,
+            deliver_at: None,
+            reply_to: None,
+            sender: None,
+            blob: None,
+            blob_url: None,
+            blob_key: None,
+            claim_key: None,
+            quantity: None,
+            audience: None,
+            extra: Default::default(),
+            id: Some("1-0".to_string()),
+        };
+        let fields: HashSet<String> = vec!["activity_type".to_string()].into_iter().collect();
+        assert_eq!(
+            activity.project(&fields),
+            serde_json::json!({"activity_type": "launch", "id": "1-0"})
+        );
+    }
+
+    #[test]
+    fn both_framings_are_parseable() {
+        let batch: Vec<Activity> = ["exia", "dynames", "kyrios"]
+            .iter()
+            .map(|name| Activity {
+                activity_type: "launch".to_string(),
+                activity: format!("{{\"unit\":\"{}\"}}", name),
+                ..Default::default()
+            })
+            .collect();
+
+        let array = FramingMode::default().encoder()(&batch).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Vec<Activity>>(&array).unwrap(),
+            batch
+        );
+
+        let ndjson = FramingMode::Ndjson.encoder()(&batch).unwrap();
+        let lines: Vec<Activity> = ndjson
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines, batch);
+    }
+
+    #[test]
+    fn unversioned_entries_are_read() {
+        let map: HashMap<String, Value> = [("activity_type", "event"), ("activity", "gn-drive")]
+            .iter()
+            .map(|(k, v)| (k.to_string(), Value::Data(v.as_bytes().to_vec())))
+            .collect();
+        let entry = StreamId {
+            id: "0-1".to_string(),
+            map,
+        };
+
+        assert_eq!(
+            Activity::from(&entry),
+            Activity {
+                activity_type: "event".to_string(),
+                activity: "gn-drive".to_string(),
+                correlation_id: None,
+                compaction_key: None,
+                idempotency_key: None,
+                expires_at: None,
+                deliver_at: None,
+                reply_to: None,
+                sender: None,
+                blob: None,
+                blob_url: None,
+                blob_key: None,
+                claim_key: None,
+                quantity: None,
+                audience: None,
+                extra: Default::default(),
+                id: None,
+            }
+        );
+    }
+}
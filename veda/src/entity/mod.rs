@@ -1,3 +1,6 @@
 mod activity;
+mod origin;
 mod platform;
-pub use self::{activity::*, platform::*};
+mod protocol;
+mod room;
+pub use self::{activity::*, origin::*, platform::*, protocol::*, room::*};
@@ -0,0 +1,8 @@
+use serde::Serialize;
+
+/// 建立websocket连接的客户端
+#[derive(Clone, Debug, Default, Serialize, PartialEq)]
+pub struct Origin {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
@@ -1,8 +1,9 @@
 use redis::{FromRedisValue, ToRedisArgs};
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 
 /// device info
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
 pub struct Info {
     device_name: String,
     factory_name: Option<String>,
@@ -13,13 +14,13 @@ impl FromRedisValue for Info {
     fn from_redis_value(v: &redis::Value) -> redis::RedisResult<Self> {
         match *v {
             redis::Value::Data(ref val) => match serde_json::from_slice(val) {
-                Err(_) => Err(((redis::ErrorKind::TypeError, "Can't serialize value")).into()),
+                Err(_) => Err((redis::ErrorKind::TypeError, "Can't serialize value").into()),
                 Ok(v) => Ok(v),
             },
-            _ => Err(((
+            _ => Err((
                 redis::ErrorKind::ResponseError,
                 "Response type not Dashboard compatible.",
-            ))
+            )
                 .into()),
         }
     }
@@ -31,7 +32,7 @@ impl ToRedisArgs for Info {
         W: ?Sized + redis::RedisWrite,
     {
         "device_name".write_redis_args(out);
-        &self.device_name.write_redis_args(out);
+        self.device_name.write_redis_args(out);
         if let Some(factory_name) = &self.factory_name {
             "factory_name".write_redis_args(out);
             factory_name.write_redis_args(out);
@@ -44,7 +45,7 @@ impl ToRedisArgs for Info {
     }
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
 #[serde(tag = "platform", content = "device")]
 pub enum Platform {
     Android(Info),
@@ -57,12 +58,10 @@ pub enum Platform {
     Windows(Info),
 }
 
-impl ToRedisArgs for Platform {
-    fn write_redis_args<W>(&self, out: &mut W)
-    where
-        W: ?Sized + redis::RedisWrite,
-    {
-        let (platform, device) = match self {
+impl Platform {
+    /// platform name and the device on it
+    fn parts(&self) -> (&'static str, &Info) {
+        match self {
             Platform::Android(info) => ("Android", info),
             Platform::Embedded(info) => ("Embedded", info),
             Platform::IPhone(info) => ("IPhone", info),
@@ -71,7 +70,20 @@ impl ToRedisArgs for Platform {
             Platform::Tablet(info) => ("Tablet", info),
             Platform::Web(info) => ("Web", info),
             Platform::Windows(info) => ("Windows", info),
-        };
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.parts().0
+    }
+}
+
+impl ToRedisArgs for Platform {
+    fn write_redis_args<W>(&self, out: &mut W)
+    where
+        W: ?Sized + redis::RedisWrite,
+    {
+        let (platform, device) = self.parts();
 
         out.write_arg(b"platform");
         out.write_arg(platform.as_bytes());
@@ -84,18 +96,34 @@ impl FromRedisValue for Platform {
     fn from_redis_value(v: &redis::Value) -> redis::RedisResult<Self> {
         match *v {
             redis::Value::Data(ref val) => match serde_json::from_slice(val) {
-                Err(_) => Err(((redis::ErrorKind::TypeError, "Can't serialize value")).into()),
+                Err(_) => Err((redis::ErrorKind::TypeError, "Can't serialize value").into()),
                 Ok(v) => Ok(v),
             },
-            _ => Err(((
+            _ => Err((
                 redis::ErrorKind::ResponseError,
                 "Response type not Dashboard compatible.",
-            ))
+            )
                 .into()),
         }
     }
 }
 
+/// reshapes a delivered activity, already in json, for the platform of the session
+pub type Render = fn(Value) -> Value;
+
+/// the APNs payload shape, the activity itself goes along as `data`
+pub fn apns(activity: Value) -> Value {
+    json!({
+        "aps": {
+            "alert": {
+                "title": activity["activity_type"],
+                "body": activity["activity"],
+            },
+        },
+        "data": activity,
+    })
+}
+
 // /// Gandum meister
 // #[derive(Deserialize, Serialize)]
 // pub struct Meister {
@@ -110,7 +138,7 @@ impl FromRedisValue for Platform {
 //     fn from_redis_value(v: &redis::Value) -> redis::RedisResult<Self> {
 //         match *v {
 //             redis::Value::Data(ref val) => match serde_json::from_slice(val) {
-//                 Err(_) => Err(((redis::ErrorKind::TypeError, "Can't serialize value")).into()),
+//                 Err(_) => Err((redis::ErrorKind::TypeError, "Can't serialize value").into()),
 //                 Ok(v) => Ok(v),
 //             },
 //             _ => Err(((
@@ -0,0 +1,77 @@
+use serde::Serialize;
+
+/// 客户端在握手时用`Sec-WebSocket-Protocol`协商的协议版本,决定错误帧的格式
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ProtocolVersion {
+    /// `collab-v1`,以及没有协商子协议的客户端,错误为`!!! ...`文本
+    #[default]
+    V1,
+    /// `collab-v2`,错误为`{"type":"error","message":"..."}`
+    V2,
+}
+
+/// `collab-v2`的错误帧
+#[derive(Serialize)]
+struct ErrorFrame<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    message: &'a str,
+}
+
+impl ProtocolVersion {
+    /// 握手时服务器支持的子协议
+    pub const SUBPROTOCOLS: &'static [&'static str] = &["collab-v2", "collab-v1"];
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "collab-v1" => Some(ProtocolVersion::V1),
+            "collab-v2" => Some(ProtocolVersion::V2),
+            _ => None,
+        }
+    }
+
+    /// 与握手时的选择一致: 客户端请求的子协议中第一个服务器支持的,都不支持时为v1
+    pub fn negotiate(requested: Option<&str>) -> Self {
+        requested
+            .and_then(|requested| {
+                requested
+                    .split(',')
+                    .find_map(|name| Self::from_name(name.trim()))
+            })
+            .unwrap_or_default()
+    }
+
+    /// 按协议版本写出发给客户端的错误
+    pub fn error(self, message: &str) -> String {
+        match self {
+            ProtocolVersion::V1 => format!("!!! {}", message),
+            ProtocolVersion::V2 => serde_json::to_string(&ErrorFrame {
+                kind: "error",
+                message,
+            })
+            .unwrap_or_else(|_| format!("!!! {}", message)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_first_supported_subprotocol_wins() {
+        assert_eq!(ProtocolVersion::negotiate(None), ProtocolVersion::V1);
+        assert_eq!(
+            ProtocolVersion::negotiate(Some("chat, collab-v2, collab-v1")),
+            ProtocolVersion::V2
+        );
+        assert_eq!(
+            ProtocolVersion::negotiate(Some("collab-v1,collab-v2")),
+            ProtocolVersion::V1
+        );
+        assert_eq!(
+            ProtocolVersion::negotiate(Some("collab-v9")),
+            ProtocolVersion::V1
+        );
+    }
+}
@@ -0,0 +1,11 @@
+use serde::{Deserialize, Serialize};
+
+/// 协作房间,成员共享同一个消息队列
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub struct Room(pub String);
+
+impl Room {
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+}
@@ -1,31 +1,571 @@
-use crate::addr::{Redis, Seravee, Websocket, WebsocketSession};
+use crate::{
+    addr::{
+        AdminCommands, AskSession, CommandAuthorizer, DrainConnections, ExportUser,
+        ExportedActivity, GetBlob, ListSessions, PendingRequests, Redis, ReloadConfig,
+        RequestError, Role, Seravee, SessionGuard, Websocket, WebsocketSession,
+    },
+    compress::{gzip, GzipChunks},
+    config::LiveConfig,
+    constants::{
+        ASK_TIMEOUT, COMPRESS_THRESHOLD, EXPORT_LIMIT, HEARTBEAT_INTERVAL, REGISTER_TIMEOUT,
+        REPORT_RATE, TENANT_HEADER,
+    },
+    entity::{DeliveryMode, FramingMode, Origin, ProtocolVersion},
+    heartbeat::AdaptiveHeartbeat,
+    metrics,
+    throttle::Throttle,
+};
 use actix::Addr;
 use actix_web::{
-    web::{self},
+    error::ErrorInternalServerError,
+    http::header,
+    web::{self, Bytes},
     Error, HttpRequest, HttpResponse,
 };
 use actix_web_actors::ws;
-use std::time::Instant;
+use futures::{stream, Stream};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// options a client picks when opening the websocket, e.g. `/ws/?backlog_summary=true`
+#[derive(Deserialize, Debug, Default)]
+pub struct ConnectOptions {
+    /// send a backlog summary frame before delivering the backlog
+    #[serde(default)]
+    pub backlog_summary: bool,
+    /// `array` or `ndjson`
+    #[serde(default)]
+    pub framing: FramingMode,
+    /// at most this many activities per frame
+    #[serde(default)]
+    pub batch_size: Option<usize>,
+    /// `at_least_once` or `at_most_once`, the latter never delivers an activity twice
+    /// but loses the frame a send fails on
+    #[serde(default)]
+    pub delivery: DeliveryMode,
+}
+
+/// one page of `/export/{username}`, e.g. `?after=1526919030474-55&limit=100`,
+/// without `limit` everything after `after`
+#[derive(Deserialize, Debug)]
+pub struct ExportQuery {
+    /// the last id of the previous page
+    pub after: Option<String>,
+    pub limit: Option<usize>,
+}
+
+lazy_static! {
+    /// shared by every websocket session
+    static ref AUTHORIZER: Arc<dyn CommandAuthorizer> = Arc::new(AdminCommands);
+}
+
+/// a configured heartbeat bound, 0 for `HEARTBEAT_INTERVAL`
+fn heartbeat_bound(secs: u64) -> Duration {
+    match secs {
+        0 => HEARTBEAT_INTERVAL,
+        secs => Duration::from_secs(secs),
+    }
+}
+
+/// the `Authorization: Bearer` token the admin routes ask for,
+/// without a configured token every request is refused
+#[derive(Debug, Default)]
+pub struct AdminToken(Option<String>);
+
+impl AdminToken {
+    pub fn new(token: Option<String>) -> Self {
+        Self(token.filter(|token| !token.is_empty()))
+    }
+
+    pub fn permits(&self, req: &HttpRequest) -> bool {
+        let expected = match &self.0 {
+            Some(token) => token,
+            None => return false,
+        };
+        req.headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|given| same_bytes(given.as_bytes(), expected.as_bytes()))
+    }
+}
+
+/// takes as long wherever the first difference is, so the token cannot be guessed byte by byte
+fn same_bytes(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// while on, new websocket upgrades are turned away and the open sessions keep going
+#[derive(Debug, Default)]
+pub struct Maintenance(AtomicBool);
+
+impl Maintenance {
+    pub fn is_on(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    pub fn set(&self, on: bool) {
+        self.0.store(on, Ordering::SeqCst);
+    }
+}
+
+/// `POST /admin/maintenance?on=true` switches maintenance on, without `on` only reports it
+#[derive(Deserialize, Debug)]
+pub struct MaintenanceQuery {
+    pub on: Option<bool>,
+}
+
+/// `/admin/sessions/{id}/ask?timeout_ms=5000`, `ASK_TIMEOUT` without `timeout_ms`
+#[derive(Deserialize, Debug)]
+pub struct AskQuery {
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Serialize, Debug)]
+struct AskFailure {
+    error: RequestError,
+}
+
+#[derive(Serialize, Debug)]
+struct MaintenanceState {
+    maintenance: bool,
+}
+
+/// where the upgrade request comes from, `X-Forwarded-For` only counts behind a trusted proxy
+fn origin(req: &HttpRequest, trust_forwarded_for: bool) -> Origin {
+    let ip = if trust_forwarded_for {
+        req.connection_info()
+            .realip_remote_addr()
+            .map(str::to_owned)
+    } else {
+        req.peer_addr().map(|addr| addr.ip().to_string())
+    };
+    let user_agent = req
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|agent| agent.to_str().ok())
+        .map(str::to_owned);
+    Origin { ip, user_agent }
+}
+
+/// the tenant named by the upgrade request, `Err` when it is not a plain label
+fn tenant(req: &HttpRequest) -> Result<Option<String>, ()> {
+    match req.headers().get(TENANT_HEADER) {
+        None => Ok(None),
+        Some(value) => match value.to_str() {
+            Ok(tenant)
+                if !tenant.is_empty()
+                    && tenant
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || "-_".contains(c)) =>
+            {
+                Ok(Some(tenant.to_owned()))
+            }
+            _ => Err(()),
+        },
+    }
+}
+
+/// the actors a websocket session talks to
+#[derive(Clone)]
+pub struct SessionActors {
+    pub redis: Addr<Redis>,
+    pub websocket: Addr<Websocket>,
+    pub grpc: Addr<Seravee>,
+}
 
 pub async fn socket_route(
     req: HttpRequest,
     stream: web::Payload,
-    grpc_addr: web::Data<Addr<Seravee>>,
-    redis_addr: web::Data<Addr<Redis>>,
-    srv: web::Data<Addr<Websocket>>,
+    options: web::Query<ConnectOptions>,
+    actors: web::Data<SessionActors>,
+    maintenance: web::Data<Maintenance>,
+    config: web::Data<LiveConfig>,
+    admin: web::Data<AdminToken>,
 ) -> Result<HttpResponse, Error> {
-    ws::start(
+    if maintenance.is_on() {
+        return Ok(HttpResponse::ServiceUnavailable()
+            .insert_header((header::RETRY_AFTER, "60"))
+            .body("under maintenance"));
+    }
+    let tenant = match tenant(&req) {
+        Ok(tenant) => tenant,
+        Err(()) => return Ok(HttpResponse::BadRequest().body("invalid tenant")),
+    };
+    let live_config = config.clone().into_inner();
+    let config = config.load();
+    let protocol = ProtocolVersion::negotiate(
+        req.headers()
+            .get(header::SEC_WEBSOCKET_PROTOCOL)
+            .and_then(|protocols| protocols.to_str().ok()),
+    );
+    ws::WsResponseBuilder::new(
         WebsocketSession {
             id: 0,
             name: None,
             hb: Instant::now(),
-            redis_addr: redis_addr.get_ref().clone(),
-            websocket_addr: srv.get_ref().clone(),
-            grpc_addr: grpc_addr.get_ref().clone(),
+            ping_at: Instant::now(),
+            rtt: None,
+            heartbeat: AdaptiveHeartbeat::new(
+                heartbeat_bound(config.heartbeat_min_secs),
+                heartbeat_bound(config.heartbeat_max_secs),
+            ),
+            redis_addr: actors.redis.clone(),
+            websocket_addr: actors.websocket.clone(),
+            grpc_addr: actors.grpc.clone(),
+            backlog_summary: options.backlog_summary,
+            framing: options.framing,
+            batch_size: options.batch_size.filter(|size| *size > 0),
+            capabilities: HashSet::new(),
+            delivery: options.delivery,
+            fields: None,
+            echo: true,
+            origin: origin(&req, config.trust_forwarded_for),
+            frame_logging: config.frame_logging,
+            quality_interval: (config.quality_interval_secs > 0)
+                .then(|| Duration::from_secs(config.quality_interval_secs)),
+            reports: Throttle::new(REPORT_RATE),
+            config: live_config,
+            commands: None,
+            requests: PendingRequests::default(),
+            guard: SessionGuard::new(actors.redis.clone(), actors.websocket.clone()),
+            authorizer: AUTHORIZER.clone(),
+            role: if admin.permits(&req) {
+                Role::Admin
+            } else {
+                Role::User
+            },
+            register_timeout: match config.register_timeout_ms {
+                0 => REGISTER_TIMEOUT,
+                ms => Duration::from_millis(ms),
+            },
+            protocol,
+            tenant,
         },
         &req,
         stream,
     )
+    .protocols(ProtocolVersion::SUBPROTOCOLS)
+    .start()
+}
+
+/// the activities of a user as ndjson, oldest first.
+/// with `limit` a single page, gzipped when it is over `COMPRESS_THRESHOLD` and the client accepts gzip,
+/// without it the whole stream, written out a page at a time and gzipped whenever the client accepts gzip
+pub async fn export_route(
+    req: HttpRequest,
+    username: web::Path<String>,
+    query: web::Query<ExportQuery>,
+    redis_addr: web::Data<Addr<Redis>>,
+    admin: web::Data<AdminToken>,
+) -> Result<HttpResponse, Error> {
+    if !admin.permits(&req) {
+        return Ok(HttpResponse::Unauthorized().finish());
+    }
+    let query = query.into_inner();
+    let accepts_gzip = req
+        .headers()
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|encoding| encoding.to_str().ok())
+        .is_some_and(|encoding| encoding.contains("gzip"));
+    let limit = match query.limit {
+        Some(limit) => limit,
+        None => {
+            let pages = export_pages(
+                redis_addr.get_ref().clone(),
+                username.into_inner(),
+                ExportCursor {
+                    after: query.after,
+                    gzip: accepts_gzip.then(GzipChunks::default),
+                    done: false,
+                },
+            );
+            let mut res = HttpResponse::Ok();
+            res.content_type("application/x-ndjson");
+            if accepts_gzip {
+                res.insert_header((header::CONTENT_ENCODING, "gzip"));
+            }
+            return Ok(res.streaming(pages));
+        }
+    };
+    let activities = redis_addr
+        .send(ExportUser {
+            username: username.into_inner(),
+            after: query.after,
+            limit,
+        })
+        .await
+        .map_err(ErrorInternalServerError)?
+        .map_err(ErrorInternalServerError)?;
+
+    let body = ndjson(&activities)?;
+    if accepts_gzip && body.len() > COMPRESS_THRESHOLD {
+        let gzipped = gzip(body.as_bytes())?;
+        return Ok(HttpResponse::Ok()
+            .content_type("application/x-ndjson")
+            .insert_header((header::CONTENT_ENCODING, "gzip"))
+            .body(gzipped));
+    }
+    Ok(HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .body(body))
+}
+
+/// one exported activity per line
+fn ndjson(activities: &[ExportedActivity]) -> serde_json::Result<String> {
+    let mut body = String::new();
+    for activity in activities {
+        body.push_str(&serde_json::to_string(activity)?);
+        body.push('\n');
+    }
+    Ok(body)
+}
+
+/// how far a streamed export got
+struct ExportCursor {
+    /// the last id written
+    after: Option<String>,
+    gzip: Option<GzipChunks>,
+    /// the last page was short, only the gzip trailer is left
+    done: bool,
+}
+
+/// every activity after the cursor as ndjson, only one page of `EXPORT_LIMIT` is held at a time
+fn export_pages(
+    redis_addr: Addr<Redis>,
+    username: String,
+    cursor: ExportCursor,
+) -> impl Stream<Item = Result<Bytes, Error>> {
+    stream::try_unfold(cursor, move |mut cursor| {
+        let redis_addr = redis_addr.clone();
+        let username = username.clone();
+        async move {
+            if cursor.done {
+                return match cursor.gzip.take() {
+                    Some(gzip) => Ok(Some((Bytes::from(gzip.finish()?), cursor))),
+                    None => Ok(None),
+                };
+            }
+            let page = redis_addr
+                .send(ExportUser {
+                    username,
+                    after: cursor.after.take(),
+                    limit: EXPORT_LIMIT,
+                })
+                .await
+                .map_err(ErrorInternalServerError)?
+                .map_err(ErrorInternalServerError)?;
+            cursor.done = page.len() < EXPORT_LIMIT;
+            cursor.after = page.last().map(|last| last.id.clone());
+            let body = ndjson(&page)?;
+            let chunk = match &mut cursor.gzip {
+                Some(gzip) => gzip.chunk(body.as_bytes())?,
+                None => body.into_bytes(),
+            };
+            Ok(Some((Bytes::from(chunk), cursor)))
+        }
+    })
+}
+
+/// the attachment behind the `blob_url` of an activity, until it expires
+pub async fn blob_route(
+    blob_ref: web::Path<String>,
+    redis_addr: web::Data<Addr<Redis>>,
+) -> Result<HttpResponse, Error> {
+    let blob = redis_addr
+        .send(GetBlob {
+            blob_ref: blob_ref.into_inner(),
+        })
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(match blob {
+        Some(blob) => HttpResponse::Ok()
+            .content_type("application/octet-stream")
+            .body(blob),
+        None => HttpResponse::NotFound().finish(),
+    })
+}
+
+/// every redis session with its delivery progress, for debugging stuck deliveries
+pub async fn sessions_route(
+    req: HttpRequest,
+    redis_addr: web::Data<Addr<Redis>>,
+    admin: web::Data<AdminToken>,
+) -> Result<HttpResponse, Error> {
+    if !admin.permits(&req) {
+        return Ok(HttpResponse::Unauthorized().finish());
+    }
+    let sessions = redis_addr
+        .send(ListSessions)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().json(sessions))
+}
+
+/// send the json body to the client of a session and answer with its reply
+pub async fn ask_route(
+    req: HttpRequest,
+    id: web::Path<usize>,
+    query: web::Query<AskQuery>,
+    payload: web::Json<serde_json::Value>,
+    redis_addr: web::Data<Addr<Redis>>,
+    admin: web::Data<AdminToken>,
+) -> Result<HttpResponse, Error> {
+    if !admin.permits(&req) {
+        return Ok(HttpResponse::Unauthorized().finish());
+    }
+    let reply = redis_addr
+        .send(AskSession {
+            id: id.into_inner(),
+            payload: payload.into_inner(),
+            timeout: query.timeout_ms.map_or(ASK_TIMEOUT, Duration::from_millis),
+        })
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(match reply {
+        Ok(reply) => HttpResponse::Ok().json(reply),
+        Err(error) => {
+            let mut res = match error {
+                RequestError::UnknownSession => HttpResponse::NotFound(),
+                RequestError::Timeout => HttpResponse::GatewayTimeout(),
+                RequestError::Closed => HttpResponse::Gone(),
+            };
+            res.json(AskFailure { error })
+        }
+    })
+}
+
+/// switch maintenance on or off, answers with the current state
+pub async fn maintenance_route(
+    req: HttpRequest,
+    query: web::Query<MaintenanceQuery>,
+    maintenance: web::Data<Maintenance>,
+    admin: web::Data<AdminToken>,
+) -> HttpResponse {
+    if !admin.permits(&req) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    if let Some(on) = query.on {
+        maintenance.set(on);
+        info!("maintenance is {}", if on { "on" } else { "off" });
+    }
+    HttpResponse::Ok().json(MaintenanceState {
+        maintenance: maintenance.is_on(),
+    })
+}
+
+/// `/admin/reload?poll_interval_ms=500&command_rate=20` swaps in a config with the given
+/// settings, the others keep their current values
+#[derive(Deserialize, Debug)]
+pub struct ReloadQuery {
+    pub poll_interval_ms: Option<u64>,
+    pub command_rate: Option<u32>,
+    pub max_sessions_per_user: Option<usize>,
+    pub heartbeat_min_secs: Option<u64>,
+    pub heartbeat_max_secs: Option<u64>,
+    pub register_timeout_ms: Option<u64>,
+    pub quality_interval_secs: Option<u64>,
+}
+
+#[derive(Serialize, Debug)]
+struct ReloadedConfig {
+    poll_interval_ms: u64,
+    command_rate: u32,
+    max_sessions_per_user: usize,
+    heartbeat_min_secs: u64,
+    heartbeat_max_secs: u64,
+    register_timeout_ms: u64,
+    quality_interval_secs: u64,
+}
+
+pub async fn reload_route(
+    req: HttpRequest,
+    query: web::Query<ReloadQuery>,
+    config: web::Data<LiveConfig>,
+    redis_addr: web::Data<Addr<Redis>>,
+    srv: web::Data<Addr<Websocket>>,
+    admin: web::Data<AdminToken>,
+) -> HttpResponse {
+    if !admin.permits(&req) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let query = query.into_inner();
+    let mut reloaded = (*config.load()).clone();
+    reloaded.poll_interval_ms = query.poll_interval_ms.unwrap_or(reloaded.poll_interval_ms);
+    reloaded.command_rate = query.command_rate.unwrap_or(reloaded.command_rate);
+    reloaded.max_sessions_per_user = query
+        .max_sessions_per_user
+        .unwrap_or(reloaded.max_sessions_per_user);
+    reloaded.heartbeat_min_secs = query
+        .heartbeat_min_secs
+        .unwrap_or(reloaded.heartbeat_min_secs);
+    reloaded.heartbeat_max_secs = query
+        .heartbeat_max_secs
+        .unwrap_or(reloaded.heartbeat_max_secs);
+    reloaded.register_timeout_ms = query
+        .register_timeout_ms
+        .unwrap_or(reloaded.register_timeout_ms);
+    reloaded.quality_interval_secs = query
+        .quality_interval_secs
+        .unwrap_or(reloaded.quality_interval_secs);
+    if heartbeat_bound(reloaded.heartbeat_min_secs) > heartbeat_bound(reloaded.heartbeat_max_secs) {
+        return HttpResponse::BadRequest().body("heartbeat_min_secs is above heartbeat_max_secs");
+    }
+
+    let reload = ReloadConfig {
+        poll_interval: Duration::from_millis(reloaded.poll_interval_ms),
+        max_sessions_per_user: reloaded.max_sessions_per_user,
+    };
+    redis_addr.do_send(reload.clone());
+    srv.do_send(reload);
+    let state = ReloadedConfig {
+        poll_interval_ms: reloaded.poll_interval_ms,
+        command_rate: reloaded.command_rate,
+        max_sessions_per_user: reloaded.max_sessions_per_user,
+        heartbeat_min_secs: reloaded.heartbeat_min_secs,
+        heartbeat_max_secs: reloaded.heartbeat_max_secs,
+        register_timeout_ms: reloaded.register_timeout_ms,
+        quality_interval_secs: reloaded.quality_interval_secs,
+    };
+    config.store(reloaded);
+    info!("config reloaded: {:?}", state);
+    HttpResponse::Ok().json(state)
+}
+
+/// `/admin/drain?reconnect_after_ms=5000` tells every client to reconnect elsewhere
+#[derive(Deserialize)]
+pub struct DrainQuery {
+    pub reconnect_after_ms: u64,
+}
+
+/// ask every session to reconnect to another node and close them, e.g. before a rolling deploy
+pub async fn drain_route(
+    req: HttpRequest,
+    query: web::Query<DrainQuery>,
+    srv: web::Data<Addr<Websocket>>,
+    admin: web::Data<AdminToken>,
+) -> HttpResponse {
+    if !admin.permits(&req) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    srv.do_send(DrainConnections {
+        reconnect_after_ms: query.reconnect_after_ms,
+    });
+    HttpResponse::Accepted().finish()
+}
+
+pub async fn metrics_route() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics::render())
 }
 
 // pub async fn push_msg_route(
@@ -36,3 +576,492 @@ pub async fn socket_route(
 //     let ws_addr = srv.as_ref();
 //     ws_addr.send(msg)
 // }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+        pin::Pin,
+        time::Duration,
+    };
+
+    use actix::Actor;
+    use actix_web::{
+        body::MessageBody,
+        http::StatusCode,
+        test::{self, TestRequest},
+        App, HttpServer,
+    };
+    use flate2::read::GzDecoder;
+
+    use super::*;
+    use crate::{
+        addr::{MemoryStore, Seravee, Trial},
+        config::CONFIG,
+        entity::Activity,
+    };
+
+    const ADMIN_TOKEN: &str = "exia";
+
+    fn admin_token() -> web::Data<AdminToken> {
+        web::Data::new(AdminToken::new(Some(ADMIN_TOKEN.to_string())))
+    }
+
+    fn as_admin(req: TestRequest) -> TestRequest {
+        req.insert_header((header::AUTHORIZATION, format!("Bearer {}", ADMIN_TOKEN)))
+    }
+
+    #[test]
+    fn origin_respects_the_proxy_trust() {
+        let req = TestRequest::default()
+            .peer_addr("10.0.0.2:41000".parse().unwrap())
+            .insert_header((header::USER_AGENT, "veda-test"))
+            .insert_header(("X-Forwarded-For", "203.0.113.7, 10.0.0.1"))
+            .to_http_request();
+
+        assert_eq!(
+            origin(&req, false),
+            Origin {
+                ip: Some("10.0.0.2".to_string()),
+                user_agent: Some("veda-test".to_string()),
+            }
+        );
+        assert_eq!(origin(&req, true).ip.as_deref(), Some("203.0.113.7"));
+    }
+
+    const UPGRADE: &[u8] = b"GET /ws/ HTTP/1.1\r\n\
+        Host: localhost\r\n\
+        Connection: Upgrade\r\n\
+        Upgrade: websocket\r\n\
+        Sec-WebSocket-Version: 13\r\n\
+        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+
+    /// a masked text frame as a client sends it, the zero mask leaves the payload as it is
+    fn text_frame(text: &str) -> Vec<u8> {
+        let mut frame = vec![0x81, 0x80 | text.len() as u8, 0, 0, 0, 0];
+        frame.extend_from_slice(text.as_bytes());
+        frame
+    }
+
+    /// read until `needle` shows up or the socket times out
+    fn read_until(stream: &mut TcpStream, needle: &str) -> String {
+        let mut received = vec![];
+        let mut buf = [0u8; 1024];
+        while !String::from_utf8_lossy(&received).contains(needle) {
+            match stream.read(&mut buf) {
+                Ok(n) if n > 0 => received.extend_from_slice(&buf[..n]),
+                _ => break,
+            }
+        }
+        String::from_utf8_lossy(&received).into_owned()
+    }
+
+    #[actix_rt::test]
+    async fn maintenance_turns_away_new_upgrades_only() {
+        let redis_addr = Redis::new(MemoryStore::default()).start();
+        let websocket_addr = Websocket::default().start();
+        let seravee_addr = Seravee {
+            addr: "127.0.0.1:0".parse().unwrap(),
+            redis_addr: redis_addr.clone(),
+        }
+        .start();
+        let maintenance = web::Data::new(Maintenance::default());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let app_redis_addr = redis_addr.clone();
+        let app_maintenance = maintenance.clone();
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(web::Data::new(SessionActors {
+                    redis: app_redis_addr.clone(),
+                    websocket: websocket_addr.clone(),
+                    grpc: seravee_addr.clone(),
+                }))
+                .app_data(web::Data::new(LiveConfig::new(CONFIG.clone())))
+                .app_data(admin_token())
+                .app_data(app_maintenance.clone())
+                .service(web::resource("/ws/").to(socket_route))
+        })
+        .workers(1)
+        .listen(listener)
+        .unwrap()
+        .run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let connect = move || {
+            let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(3)))
+                .unwrap();
+            stream.write_all(UPGRADE).unwrap();
+            stream
+        };
+        let mut session = actix_web::rt::task::spawn_blocking(move || {
+            let mut stream = connect();
+            assert!(read_until(&mut stream, "\r\n\r\n").starts_with("HTTP/1.1 101"));
+            stream.write_all(&text_frame("/login neil")).unwrap();
+            read_until(&mut stream, "neil");
+            stream
+        })
+        .await
+        .unwrap();
+
+        let req = as_admin(TestRequest::post().uri("/admin/maintenance?on=true")).to_request();
+        let app = test::init_service(
+            App::new()
+                .app_data(maintenance.clone())
+                .app_data(admin_token())
+                .service(
+                    web::resource("/admin/maintenance").route(web::post().to(maintenance_route)),
+                ),
+        )
+        .await;
+        let state: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(state["maintenance"], true);
+
+        let rejected = actix_web::rt::task::spawn_blocking(move || {
+            let mut stream = connect();
+            read_until(&mut stream, "\r\n\r\n")
+        })
+        .await
+        .unwrap();
+        assert!(rejected.starts_with("HTTP/1.1 503"), "{}", rejected);
+
+        // the open session still gets its activities
+        redis_addr
+            .send(Trial {
+                message: Activity {
+                    activity_type: "message".to_string(),
+                    activity: "still-delivered".to_string(),
+                    ..Default::default()
+                },
+                receivers: vec!["neil".to_string()],
+                quantity: None,
+            })
+            .await
+            .unwrap();
+        let received = actix_web::rt::task::spawn_blocking(move || {
+            read_until(&mut session, "still-delivered")
+        })
+        .await
+        .unwrap();
+        assert!(received.contains("still-delivered"));
+        handle.stop(false).await;
+    }
+
+    #[actix_rt::test]
+    async fn the_client_answers_a_server_request() {
+        let redis_addr = Redis::new(MemoryStore::default()).start();
+        let websocket_addr = Websocket::default().start();
+        let seravee_addr = Seravee {
+            addr: "127.0.0.1:0".parse().unwrap(),
+            redis_addr: redis_addr.clone(),
+        }
+        .start();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let app_redis_addr = redis_addr.clone();
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(web::Data::new(SessionActors {
+                    redis: app_redis_addr.clone(),
+                    websocket: websocket_addr.clone(),
+                    grpc: seravee_addr.clone(),
+                }))
+                .app_data(web::Data::new(Maintenance::default()))
+                .app_data(web::Data::new(LiveConfig::new(CONFIG.clone())))
+                .app_data(admin_token())
+                .service(web::resource("/ws/").to(socket_route))
+        })
+        .workers(1)
+        .listen(listener)
+        .unwrap()
+        .run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let mut session = actix_web::rt::task::spawn_blocking(move || {
+            let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(3)))
+                .unwrap();
+            stream.write_all(UPGRADE).unwrap();
+            read_until(&mut stream, "\r\n\r\n");
+            stream.write_all(&text_frame("/login marie")).unwrap();
+            read_until(&mut stream, "marie");
+            stream
+        })
+        .await
+        .unwrap();
+        let id = redis_addr.send(ListSessions).await.unwrap()[0].id;
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(redis_addr))
+                .app_data(admin_token())
+                .service(
+                    web::resource("/admin/sessions/{id}/ask").route(web::post().to(ask_route)),
+                ),
+        )
+        .await;
+        let ask = |id: usize, timeout_ms: u64| {
+            as_admin(TestRequest::post().uri(&format!(
+                "/admin/sessions/{}/ask?timeout_ms={}",
+                id, timeout_ms
+            )))
+            .set_json(serde_json::json!({"confirm": "purge"}))
+            .to_request()
+        };
+        let client = actix_web::rt::task::spawn_blocking(move || {
+            let request = read_until(&mut session, "purge");
+            let req_id = request
+                .split("\"req_id\":\"")
+                .nth(1)
+                .and_then(|rest| rest.split('"').next())
+                .unwrap()
+                .to_owned();
+            let reply = format!("/reply {} {{\"confirmed\":true}}", req_id);
+            session.write_all(&text_frame(&reply)).unwrap();
+            session
+        });
+        let reply: serde_json::Value = test::call_and_read_body_json(&app, ask(id, 3000)).await;
+        assert_eq!(reply, serde_json::json!({"confirmed": true}));
+        let _session = client.await.unwrap();
+
+        // nobody answers in time
+        let res = test::call_service(&app, ask(id, 50)).await;
+        assert_eq!(res.status(), StatusCode::GATEWAY_TIMEOUT);
+        let res = test::call_service(&app, ask(id + 1, 50)).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let anonymous = TestRequest::post()
+            .uri(&format!("/admin/sessions/{}/ask", id))
+            .set_json(serde_json::json!({"confirm": "purge"}))
+            .to_request();
+        let res = test::call_service(&app, anonymous).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        handle.stop(false).await;
+    }
+
+    #[actix_rt::test]
+    async fn export_pages_through_the_stream_as_ndjson() {
+        let redis_addr = Redis::new(MemoryStore::default()).start();
+        let mut ids = vec![];
+        for activity in &["gn-x", "gn-y", "gn-z"] {
+            let pushed = redis_addr
+                .send(Trial {
+                    message: Activity {
+                        activity_type: "message".to_string(),
+                        activity: activity.to_string(),
+                        ..Default::default()
+                    },
+                    receivers: vec!["kyrios".to_string()],
+                    quantity: None,
+                })
+                .await
+                .unwrap();
+            ids.push(pushed[0].1.clone());
+        }
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(redis_addr))
+                .app_data(admin_token())
+                .service(web::resource("/export/{username}").to(export_route)),
+        )
+        .await;
+
+        let page = |uri: String| {
+            let req = as_admin(TestRequest::get().uri(&uri)).to_request();
+            test::call_and_read_body(&app, req)
+        };
+        let lines = |body: &[u8]| -> Vec<serde_json::Value> {
+            std::str::from_utf8(body)
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect()
+        };
+
+        let first = lines(&page("/export/kyrios?limit=2".to_string()).await);
+        assert_eq!(first.len(), 2);
+        assert_eq!(first[0]["id"], ids[0].as_str());
+        assert_eq!(first[0]["activity"], "gn-x");
+        assert_eq!(first[1]["id"], ids[1].as_str());
+        assert!(first[0]["timestamp"].is_string());
+
+        let rest = lines(&page(format!("/export/kyrios?after={}", ids[1])).await);
+        assert_eq!(rest.len(), 1);
+        assert_eq!(rest[0]["id"], ids[2].as_str());
+        assert_eq!(rest[0]["activity"], "gn-z");
+    }
+
+    #[actix_rt::test]
+    async fn a_whole_export_is_streamed_a_page_at_a_time() {
+        let redis_addr = Redis::new(MemoryStore::default()).start();
+        let total = EXPORT_LIMIT * 2 + 5;
+        for n in 0..total {
+            redis_addr
+                .send(Trial {
+                    message: Activity {
+                        activity_type: "message".to_string(),
+                        activity: n.to_string(),
+                        ..Default::default()
+                    },
+                    receivers: vec!["kyrios".to_string()],
+                    quantity: None,
+                })
+                .await
+                .unwrap();
+        }
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(redis_addr))
+                .app_data(admin_token())
+                .service(web::resource("/export/{username}").to(export_route)),
+        )
+        .await;
+
+        let req = as_admin(TestRequest::get().uri("/export/kyrios")).to_request();
+        let mut body = test::call_service(&app, req).await.into_body();
+        let mut exported = vec![];
+        while let Some(chunk) =
+            futures::future::poll_fn(|cx| Pin::new(&mut body).poll_next(cx)).await
+        {
+            let chunk = chunk.unwrap();
+            let lines: Vec<serde_json::Value> = std::str::from_utf8(&chunk)
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect();
+            // never more than a page in memory
+            assert!(lines.len() <= EXPORT_LIMIT, "{}", lines.len());
+            exported.extend(lines);
+        }
+        assert_eq!(exported.len(), total);
+        for (n, line) in exported.iter().enumerate() {
+            assert_eq!(line["activity"], n.to_string());
+        }
+
+        // gzipped as a whole, chunk by chunk
+        let req = as_admin(TestRequest::get().uri("/export/kyrios"))
+            .insert_header((header::ACCEPT_ENCODING, "gzip"))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.headers().get(header::CONTENT_ENCODING).unwrap(), "gzip");
+        let gzipped = test::read_body(res).await;
+        let mut ndjson = String::new();
+        GzDecoder::new(&gzipped[..])
+            .read_to_string(&mut ndjson)
+            .unwrap();
+        assert_eq!(ndjson.lines().count(), total);
+    }
+
+    #[actix_rt::test]
+    async fn export_asks_for_the_admin_token() {
+        let redis_addr = Redis::new(MemoryStore::default()).start();
+        let export = |admin: AdminToken| {
+            App::new()
+                .app_data(web::Data::new(redis_addr.clone()))
+                .app_data(web::Data::new(admin))
+                .service(web::resource("/export/{username}").to(export_route))
+        };
+
+        let app = test::init_service(export(AdminToken::new(Some(ADMIN_TOKEN.to_string())))).await;
+        let anonymous = TestRequest::get().uri("/export/kyrios").to_request();
+        let res = test::call_service(&app, anonymous).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let wrong = TestRequest::get()
+            .uri("/export/kyrios")
+            .insert_header((header::AUTHORIZATION, "Bearer virtue"))
+            .to_request();
+        let res = test::call_service(&app, wrong).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let admin = as_admin(TestRequest::get().uri("/export/kyrios")).to_request();
+        let res = test::call_service(&app, admin).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // no token configured, nobody gets in
+        let app = test::init_service(export(AdminToken::new(None))).await;
+        let req = TestRequest::get()
+            .uri("/export/kyrios")
+            .insert_header((header::AUTHORIZATION, "Bearer "))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_rt::test]
+    async fn admin_routes_ask_for_the_admin_token() {
+        let redis_addr = Redis::new(MemoryStore::default()).start();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(redis_addr))
+                .app_data(web::Data::new(Websocket::default().start()))
+                .app_data(web::Data::new(Maintenance::default()))
+                .app_data(web::Data::new(LiveConfig::new(CONFIG.clone())))
+                .app_data(admin_token())
+                .service(web::resource("/admin/sessions").to(sessions_route))
+                .service(
+                    web::resource("/admin/maintenance").route(web::post().to(maintenance_route)),
+                )
+                .service(web::resource("/admin/drain").route(web::post().to(drain_route)))
+                .service(web::resource("/admin/reload").route(web::post().to(reload_route))),
+        )
+        .await;
+
+        for (req, status) in [
+            (
+                TestRequest::get().uri("/admin/sessions"),
+                StatusCode::UNAUTHORIZED,
+            ),
+            (
+                as_admin(TestRequest::get().uri("/admin/sessions")),
+                StatusCode::OK,
+            ),
+            (
+                TestRequest::post().uri("/admin/maintenance?on=true"),
+                StatusCode::UNAUTHORIZED,
+            ),
+            (
+                as_admin(TestRequest::get().uri("/admin/maintenance?on=true")),
+                StatusCode::METHOD_NOT_ALLOWED,
+            ),
+            (
+                as_admin(TestRequest::post().uri("/admin/maintenance")),
+                StatusCode::OK,
+            ),
+            (
+                TestRequest::post().uri("/admin/drain?reconnect_after_ms=5000"),
+                StatusCode::UNAUTHORIZED,
+            ),
+            (
+                as_admin(TestRequest::post().uri("/admin/drain?reconnect_after_ms=5000")),
+                StatusCode::ACCEPTED,
+            ),
+            (
+                TestRequest::post().uri("/admin/reload?command_rate=20"),
+                StatusCode::UNAUTHORIZED,
+            ),
+            (
+                as_admin(
+                    TestRequest::post()
+                        .uri("/admin/reload?heartbeat_min_secs=60&heartbeat_max_secs=10"),
+                ),
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                as_admin(
+                    TestRequest::post()
+                        .uri("/admin/reload?heartbeat_min_secs=5&heartbeat_max_secs=30"),
+                ),
+                StatusCode::OK,
+            ),
+        ] {
+            let res = test::call_service(&app, req.to_request()).await;
+            assert_eq!(res.status(), status);
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use std::time::Duration;
+
+use crate::constants::{HEARTBEAT_INTERVAL, LOW_RTT};
+
+/// ping interval of a session, adapted to the round-trip times of its pongs:
+/// it grows while the link stays fast and halves when the rtt spikes
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdaptiveHeartbeat {
+    min: Duration,
+    max: Duration,
+    interval: Duration,
+    /// smoothed rtt, `None` before the first pong
+    srtt: Option<Duration>,
+    /// set by the client with `/keepalive`, no longer adapted
+    pinned: bool,
+    /// the client sends its own pings, the server only waits for them
+    silent: bool,
+    /// bumped whenever the client sets the interval, ends the loop on the old one
+    epoch: u32,
+}
+
+impl AdaptiveHeartbeat {
+    /// starts at `HEARTBEAT_INTERVAL` within `min..=max`, equal bounds keep the interval fixed
+    pub fn new(min: Duration, max: Duration) -> Self {
+        let max = max.max(min);
+        Self {
+            min,
+            max,
+            interval: HEARTBEAT_INTERVAL.clamp(min, max),
+            srtt: None,
+            pinned: false,
+            silent: false,
+            epoch: 0,
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// a client silent for this long is gone, two intervals
+    pub fn timeout(&self) -> Duration {
+        self.interval * 2
+    }
+
+    /// whether the server sends pings
+    pub fn pings(&self) -> bool {
+        !self.silent
+    }
+
+    pub fn epoch(&self) -> u32 {
+        self.epoch
+    }
+
+    /// fix the interval the client asked for within `min..=max`, `None` stops the pings
+    /// and waits the longest interval for the pings of the client; returns the interval pinged at
+    pub fn pin(&mut self, interval: Option<Duration>) -> Option<Duration> {
+        self.pinned = true;
+        self.silent = interval.is_none();
+        self.interval = interval.unwrap_or(self.max).clamp(self.min, self.max);
+        self.epoch += 1;
+        interval.map(|_| self.interval)
+    }
+
+    /// take the rtt of a pong into account
+    pub fn observe(&mut self, rtt: Duration) {
+        if self.pinned {
+            return;
+        }
+        let srtt = match self.srtt {
+            Some(srtt) => (srtt * 7 + rtt) / 8,
+            None => rtt,
+        };
+        self.srtt = Some(srtt);
+        if rtt > LOW_RTT && rtt > srtt * 2 {
+            // the link degrades, notice a failure sooner
+            self.interval = (self.interval / 2).max(self.min);
+        } else if rtt > LOW_RTT && srtt > LOW_RTT {
+            self.interval = (self.interval * 3 / 4).max(self.min);
+        } else if srtt <= LOW_RTT {
+            self.interval = (self.interval * 5 / 4).min(self.max);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rising_rtt_tightens_the_interval() {
+        let mut heartbeat = AdaptiveHeartbeat::new(Duration::from_secs(5), Duration::from_secs(60));
+        assert_eq!(heartbeat.interval(), HEARTBEAT_INTERVAL);
+
+        for _ in 0..10 {
+            heartbeat.observe(Duration::from_millis(20));
+        }
+        let relaxed = heartbeat.interval();
+        assert_eq!(relaxed, Duration::from_secs(60));
+
+        let mut intervals = vec![];
+        for rtt in &[300, 800, 1500, 3000] {
+            heartbeat.observe(Duration::from_millis(*rtt));
+            intervals.push(heartbeat.interval());
+        }
+        assert!(intervals.windows(2).all(|w| w[1] < w[0]), "{:?}", intervals);
+        assert!(heartbeat.timeout() < relaxed * 2);
+
+        for _ in 0..20 {
+            heartbeat.observe(Duration::from_secs(5));
+        }
+        assert_eq!(heartbeat.interval(), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn equal_bounds_keep_the_interval() {
+        let mut heartbeat = AdaptiveHeartbeat::new(HEARTBEAT_INTERVAL, HEARTBEAT_INTERVAL);
+        heartbeat.observe(Duration::from_millis(10));
+        heartbeat.observe(Duration::from_secs(10));
+        assert_eq!(heartbeat.interval(), HEARTBEAT_INTERVAL);
+    }
+
+    #[test]
+    fn a_pinned_interval_stays_within_the_bounds() {
+        let mut heartbeat = AdaptiveHeartbeat::new(Duration::from_secs(5), Duration::from_secs(60));
+        assert_eq!(
+            heartbeat.pin(Some(Duration::from_secs(1))),
+            Some(Duration::from_secs(5))
+        );
+        heartbeat.observe(Duration::from_millis(10));
+        assert_eq!(heartbeat.interval(), Duration::from_secs(5));
+        assert_eq!(
+            heartbeat.pin(Some(Duration::from_secs(600))),
+            Some(Duration::from_secs(60))
+        );
+        assert!(heartbeat.pings());
+
+        assert_eq!(heartbeat.pin(None), None);
+        assert!(!heartbeat.pings());
+        assert_eq!(heartbeat.timeout(), Duration::from_secs(120));
+        assert_eq!(heartbeat.epoch(), 3);
+    }
+}
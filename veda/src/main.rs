@@ -6,11 +6,17 @@ pub mod activity {
     tonic::include_proto!("activity");
 }
 
+mod compress;
 mod config;
 mod constants;
 mod entity;
 mod handler;
+mod heartbeat;
+mod metrics;
+mod retry;
 mod server;
+mod throttle;
+mod trace;
 use server::serv;
 
 #[actix_web::main]
@@ -0,0 +1,212 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+lazy_static! {
+    /// redis errors swallowed by the redis sessions, labeled by command
+    pub static ref REDIS_ERRORS: CounterVec = CounterVec::new(
+        "veda_redis_errors_total",
+        "redis errors swallowed while delivering activities",
+        "op",
+    );
+    /// activities through the delivery path, labeled by outcome:
+    /// `pushed`, `push_failed`, `duplicate`, `delivered`, `retried`, `failed`, `redelivered`,
+    /// `skipped`, `expired`, `compacted`, `overflowed`, `webhook_posted`, `webhook_failed`
+    /// and `acked`
+    pub static ref DELIVERIES: CounterVec = CounterVec::new(
+        "veda_deliveries_total",
+        "activities pushed, delivered and acked",
+        "outcome",
+    );
+    /// backlog of the streams of online users at the last sample, labeled by stat:
+    /// `streams`, `total`, `max` and `over_threshold`
+    pub static ref BACKLOG: GaugeVec = GaugeVec::new(
+        "veda_stream_backlog",
+        "undelivered activities in the streams of online users",
+        "stat",
+    );
+}
+
+/// render all metrics in the prometheus text format
+pub fn render() -> String {
+    let mut out = String::new();
+    REDIS_ERRORS.render(&mut out);
+    DELIVERIES.render(&mut out);
+    BACKLOG.render(&mut out);
+    out
+}
+
+/// a counter partitioned by the value of one label
+pub struct CounterVec {
+    name: &'static str,
+    help: &'static str,
+    label: &'static str,
+    values: Mutex<BTreeMap<String, u64>>,
+}
+
+impl CounterVec {
+    pub fn new(name: &'static str, help: &'static str, label: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            label,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn inc(&self, value: &str) {
+        self.inc_by(value, 1);
+    }
+
+    pub fn inc_by(&self, value: &str, by: u64) {
+        *self
+            .values
+            .lock()
+            .unwrap()
+            .entry(value.to_owned())
+            .or_default() += by;
+    }
+
+    #[cfg(test)]
+    pub fn get(&self, value: &str) -> u64 {
+        self.values
+            .lock()
+            .unwrap()
+            .get(value)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn render(&self, out: &mut String) {
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help);
+        let _ = writeln!(out, "# TYPE {} counter", self.name);
+        for (value, count) in self.values.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "{}{{{}=\"{}\"}} {}",
+                self.name, self.label, value, count
+            );
+        }
+    }
+}
+
+/// a gauge partitioned by the value of one label, each value is replaced by the next sample
+pub struct GaugeVec {
+    name: &'static str,
+    help: &'static str,
+    label: &'static str,
+    values: Mutex<BTreeMap<String, u64>>,
+}
+
+impl GaugeVec {
+    pub fn new(name: &'static str, help: &'static str, label: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            label,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn set(&self, value: &str, to: u64) {
+        self.values.lock().unwrap().insert(value.to_owned(), to);
+    }
+
+    #[cfg(test)]
+    pub fn get(&self, value: &str) -> u64 {
+        self.values
+            .lock()
+            .unwrap()
+            .get(value)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn render(&self, out: &mut String) {
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help);
+        let _ = writeln!(out, "# TYPE {} gauge", self.name);
+        for (value, gauge) in self.values.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "{}{{{}=\"{}\"}} {}",
+                self.name, self.label, value, gauge
+            );
+        }
+    }
+}
+
+/// counts errors within a fixed window, tells when the threshold is reached
+pub struct ErrorRate {
+    pub total: u64,
+    in_window: u64,
+    window_start: Instant,
+    window: Duration,
+    threshold: u64,
+}
+
+impl ErrorRate {
+    pub fn new(window: Duration, threshold: u64) -> Self {
+        Self {
+            total: 0,
+            in_window: 0,
+            window_start: Instant::now(),
+            window,
+            threshold,
+        }
+    }
+
+    /// record an error, returns `true` when the error rate just crossed the threshold
+    pub fn record(&mut self) -> bool {
+        if self.window_start.elapsed() > self.window {
+            self.window_start = Instant::now();
+            self.in_window = 0;
+        }
+        self.total += 1;
+        self.in_window += 1;
+        self.in_window == self.threshold
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counter_vec_counts_per_label() {
+        let counter = CounterVec::new("veda_test_total", "test counter", "op");
+        counter.inc("xread");
+        counter.inc("xread");
+        counter.inc("xdel");
+        assert_eq!(counter.get("xread"), 2);
+        assert_eq!(counter.get("xdel"), 1);
+        assert_eq!(counter.get("xinfo"), 0);
+
+        let mut out = String::new();
+        counter.render(&mut out);
+        assert!(out.contains("veda_test_total{op=\"xread\"} 2"));
+    }
+
+    #[test]
+    fn gauge_vec_keeps_the_last_value() {
+        let gauge = GaugeVec::new("veda_test", "test gauge", "stat");
+        gauge.set("max", 12);
+        gauge.set("max", 3);
+        assert_eq!(gauge.get("max"), 3);
+
+        let mut out = String::new();
+        gauge.render(&mut out);
+        assert!(out.contains("# TYPE veda_test gauge"));
+        assert!(out.contains("veda_test{stat=\"max\"} 3"));
+    }
+
+    #[test]
+    fn error_rate_crosses_threshold_once_per_window() {
+        let mut rate = ErrorRate::new(Duration::from_secs(60), 3);
+        let crossed: Vec<bool> = (0..5).map(|_| rate.record()).collect();
+        assert_eq!(crossed, vec![false, false, true, false, false]);
+        assert_eq!(rate.total, 5);
+    }
+}
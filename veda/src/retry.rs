@@ -0,0 +1,55 @@
+use std::time::Duration;
+
+/// how a frame that a busy session did not take in time is sent again,
+/// the default sends once and waits as long as it takes
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// attempts in total, including the first one
+    pub max_attempts: u32,
+    /// wait before the first retry, doubled for every further retry
+    pub backoff: Duration,
+    /// an attempt not taken within it failed, `None` waits forever
+    pub timeout: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 1,
+            backoff: Duration::ZERO,
+            timeout: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// wait before attempt `attempt + 1`, `attempt` counts from 1
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        self.backoff * 2u32.saturating_pow(attempt.saturating_sub(1))
+    }
+
+    /// whether another attempt follows a failed `attempt`
+    pub fn retries_after(&self, attempt: u32) -> bool {
+        attempt < self.max_attempts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_until_attempts_run_out() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            backoff: Duration::from_millis(50),
+            timeout: Some(Duration::from_millis(100)),
+        };
+        assert_eq!(policy.backoff(1), Duration::from_millis(50));
+        assert_eq!(policy.backoff(2), Duration::from_millis(100));
+        assert!(policy.retries_after(2));
+        assert!(!policy.retries_after(3));
+
+        assert!(!RetryPolicy::default().retries_after(1));
+    }
+}
@@ -1,6 +1,12 @@
-use std::net::SocketAddr;
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    net::{SocketAddr, TcpListener, ToSocketAddrs},
+    time::Duration,
+};
 
 use actix::Actor;
+use socket2::{Domain, Socket, TcpKeepalive, Type};
 
 use actix_web::{
     middleware::Logger,
@@ -11,20 +17,89 @@ use tonic::transport::Server;
 
 use crate::{
     activity::activity_source_server::ActivitySourceServer,
-    addr::{add_websocket, init_redis, Seravee},
-    config::CONFIG,
-    handler::socket_route,
+    addr::{init_redis, init_websocket, Seravee},
+    config::{LiveConfig, TlsConfig, CONFIG},
+    handler::{
+        ask_route, blob_route, drain_route, export_route, maintenance_route, metrics_route,
+        reload_route, sessions_route, socket_route, AdminToken, Maintenance, SessionActors,
+    },
 };
 
+/// open a pem file, naming the path when it cannot be read
+fn open_pem(kind: &str, path: &str) -> io::Result<BufReader<File>> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| io::Error::new(e.kind(), format!("tls {} {}: {}", kind, path, e)))
+}
+
+/// load the certificate chain and pkcs8 private key for wss
+pub fn load_rustls_config(tls: &TlsConfig) -> io::Result<rustls::ServerConfig> {
+    let invalid = |kind: &str, path: &str, reason: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("tls {} {}: {}", kind, path, reason),
+        )
+    };
+
+    let certs: Vec<rustls::Certificate> =
+        rustls_pemfile::certs(&mut open_pem("cert", &tls.cert_path)?)
+            .map_err(|e| invalid("cert", &tls.cert_path, &e.to_string()))?
+            .into_iter()
+            .map(rustls::Certificate)
+            .collect();
+    if certs.is_empty() {
+        return Err(invalid("cert", &tls.cert_path, "no certificate found"));
+    }
+
+    let key = rustls_pemfile::pkcs8_private_keys(&mut open_pem("key", &tls.key_path)?)
+        .map_err(|e| invalid("key", &tls.key_path, &e.to_string()))?
+        .into_iter()
+        .next()
+        .map(rustls::PrivateKey)
+        .ok_or_else(|| invalid("key", &tls.key_path, "no pkcs8 private key found"))?;
+
+    rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| invalid("key", &tls.key_path, &e.to_string()))
+}
+
+/// bind the http listener, with tcp keepalive the os notices dead peers before the heartbeat does.
+/// accepted connections inherit the keepalive of the listener
+pub fn bind_listener(addr: &str, keepalive: Option<Duration>) -> io::Result<TcpListener> {
+    let addr = addr.to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("cannot resolve {}", addr),
+        )
+    })?;
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    if let Some(keepalive) = keepalive {
+        socket.set_tcp_keepalive(
+            &TcpKeepalive::new()
+                .with_time(keepalive)
+                .with_interval(keepalive),
+        )?;
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
+
 pub async fn serv() -> std::io::Result<()> {
     std::env::set_var("RUST_LOG", &CONFIG.log);
     env_logger::init();
-    let redis_addr = init_redis(&CONFIG.redis_url);
+    // fail before any actor starts when the cert or key is unusable
+    let tls = CONFIG.tls.as_ref().map(load_rustls_config).transpose()?;
+    let redis_addr = init_redis(&CONFIG)?;
+    let websocket_addr = init_websocket(&CONFIG);
     let addr: SocketAddr = CONFIG.grpc_url.parse().unwrap();
 
     let seravee = Seravee {
-        addr: addr,
-        redis_addr: redis_addr.clone().recipient(),
+        addr,
+        redis_addr: redis_addr.clone(),
     };
 
     let seravee_addr = seravee.clone().start();
@@ -35,15 +110,171 @@ pub async fn serv() -> std::io::Result<()> {
             .await;
     });
 
-    HttpServer::new(move || {
+    let maintenance = Data::new(Maintenance::default());
+    let live_config = Data::new(LiveConfig::new(CONFIG.clone()));
+    let admin_token = Data::new(AdminToken::new(CONFIG.admin_token.clone()));
+    let server = HttpServer::new(move || {
         App::new()
             .wrap(Logger::default())
-            .configure(add_websocket)
+            .app_data(maintenance.clone())
+            .app_data(live_config.clone())
+            .app_data(admin_token.clone())
             .app_data(Data::new(redis_addr.clone()))
-            .app_data(Data::new(seravee_addr.clone()))
+            .app_data(Data::new(websocket_addr.clone()))
+            .app_data(Data::new(SessionActors {
+                redis: redis_addr.clone(),
+                websocket: websocket_addr.clone(),
+                grpc: seravee_addr.clone(),
+            }))
             .service(web::resource("/ws/").to(socket_route))
-    })
-    .bind(&CONFIG.server)?
+            .service(web::resource("/metrics").to(metrics_route))
+            .service(web::resource("/export/{username}").to(export_route))
+            .service(web::resource("/blobs/{blob_ref}").to(blob_route))
+            .service(web::resource("/admin/sessions").to(sessions_route))
+            .service(web::resource("/admin/sessions/{id}/ask").route(web::post().to(ask_route)))
+            .service(web::resource("/admin/maintenance").route(web::post().to(maintenance_route)))
+            .service(web::resource("/admin/drain").route(web::post().to(drain_route)))
+            .service(web::resource("/admin/reload").route(web::post().to(reload_route)))
+    });
+    let keepalive = Some(Duration::from_secs(CONFIG.tcp_keepalive_secs)).filter(|d| !d.is_zero());
+    let listener = bind_listener(&CONFIG.server, keepalive)?;
+    match tls {
+        Some(tls) => server.listen_rustls(listener, tls)?,
+        None => server.listen(listener)?,
+    }
     .run()
     .await
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::TryFrom,
+        io::{Read, Write},
+        net::TcpStream,
+        path::PathBuf,
+        sync::Arc,
+    };
+
+    use super::*;
+    use crate::addr::{MemoryStore, Redis, Websocket};
+
+    /// write a self-signed cert for localhost into a fresh temp dir
+    fn self_signed(dir: &str) -> (TlsConfig, rustls::Certificate) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = std::env::temp_dir().join(format!("veda-{}-{}", dir, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| -> PathBuf { dir.join(name) };
+        std::fs::write(path("cert.pem"), cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(path("key.pem"), cert.serialize_private_key_pem()).unwrap();
+        let tls = TlsConfig {
+            cert_path: path("cert.pem").to_string_lossy().into_owned(),
+            key_path: path("key.pem").to_string_lossy().into_owned(),
+        };
+        (tls, rustls::Certificate(cert.serialize_der().unwrap()))
+    }
+
+    #[test]
+    fn unusable_tls_files_are_reported() {
+        let (tls, _) = self_signed("tls-missing");
+        let missing = TlsConfig {
+            cert_path: "/nonexistent/cert.pem".to_string(),
+            ..tls.clone()
+        };
+        let err = load_rustls_config(&missing).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err.to_string().contains("/nonexistent/cert.pem"));
+
+        // a certificate is not a private key
+        let swapped = TlsConfig {
+            key_path: tls.cert_path.clone(),
+            ..tls
+        };
+        let err = load_rustls_config(&swapped).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("no pkcs8 private key"));
+    }
+
+    #[test]
+    fn accepted_connections_keep_alive() {
+        let listener = bind_listener("127.0.0.1:0", Some(Duration::from_secs(15))).unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+
+        let socket = socket2::SockRef::from(&accepted);
+        assert!(socket.keepalive().unwrap());
+        assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(15));
+
+        let listener = bind_listener("127.0.0.1:0", None).unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        assert!(!socket2::SockRef::from(&accepted).keepalive().unwrap());
+    }
+
+    #[actix_rt::test]
+    async fn wss_handshake() {
+        let (tls, cert) = self_signed("tls-handshake");
+        let config = load_rustls_config(&tls).unwrap();
+
+        let redis_addr = Redis::new(MemoryStore::default()).start();
+        let websocket_addr = Websocket::default().start();
+        let seravee_addr = Seravee {
+            addr: "127.0.0.1:0".parse().unwrap(),
+            redis_addr: redis_addr.clone(),
+        }
+        .start();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(Data::new(SessionActors {
+                    redis: redis_addr.clone(),
+                    websocket: websocket_addr.clone(),
+                    grpc: seravee_addr.clone(),
+                }))
+                .app_data(Data::new(Maintenance::default()))
+                .app_data(Data::new(LiveConfig::new(CONFIG.clone())))
+                .app_data(Data::new(AdminToken::default()))
+                .service(web::resource("/ws/").to(socket_route))
+        })
+        .workers(1)
+        .listen_rustls(listener, config)
+        .unwrap()
+        .run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let response = actix_web::rt::task::spawn_blocking(move || {
+            let mut roots = rustls::RootCertStore::empty();
+            roots.add(&cert).unwrap();
+            let client = rustls::ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+            let name = rustls::ServerName::try_from("localhost").unwrap();
+            let conn = rustls::ClientConnection::new(Arc::new(client), name).unwrap();
+            let sock = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            let mut stream = rustls::StreamOwned::new(conn, sock);
+            stream
+                .write_all(
+                    b"GET /ws/ HTTP/1.1\r\n\
+                      Host: localhost\r\n\
+                      Connection: Upgrade\r\n\
+                      Upgrade: websocket\r\n\
+                      Sec-WebSocket-Version: 13\r\n\
+                      Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+                )
+                .unwrap();
+            let mut buf = [0u8; 1024];
+            let n = stream.read(&mut buf).unwrap();
+            String::from_utf8_lossy(&buf[..n]).into_owned()
+        })
+        .await
+        .unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 101"), "{}", response);
+        assert!(response.contains("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+        handle.stop(false).await;
+    }
+}
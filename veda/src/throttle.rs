@@ -0,0 +1,56 @@
+use std::time::Instant;
+
+/// token bucket limiting how many activities a session receives per second,
+/// bursts up to one second worth of activities
+pub struct Throttle {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Throttle {
+    /// `rate` activities per second, starts with a full bucket
+    pub fn new(rate: u32) -> Self {
+        Self {
+            rate: f64::from(rate),
+            tokens: f64::from(rate),
+            last: Instant::now(),
+        }
+    }
+
+    /// how many activities can be delivered at `now`
+    pub fn available(&mut self, now: Instant) -> usize {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last = now;
+        self.tokens as usize
+    }
+
+    /// spend tokens for `delivered` activities
+    pub fn take(&mut self, delivered: usize) {
+        self.tokens = (self.tokens - delivered as f64).max(0.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn refills_at_the_configured_rate() {
+        let mut throttle = Throttle::new(10);
+        let start = throttle.last;
+        assert_eq!(throttle.available(start), 10);
+        throttle.take(10);
+        assert_eq!(throttle.available(start), 0);
+
+        assert_eq!(throttle.available(start + Duration::from_millis(500)), 5);
+        throttle.take(3);
+        assert_eq!(throttle.available(start + Duration::from_millis(500)), 2);
+
+        // never more than one second worth of activities
+        assert_eq!(throttle.available(start + Duration::from_secs(60)), 10);
+    }
+}
@@ -0,0 +1,93 @@
+use std::fmt::Display;
+
+use chrono::Utc;
+use log::info;
+
+/// log target of the delivery hops, filter on it to reconstruct latencies
+pub const TARGET: &str = "veda::trace";
+
+/// activity pushed through grpc
+pub const PUSH_RECEIVED: &str = "push_received";
+/// activity written to the receiver's stream
+pub const XADD_DONE: &str = "xadd_done";
+/// activity read from the stream by a redis session
+pub const XREAD: &str = "xread";
+/// activity handed over to the websocket session
+pub const FRAME_SENT: &str = "frame_sent";
+
+pub fn new_correlation_id() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
+/// log one hop of an activity on its way to the client
+pub fn hop(correlation_id: &str, hop: &str, detail: impl Display) {
+    info!(
+        target: TARGET,
+        "correlation_id={} hop={} ts={} {}",
+        correlation_id,
+        hop,
+        Utc::now().timestamp_millis(),
+        detail
+    );
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::{Mutex, Once};
+
+    use log::{Level, Log, Metadata, Record};
+
+    use super::*;
+
+    lazy_static! {
+        static ref LINES: Mutex<Vec<String>> = Mutex::new(vec![]);
+    }
+
+    struct CaptureLogger;
+
+    impl Log for CaptureLogger {
+        fn enabled(&self, metadata: &Metadata) -> bool {
+            metadata.target() == TARGET && metadata.level() <= Level::Info
+        }
+
+        fn log(&self, record: &Record) {
+            if self.enabled(record.metadata()) {
+                LINES.lock().unwrap().push(record.args().to_string());
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    /// capture the trace lines logged by every test from now on
+    pub fn captured() -> Vec<String> {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| {
+            log::set_boxed_logger(Box::new(CaptureLogger)).unwrap();
+            log::set_max_level(log::LevelFilter::Info);
+        });
+        LINES.lock().unwrap().clone()
+    }
+
+    /// the hops logged for a correlation id, in order
+    pub fn hops_of(correlation_id: &str) -> Vec<String> {
+        let prefix = format!("correlation_id={} hop=", correlation_id);
+        captured()
+            .iter()
+            .filter_map(|line| line.strip_prefix(&prefix))
+            .filter_map(|rest| rest.split(' ').next())
+            .map(str::to_owned)
+            .collect()
+    }
+
+    #[test]
+    fn hops_are_logged_with_the_correlation_id() {
+        captured();
+        let correlation_id = new_correlation_id();
+        hop(&correlation_id, PUSH_RECEIVED, "receivers:[\"lyle\"]");
+        hop(&correlation_id, FRAME_SENT, "session:1");
+        hop(&new_correlation_id(), PUSH_RECEIVED, "receivers:[]");
+
+        assert_eq!(hops_of(&correlation_id), vec![PUSH_RECEIVED, FRAME_SENT]);
+    }
+}